@@ -0,0 +1,223 @@
+use core::mem::size_of;
+
+use spin::Once;
+
+use crate::memory::physical_to_virtual;
+
+// Just enough ACPI table walking to find the FADT for power management.
+// Reference: https://uefi.org/specs/ACPI/6.4/05_ACPI_Software_Programming_Model/ACPI_Software_Programming_Model.html
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// The RSDP lives on a 16 byte boundary in either the first KB of the EBDA or the BIOS ROM area
+const EBDA_POINTER: usize = 0x40E;
+const BIOS_ROM_AREA: (usize, usize) = (0xE0000, 0x100000);
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below are only valid for revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    // The whole table including the header, as mapped through the physical memory offset
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes().get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+// Offsets into the FADT ("FACP") table
+const FADT_DSDT: usize = 40;
+const FADT_PM1A_CONTROL_BLOCK: usize = 64;
+const FADT_PM1B_CONTROL_BLOCK: usize = 68;
+
+static RSDP: Once<Option<Rsdp>> = Once::new();
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn scan_for_rsdp(start: usize, end: usize) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|address| {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(physical_to_virtual(address) as *const u8, 20) };
+        if &bytes[..8] == RSDP_SIGNATURE && checksum_ok(bytes) {
+            Some(unsafe { (physical_to_virtual(address) as *const Rsdp).read_unaligned() })
+        } else {
+            None
+        }
+    })
+}
+
+fn rsdp() -> Option<Rsdp> {
+    *RSDP.call_once(|| {
+        // Some bootloaders (multiboot2) hand us a copy; otherwise go looking where the BIOS puts it
+        if let Some(address) = crate::boot::config().rsdp {
+            return scan_for_rsdp(address, address + 16);
+        }
+        let ebda = unsafe { (physical_to_virtual(EBDA_POINTER) as *const u16).read() } as usize;
+        let ebda = ebda << 4;
+        let from_ebda = if ebda != 0 {
+            scan_for_rsdp(ebda, ebda + 1024)
+        } else {
+            None
+        };
+        from_ebda.or_else(|| scan_for_rsdp(BIOS_ROM_AREA.0, BIOS_ROM_AREA.1))
+    })
+}
+
+// Safety-ish: ACPI tables are in firmware-reserved memory that we never hand out
+fn table_at(physical_address: usize) -> Option<&'static SdtHeader> {
+    if physical_address == 0 {
+        return None;
+    }
+    let header = unsafe { &*(physical_to_virtual(physical_address) as *const SdtHeader) };
+    if (header.length as usize) < size_of::<SdtHeader>() || !checksum_ok(header.bytes()) {
+        return None;
+    }
+    Some(header)
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let rsdp = rsdp()?;
+    // Prefer the XSDT (64 bit pointers) if the firmware provides one
+    let (root, pointer_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (table_at(rsdp.xsdt_address as usize)?, 8)
+    } else {
+        (table_at(rsdp.rsdt_address as usize)?, 4)
+    };
+    root.bytes()[size_of::<SdtHeader>()..]
+        .chunks_exact(pointer_size)
+        .map(|pointer| {
+            pointer
+                .iter()
+                .rev()
+                .fold(0usize, |address, b| address << 8 | *b as usize)
+        })
+        .filter_map(table_at)
+        .find(|table| &table.signature == signature)
+}
+
+pub fn fadt() -> Option<&'static SdtHeader> {
+    find_table(b"FACP")
+}
+
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    table_at(fadt()?.read_u32(FADT_DSDT)? as usize)
+}
+
+// AML opcodes needed to read the \\_S5 package
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+
+// One integer constant from an AML package, and the rest of the package after it
+fn aml_integer(aml: &[u8]) -> Option<(u16, &[u8])> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, &aml[1..])),
+        AML_ONE_OP => Some((1, &aml[1..])),
+        AML_BYTE_PREFIX => Some((*aml.get(1)? as u16, aml.get(2..)?)),
+        AML_WORD_PREFIX => Some((
+            u16::from_le_bytes(aml.get(1..3)?.try_into().ok()?),
+            aml.get(3..)?,
+        )),
+        _ => None,
+    }
+}
+
+// SLP_TYPa and SLP_TYPb from `Name(\_S5, Package() { a, b, ... })`. Rather than interpret the
+// whole DSDT we look for the name and decode the package right after it, which is how every
+// hobby OS does it, and is good enough for any firmware that defines \_S5 statically.
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let position = aml.windows(4).enumerate().position(|(i, name)| {
+        let preceded_by_name_op = match i {
+            0 => false,
+            1 => aml[0] == AML_NAME_OP,
+            _ => aml[i - 1] == AML_NAME_OP || (aml[i - 2..i] == [AML_NAME_OP, AML_ROOT_CHAR]),
+        };
+        name == b"_S5_" && preceded_by_name_op
+    })?;
+    let package = aml.get(position + 4..)?;
+    if *package.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: the top two bits of the lead byte count the bytes that follow it
+    let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+    // Then the element count
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (a, elements) = aml_integer(elements)?;
+    let (b, _) = aml_integer(elements)?;
+    Some((a, b))
+}
+
+// SLP_TYPa and SLP_TYPb values for entering S5 (soft off)
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    let dsdt = dsdt()?;
+    parse_s5(&dsdt.bytes()[size_of::<SdtHeader>()..])
+}
+
+// I/O ports of the PM1 control registers, used to enter sleep states
+pub fn pm1_control_blocks() -> Option<(u16, Option<u16>)> {
+    let fadt = fadt()?;
+    let pm1a = fadt.read_u32(FADT_PM1A_CONTROL_BLOCK)? as u16;
+    let pm1b = fadt
+        .read_u32(FADT_PM1B_CONTROL_BLOCK)
+        .map(|port| port as u16);
+    match pm1a {
+        0 => None,
+        _ => Some((pm1a, pm1b.filter(|port| *port != 0))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_s5() {
+        // Name(_S5, Package(4) { 5, 0, Zero, Zero }) as compiled by iasl, after some other AML
+        let aml = [
+            0x10, 0x05, b'_', b'S', b'B', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04,
+            0x0A, 0x05, 0x0A, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(parse_s5(&aml), Some((5, 0)));
+        // Rooted name and a two byte PkgLength
+        let aml = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x40, 0x01, 0x02, 0x01, 0x0B, 0x07, 0x00,
+        ];
+        assert_eq!(parse_s5(&aml), Some((1, 7)));
+        // Only a reference to \_S5, not its definition
+        assert_eq!(parse_s5(b"\x70_S5_\x60"), None);
+    }
+}
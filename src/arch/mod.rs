@@ -0,0 +1,29 @@
+// Architecture specific code, behind traits the rest of the kernel uses instead of reaching for
+// registers and instructions itself. A new architecture implements them on its own `Arch` type in
+// a sibling module, and memory management, the scheduler, filesystems and drivers that don't
+// need port I/O stay shared.
+//
+// Port I/O only exists on x86, so `port` is only exported there; drivers using it are x86 only.
+// TODO: only the memory API is behind Arch so far. Still to move: the GDT
+// (global_descriptor_table), the IDT and interrupt frames (interrupt::table and
+// interrupt::exception), task::switch, cpu feature setup, smp::ipi, and memory::fault's probe
+// routines. Page table entries are still x86's format.
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::{port, X86_64 as Arch};
+
+// The MMU, as memory management needs it
+pub trait Mmu {
+    // Invalidate this CPU's TLB entry for the page containing `address`
+    fn flush_page(address: usize);
+    // Invalidate all of this CPU's non-global TLB entries
+    fn flush_all();
+    // Physical address of the top level page table in use
+    fn page_table_root() -> usize;
+    // Have the MMU enforce read-only and no-execute pages in kernel mode as well as user mode.
+    // Unsafe since writes to pages already mapped read-only start faulting.
+    unsafe fn enforce_page_permissions();
+}
@@ -0,0 +1,38 @@
+use core::arch::asm;
+
+use ::x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Efer, EferFlags};
+
+use super::Mmu;
+
+pub mod port;
+
+pub struct X86_64;
+
+impl Mmu for X86_64 {
+    #[inline]
+    fn flush_page(address: usize) {
+        unsafe { asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags)) };
+    }
+
+    // By reloading cr3
+    fn flush_all() {
+        unsafe {
+            asm!(
+                "mov {0}, cr3",
+                "mov cr3, {0}",
+                out(reg) _,
+                options(nostack, preserves_flags)
+            )
+        };
+    }
+
+    fn page_table_root() -> usize {
+        Cr3::read().0.start_address().as_u64() as usize
+    }
+
+    // Without these, the WRITABLE/NO_EXECUTE bits are ignored for kernel-mode accesses
+    unsafe fn enforce_page_permissions() {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
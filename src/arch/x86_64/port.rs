@@ -0,0 +1,128 @@
+use core::arch::asm;
+use core::marker::PhantomData;
+
+// x86 I/O ports, typed by the width of the value they take and whether they can be read, written
+// or both. Reading and writing are unsafe: either can have any side effect the device likes.
+
+pub trait PortValue: Copy {
+    unsafe fn read_from(port: u16) -> Self;
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+// Rust inline asm reference: https://doc.rust-lang.org/nightly/reference/inline-assembly.html
+// IN/OUT instruction reference: https://www.felixcloutier.com/x86/in
+impl PortValue for u8 {
+    unsafe fn read_from(port: u16) -> u8 {
+        let value: u8;
+        asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u8) {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from(port: u16) -> u16 {
+        let value: u16;
+        asm!("in ax, dx", in("dx") port, out("ax") value, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u16) {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from(port: u16) -> u32 {
+        let value: u32;
+        asm!("in eax, dx", in("dx") port, out("eax") value, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u32) {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T: PortValue> {
+    number: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(number: u16) -> Self {
+        Port {
+            number,
+            value: PhantomData,
+        }
+    }
+
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    pub unsafe fn read(&self) -> T {
+        T::read_from(self.number)
+    }
+
+    pub unsafe fn write(&self, value: T) {
+        T::write_to(self.number, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyPort<T: PortValue> {
+    number: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> ReadOnlyPort<T> {
+    pub const fn new(number: u16) -> Self {
+        ReadOnlyPort {
+            number,
+            value: PhantomData,
+        }
+    }
+
+    pub unsafe fn read(&self) -> T {
+        T::read_from(self.number)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOnlyPort<T: PortValue> {
+    number: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> WriteOnlyPort<T> {
+    pub const fn new(number: u16) -> Self {
+        WriteOnlyPort {
+            number,
+            value: PhantomData,
+        }
+    }
+
+    pub unsafe fn write(&self, value: T) {
+        T::write_to(self.number, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_port_round_trip() {
+        // COM1's scratch register holds whatever's written to it, and nothing else uses it
+        let scratch = Port::<u8>::new(0x3FF);
+        unsafe {
+            scratch.write(0x5a);
+            assert_eq!(scratch.read(), 0x5a);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use core::arch::asm;
+use core::fmt;
+
+use crate::memory;
+use crate::memory::page_table::PageTableFlags;
+use crate::symbols::Symbolized;
+use crate::unwind;
+
+// Stack traces. Backtrace::capture unwinds using .eh_frame (see unwind.rs) when it can, and
+// otherwise walks the saved frame pointer chain. The latter relies on the kernel being built with
+// `-C force-frame-pointers=yes` (see .cargo/config.toml), so every frame starts with
+// [saved rbp, return address], but it's cheap and works before the kernel image is found, so
+// it's what capture_into uses.
+
+const MAX_FRAMES: usize = 32;
+
+// Return addresses captured without allocating, so it's usable from panics and exceptions
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+fn is_mapped(address: u64) -> bool {
+    matches!(memory::page_flags(address as usize), Ok(flags) if flags.contains(PageTableFlags::PRESENT))
+}
+
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let frame_pointer: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags))
+    };
+    frame_pointer
+}
+
+// Fill `frames` with return addresses starting from `frame_pointer`, returning how many we found
+pub fn walk(mut frame_pointer: u64, frames: &mut [u64]) -> usize {
+    let mut len = 0;
+    while len < frames.len() {
+        // Stop at the end of the chain, or anything that doesn't look like a frame
+        if frame_pointer == 0
+            || frame_pointer % 8 != 0
+            || !is_mapped(frame_pointer)
+            || !is_mapped(frame_pointer + 8)
+        {
+            break;
+        }
+        let frame = frame_pointer as *const u64;
+        let (caller_frame_pointer, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        frames[len] = return_address;
+        len += 1;
+        // Stacks grow down, so callers' frames are always above ours. Anything else is a loop.
+        if caller_frame_pointer <= frame_pointer {
+            break;
+        }
+        frame_pointer = caller_frame_pointer;
+    }
+    len
+}
+
+// Like Backtrace::capture, but only as many frames as fit in `frames`
+#[inline(always)]
+pub fn capture_into(frames: &mut [u64]) -> usize {
+    walk(frame_pointer(), frames)
+}
+
+impl Backtrace {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut frames = [0; MAX_FRAMES];
+        let len = match unwind::walk(unwind::Registers::capture(), &mut frames) {
+            0 => walk(frame_pointer(), &mut frames),
+            len => len,
+        };
+        Backtrace { frames, len }
+    }
+
+    pub fn from_frame_pointer(frame_pointer: u64) -> Self {
+        let mut frames = [0; MAX_FRAMES];
+        let len = walk(frame_pointer, &mut frames);
+        Backtrace { frames, len }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (i, address) in self.frames().iter().enumerate() {
+            write!(f, "\n  {:>2}: {}", i, Symbolized(*address))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn capture_from_callee() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    #[test_case]
+    fn test_backtrace_includes_caller() {
+        let backtrace = capture_from_callee();
+        let caller = crate::symbols::lookup(backtrace.frames()[0]).expect("caller not found");
+        assert!(caller.name.contains("test_backtrace_includes_caller"));
+    }
+}
@@ -0,0 +1,101 @@
+use core::fmt;
+
+use spin::Once;
+
+pub mod protocol;
+
+pub use protocol::{
+    BootProtocol, Cmdline, FramebufferInfo, MemoryMap, MemoryRegion, Module, RegionKind,
+    MAX_MODULES,
+};
+
+// Everything we learn from the bootloader, captured exactly once at the start of `init` and
+// read-only after that. Copied out of the bootloader's own structures through a BootProtocol
+// adapter, so the rest of the kernel doesn't care which bootloader started it. Nothing in here
+// points back into the bootloader's memory, so it can be reclaimed or unmapped once we're up.
+
+#[derive(Debug)]
+pub struct KernelConfig {
+    // Which boot protocol we came up through
+    pub protocol: &'static str,
+    // All of physical memory is mapped starting at this virtual address
+    pub physical_memory_offset: usize,
+    pub memory_map: MemoryMap,
+    pub framebuffer: Option<FramebufferInfo>,
+    pub modules: [Option<Module>; MAX_MODULES],
+    pub cmdline: Cmdline,
+    // Physical address of the ACPI RSDP, if the bootloader passed it
+    pub rsdp: Option<usize>,
+}
+
+static CONFIG: Once<KernelConfig> = Once::new();
+
+pub fn init(protocol: &dyn BootProtocol) -> &'static KernelConfig {
+    assert!(!CONFIG.is_completed(), "boot::init called twice");
+    CONFIG.call_once(|| KernelConfig {
+        protocol: protocol.name(),
+        physical_memory_offset: protocol.physical_memory_offset(),
+        memory_map: protocol.memory_map(),
+        framebuffer: protocol.framebuffer(),
+        modules: protocol.modules(),
+        cmdline: Cmdline::new(protocol.cmdline()),
+        rsdp: protocol.rsdp(),
+    })
+}
+
+pub fn config() -> &'static KernelConfig {
+    CONFIG.get().expect("Kernel config read before boot::init")
+}
+
+// Shortcuts for the parts subsystems initialized later usually want
+
+pub fn memory_map() -> &'static MemoryMap {
+    &config().memory_map
+}
+
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    config().framebuffer
+}
+
+pub fn cmdline() -> &'static str {
+    config().cmdline.as_str()
+}
+
+// The boot info as text, for /proc/boot
+pub struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = config();
+        writeln!(f, "protocol {}", config.protocol)?;
+        writeln!(f, "cmdline {}", config.cmdline.as_str())?;
+        writeln!(
+            f,
+            "physical_memory_offset {:#x}",
+            config.physical_memory_offset
+        )?;
+        match config.framebuffer {
+            Some(fb) => writeln!(
+                f,
+                "framebuffer {:#x} {}x{} stride {} bpp {}",
+                fb.address,
+                fb.width,
+                fb.height,
+                fb.stride,
+                fb.bytes_per_pixel * 8
+            )?,
+            None => writeln!(f, "framebuffer none")?,
+        }
+        for module in config.modules.iter().flatten() {
+            writeln!(f, "module {:#x}..{:#x}", module.start, module.end)?;
+        }
+        for region in config.memory_map.iter() {
+            writeln!(
+                f,
+                "region {:#x}..{:#x} {:?}",
+                region.start, region.end, region.kind
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use core::fmt;
+use core::ops::Range;
+
+use crate::memory::PAGE_SIZE;
+
+pub mod multiboot2;
+pub mod rust_bootloader;
+
+// What a bootloader tells us, whichever one it is. Each boot protocol has an adapter turning its
+// own structures into these, so nothing past boot::init depends on one bootloader's layout.
+//
+// Everything here is copied out (see boot::KernelConfig) before the heap and page allocators
+// start handing out memory, since the bootloader's structures may be sitting in usable RAM, and
+// may not stay mapped once the kernel manages its own address space.
+
+// Upper bounds, so that the copies don't need a heap
+pub const MAX_REGIONS: usize = 64;
+pub const MAX_MODULES: usize = 8;
+// Bytes of command line kept, the rest is dropped
+pub const MAX_CMDLINE: usize = 256;
+
+pub trait BootProtocol {
+    fn name(&self) -> &'static str;
+    // All of physical memory is mapped starting at this virtual address
+    fn physical_memory_offset(&self) -> usize;
+    fn memory_map(&self) -> MemoryMap;
+    fn framebuffer(&self) -> Option<FramebufferInfo>;
+    // Files loaded alongside the kernel, eg. an initrd
+    fn modules(&self) -> [Option<Module>; MAX_MODULES];
+    fn cmdline(&self) -> &'static str;
+    // Physical address of the ACPI RSDP, if the bootloader found it for us
+    fn rsdp(&self) -> Option<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub address: usize,
+    pub width: usize,
+    pub height: usize,
+    // In pixels
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+}
+
+// Physical address range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    // Free for the page allocator
+    Usable,
+    // The kernel image
+    Kernel,
+    // The page tables the bootloader built, which we're still running on
+    PageTable,
+    // The stack the kernel was entered on
+    BootStack,
+    // Anything else the bootloader set up for us, eg. the boot info itself
+    Bootloader,
+    Module,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    Reserved,
+}
+
+// Physical address range, page aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn frames(&self) -> Range<usize> {
+        self.start / PAGE_SIZE..self.end / PAGE_SIZE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    TooManyRegions,
+}
+
+// Regions in address order, not overlapping
+#[derive(Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        const EMPTY: MemoryRegion = MemoryRegion {
+            start: 0,
+            end: 0,
+            kind: RegionKind::Reserved,
+        };
+        MemoryMap {
+            regions: [EMPTY; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.len].iter()
+    }
+
+    // Usable regions are shrunk to whole pages; anything else grows to cover its partial pages
+    pub fn push(&mut self, start: usize, end: usize, kind: RegionKind) -> Result<(), Err> {
+        let (start, end) = match kind {
+            RegionKind::Usable => (start.next_multiple_of(PAGE_SIZE), end & !(PAGE_SIZE - 1)),
+            _ => (start & !(PAGE_SIZE - 1), end.next_multiple_of(PAGE_SIZE)),
+        };
+        if start >= end {
+            return Ok(());
+        }
+        if self.len == MAX_REGIONS {
+            return Err(Err::TooManyRegions);
+        }
+        let index = self.regions[..self.len].partition_point(|r| r.start < start);
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = MemoryRegion { start, end, kind };
+        self.len += 1;
+        Ok(())
+    }
+
+    // Take `range` out of any usable regions it overlaps, as `kind`. For protocols whose map
+    // doesn't mark what the bootloader loaded (eg. multiboot2 leaves the kernel in usable RAM).
+    pub fn carve(&mut self, range: Range<usize>, kind: RegionKind) -> Result<(), Err> {
+        let (start, end) = (
+            range.start & !(PAGE_SIZE - 1),
+            range.end.next_multiple_of(PAGE_SIZE),
+        );
+        let mut index = 0;
+        while index < self.len {
+            let region = self.regions[index];
+            if region.kind != RegionKind::Usable || region.end <= start || end <= region.start {
+                index += 1;
+                continue;
+            }
+            self.regions.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+            self.push(region.start, start.max(region.start), RegionKind::Usable)?;
+            self.push(start.max(region.start), end.min(region.end), kind)?;
+            self.push(end.min(region.end), region.end, RegionKind::Usable)?;
+            // Whatever's at `index` now is either what we pushed or the next region
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// A copy of the command line, so it doesn't borrow from the bootloader's memory
+#[derive(Clone, Copy)]
+pub struct Cmdline {
+    bytes: [u8; MAX_CMDLINE],
+    len: usize,
+}
+
+impl Cmdline {
+    // Truncated to MAX_CMDLINE bytes, on a character boundary
+    pub fn new(cmdline: &str) -> Self {
+        let mut len = cmdline.len().min(MAX_CMDLINE);
+        while !cmdline.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; MAX_CMDLINE];
+        bytes[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        Cmdline { bytes, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Safety: copied from a str, cut on a character boundary
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+impl fmt::Debug for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_push_sorts_and_aligns() {
+        let mut map = MemoryMap::new();
+        map.push(0x10_0000, 0x20_0000, RegionKind::Usable).unwrap();
+        map.push(0x100, 0x9_f123, RegionKind::Usable).unwrap();
+        map.push(0xe_0010, 0xf_ff00, RegionKind::Reserved).unwrap();
+        let regions: Vec<_> = map.iter().map(|r| (r.start, r.end, r.kind)).collect();
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x9_f000, RegionKind::Usable),
+                (0xe_0000, 0x10_0000, RegionKind::Reserved),
+                (0x10_0000, 0x20_0000, RegionKind::Usable),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn test_cmdline_copy() {
+        assert_eq!(Cmdline::new("console=serial").as_str(), "console=serial");
+        let long = alloc::format!("{}é", "x".repeat(MAX_CMDLINE - 1));
+        assert_eq!(Cmdline::new(&long).as_str(), &long[..MAX_CMDLINE - 1]);
+    }
+
+    #[test_case]
+    fn test_carve() {
+        let mut map = MemoryMap::new();
+        map.push(0x1000, 0x9_f000, RegionKind::Usable).unwrap();
+        map.push(0x10_0000, 0x80_0000, RegionKind::Usable).unwrap();
+        map.carve(0x20_0000..0x30_0800, RegionKind::Kernel).unwrap();
+        let regions: Vec<_> = map.iter().map(|r| (r.start, r.end, r.kind)).collect();
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x9_f000, RegionKind::Usable),
+                (0x10_0000, 0x20_0000, RegionKind::Usable),
+                (0x20_0000, 0x30_1000, RegionKind::Kernel),
+                (0x30_1000, 0x80_0000, RegionKind::Usable),
+            ]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use core::ops::Range;
+
+use super::{BootProtocol, FramebufferInfo, MemoryMap, Module, RegionKind, MAX_MODULES};
+
+// The multiboot2 boot information structure, as GRUB (or Limine, which speaks multiboot2 too)
+// leaves it: a header, then a list of 8 byte aligned tags, ending with a tag of type 0.
+// Reference: https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+//
+// TODO: there's no entry point for it yet. Multiboot2 starts us in 32 bit protected mode, so it
+// needs a stub that sets up long mode and maps physical memory before handing us the structure.
+// The stub also needs to tell us where the kernel was loaded, since multiboot2's memory map
+// doesn't say.
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_RSDP_V1: u32 = 14;
+const TAG_RSDP_V2: u32 = 15;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_ACPI_NVS: u32 = 4;
+const MEMORY_BAD: u32 = 5;
+
+// Framebuffer type 1 is direct RGB; 0 is indexed and 2 is EGA text, which we can't draw pixels to
+const FRAMEBUFFER_RGB: u8 = 1;
+
+pub struct Multiboot2 {
+    // Physical address of the boot information structure
+    info: usize,
+    physical_memory_offset: usize,
+    kernel: Range<usize>,
+}
+
+struct Tag {
+    kind: u32,
+    // Physical address of the tag, header included
+    address: usize,
+    size: usize,
+}
+
+impl Multiboot2 {
+    // Safety: `info` must be the structure the bootloader handed us, with all of physical memory
+    // mapped at `physical_memory_offset`. `kernel` is the kernel image's physical range.
+    pub unsafe fn new(info: usize, physical_memory_offset: usize, kernel: Range<usize>) -> Self {
+        Multiboot2 {
+            info,
+            physical_memory_offset,
+            kernel,
+        }
+    }
+
+    fn read<T: Copy>(&self, address: usize) -> T {
+        unsafe { ((address + self.physical_memory_offset) as *const T).read_unaligned() }
+    }
+
+    fn total_size(&self) -> usize {
+        self.read::<u32>(self.info) as usize
+    }
+
+    fn tags(&self) -> impl Iterator<Item = Tag> + '_ {
+        let end = self.info + self.total_size();
+        let mut address = self.info + 8;
+        core::iter::from_fn(move || {
+            if address + 8 > end {
+                return None;
+            }
+            let tag = Tag {
+                kind: self.read(address),
+                address,
+                size: self.read::<u32>(address + 4) as usize,
+            };
+            if tag.kind == TAG_END || tag.size < 8 {
+                return None;
+            }
+            address += tag.size.next_multiple_of(8);
+            Some(tag)
+        })
+    }
+
+    fn tag(&self, kind: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.kind == kind)
+    }
+
+    // A NUL terminated string starting at `address`, within `end`
+    fn string(&self, address: usize, end: usize) -> &'static str {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (address + self.physical_memory_offset) as *const u8,
+                end.saturating_sub(address),
+            )
+        };
+        let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..length]).unwrap_or("")
+    }
+}
+
+impl BootProtocol for Multiboot2 {
+    fn name(&self) -> &'static str {
+        "multiboot2"
+    }
+
+    fn physical_memory_offset(&self) -> usize {
+        self.physical_memory_offset
+    }
+
+    // Multiboot2 only describes RAM and firmware regions, so everything the bootloader put in
+    // RAM for us is carved back out of it
+    fn memory_map(&self) -> MemoryMap {
+        let mut map = MemoryMap::new();
+        if let Some(tag) = self.tag(TAG_MEMORY_MAP) {
+            let entry_size = self.read::<u32>(tag.address + 8) as usize;
+            let entries = (tag.address + 16..tag.address + tag.size).step_by(entry_size.max(24));
+            for entry in entries {
+                let base = self.read::<u64>(entry) as usize;
+                let length = self.read::<u64>(entry + 8) as usize;
+                let kind = match self.read::<u32>(entry + 16) {
+                    MEMORY_AVAILABLE => RegionKind::Usable,
+                    MEMORY_ACPI_RECLAIMABLE => RegionKind::AcpiReclaimable,
+                    MEMORY_ACPI_NVS => RegionKind::AcpiNvs,
+                    MEMORY_BAD => RegionKind::BadMemory,
+                    _ => RegionKind::Reserved,
+                };
+                map.push(base, base + length, kind)
+                    .expect("The multiboot2 memory map has too many regions");
+            }
+        }
+        let mut carve = |range: Range<usize>, kind| {
+            map.carve(range, kind)
+                .expect("The multiboot2 memory map has too many regions");
+        };
+        carve(self.kernel.clone(), RegionKind::Kernel);
+        carve(
+            self.info..self.info + self.total_size(),
+            RegionKind::Bootloader,
+        );
+        for module in self.modules().into_iter().flatten() {
+            carve(module.start..module.end, RegionKind::Module);
+        }
+        map
+    }
+
+    fn framebuffer(&self) -> Option<FramebufferInfo> {
+        let tag = self.tag(TAG_FRAMEBUFFER)?;
+        if self.read::<u8>(tag.address + 29) != FRAMEBUFFER_RGB {
+            return None;
+        }
+        let bytes_per_pixel = (self.read::<u8>(tag.address + 28) as usize).div_ceil(8);
+        let pitch = self.read::<u32>(tag.address + 16) as usize;
+        Some(FramebufferInfo {
+            address: self.read::<u64>(tag.address + 8) as usize,
+            width: self.read::<u32>(tag.address + 20) as usize,
+            height: self.read::<u32>(tag.address + 24) as usize,
+            stride: pitch / bytes_per_pixel.max(1),
+            bytes_per_pixel,
+        })
+    }
+
+    fn modules(&self) -> [Option<Module>; MAX_MODULES] {
+        let mut modules = [None; MAX_MODULES];
+        let tags = self.tags().filter(|tag| tag.kind == TAG_MODULE);
+        for (module, tag) in modules.iter_mut().zip(tags) {
+            *module = Some(Module {
+                start: self.read::<u32>(tag.address + 8) as usize,
+                end: self.read::<u32>(tag.address + 12) as usize,
+            });
+        }
+        modules
+    }
+
+    fn cmdline(&self) -> &'static str {
+        match self.tag(TAG_CMDLINE) {
+            Some(tag) => self.string(tag.address + 8, tag.address + tag.size),
+            None => "",
+        }
+    }
+
+    // The tag holds a copy of the RSDP itself
+    fn rsdp(&self) -> Option<usize> {
+        self.tag(TAG_RSDP_V2)
+            .or_else(|| self.tag(TAG_RSDP_V1))
+            .map(|tag| tag.address + 8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Tags are padded to 8 bytes
+    fn push_tag(info: &mut Vec<u8>, kind: u32, body: &[u8]) {
+        info.extend_from_slice(&kind.to_ne_bytes());
+        info.extend_from_slice(&(8 + body.len() as u32).to_ne_bytes());
+        info.extend_from_slice(body);
+        while info.len() % 8 != 0 {
+            info.push(0);
+        }
+    }
+
+    #[test_case]
+    fn test_parse_info() {
+        let mut info = Vec::from([0u8; 8]);
+        push_tag(&mut info, TAG_CMDLINE, b"console=serial\0");
+        let mut memory_map = Vec::new();
+        memory_map.extend_from_slice(&24u32.to_ne_bytes());
+        memory_map.extend_from_slice(&0u32.to_ne_bytes());
+        for (base, length, kind) in [(0u64, 0x9_f000u64, 1u32), (0x10_0000, 0x70_0000, 1)] {
+            memory_map.extend_from_slice(&base.to_ne_bytes());
+            memory_map.extend_from_slice(&length.to_ne_bytes());
+            memory_map.extend_from_slice(&kind.to_ne_bytes());
+            memory_map.extend_from_slice(&0u32.to_ne_bytes());
+        }
+        push_tag(&mut info, TAG_MEMORY_MAP, &memory_map);
+        let mut module = Vec::new();
+        module.extend_from_slice(&0x40_0000u32.to_ne_bytes());
+        module.extend_from_slice(&0x40_2000u32.to_ne_bytes());
+        module.extend_from_slice(b"initrd\0");
+        push_tag(&mut info, TAG_MODULE, &module);
+        push_tag(&mut info, TAG_END, &[]);
+        let size = info.len() as u32;
+        info[..4].copy_from_slice(&size.to_ne_bytes());
+
+        // Treat the buffer's virtual address as physical address 0x1000 in an identity-ish map
+        let physical = 0x1000;
+        let offset = info.as_ptr() as usize - physical;
+        let boot = unsafe { Multiboot2::new(physical, offset, 0x20_0000..0x30_0000) };
+        assert_eq!(boot.cmdline(), "console=serial");
+        assert_eq!(
+            boot.modules()[0],
+            Some(Module {
+                start: 0x40_0000,
+                end: 0x40_2000
+            })
+        );
+        assert_eq!(boot.framebuffer(), None);
+        assert_eq!(boot.rsdp(), None);
+        let regions: Vec<_> = boot
+            .memory_map()
+            .iter()
+            .map(|r| (r.start, r.end, r.kind))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0, 0x1000, RegionKind::Usable),
+                (0x1000, 0x2000, RegionKind::Bootloader),
+                (0x2000, 0x9_f000, RegionKind::Usable),
+                (0x10_0000, 0x20_0000, RegionKind::Usable),
+                (0x20_0000, 0x30_0000, RegionKind::Kernel),
+                (0x30_0000, 0x40_0000, RegionKind::Usable),
+                (0x40_0000, 0x40_2000, RegionKind::Module),
+                (0x40_2000, 0x80_0000, RegionKind::Usable),
+            ]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
+
+use super::{BootProtocol, FramebufferInfo, MemoryMap, Module, RegionKind, MAX_MODULES};
+
+// The bootloader crate (0.9), which is what we boot with today. It maps all of physical memory
+// for us (the map_physical_memory feature), but leaves us in VGA text mode and has no way to
+// pass a command line or modules, so the command line is baked in at build time from SOS_CMDLINE.
+
+fn kind(region_type: MemoryRegionType) -> Option<RegionKind> {
+    use MemoryRegionType::*;
+    Some(match region_type {
+        Usable => RegionKind::Usable,
+        Kernel => RegionKind::Kernel,
+        PageTable => RegionKind::PageTable,
+        KernelStack => RegionKind::BootStack,
+        InUse | Bootloader | BootInfo => RegionKind::Bootloader,
+        Package => RegionKind::Module,
+        AcpiReclaimable => RegionKind::AcpiReclaimable,
+        AcpiNvs => RegionKind::AcpiNvs,
+        BadMemory => RegionKind::BadMemory,
+        Empty => return None,
+        _ => RegionKind::Reserved,
+    })
+}
+
+impl BootProtocol for BootInfo {
+    fn name(&self) -> &'static str {
+        "bootloader 0.9"
+    }
+
+    fn physical_memory_offset(&self) -> usize {
+        self.physical_memory_offset as usize
+    }
+
+    fn memory_map(&self) -> MemoryMap {
+        let mut map = MemoryMap::new();
+        for region in self.memory_map.iter() {
+            if let Some(kind) = kind(region.region_type) {
+                let (start, end) = (region.range.start_addr(), region.range.end_addr());
+                map.push(start as usize, end as usize, kind)
+                    .expect("The bootloader's memory map has more regions than ours");
+            }
+        }
+        map
+    }
+
+    fn framebuffer(&self) -> Option<FramebufferInfo> {
+        None
+    }
+
+    fn modules(&self) -> [Option<Module>; MAX_MODULES] {
+        [None; MAX_MODULES]
+    }
+
+    fn cmdline(&self) -> &'static str {
+        option_env!("SOS_CMDLINE").unwrap_or("")
+    }
+
+    // ACPI finds it by scanning the BIOS areas instead
+    fn rsdp(&self) -> Option<usize> {
+        None
+    }
+}
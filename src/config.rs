@@ -0,0 +1,177 @@
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::println;
+
+// Kernel tunables in one place. Most have a built in default that the kernel command line can
+// override, eg. `heap_size=256k tick_hz=1000 log_level=warn`, read once during boot (see
+// sos::init) and fixed after. The rest size static arrays, so can only be changed here and
+// rebuilt. All of them are listed in /proc/config.
+// TODO: scrollback lines and a scheduler quantum, once there's console scrollback and preemption
+
+// Task stacks, which come from a static pool (see task::stack)
+pub const STACK_SIZE: usize = 16 * 1024;
+// The double fault handler's stack, see global_descriptor_table
+pub const INTERRUPT_STACK_SIZE: usize = 5 * 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tunable {
+    // Bytes of kernel heap, mapped at boot
+    HeapSize,
+    // Timer interrupts per second, see drivers::pit
+    TickHz,
+    // The most verbose serial::Level logged, as a number: 0 (errors only) to 3 (debug)
+    LogLevel,
+    // 1 for RTS/CTS flow control on the serial ports
+    SerialFlowControl,
+}
+
+const TUNABLES: usize = 4;
+
+struct Entry {
+    name: &'static str,
+    default: usize,
+    range: RangeInclusive<usize>,
+    parse: fn(&str) -> Option<usize>,
+}
+
+const ENTRIES: [Entry; TUNABLES] = [
+    Entry {
+        name: "heap_size",
+        default: 100 * 1024,
+        range: 64 * 1024..=64 * 1024 * 1024,
+        parse: parse_size,
+    },
+    Entry {
+        name: "tick_hz",
+        default: 100,
+        range: 19..=10_000,
+        parse: parse_number,
+    },
+    Entry {
+        name: "log_level",
+        default: 3,
+        range: 0..=3,
+        parse: parse_level,
+    },
+    Entry {
+        name: "serial_flow_control",
+        default: 0,
+        range: 0..=1,
+        parse: parse_number,
+    },
+];
+
+const UNSET: AtomicUsize = AtomicUsize::new(usize::MAX);
+// usize::MAX until overridden
+static VALUES: [AtomicUsize; TUNABLES] = [UNSET; TUNABLES];
+
+fn parse_number(s: &str) -> Option<usize> {
+    s.parse().ok()
+}
+
+// A number of bytes, optionally with a k or m suffix
+fn parse_size(s: &str) -> Option<usize> {
+    let (number, multiplier) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 1024),
+        b'm' | b'M' => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+    parse_number(number)?.checked_mul(multiplier)
+}
+
+fn parse_level(s: &str) -> Option<usize> {
+    match s {
+        "error" => Some(0),
+        "warn" => Some(1),
+        "info" => Some(2),
+        "debug" => Some(3),
+        _ => parse_number(s),
+    }
+}
+
+pub fn get(tunable: Tunable) -> usize {
+    match VALUES[tunable as usize].load(Ordering::Relaxed) {
+        usize::MAX => ENTRIES[tunable as usize].default,
+        value => value,
+    }
+}
+
+// Take overrides from the kernel command line, ignoring options that aren't tunables. Before
+// anything reads them, ie. before memory::init.
+pub fn init(cmdline: &str) {
+    for option in cmdline.split_whitespace() {
+        let (name, value) = match option.split_once('=') {
+            Some(option) => option,
+            None => continue,
+        };
+        let index = match ENTRIES.iter().position(|entry| entry.name == name) {
+            Some(index) => index,
+            None => continue,
+        };
+        let entry = &ENTRIES[index];
+        match (entry.parse)(value) {
+            Some(value) if entry.range.contains(&value) => {
+                VALUES[index].store(value, Ordering::Relaxed)
+            }
+            _ => println!(
+                "config: ignoring {}={}, expected {}..={}",
+                name,
+                value,
+                entry.range.start(),
+                entry.range.end()
+            ),
+        }
+    }
+}
+
+// Every tunable and its value, as listed in /proc/config
+pub struct Table;
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, entry) in ENTRIES.iter().enumerate() {
+            match VALUES[index].load(Ordering::Relaxed) {
+                usize::MAX => writeln!(f, "{} {} (default)", entry.name, entry.default)?,
+                value => writeln!(f, "{} {} (default {})", entry.name, value, entry.default)?,
+            }
+        }
+        writeln!(f, "stack_size {} (built in)", STACK_SIZE)?;
+        writeln!(
+            f,
+            "interrupt_stack_size {} (built in)",
+            INTERRUPT_STACK_SIZE
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_values() {
+        assert_eq!(parse_size("256k"), Some(256 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_level("warn"), Some(1));
+        assert_eq!(parse_level("2"), Some(2));
+        assert_eq!(parse_level("loud"), None);
+    }
+
+    #[test_case]
+    fn test_cmdline_overrides() {
+        let value = &VALUES[Tunable::LogLevel as usize];
+        let saved = value.load(Ordering::Relaxed);
+        let before = get(Tunable::LogLevel);
+        // Out of range, and not a tunable
+        init("log_level=9 console=serial tick_hz");
+        assert_eq!(get(Tunable::LogLevel), before);
+        init("quiet log_level=error");
+        assert_eq!(get(Tunable::LogLevel), 0);
+        value.store(saved, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,126 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::line::Input;
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+
+// A kernel clipboard for console text. Ctrl+Shift+C starts selecting lines of the screen, from
+// the one above the cursor; up and down grow and shrink the selection, Enter or Ctrl+Shift+C
+// again copies it, and anything else cancels. Ctrl+Shift+V pastes, feeding the clipboard back
+// in as if it were typed.
+
+static CLIPBOARD: Mutex<String> = Mutex::new(String::new());
+
+// Output is always written to the bottom row, so that's where the cursor is
+const CURSOR_ROW: usize = BUFFER_HEIGHT - 1;
+
+pub fn copy(text: &str) {
+    crate::without_interrupt! {{
+        let mut clipboard = CLIPBOARD.lock();
+        clipboard.clear();
+        clipboard.push_str(text);
+    }}
+}
+
+pub fn contents() -> String {
+    crate::without_interrupt! {{
+        CLIPBOARD.lock().clone()
+    }}
+}
+
+// Rows reserved at the top (the status bar) can't be selected
+fn first_row() -> usize {
+    crate::without_interrupt! {{
+        WRITER.lock().first_row()
+    }}
+}
+
+// Highlighted screen rows, from `top` down to just above the cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    top: usize,
+}
+
+impl Selection {
+    pub fn start() -> Self {
+        let selection = Selection {
+            top: CURSOR_ROW - 1,
+        };
+        selection.highlight();
+        selection
+    }
+
+    fn rows(&self) -> core::ops::Range<usize> {
+        self.top..CURSOR_ROW
+    }
+
+    // Toggles, so calling it again removes the highlight
+    fn highlight(&self) {
+        crate::without_interrupt! {{
+            let mut writer = WRITER.lock();
+            self.rows().for_each(|row| writer.invert_row(row));
+        }}
+    }
+
+    // The selected rows without their trailing blanks, one per line
+    pub fn text(&self) -> String {
+        let rows: Vec<[u8; BUFFER_WIDTH]> = crate::without_interrupt! {{
+            let writer = WRITER.lock();
+            self.rows().map(|row| writer.row(row)).collect()
+        }};
+        let mut text = String::new();
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                text.push('\n');
+            }
+            let line: String = row.iter().map(|&byte| byte as char).collect();
+            text.push_str(line.trim_end());
+        }
+        text
+    }
+
+    // Returns the selection if it's still going
+    pub fn input(mut self, input: Input) -> Option<Self> {
+        self.highlight();
+        match input {
+            Input::HistoryPrevious => self.top = self.top.saturating_sub(1).max(first_row()),
+            Input::HistoryNext => self.top = (self.top + 1).min(CURSOR_ROW - 1),
+            Input::Enter | Input::Copy => {
+                copy(&self.text());
+                return None;
+            }
+            _ => return None,
+        }
+        self.highlight();
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn test_select_and_copy() {
+        crate::without_interrupt! {{
+            write!(WRITER.lock(), "\nfirst\nsecond\n").unwrap();
+        }}
+        let selection = Selection::start();
+        assert_eq!(selection.text(), "second");
+        let selection = selection.input(Input::HistoryPrevious).unwrap();
+        assert_eq!(selection.text(), "first\nsecond");
+        assert_eq!(selection.input(Input::Copy), None);
+        assert_eq!(contents(), "first\nsecond");
+    }
+
+    #[test_case]
+    fn test_selection_cancelled() {
+        copy("kept");
+        let selection = Selection::start();
+        assert_eq!(selection.input(Input::Escape), None);
+        assert_eq!(contents(), "kept");
+    }
+}
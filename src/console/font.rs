@@ -0,0 +1,213 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::fs::fd;
+use crate::fs::file;
+use crate::println;
+
+// PC Screen Font version 2, the format Linux console fonts come in (eg. Lat2-Terminus16.psfu,
+// or ter-132n for 16x32 on high resolution screens). A header, then every glyph as a bitmap of
+// rows, each row padded to whole bytes with the leftmost pixel in the top bit, then optionally
+// a table mapping characters to glyphs.
+// Reference: https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+//
+// TODO: there's no framebuffer console to draw with these yet; the VGA text console uses the
+// font built into the card. It should take its rows and columns from Font::grid.
+
+const MAGIC: u32 = 0x864a_b572;
+const HEADER_SIZE: usize = 32;
+const HAS_UNICODE_TABLE: u32 = 1;
+// In the unicode table, ends a glyph's entry, and starts a multi-character sequence
+const TABLE_SEPARATOR: u8 = 0xff;
+const TABLE_SEQUENCE: u8 = 0xfe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    NotPsf2,
+    Truncated,
+    File(file::Err),
+}
+
+impl From<file::Err> for Err {
+    fn from(err: file::Err) -> Self {
+        Err::File(err)
+    }
+}
+
+pub struct Font {
+    data: Vec<u8>,
+    glyphs_offset: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    pub width: usize,
+    pub height: usize,
+    // Empty if the font has no table, in which case characters index glyphs directly
+    unicode: BTreeMap<char, usize>,
+}
+
+pub struct Glyph<'a> {
+    bitmap: &'a [u8],
+    width: usize,
+}
+
+impl Glyph<'_> {
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let row = &self.bitmap[y * self.width.div_ceil(8)..];
+        row[x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl Font {
+    pub fn parse(data: Vec<u8>) -> Result<Self, Err> {
+        if data.len() < HEADER_SIZE || read_u32(&data, 0) != MAGIC {
+            return Err(Err::NotPsf2);
+        }
+        let header = |field: usize| read_u32(&data, 8 + 4 * field) as usize;
+        let (glyphs_offset, flags, glyph_count) = (header(0), header(1), header(2));
+        let (bytes_per_glyph, height, width) = (header(3), header(4), header(5));
+        if bytes_per_glyph < height * width.div_ceil(8) {
+            return Err(Err::NotPsf2);
+        }
+        let glyphs_end = glyphs_offset + glyph_count * bytes_per_glyph;
+        if glyph_count == 0 || data.len() < glyphs_end {
+            return Err(Err::Truncated);
+        }
+        let mut unicode = BTreeMap::new();
+        if flags as u32 & HAS_UNICODE_TABLE != 0 {
+            let table = data[glyphs_end..].split(|&byte| byte == TABLE_SEPARATOR);
+            for (glyph, entry) in table.take(glyph_count).enumerate() {
+                // Only single characters; sequences (eg. combining accents) come after
+                let singles = entry.split(|&byte| byte == TABLE_SEQUENCE).next().unwrap();
+                for c in core::str::from_utf8(singles).unwrap_or("").chars() {
+                    unicode.entry(c).or_insert(glyph);
+                }
+            }
+        }
+        Ok(Font {
+            data,
+            glyphs_offset,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode,
+        })
+    }
+
+    fn index(&self, c: char) -> Option<usize> {
+        match self.unicode.is_empty() {
+            true => Some(c as usize).filter(|&index| index < self.glyph_count),
+            false => self.unicode.get(&c).copied(),
+        }
+    }
+
+    // Characters the font doesn't have are drawn as `?`, or failing that its first glyph
+    pub fn glyph(&self, c: char) -> Glyph<'_> {
+        let index = self.index(c).or_else(|| self.index('?')).unwrap_or(0);
+        let start = self.glyphs_offset + index * self.bytes_per_glyph;
+        Glyph {
+            bitmap: &self.data[start..start + self.bytes_per_glyph],
+            width: self.width,
+        }
+    }
+
+    // Columns and rows of text that fit on a screen this many pixels across and down
+    pub fn grid(&self, width: usize, height: usize) -> (usize, usize) {
+        (width / self.width, height / self.height)
+    }
+}
+
+pub fn load(path: &str) -> Result<Font, Err> {
+    Font::parse(fd::read_all(path)?)
+}
+
+// The console font, None for the VGA card's own
+static FONT: Mutex<Option<Font>> = Mutex::new(None);
+
+// Switch the console to a font file, eg. from the initramfs. The current font is kept if the new
+// one can't be loaded.
+pub fn set_font(path: &str) -> Result<(), Err> {
+    let font = load(path)?;
+    *FONT.lock() = Some(font);
+    Ok(())
+}
+
+// Width and height of a character in pixels, if a font has been loaded
+pub fn size() -> Option<(usize, usize)> {
+    FONT.lock().as_ref().map(|font| (font.width, font.height))
+}
+
+fn font_command(args: &[&str]) {
+    match args {
+        [path] => match set_font(path) {
+            Ok(()) => println!("font: {:?}", size()),
+            Err(err) => println!("font: {}: {:?}", path, err),
+        },
+        _ => println!("usage: font <path to a PSF2 font>"),
+    }
+}
+
+pub fn init() {
+    crate::shell::register("font", "load a PSF2 console font", font_command);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Two 5x2 glyphs: a bar across the top and a dot at the left, for 'A' and 'é'
+    fn test_font(unicode: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        let flags = unicode as u32 * HAS_UNICODE_TABLE;
+        for field in [MAGIC, 0, HEADER_SIZE as u32, flags, 2, 2, 2, 5] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[0xf8, 0x00, 0x80, 0x00]);
+        if unicode {
+            data.extend_from_slice(b"A\xff");
+            data.extend_from_slice("é".as_bytes());
+            data.push(TABLE_SEQUENCE);
+            data.extend_from_slice("e\u{301}".as_bytes());
+            data.push(TABLE_SEPARATOR);
+        }
+        data
+    }
+
+    #[test_case]
+    fn test_parse() {
+        let font = Font::parse(test_font(true)).unwrap();
+        assert_eq!((font.width, font.height), (5, 2));
+        let glyph = font.glyph('A');
+        assert!(glyph.pixel(0, 0) && glyph.pixel(4, 0) && !glyph.pixel(0, 1));
+        let glyph = font.glyph('é');
+        assert!(glyph.pixel(0, 0) && !glyph.pixel(1, 0));
+        // No '?' either, so the first glyph
+        assert!(font.glyph('z').pixel(4, 0));
+        assert_eq!(font.grid(640, 480), (128, 240));
+    }
+
+    #[test_case]
+    fn test_no_unicode_table() {
+        let font = Font::parse(test_font(false)).unwrap();
+        assert!(!font.glyph('\u{1}').pixel(1, 0));
+        assert!(font.glyph('\u{0}').pixel(1, 0));
+    }
+
+    #[test_case]
+    fn test_bad_font() {
+        assert_eq!(Font::parse(Vec::from(*b"PSF1")).err(), Some(Err::NotPsf2));
+        let mut data = test_font(false);
+        data.truncate(HEADER_SIZE + 2);
+        assert_eq!(Font::parse(data).err(), Some(Err::Truncated));
+        assert_eq!(
+            load("/nowhere/font.psf").err(),
+            Some(Err::File(file::Err::NotFound))
+        );
+    }
+}
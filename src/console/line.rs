@@ -0,0 +1,416 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::clipboard::{self, Selection};
+use super::task_manager;
+use crate::keyboard::{self, Key, KeyboardModifiers};
+use crate::serial::SERIAL1;
+use crate::sync::Semaphore;
+
+// The line discipline, between input devices (the keyboard, serial) and whatever reads input
+// (the shell). In cooked mode keystrokes are echoed and collected into a line, with some editing:
+// backspace, Ctrl-U to erase the line, Ctrl-W to erase a word, and up/down to go through
+// previous lines. Only whole lines are handed on. Raw mode hands on each input as it arrives,
+// without echoing it. In either mode copying and pasting (see console::clipboard) is handled
+// before the line discipline sees anything.
+
+const MAX_HISTORY: usize = 32;
+// Lines or raw inputs waiting to be read; more are dropped
+const MAX_PENDING: usize = 64;
+const ESCAPE: u8 = 0x1b;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Character(char),
+    Enter,
+    Backspace,
+    KillLine,
+    KillWord,
+    HistoryPrevious,
+    HistoryNext,
+    Escape,
+    // Ctrl+Shift+C and Ctrl+Shift+V
+    Copy,
+    Paste,
+    // Ctrl+Shift+T, see console::task_manager
+    TaskManager,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Cooked,
+    Raw,
+}
+
+pub struct LineDiscipline {
+    line: String,
+    history: VecDeque<String>,
+    // Which history entry is being shown, while going through it with up/down
+    browsing: Option<usize>,
+}
+
+impl LineDiscipline {
+    pub fn new() -> Self {
+        LineDiscipline {
+            line: String::new(),
+            history: VecDeque::new(),
+            browsing: None,
+        }
+    }
+
+    // Erase `count` characters from the end of the line, on screen too. A serial terminal only
+    // moves the cursor back on backspace, so overwrite the character with a space.
+    fn erase(&mut self, count: usize, echo: &mut dyn Write) {
+        for _ in 0..count {
+            if self.line.pop().is_some() {
+                echo.write_str("\x08 \x08").ok();
+            }
+        }
+    }
+
+    fn replace_line(&mut self, line: String, echo: &mut dyn Write) {
+        self.erase(self.line.chars().count(), echo);
+        echo.write_str(&line).ok();
+        self.line = line;
+    }
+
+    // Apply one input, echoing its effect to `echo`. Returns the line once Enter completes it.
+    pub fn input(&mut self, input: Input, echo: &mut dyn Write) -> Option<String> {
+        match input {
+            Input::Character(c) => {
+                self.line.push(c);
+                echo.write_char(c).ok();
+            }
+            Input::Backspace => self.erase(1, echo),
+            Input::KillLine => self.erase(self.line.chars().count(), echo),
+            Input::KillWord => {
+                let trimmed = self.line.trim_end();
+                let word_start = trimmed.rfind(' ').map_or(0, |i| i + 1);
+                let count = self.line[word_start..].chars().count();
+                self.erase(count, echo);
+            }
+            Input::HistoryPrevious => {
+                let index = match self.browsing {
+                    Some(0) | None if self.history.is_empty() => return None,
+                    Some(index) => index.saturating_sub(1),
+                    None => self.history.len() - 1,
+                };
+                self.browsing = Some(index);
+                self.replace_line(self.history[index].clone(), echo);
+            }
+            Input::HistoryNext => {
+                let index = match self.browsing {
+                    Some(index) => index + 1,
+                    None => return None,
+                };
+                match self.history.get(index) {
+                    Some(line) => {
+                        self.browsing = Some(index);
+                        self.replace_line(line.clone(), echo);
+                    }
+                    None => {
+                        self.browsing = None;
+                        self.replace_line(String::new(), echo);
+                    }
+                }
+            }
+            Input::Escape | Input::Copy | Input::Paste | Input::TaskManager => (),
+            Input::Enter => {
+                echo.write_char('\n').ok();
+                self.browsing = None;
+                let line = core::mem::take(&mut self.line);
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == MAX_HISTORY {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+// What a key press means to the line discipline
+pub fn from_key(key: Key, modifiers: KeyboardModifiers) -> Option<Input> {
+    let copy_paste = KeyboardModifiers::CONTROL | KeyboardModifiers::SHIFT;
+    match key {
+        Key::Backspace => Some(Input::Backspace),
+        Key::UpArrow => Some(Input::HistoryPrevious),
+        Key::DownArrow => Some(Input::HistoryNext),
+        Key::Escape => Some(Input::Escape),
+        Key::Character('\n', _) => Some(Input::Enter),
+        Key::Character('c', _) if modifiers.contains(copy_paste) => Some(Input::Copy),
+        Key::Character('v', _) if modifiers.contains(copy_paste) => Some(Input::Paste),
+        Key::Character('t', _) if modifiers.contains(copy_paste) => Some(Input::TaskManager),
+        Key::Character('u', _) if modifiers.contains(KeyboardModifiers::CONTROL) => {
+            Some(Input::KillLine)
+        }
+        Key::Character('w', _) if modifiers.contains(KeyboardModifiers::CONTROL) => {
+            Some(Input::KillWord)
+        }
+        _ => keyboard::character(key, modifiers).map(Input::Character),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    Escape,
+    // Control sequence introducer, ESC [
+    Csi,
+}
+
+// Turns bytes from a serial terminal into inputs, including VT100 arrow key sequences
+pub struct SerialDecoder {
+    state: EscapeState,
+}
+
+impl SerialDecoder {
+    pub const fn new() -> Self {
+        SerialDecoder {
+            state: EscapeState::None,
+        }
+    }
+
+    pub fn decode(&mut self, byte: u8) -> Option<Input> {
+        let state = core::mem::replace(&mut self.state, EscapeState::None);
+        match (state, byte) {
+            (EscapeState::None, ESCAPE) => self.state = EscapeState::Escape,
+            (EscapeState::Escape, b'[') => self.state = EscapeState::Csi,
+            (EscapeState::Csi, b'A') => return Some(Input::HistoryPrevious),
+            (EscapeState::Csi, b'B') => return Some(Input::HistoryNext),
+            // Anything else in an escape sequence is ignored
+            (EscapeState::Escape | EscapeState::Csi, _) => (),
+            (EscapeState::None, b'\r' | b'\n') => return Some(Input::Enter),
+            (EscapeState::None, 0x08 | 0x7f) => return Some(Input::Backspace),
+            // Ctrl-U and Ctrl-W
+            (EscapeState::None, 0x15) => return Some(Input::KillLine),
+            (EscapeState::None, 0x17) => return Some(Input::KillWord),
+            (EscapeState::None, 0x20..=0x7e) => return Some(Input::Character(byte as char)),
+            (EscapeState::None, _) => (),
+        }
+        None
+    }
+}
+
+struct Console {
+    mode: Mode,
+    discipline: LineDiscipline,
+    lines: VecDeque<String>,
+    raw: VecDeque<Input>,
+    serial: SerialDecoder,
+    // Each terminal has its own selection
+    selection: Option<Selection>,
+}
+
+lazy_static! {
+    static ref CONSOLE: Mutex<Console> = Mutex::new(Console {
+        mode: Mode::Cooked,
+        discipline: LineDiscipline::new(),
+        lines: VecDeque::new(),
+        raw: VecDeque::new(),
+        serial: SerialDecoder::new(),
+        selection: None,
+    });
+}
+
+// Counts of CONSOLE.lines and CONSOLE.raw, for readers to block on
+static LINES: Semaphore = Semaphore::new(0);
+static RAW: Semaphore = Semaphore::new(0);
+
+// Echo to the console sinks
+struct Echo;
+
+impl Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+pub fn mode() -> Mode {
+    crate::without_interrupt! {{
+        CONSOLE.lock().mode
+    }}
+}
+
+pub fn set_mode(mode: Mode) {
+    crate::without_interrupt! {{
+        CONSOLE.lock().mode = mode;
+    }}
+}
+
+// Called by input devices, usually from their interrupt handlers
+pub fn feed(input: Input) {
+    if task_manager::input(input) {
+        return;
+    }
+    if input == Input::Paste {
+        for c in clipboard::contents().chars() {
+            feed(match c {
+                '\n' => Input::Enter,
+                c => Input::Character(c),
+            });
+        }
+        return;
+    }
+    let (line_ready, raw_ready) = crate::without_interrupt! {{
+        let mut console = CONSOLE.lock();
+        let console = &mut *console;
+        if let Some(selection) = console.selection.take() {
+            console.selection = selection.input(input);
+            return;
+        }
+        if input == Input::Copy {
+            console.selection = Some(Selection::start());
+            return;
+        }
+        match console.mode {
+            Mode::Cooked => match console.discipline.input(input, &mut Echo) {
+                Some(line) if console.lines.len() < MAX_PENDING => {
+                    console.lines.push_back(line);
+                    (true, false)
+                }
+                _ => (false, false),
+            },
+            Mode::Raw if console.raw.len() < MAX_PENDING => {
+                console.raw.push_back(input);
+                (false, true)
+            }
+            Mode::Raw => (false, false),
+        }
+    }};
+    if line_ready {
+        LINES.up();
+    }
+    if raw_ready {
+        RAW.up();
+    }
+}
+
+// Block until a whole line has been entered in cooked mode
+pub fn read_line() -> String {
+    LINES.down();
+    crate::without_interrupt! {{
+        CONSOLE.lock().lines.pop_front().unwrap()
+    }}
+}
+
+// Block until there's input in raw mode
+pub fn read_raw() -> Input {
+    RAW.down();
+    crate::without_interrupt! {{
+        CONSOLE.lock().raw.pop_front().unwrap()
+    }}
+}
+
+// Feed anything that's arrived on COM1. Skipped if someone's holding the port, eg. the tick
+// interrupted a serial_println!; the bytes wait in the UART until next time.
+fn poll_serial(_now: u64) {
+    let mut bytes = [0; 16];
+    let mut count = 0;
+    // Echoing needs the port too, so let go of it before feeding anything
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        while count < bytes.len() {
+            match serial.try_read_byte() {
+                Some(byte) => bytes[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+    }
+    for &byte in &bytes[..count] {
+        let input = crate::without_interrupt! {{
+            CONSOLE.lock().serial.decode(byte)
+        }};
+        if let Some(input) = input {
+            feed(input);
+        }
+    }
+}
+
+// Start taking input from the serial port as well as the keyboard. Not on by default, since the
+// test runner reads its filters from serial.
+pub fn start_serial_input() {
+    crate::time::tick::register("serial_console", 1, poll_serial);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn feed_str(discipline: &mut LineDiscipline, echo: &mut String, s: &str) -> Option<String> {
+        s.chars()
+            .filter_map(|c| match c {
+                '\n' => discipline.input(Input::Enter, echo),
+                c => discipline.input(Input::Character(c), echo),
+            })
+            .last()
+    }
+
+    #[test_case]
+    fn test_line_editing() {
+        let mut discipline = LineDiscipline::new();
+        let mut echo = String::new();
+        assert_eq!(feed_str(&mut discipline, &mut echo, "help  me"), None);
+        discipline.input(Input::KillWord, &mut echo);
+        assert_eq!(discipline.line, "help  ");
+        discipline.input(Input::Backspace, &mut echo);
+        discipline.input(Input::KillWord, &mut echo);
+        assert_eq!(discipline.line, "");
+        let line = feed_str(&mut discipline, &mut echo, "nice 1 2\n");
+        assert_eq!(line, Some("nice 1 2".to_string()));
+        feed_str(&mut discipline, &mut echo, "oops");
+        discipline.input(Input::KillLine, &mut echo);
+        assert_eq!(discipline.line, "");
+        assert!(echo.ends_with("oops\x08 \x08\x08 \x08\x08 \x08\x08 \x08"));
+    }
+
+    #[test_case]
+    fn test_history() {
+        let mut discipline = LineDiscipline::new();
+        let mut echo = String::new();
+        feed_str(&mut discipline, &mut echo, "first\nsecond\nsecond\n");
+        assert_eq!(discipline.history.len(), 2);
+        discipline.input(Input::HistoryPrevious, &mut echo);
+        assert_eq!(discipline.line, "second");
+        discipline.input(Input::HistoryPrevious, &mut echo);
+        discipline.input(Input::HistoryPrevious, &mut echo);
+        assert_eq!(discipline.line, "first");
+        discipline.input(Input::HistoryNext, &mut echo);
+        assert_eq!(discipline.line, "second");
+        discipline.input(Input::HistoryNext, &mut echo);
+        assert_eq!(discipline.line, "");
+        let line = discipline.input(Input::Enter, &mut echo);
+        assert_eq!(line, Some(String::new()));
+        assert_eq!(discipline.history.len(), 2);
+    }
+
+    #[test_case]
+    fn test_serial_decoder() {
+        let mut decoder = SerialDecoder::new();
+        let inputs: alloc::vec::Vec<_> = b"a\x1b[A\x1b[B\x7f\x15\x17\x1b[C\r"
+            .iter()
+            .filter_map(|&byte| decoder.decode(byte))
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                Input::Character('a'),
+                Input::HistoryPrevious,
+                Input::HistoryNext,
+                Input::Backspace,
+                Input::KillLine,
+                Input::KillWord,
+                Input::Enter,
+            ]
+        );
+    }
+}
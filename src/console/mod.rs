@@ -0,0 +1,164 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::boot::KernelConfig;
+use crate::serial::SERIAL1;
+use crate::vga_buffer::WRITER;
+
+pub mod clipboard;
+pub mod font;
+pub mod line;
+pub mod screenshot;
+pub mod status_bar;
+pub mod task_manager;
+
+pub use font::set_font;
+pub use screenshot::screenshot;
+
+// Where print!/println! output goes. Any combination of the VGA text buffer, COM1 and an
+// in-memory log of recent output, picked on the kernel command line with `console=vga,serial`,
+// or `headless` for serial only. serial_print! always goes to serial regardless.
+
+bitflags! {
+    pub struct Sinks: u8 {
+        const VGA = 1;
+        const SERIAL = 1 << 1;
+        const LOG = 1 << 2;
+    }
+}
+
+const LOG_SIZE: usize = 8 * 1024;
+
+static SINKS: AtomicU8 = AtomicU8::new(Sinks::VGA.bits());
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        // Static locks, so avoid deadlocks where interrupt handlers try to aquire them
+        // by disabling interrupts.
+        $crate::without_interrupt! {{
+            $crate::console::_print(format_args!($($arg)*));
+        }}
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+// Ring buffer of the most recent output
+struct Log {
+    buffer: [u8; LOG_SIZE],
+    // Total bytes ever written; the next write goes at written % LOG_SIZE
+    written: usize,
+}
+
+impl Write for Log {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.written % LOG_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    buffer: [0; LOG_SIZE],
+    written: 0,
+});
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let sinks = sinks();
+    if sinks.contains(Sinks::VGA) {
+        WRITER.lock().write_fmt(args).unwrap();
+    }
+    if sinks.contains(Sinks::SERIAL) {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    }
+    if sinks.contains(Sinks::LOG) {
+        LOG.lock().write_fmt(args).unwrap();
+    }
+}
+
+pub fn sinks() -> Sinks {
+    Sinks::from_bits_truncate(SINKS.load(Ordering::Relaxed))
+}
+
+pub fn set_sinks(sinks: Sinks) {
+    SINKS.store(sinks.bits(), Ordering::Relaxed);
+}
+
+// Copy the most recent logged output into `buffer`, oldest first. Returns how many bytes.
+pub fn read_log(buffer: &mut [u8]) -> usize {
+    crate::without_interrupt! {{
+        let log = LOG.lock();
+        let length = log.written.min(LOG_SIZE).min(buffer.len());
+        let start = log.written - length;
+        for (i, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = log.buffer[(start + i) % LOG_SIZE];
+        }
+        length
+    }}
+}
+
+// Sinks selected by a kernel command line, or None if it doesn't say
+fn parse_cmdline(cmdline: &str) -> Option<Sinks> {
+    let mut selected = None;
+    for option in cmdline.split_whitespace() {
+        if option == "headless" {
+            selected = Some(Sinks::SERIAL);
+        } else if let Some(names) = option.strip_prefix("console=") {
+            let sinks = names
+                .split(',')
+                .fold(Sinks::empty(), |sinks, name| match name {
+                    "vga" => sinks | Sinks::VGA,
+                    "serial" => sinks | Sinks::SERIAL,
+                    "log" => sinks | Sinks::LOG,
+                    _ => sinks,
+                });
+            selected = Some(sinks);
+        }
+    }
+    selected
+}
+
+pub fn init(config: &KernelConfig) {
+    if let Some(sinks) = parse_cmdline(config.cmdline.as_str()) {
+        set_sinks(sinks);
+    }
+    screenshot::init();
+    font::init();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_cmdline() {
+        assert_eq!(parse_cmdline(""), None);
+        assert_eq!(parse_cmdline("quiet headless"), Some(Sinks::SERIAL));
+        assert_eq!(
+            parse_cmdline("console=vga,log"),
+            Some(Sinks::VGA | Sinks::LOG)
+        );
+    }
+
+    #[test_case]
+    fn test_log_sink() {
+        let previous = sinks();
+        set_sinks(Sinks::LOG);
+        crate::print!("logged {}", 42);
+        set_sinks(previous);
+        let mut buffer = [0; 9];
+        assert_eq!(read_log(&mut buffer), 9);
+        assert_eq!(&buffer, b"logged 42");
+    }
+}
@@ -0,0 +1,46 @@
+use core::fmt;
+
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+// Dump what's on the VGA text screen to COM1 between delimiter lines, so CI logs keep exactly
+// what was on screen, eg. when a test fails. Pull it out of a log with
+//     sed -n '/^-----BEGIN SCREENSHOT/,/^-----END SCREENSHOT/p'
+// Rows keep their trailing spaces so the block is always BUFFER_WIDTH x BUFFER_HEIGHT.
+// TODO: a PPM of the framebuffer, once there's a framebuffer console
+
+// Lock-free while panicking, since whoever holds SERIAL1 may be the one who panicked
+fn emit(args: fmt::Arguments) {
+    if crate::panicking::is_panicking() {
+        crate::serial::force_print(args);
+    } else {
+        crate::serial_print!("{}", args);
+    }
+}
+
+pub fn screenshot() {
+    emit(format_args!(
+        "-----BEGIN SCREENSHOT {}x{}-----\n",
+        BUFFER_WIDTH, BUFFER_HEIGHT
+    ));
+    for row in 0..BUFFER_HEIGHT {
+        // Only printable ASCII is ever written, but video memory is anyone's to scribble on
+        let text = vga_buffer::screen_row(row).map(|byte| match byte {
+            0x20..=0x7e => byte,
+            _ => b'?',
+        });
+        emit(format_args!("{}\n", core::str::from_utf8(&text).unwrap()));
+    }
+    emit(format_args!("-----END SCREENSHOT-----\n"));
+}
+
+fn screenshot_command(_args: &[&str]) {
+    screenshot();
+}
+
+pub fn init() {
+    crate::shell::register(
+        "screenshot",
+        "dump the screen to serial",
+        screenshot_command,
+    );
+}
@@ -0,0 +1,72 @@
+use alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Semaphore;
+use crate::task::scheduler;
+use crate::time::{self, tick};
+use crate::vga_buffer::{Color, WRITER};
+
+// An always-on health display in the top row of the screen: uptime, free physical memory, live
+// tasks and the hardware interrupt rate. Output scrolls in the 24 rows below it. Redrawn once a
+// second by its own task, woken from the timer tick.
+
+const STATUS_ROW: usize = 0;
+
+static REDRAW: Semaphore = Semaphore::new(0);
+// Interrupt count and uptime (in ms) at the last redraw, for the rate
+static LAST_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static LAST_UPTIME_MS: AtomicU64 = AtomicU64::new(0);
+
+fn render() {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    let interrupts = crate::interrupt::hardware_interrupts();
+    let uptime_ms = uptime.as_millis() as u64;
+    let elapsed_ms = uptime_ms - LAST_UPTIME_MS.swap(uptime_ms, Ordering::Relaxed);
+    let new_interrupts = interrupts - LAST_INTERRUPTS.swap(interrupts, Ordering::Relaxed);
+    let rate = match elapsed_ms {
+        0 => 0,
+        elapsed_ms => new_interrupts * 1000 / elapsed_ms,
+    };
+    // Unknown if the page allocator is busy
+    let free_memory = match crate::memory::stats().physical_memory {
+        Some(physical_memory) => format!("{} KiB", physical_memory.free / 1024),
+        None => "?".into(),
+    };
+    let text = format!(
+        " up {}:{:02}:{:02} | free {} | tasks {} | {} irq/s",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        free_memory,
+        scheduler::task_count(),
+        rate
+    );
+    crate::without_interrupt! {{
+        WRITER
+            .lock()
+            .write_reserved_row(STATUS_ROW, &text, Color::Black, Color::LightGray);
+    }}
+}
+
+fn status_bar_task() {
+    loop {
+        render();
+        REDRAW.down();
+    }
+}
+
+fn request_redraw(_now: u64) {
+    if REDRAW.count() == 0 {
+        REDRAW.up();
+    }
+}
+
+// After driver::init, so the tick rate is the one the PIT ends up with
+pub fn init() {
+    crate::without_interrupt! {{
+        WRITER.lock().reserve_rows(STATUS_ROW + 1);
+    }}
+    scheduler::spawn("status_bar", status_bar_task).expect("Failed to start the status bar");
+    tick::register("status_bar", time::ticks_per_second(), request_redraw);
+}
@@ -0,0 +1,204 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::line::Input;
+use super::Sinks;
+use crate::sync::Semaphore;
+use crate::task::idle;
+use crate::task::scheduler::{self, TaskId, TaskSummary};
+use crate::time::{self, tick};
+use crate::vga_buffer::{Color, Screen, BUFFER_HEIGHT, WRITER};
+
+// A fullscreen, top-like view of the running tasks, toggled with Ctrl+Shift+T (or Escape/q to
+// close). While it's up it owns every row below the status bar: print! output stops going to the
+// screen, and what was there is put back when it closes. Redrawn once a second by its own task,
+// woken from the timer tick.
+
+static SHOWN: AtomicBool = AtomicBool::new(false);
+static REDRAW: Semaphore = Semaphore::new(0);
+
+pub fn is_shown() -> bool {
+    SHOWN.load(Ordering::Relaxed)
+}
+
+fn request_redraw() {
+    if REDRAW.count() == 0 {
+        REDRAW.up();
+    }
+}
+
+// Called by console::line::feed for every input. Returns whether it was for us.
+pub(super) fn input(input: Input) -> bool {
+    let shown = is_shown();
+    let toggle = match input {
+        Input::TaskManager => true,
+        Input::Escape | Input::Character('q') => shown,
+        _ => false,
+    };
+    if toggle {
+        SHOWN.store(!shown, Ordering::Relaxed);
+        request_redraw();
+    }
+    // Swallow everything else while we're up, rather than typing blind into the shell
+    toggle || shown
+}
+
+// Each task's run ticks at the last redraw, and when that was, for CPU%
+struct Sample {
+    run_ticks: BTreeMap<TaskId, u64>,
+    jiffies: u64,
+}
+
+impl Sample {
+    fn take(tasks: &[TaskSummary]) -> Self {
+        Sample {
+            run_ticks: tasks
+                .iter()
+                .map(|task| (task.id, task.accounting.run_ticks))
+                .collect(),
+            jiffies: time::jiffies(),
+        }
+    }
+
+    // Of the time since this sample; tasks started since get charged for their whole life
+    fn cpu_percent(&self, task: &TaskSummary, now: u64) -> u64 {
+        let previous = self.run_ticks.get(&task.id).copied().unwrap_or(0);
+        match now - self.jiffies {
+            0 => 0,
+            elapsed => (task.accounting.run_ticks - previous) * 100 / elapsed,
+        }
+    }
+}
+
+fn lines(busy_percent: u64, tasks: &[TaskSummary], last: &Sample) -> Vec<String> {
+    let memory = crate::memory::stats();
+    let heap = match memory.heap {
+        Some(heap) => format!("{} / {} KiB", heap.used / 1024, heap.size / 1024),
+        None => "?".into(),
+    };
+    let free = match memory.physical_memory {
+        Some(physical_memory) => format!("{} KiB", physical_memory.free / 1024),
+        None => "?".into(),
+    };
+    let now = time::jiffies();
+    let header = [
+        format!(
+            " cpu {}% busy | heap {} | free {} | {} tasks",
+            busy_percent,
+            heap,
+            free,
+            tasks.len()
+        ),
+        String::new(),
+        format!(
+            " {:>4}  {:<20} {:>8}  {:<8} {:>4} {:>10} {:>10}",
+            "ID", "NAME", "PRIORITY", "STATE", "CPU%", "TICKS", "SWITCHES"
+        ),
+    ];
+    let rows = tasks.iter().map(|task| {
+        format!(
+            " {:>4}  {:<20} {:>8}  {:<8} {:>4} {:>10} {:>10}",
+            task.id.as_u64(),
+            task.name,
+            task.priority,
+            format!("{:?}", task.state),
+            last.cpu_percent(task, now),
+            task.accounting.run_ticks,
+            task.accounting.switches
+        )
+    });
+    header.into_iter().chain(rows).collect()
+}
+
+fn render(lines: Vec<String>) {
+    crate::without_interrupt! {{
+        let mut writer = WRITER.lock();
+        let first_row = writer.first_row();
+        let mut lines = lines.into_iter();
+        for row in first_row..BUFFER_HEIGHT {
+            let line = lines.next().unwrap_or_default();
+            let (foreground, background) = match row - first_row {
+                0 | 2 => (Color::Black, Color::LightGray),
+                _ => (Color::White, Color::Blue),
+            };
+            writer.write_row(row, &line, foreground, background);
+        }
+    }}
+}
+
+fn task_manager_task() {
+    // What the screen looked like and where print! went before we took over
+    let mut saved: Option<(Screen, Sinks)> = None;
+    let mut last_stats = idle::stats();
+    let mut last_sample = Sample::take(&scheduler::tasks());
+    loop {
+        REDRAW.down();
+        let stats = idle::stats();
+        let busy_percent = idle::Stats {
+            idle_ticks: stats.idle_ticks - last_stats.idle_ticks,
+            busy_ticks: stats.busy_ticks - last_stats.busy_ticks,
+        }
+        .busy_percent();
+        last_stats = stats;
+        let tasks = scheduler::tasks();
+        let lines = lines(busy_percent, &tasks, &last_sample);
+        last_sample = Sample::take(&tasks);
+        match (is_shown(), saved.take()) {
+            (true, saved_state) => {
+                let saved_state = saved_state.unwrap_or_else(|| {
+                    let sinks = super::sinks();
+                    super::set_sinks(sinks - Sinks::VGA);
+                    let screen = crate::without_interrupt! {{ WRITER.lock().save() }};
+                    (screen, sinks)
+                });
+                saved = Some(saved_state);
+                render(lines);
+            }
+            (false, Some((screen, sinks))) => {
+                crate::without_interrupt! {{ WRITER.lock().restore(&screen) }};
+                super::set_sinks(sinks);
+            }
+            (false, None) => (),
+        }
+    }
+}
+
+fn tick_redraw(_now: u64) {
+    if is_shown() {
+        request_redraw();
+    }
+}
+
+// After driver::init, so the tick rate is the one the PIT ends up with
+pub fn init() {
+    scheduler::spawn("task_manager", task_manager_task).expect("Failed to start the task manager");
+    tick::register("task_manager", time::ticks_per_second(), tick_redraw);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_toggle_input() {
+        assert!(!is_shown());
+        assert!(!input(Input::Character('q')));
+        assert!(input(Input::TaskManager));
+        assert!(is_shown());
+        // Swallowed while shown
+        assert!(input(Input::Character('x')));
+        assert!(input(Input::Escape));
+        assert!(!is_shown());
+    }
+
+    #[test_case]
+    fn test_lists_tasks() {
+        let tasks = scheduler::tasks();
+        let lines = lines(0, &tasks, &Sample::take(&tasks));
+        assert!(lines[0].contains("cpu 0% busy"));
+        assert!(lines.iter().any(|line| line.contains("Running")));
+    }
+}
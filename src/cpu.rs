@@ -0,0 +1,88 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::model_specific::Msr;
+
+// Processor feature setup, run once per CPU.
+
+const IA32_PAT: u32 = 0x277;
+// CPUID leaf 1, EDX
+const FEATURE_PAT: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    // Uncacheable, but an MTRR can override it to write-combining
+    UncachedMinus = 7,
+}
+
+// The page attribute table: a page's PAT, PCD and PWT bits (in that order) index into this to pick
+// its memory type. The power-on default has write-through at PWT alone; we swap in
+// write-combining there, so PageTableFlags::WRITE_COMBINING doesn't need the PAT bit (which is in
+// a different place in huge pages), and move write-through to PAT | PWT. Same as Linux.
+pub const PAGE_ATTRIBUTE_TABLE: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+pub fn has_pat() -> bool {
+    unsafe { __cpuid(1) }.edx & FEATURE_PAT != 0
+}
+
+fn pat_value() -> u64 {
+    PAGE_ATTRIBUTE_TABLE
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, memory_type)| {
+            value | (*memory_type as u64) << (i * 8)
+        })
+}
+
+pub fn read_pat() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() }
+}
+
+// Without a PAT, WRITE_COMBINING pages are write-through instead: slower, but still correct
+fn init_pat() {
+    if !has_pat() {
+        return;
+    }
+    crate::without_interrupt! {{
+        // Nothing may be cached under the old types once they change
+        unsafe {
+            asm!("wbinvd", options(nomem, nostack));
+            Msr::new(IA32_PAT).write(pat_value());
+            asm!("wbinvd", options(nomem, nostack));
+        }
+        crate::memory::tlb::flush_all();
+    }}
+}
+
+pub fn init() {
+    init_pat();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_pat_programmed() {
+        // Write-combining at PWT alone
+        assert_eq!((pat_value() >> 8) & 0xff, MemoryType::WriteCombining as u64);
+        if has_pat() {
+            assert_eq!(read_pat(), pat_value());
+        }
+    }
+}
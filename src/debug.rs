@@ -0,0 +1,164 @@
+use core::fmt;
+use core::ops::Range;
+
+use crate::memory;
+use crate::println;
+
+// Debugging aids for poking at memory by hand: hexdumps in the usual offset, hex, ASCII layout,
+// eg.
+//   ffff800000001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 00  |Hello, world!...|
+// Every line is read with memory::try_read, so dumping something unmapped prints `<unmapped>`
+// (once for a whole run of unmapped lines) instead of faulting. Lines are 16 aligned bytes, so
+// never straddle a page, and an unmapped line means the rest of its page is skipped too.
+
+const LINE: usize = 16;
+const DEFAULT_PEEK_LENGTH: usize = 64;
+// More than fits on the screen many times over
+const MAX_PEEK_LENGTH: usize = 64 * 1024;
+
+// Displays as a hexdump of whatever's in `range`, read when it's formatted
+pub struct Hexdump(Range<usize>);
+
+pub fn hexdump(range: Range<usize>) -> Hexdump {
+    Hexdump(range)
+}
+
+impl fmt::Display for Hexdump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Range { start, end } = self.0;
+        let mut unmapped = false;
+        let mut next = start & !(LINE - 1);
+        while next < end {
+            let line = next;
+            next = line.saturating_add(LINE);
+            let bytes = match memory::try_read::<[u8; LINE]>(line) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    if !unmapped {
+                        writeln!(f, "{:016x}  <unmapped>", line)?;
+                    }
+                    unmapped = true;
+                    next = (line | (memory::PAGE_SIZE - 1)).saturating_add(1);
+                    continue;
+                }
+            };
+            unmapped = false;
+            let shown = |i: usize| (start..end).contains(&(line + i));
+            write!(f, "{:016x} ", line)?;
+            for (i, byte) in bytes.iter().enumerate() {
+                if i % 8 == 0 {
+                    write!(f, " ")?;
+                }
+                match shown(i) {
+                    true => write!(f, "{:02x} ", byte)?,
+                    false => write!(f, "   ")?,
+                }
+            }
+            write!(f, " |")?;
+            for (i, &byte) in bytes.iter().enumerate() {
+                let c = match byte {
+                    _ if !shown(i) => ' ',
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+// Hex with or without 0x, or decimal
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// What `peek <address> [length]` dumps, with the length clamped to MAX_PEEK_LENGTH
+fn peek_range(args: &[&str]) -> Option<Range<usize>> {
+    let address = parse_number(args.first()?)?;
+    let length = match args.get(1) {
+        Some(length) => parse_number(length)?,
+        None => DEFAULT_PEEK_LENGTH,
+    };
+    Some(address..address.saturating_add(length.min(MAX_PEEK_LENGTH)))
+}
+
+fn peek_command(args: &[&str]) {
+    match peek_range(args) {
+        Some(range) => crate::print!("{}", hexdump(range)),
+        None => println!("usage: peek <address> [length]"),
+    }
+}
+
+pub fn init() {
+    crate::shell::register("peek", "hexdump memory", peek_command);
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+
+    use super::*;
+
+    #[test_case]
+    fn test_hexdump_layout() {
+        #[repr(align(16))]
+        struct Aligned([u8; 32]);
+        let mut buffer = Aligned([0; 32]);
+        buffer.0[..13].copy_from_slice(b"Hello, world!");
+        let start = buffer.0.as_ptr() as usize;
+        let dump = format!("{}", hexdump(start + 7..start + 20));
+        let mut lines = dump.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "{:016x}                       77  6f 72 6c 64 21 00 00 00  |       world!...|",
+                start
+            )
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "{:016x}  00 00 00 00                                       |....            |",
+                start + 16
+            )
+        );
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test_case]
+    fn test_hexdump_unmapped() {
+        // Nothing is mapped this far into the lower half
+        let start = 0x7000_0000_0000;
+        let dump = format!("{}", hexdump(start..start + 4 * LINE));
+        assert_eq!(dump, format!("{:016x}  <unmapped>\n", start));
+        // Skipped a page at a time
+        let dump = format!("{}", hexdump(start + 8..start + (64 << 20)));
+        assert_eq!(dump, format!("{:016x}  <unmapped>\n", start));
+        // Into the last page, without overflowing
+        let dump = format!("{}", hexdump(usize::MAX - LINE..usize::MAX));
+        assert_eq!(
+            dump,
+            format!("{:016x}  <unmapped>\n", usize::MAX - 2 * LINE + 1)
+        );
+    }
+
+    #[test_case]
+    fn test_peek_range() {
+        assert_eq!(
+            peek_range(&["0x1000"]),
+            Some(0x1000..0x1000 + DEFAULT_PEEK_LENGTH)
+        );
+        assert_eq!(peek_range(&["4096", "16"]), Some(0x1000..0x1010));
+        assert_eq!(
+            peek_range(&["0x1000", "0xffffffffffff"]),
+            Some(0x1000..0x1000 + MAX_PEEK_LENGTH)
+        );
+        assert_eq!(peek_range(&["0x1000", "lots"]), None);
+        assert_eq!(peek_range(&[]), None);
+    }
+}
@@ -0,0 +1,202 @@
+use core::fmt;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::error::KResult;
+use crate::println;
+
+// Device drivers. A driver declares itself with register_driver!, which puts a descriptor in the
+// sos_drivers link section, and `init` walks each bus's devices handing every device to the first
+// registered driver whose `probe` accepts it. Nothing outside the driver has to know it exists.
+//
+// The linker defines __start_/__stop_ symbols around any section whose name is a valid C
+// identifier, which is how we find the descriptors without a linker script.
+//
+// TODO: there's no PCI enumeration yet, so the only bus is the fixed set of legacy PC devices.
+
+const MAX_BOUND: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Platform,
+}
+
+#[derive(Debug)]
+pub struct Device {
+    pub bus: Bus,
+    pub name: &'static str,
+    pub ports: Range<u16>,
+    pub irq: Option<u8>,
+}
+
+pub trait Driver: Sync {
+    // Whether this driver can drive `device`. Mustn't touch the hardware.
+    fn probe(&self, device: &Device) -> bool;
+    fn init(&self, device: &Device) -> KResult<()>;
+    // Quiesce the device before shutdown or reboot
+    fn shutdown(&self, _device: &Device) {}
+}
+
+pub struct DriverDescriptor {
+    pub name: &'static str,
+    pub driver: &'static dyn Driver,
+}
+
+// Register a driver, eg. `register_driver!(PIT_DRIVER, Pit);`
+#[macro_export]
+macro_rules! register_driver {
+    ($name:ident, $driver:expr) => {
+        #[used]
+        #[link_section = "sos_drivers"]
+        static $name: $crate::driver::DriverDescriptor = $crate::driver::DriverDescriptor {
+            name: stringify!($name),
+            driver: &$driver,
+        };
+    };
+}
+
+extern "C" {
+    static __start_sos_drivers: DriverDescriptor;
+    static __stop_sos_drivers: DriverDescriptor;
+}
+
+pub fn drivers() -> &'static [DriverDescriptor] {
+    unsafe {
+        let start = &__start_sos_drivers as *const DriverDescriptor;
+        let stop = &__stop_sos_drivers as *const DriverDescriptor;
+        let count = (stop as usize - start as usize) / core::mem::size_of::<DriverDescriptor>();
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+// Legacy devices every PC has, in the order they need initializing: the PIT and the UARTs have
+// to be set up before the PIC enables interrupts.
+static PLATFORM_DEVICES: [Device; 7] = [
+    Device {
+        bus: Bus::Platform,
+        name: "i8254",
+        ports: 0x40..0x44,
+        irq: Some(0),
+    },
+    Device {
+        bus: Bus::Platform,
+        name: "ns16550",
+        ports: 0x3f8..0x400,
+        irq: Some(4),
+    },
+    Device {
+        bus: Bus::Platform,
+        name: "ns16550",
+        ports: 0x2f8..0x300,
+        irq: Some(3),
+    },
+    Device {
+        bus: Bus::Platform,
+        name: "ns16550",
+        ports: 0x3e8..0x3f0,
+        irq: Some(4),
+    },
+    Device {
+        bus: Bus::Platform,
+        name: "ns16550",
+        ports: 0x2e8..0x2f0,
+        irq: Some(3),
+    },
+    Device {
+        bus: Bus::Platform,
+        name: "i8259",
+        ports: 0x20..0x22,
+        irq: None,
+    },
+    // Only under QEMU, see drivers::fwcfg
+    Device {
+        bus: Bus::Platform,
+        name: "fw_cfg",
+        ports: 0x510..0x512,
+        irq: None,
+    },
+];
+
+#[derive(Clone, Copy)]
+pub struct Binding {
+    pub device: &'static Device,
+    pub driver: &'static DriverDescriptor,
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {} (ports {:#x}..{:#x}) -> {}",
+            self.device.bus,
+            self.device.name,
+            self.device.ports.start,
+            self.device.ports.end,
+            self.driver.name
+        )
+    }
+}
+
+// Devices with an initialized driver, in initialization order
+static BOUND: Mutex<[Option<Binding>; MAX_BOUND]> = Mutex::new([None; MAX_BOUND]);
+
+pub fn bindings() -> [Option<Binding>; MAX_BOUND] {
+    crate::without_interrupt! {{
+        let bound = *BOUND.lock();
+        bound
+    }}
+}
+
+fn bind(device: &'static Device) {
+    let driver = match drivers().iter().find(|d| d.driver.probe(device)) {
+        Some(driver) => driver,
+        None => return println!("driver: no driver for {:?} {}", device.bus, device.name),
+    };
+    if let Err(err) = driver.driver.init(device) {
+        return println!("driver: {} failed on {}: {}", driver.name, device.name, err);
+    }
+    crate::without_interrupt! {{
+        let mut bound = BOUND.lock();
+        match bound.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => *slot = Some(Binding { device, driver }),
+            None => panic!("Too many bound devices binding {}", device.name),
+        }
+    }}
+}
+
+// Probe and initialize drivers for every device we know about
+pub fn init() {
+    PLATFORM_DEVICES.iter().for_each(bind);
+    crate::shell::register("drivers", "list devices and their drivers", drivers_command);
+}
+
+// Shut devices down in the reverse of the order they came up
+pub fn shutdown() {
+    let bound = bindings();
+    for binding in bound.iter().rev().flatten() {
+        binding.driver.driver.shutdown(binding.device);
+    }
+}
+
+fn drivers_command(_args: &[&str]) {
+    bindings().iter().flatten().for_each(|b| println!("{}", b));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_platform_devices_bound() {
+        assert!(drivers().iter().any(|d| d.name == "PIT_DRIVER"));
+        let bound = bindings();
+        let names = bound
+            .iter()
+            .flatten()
+            .map(|b| (b.device.name, b.driver.name));
+        assert!(names.clone().any(|n| n == ("i8254", "PIT_DRIVER")));
+        assert!(names.clone().any(|n| n == ("i8259", "PIC_DRIVER")));
+        assert!(names.clone().any(|n| n == ("ns16550", "UART_DRIVER")));
+    }
+}
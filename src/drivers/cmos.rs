@@ -0,0 +1,90 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::port::{Port, WriteOnlyPort};
+
+// CMOS RAM, home of the real time clock's registers. Registers are read and written by writing
+// their index to the index port then going through the data port, so an access interrupted between
+// the two (say by an NMI handler that touches the CMOS) reads or writes the wrong register.
+//
+// The top bit of the index port also happens to be the NMI mask, so every access sets it for the
+// duration and then puts back whatever set_nmi_enabled last asked for.
+// Reference: https://wiki.osdev.org/CMOS
+
+const INDEX_PORT: WriteOnlyPort<u8> = WriteOnlyPort::new(0x70);
+const DATA_PORT: Port<u8> = Port::new(0x71);
+const NMI_DISABLE: u8 = 1 << 7;
+
+pub const REGISTER_STATUS_A: u8 = 0x0A;
+pub const REGISTER_STATUS_B: u8 = 0x0B;
+pub const REGISTER_STATUS_C: u8 = 0x0C;
+pub const REGISTER_STATUS_D: u8 = 0x0D;
+// Set in status register D while the CMOS battery is good
+pub const STATUS_D_VALID_RAM: u8 = 1 << 7;
+
+// The index port can't be read back, so remember the NMI mask
+static NMI_DISABLED: AtomicBool = AtomicBool::new(false);
+// Keeps index/data pairs together
+static CMOS: Mutex<()> = Mutex::new(());
+
+fn nmi_bit() -> u8 {
+    if NMI_DISABLED.load(Ordering::SeqCst) {
+        NMI_DISABLE
+    } else {
+        0
+    }
+}
+
+// Run `f` between selecting `register` and restoring the NMI mask
+fn access<T>(register: u8, f: impl FnOnce() -> T) -> T {
+    crate::without_interrupt! {{
+        let _cmos = CMOS.lock();
+        unsafe { INDEX_PORT.write(NMI_DISABLE | register) };
+        let result = f();
+        // Leave status register D selected, some chipsets misbehave otherwise
+        unsafe { INDEX_PORT.write(nmi_bit() | REGISTER_STATUS_D) };
+        result
+    }}
+}
+
+pub fn read(register: u8) -> u8 {
+    access(register, || unsafe { DATA_PORT.read() })
+}
+
+// Safety: CMOS registers configure the RTC and hold firmware settings
+pub unsafe fn write(register: u8, value: u8) {
+    access(register, || DATA_PORT.write(value))
+}
+
+pub fn nmi_enabled() -> bool {
+    !NMI_DISABLED.load(Ordering::SeqCst)
+}
+
+pub fn set_nmi_enabled(enabled: bool) {
+    crate::without_interrupt! {{
+        let _cmos = CMOS.lock();
+        NMI_DISABLED.store(!enabled, Ordering::SeqCst);
+        unsafe { INDEX_PORT.write(nmi_bit() | REGISTER_STATUS_D) };
+    }}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_read_status() {
+        assert_ne!(read(REGISTER_STATUS_D) & STATUS_D_VALID_RAM, 0);
+        assert!(nmi_enabled());
+    }
+
+    #[test_case]
+    fn test_nmi_mask_survives_access() {
+        set_nmi_enabled(false);
+        read(REGISTER_STATUS_B);
+        assert!(!nmi_enabled());
+        set_nmi_enabled(true);
+        assert!(nmi_enabled());
+    }
+}
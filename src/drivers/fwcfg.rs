@@ -0,0 +1,186 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str;
+
+use spin::Mutex;
+
+use crate::arch::port::Port;
+use crate::driver::{Device, Driver};
+use crate::error::{ErrorCode, KError, KResult};
+use crate::println;
+
+// QEMU's firmware configuration device: named blobs the host passes in with eg.
+// `-fw_cfg name=opt/sos/fixture,file=fixture.bin` (or `string=...`), which is how integration
+// tests hand the kernel fixtures and expected outputs without a disk, a network or baking them
+// into the image. See testing::fixture.
+//
+// An item is read by writing its key to the selector port, then reading its bytes one at a time
+// from the data port. Named files are listed in a directory item.
+// Reference: https://www.qemu.org/docs/master/specs/fw_cfg.html
+//
+// TODO: the DMA interface (0x514) would be much faster for big files, and other architectures have
+// the same registers memory mapped instead
+
+const SELECTOR_PORT: Port<u16> = Port::new(0x510);
+const DATA_PORT: Port<u8> = Port::new(0x511);
+
+const KEY_SIGNATURE: u16 = 0x0000;
+const KEY_FILE_DIRECTORY: u16 = 0x0019;
+const SIGNATURE: &[u8; 4] = b"QEMU";
+
+const NAME_LENGTH: usize = 56;
+
+// Big files are read this many bytes at a time, with interrupts let in between
+const CHUNK: usize = 4096;
+
+// The selected item and how far into it we've read, if we know. Keeps selecting an item and
+// reading it together, and lets a chunked read carry on where it left off.
+static FW_CFG: Mutex<Option<(u16, usize)>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+pub struct File {
+    name: [u8; NAME_LENGTH],
+    pub size: u32,
+    pub key: u16,
+}
+
+impl File {
+    pub fn name(&self) -> &str {
+        let length = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(NAME_LENGTH);
+        str::from_utf8(&self.name[..length]).unwrap_or("?")
+    }
+}
+
+// Select `key` and hand `f` the function that reads the next byte of it
+fn read_item<T>(key: u16, f: impl FnOnce(&mut dyn FnMut() -> u8) -> T) -> T {
+    crate::without_interrupt! {{
+        let mut position = FW_CFG.lock();
+        *position = None;
+        unsafe { SELECTOR_PORT.write(key) };
+        f(&mut || unsafe { DATA_PORT.read() })
+    }}
+}
+
+// Fill `buffer` from `offset` into `key`. There's no seeking, so if something else selected an
+// item since we last read this one, it's selected again and read up to `offset`.
+fn read_at(key: u16, offset: usize, buffer: &mut [u8]) {
+    crate::without_interrupt! {{
+        let mut position = FW_CFG.lock();
+        if *position != Some((key, offset)) {
+            unsafe { SELECTOR_PORT.write(key) };
+            for _ in 0..offset {
+                unsafe { DATA_PORT.read() };
+            }
+        }
+        buffer
+            .iter_mut()
+            .for_each(|byte| *byte = unsafe { DATA_PORT.read() });
+        *position = Some((key, offset + buffer.len()));
+    }}
+}
+
+fn read_bytes<const N: usize>(next: &mut dyn FnMut() -> u8) -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.iter_mut().for_each(|byte| *byte = next());
+    bytes
+}
+
+// Without QEMU (or with fw_cfg disabled) the data port reads as all ones
+pub fn is_present() -> bool {
+    read_item(KEY_SIGNATURE, |next| read_bytes::<4>(next)) == *SIGNATURE
+}
+
+// Directory entries are big endian, unlike every other fw_cfg item
+pub fn files() -> Vec<File> {
+    if !is_present() {
+        return Vec::new();
+    }
+    read_item(KEY_FILE_DIRECTORY, |next| {
+        let count = u32::from_be_bytes(read_bytes(next));
+        (0..count)
+            .map(|_| {
+                let size = u32::from_be_bytes(read_bytes(next));
+                let key = u16::from_be_bytes(read_bytes(next));
+                let _reserved: [u8; 2] = read_bytes(next);
+                let name = read_bytes(next);
+                File { name, size, key }
+            })
+            .collect()
+    })
+}
+
+pub fn find(name: &str) -> Option<File> {
+    files().into_iter().find(|file| file.name() == name)
+}
+
+// A chunk at a time, so a big file doesn't hold interrupts off for the whole read
+pub fn read(file: &File) -> Vec<u8> {
+    let mut data = vec![0; file.size as usize];
+    for (index, chunk) in data.chunks_mut(CHUNK).enumerate() {
+        read_at(file.key, index * CHUNK, chunk);
+    }
+    data
+}
+
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    find(name).map(|file| read(&file))
+}
+
+struct FwCfg;
+
+impl Driver for FwCfg {
+    fn probe(&self, device: &Device) -> bool {
+        device.name == "fw_cfg"
+    }
+
+    // The ports are only ours to claim under QEMU
+    fn init(&self, _device: &Device) -> KResult<()> {
+        if !is_present() {
+            return Err(KError::new(ErrorCode::DeviceNotResponding).with_message("not QEMU"));
+        }
+        crate::shell::register("fwcfg", "list the host's fw_cfg files", fwcfg_command);
+        Ok(())
+    }
+}
+
+crate::register_driver!(FWCFG_DRIVER, FwCfg);
+
+fn fwcfg_command(_args: &[&str]) {
+    for file in files() {
+        println!("{:#06x} {:>10} {}", file.key, file.size, file.name());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Passed in by the test-args in Cargo.toml
+    #[test_case]
+    fn test_read_fixture() {
+        assert!(is_present());
+        let file = find("opt/sos/fixture").expect("fixture not passed in");
+        assert_eq!(read(&file), b"fw_cfg fixture");
+        assert_eq!(read_file("opt/sos/missing"), None);
+        assert_eq!(
+            crate::testing::fixture("fixture").as_deref(),
+            Some(&b"fw_cfg fixture"[..])
+        );
+    }
+
+    #[test_case]
+    fn test_read_resumes_after_another_item() {
+        let file = find("opt/sos/fixture").expect("fixture not passed in");
+        let mut data = [0; 14];
+        read_at(file.key, 0, &mut data[..4]);
+        read_at(file.key, 4, &mut data[4..8]);
+        // Selects the signature, so the next chunk has to find its place again
+        assert!(is_present());
+        read_at(file.key, 8, &mut data[8..]);
+        assert_eq!(&data, b"fw_cfg fixture");
+    }
+}
@@ -0,0 +1,4 @@
+pub mod cmos;
+pub mod fwcfg;
+pub mod pit;
+pub mod virtio;
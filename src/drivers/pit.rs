@@ -0,0 +1,170 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::arch::port::{Port, WriteOnlyPort};
+use crate::config::{self, Tunable};
+use crate::driver::{Device, Driver};
+use crate::error::KResult;
+use crate::time::delay;
+use crate::time::tick::{self, OneShotTimer};
+use crate::time::vdso::rdtsc;
+
+// Intel 8253/8254 programmable interval timer. Channel 0 is wired to IRQ0, which drives the
+// kernel tick (see time::tick). It's also the one-shot timer for tickless idle: channel 0 switches
+// to interrupting once on terminal count, and the TSC says how long it was stopped for.
+// Reference: https://wiki.osdev.org/Programmable_Interval_Timer
+
+// The PIT's input clock
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_DATA_PORT: Port<u8> = Port::new(0x40);
+const COMMAND_PORT: WriteOnlyPort<u8> = WriteOnlyPort::new(0x43);
+// Channel 0, access lobyte/hibyte, mode 3 (square wave generator), binary
+const COMMAND_CHANNEL_0_SQUARE_WAVE: u8 = 0b00_11_011_0;
+// Channel 0, access lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const COMMAND_CHANNEL_0_ONE_SHOT: u8 = 0b00_11_000_0;
+
+// Channel 2 normally drives the PC speaker, which makes it free for calibrating against.
+// Its gate and output are in system control port B.
+const CHANNEL_2_DATA_PORT: Port<u8> = Port::new(0x42);
+const SYSTEM_CONTROL_PORT_B: Port<u8> = Port::new(0x61);
+const CHANNEL_2_GATE: u8 = 1;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+// Channel 2, access lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const COMMAND_CHANNEL_2_ONE_SHOT: u8 = 0b10_11_000_0;
+
+// 0 is interpreted as 65536, the power-on default
+const MAX_DIVISOR: u32 = 65536;
+
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+// When the one-shot timer was armed
+static ARMED_AT_TSC: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    set_frequency(config::get(Tunable::TickHz) as u32);
+    // Without a calibrated TSC there's no telling how long the tick was stopped for
+    if delay::tsc_frequency().is_some() {
+        tick::set_one_shot_timer(OneShotTimer {
+            arm: arm_one_shot,
+            stop: stop_one_shot,
+        });
+    }
+}
+
+struct Pit;
+
+impl Driver for Pit {
+    fn probe(&self, device: &Device) -> bool {
+        device.name == "i8254"
+    }
+
+    fn init(&self, _device: &Device) -> KResult<()> {
+        init();
+        Ok(())
+    }
+}
+
+crate::register_driver!(PIT_DRIVER, Pit);
+
+// Program channel 0 to interrupt at (approximately) `hz`, returning the frequency we actually got.
+// Anything from ~19Hz up to BASE_FREQUENCY is possible.
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY / hz.max(1)).clamp(1, MAX_DIVISOR);
+    crate::without_interrupt! {{
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        program_channel_0(COMMAND_CHANNEL_0_SQUARE_WAVE, divisor);
+    }}
+    frequency()
+}
+
+fn program_channel_0(command: u8, count: u32) {
+    let [low, high, ..] = (count % MAX_DIVISOR).to_le_bytes();
+    unsafe {
+        COMMAND_PORT.write(command);
+        CHANNEL_0_DATA_PORT.write(low);
+        CHANNEL_0_DATA_PORT.write(high);
+    }
+}
+
+// Interrupt once, `ticks` ticks from now, or as long from now as the counter holds (~55ms) if
+// that's sooner. Called with interrupts disabled.
+fn arm_one_shot(ticks: u64) {
+    let count = (ticks * DIVISOR.load(Ordering::Relaxed) as u64).clamp(1, MAX_DIVISOR as u64 - 1);
+    ARMED_AT_TSC.store(rdtsc(), Ordering::Relaxed);
+    program_channel_0(COMMAND_CHANNEL_0_ONE_SHOT, count as u32);
+}
+
+// Back to the periodic tick, returning the whole ticks since arm_one_shot
+fn stop_one_shot() -> u64 {
+    let elapsed = rdtsc() - ARMED_AT_TSC.load(Ordering::Relaxed);
+    program_channel_0(
+        COMMAND_CHANNEL_0_SQUARE_WAVE,
+        DIVISOR.load(Ordering::Relaxed),
+    );
+    let tsc_per_tick = delay::tsc_frequency().unwrap_or(1) * period_nanos() / 1_000_000_000;
+    elapsed / tsc_per_tick.max(1)
+}
+
+// Count TSC cycles while channel 2 counts down `millis` milliseconds (at most 54, what the
+// counter can hold), returning TSC cycles per second. Polls rather than waiting for an
+// interrupt, so it works before interrupts are set up.
+pub fn measure_tsc_frequency(millis: u32) -> u64 {
+    let count = (BASE_FREQUENCY as u64 * millis as u64 / 1000).clamp(1, MAX_DIVISOR as u64 - 1);
+    crate::without_interrupt! {{
+        unsafe {
+            let control = SYSTEM_CONTROL_PORT_B.read();
+            SYSTEM_CONTROL_PORT_B.write((control & !SPEAKER_ENABLE) | CHANNEL_2_GATE);
+            COMMAND_PORT.write(COMMAND_CHANNEL_2_ONE_SHOT);
+            let [low, high, ..] = count.to_le_bytes();
+            CHANNEL_2_DATA_PORT.write(low);
+            CHANNEL_2_DATA_PORT.write(high);
+            let start = crate::time::vdso::rdtsc();
+            while SYSTEM_CONTROL_PORT_B.read() & CHANNEL_2_OUTPUT == 0 {
+                core::hint::spin_loop();
+            }
+            let cycles = crate::time::vdso::rdtsc() - start;
+            SYSTEM_CONTROL_PORT_B.write(control);
+            cycles * BASE_FREQUENCY as u64 / count
+        }
+    }}
+}
+
+// Current interrupt frequency in Hz, rounded down
+pub fn frequency() -> u32 {
+    BASE_FREQUENCY / DIVISOR.load(Ordering::Relaxed)
+}
+
+// Exact time between interrupts
+pub fn period_nanos() -> u64 {
+    DIVISOR.load(Ordering::Relaxed) as u64 * 1_000_000_000 / BASE_FREQUENCY as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_set_frequency() {
+        let original = frequency();
+        assert_eq!(set_frequency(1000), 1000);
+        assert_eq!(period_nanos(), 1193 * 1_000_000_000 / BASE_FREQUENCY as u64);
+        // Clamped to what the divisor can express
+        assert_eq!(set_frequency(1), BASE_FREQUENCY / MAX_DIVISOR);
+        set_frequency(original);
+    }
+
+    #[test_case]
+    fn test_one_shot_counts_elapsed_ticks() {
+        let tick_micros = period_nanos() / 1000;
+        crate::without_interrupt! {{
+            arm_one_shot(10);
+            delay::delay_us(tick_micros * 5 / 2);
+            assert_eq!(stop_one_shot(), 2);
+        }}
+        // And ticking periodically again
+        let before = crate::time::jiffies();
+        while crate::time::jiffies() < before + 2 {
+            core::hint::spin_loop();
+        }
+    }
+}
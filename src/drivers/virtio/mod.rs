@@ -0,0 +1,326 @@
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+use crate::error::{ErrorCode, KError};
+
+#[cfg(feature = "net")]
+pub mod net;
+pub mod queue;
+pub mod transport;
+
+use queue::{Buffer, Completion, Virtqueue};
+use transport::Transport;
+
+// The parts of a virtio driver every device type shares: the status handshake, feature
+// negotiation, virtqueues and interrupt acknowledgement. A device driver (blk, net, ...) brings up
+// a VirtioDevice over whichever transport found the device, then only deals with its own feature
+// bits, configuration layout and request formats.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html
+//
+// TODO: no interrupt routing yet: there are no PCI IRQs, and the IDT's handlers are fixed. Drivers
+// can poll `pop_used` until then; their IRQ handler should call `interrupt` and then do the same.
+
+bitflags! {
+    pub struct Status: u8 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 1 << 1;
+        const DRIVER_OK = 1 << 2;
+        const FEATURES_OK = 1 << 3;
+        const DEVICE_NEEDS_RESET = 1 << 6;
+        const FAILED = 1 << 7;
+    }
+}
+
+bitflags! {
+    // What the ISR status register says an interrupt was for
+    pub struct InterruptStatus: u8 {
+        const QUEUE = 1;
+        const CONFIG = 1 << 1;
+    }
+}
+
+// Feature bits which aren't specific to a device type
+pub const FEATURE_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Modern devices let us pick a smaller queue than they offer; legacy ones don't
+const MAX_QUEUE_SIZE: u16 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    // The device didn't accept the features we asked for, or lacks ones we need
+    FeaturesRejected,
+    NoSuchQueue(u16),
+    BadQueueSize(u16),
+    QueueFull,
+    // A request bigger than the device takes
+    TooLarge(usize),
+    NoBuffers,
+    TooManyBuffers(usize),
+    OutOfMemory,
+}
+
+impl From<Err> for KError {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::FeaturesRejected => {
+                KError::new(ErrorCode::Unsupported).with_message("virtio features rejected")
+            }
+            Err::NoSuchQueue(_) | Err::BadQueueSize(_) => {
+                KError::new(ErrorCode::DeviceNotResponding).with_message("virtio queue setup")
+            }
+            Err::QueueFull | Err::OutOfMemory => KError::new(ErrorCode::OutOfMemory),
+            Err::TooLarge(_) | Err::NoBuffers | Err::TooManyBuffers(_) => {
+                KError::new(ErrorCode::InvalidArgument)
+            }
+        }
+    }
+}
+
+pub struct VirtioDevice<T: Transport> {
+    transport: T,
+    features: u64,
+    queues: Vec<Virtqueue>,
+}
+
+impl<T: Transport> VirtioDevice<T> {
+    // Reset the device and bring it up with whichever of `features` it also supports, and its
+    // first `queues` queues. The transport and ring features are negotiated here.
+    pub fn new(mut transport: T, features: u64, queues: u16) -> Result<Self, Err> {
+        transport.set_status(Status::empty());
+        let mut status = Status::ACKNOWLEDGE;
+        transport.set_status(status);
+        status |= Status::DRIVER;
+        transport.set_status(status);
+        let mut virtqueues = Vec::new();
+        match Self::negotiate(
+            &mut transport,
+            &mut status,
+            features,
+            queues,
+            &mut virtqueues,
+        ) {
+            Ok(features) => {
+                transport.set_status(status | Status::DRIVER_OK);
+                Ok(VirtioDevice {
+                    transport,
+                    features,
+                    queues: virtqueues,
+                })
+            }
+            Err(err) => {
+                transport.set_status(status | Status::FAILED);
+                // The device may already be using some of the queues, so they're only freed
+                // once it's reset
+                transport.set_status(Status::empty());
+                drop(virtqueues);
+                Err(err)
+            }
+        }
+    }
+
+    // Set up queues into `virtqueues`, which the caller frees
+    fn negotiate(
+        transport: &mut T,
+        status: &mut Status,
+        features: u64,
+        queues: u16,
+        virtqueues: &mut Vec<Virtqueue>,
+    ) -> Result<u64, Err> {
+        let offered = transport.device_features();
+        let mut wanted = features | FEATURE_RING_INDIRECT_DESC;
+        if transport.is_modern() {
+            if offered & FEATURE_VERSION_1 == 0 {
+                return Err(Err::FeaturesRejected);
+            }
+            wanted |= FEATURE_VERSION_1;
+        }
+        let features = offered & wanted;
+        transport.set_driver_features(features);
+        // Legacy devices have no way to refuse
+        if transport.is_modern() {
+            *status |= Status::FEATURES_OK;
+            transport.set_status(*status);
+            if !transport.status().contains(Status::FEATURES_OK) {
+                return Err(Err::FeaturesRejected);
+            }
+        }
+        let indirect = features & FEATURE_RING_INDIRECT_DESC != 0;
+        for index in 0..queues {
+            let size = match transport.max_queue_size(index) {
+                0 => return Err(Err::NoSuchQueue(index)),
+                size if transport.is_modern() => size.min(MAX_QUEUE_SIZE),
+                size => size,
+            };
+            virtqueues.push(Virtqueue::new(index, size, indirect)?);
+            transport.setup_queue(&virtqueues[index as usize])?;
+        }
+        Ok(features)
+    }
+
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    fn queue(&mut self, queue: u16) -> Result<&mut Virtqueue, Err> {
+        self.queues
+            .get_mut(queue as usize)
+            .ok_or(Err::NoSuchQueue(queue))
+    }
+
+    // Offer a request to the device and tell it so. Returns the token its completion carries.
+    pub fn submit(&mut self, queue: u16, buffers: &[Buffer]) -> Result<u16, Err> {
+        let token = self.queue(queue)?.add(buffers)?;
+        self.transport.notify(queue);
+        Ok(token)
+    }
+
+    pub fn pop_used(&mut self, queue: u16) -> Option<Completion> {
+        self.queue(queue).ok()?.pop_used()
+    }
+
+    // Acknowledge an interrupt, returning what it was for
+    pub fn interrupt(&self) -> InterruptStatus {
+        InterruptStatus::from_bits_truncate(self.transport.interrupt_status())
+    }
+
+    pub fn read_config(&self, offset: usize, buffer: &mut [u8]) {
+        self.transport.read_config(offset, buffer)
+    }
+}
+
+// Reset first, so the device has let go of the queues before they're freed
+impl<T: Transport> Drop for VirtioDevice<T> {
+    fn drop(&mut self) {
+        self.transport.set_status(Status::empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    // A device which offers `features` and accepts whatever it's given, except for setting up
+    // queue `refused_queue`
+    pub(super) struct FakeTransport {
+        modern: bool,
+        features: u64,
+        driver_features: u64,
+        status: Status,
+        // Every status the driver set, kept after the device is dropped
+        statuses: Arc<Mutex<Vec<Status>>>,
+        queues_set_up: u16,
+        refused_queue: Option<u16>,
+    }
+
+    impl FakeTransport {
+        pub(super) fn new(modern: bool, features: u64) -> Self {
+            FakeTransport {
+                modern,
+                features,
+                driver_features: 0,
+                status: Status::empty(),
+                statuses: Arc::new(Mutex::new(Vec::new())),
+                queues_set_up: 0,
+                refused_queue: None,
+            }
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn is_modern(&self) -> bool {
+            self.modern
+        }
+
+        fn device_features(&mut self) -> u64 {
+            self.features
+        }
+
+        fn set_driver_features(&mut self, features: u64) {
+            self.driver_features = features;
+        }
+
+        fn status(&self) -> Status {
+            self.status
+        }
+
+        fn set_status(&mut self, status: Status) {
+            self.status = status;
+            self.statuses.lock().push(status);
+        }
+
+        fn max_queue_size(&mut self, queue: u16) -> u16 {
+            if queue < 2 {
+                1024
+            } else {
+                0
+            }
+        }
+
+        fn setup_queue(&mut self, queue: &Virtqueue) -> Result<(), Err> {
+            if self.refused_queue == Some(queue.index()) {
+                return Err(Err::BadQueueSize(queue.size()));
+            }
+            self.queues_set_up += 1;
+            Ok(())
+        }
+
+        fn notify(&self, _queue: u16) {}
+
+        fn interrupt_status(&self) -> u8 {
+            InterruptStatus::QUEUE.bits()
+        }
+
+        fn read_config(&self, _offset: usize, buffer: &mut [u8]) {
+            buffer.fill(0xab);
+        }
+    }
+
+    const FEATURE_DEVICE_THING: u64 = 1 << 5;
+
+    #[test_case]
+    fn test_negotiation() {
+        let offered = FEATURE_VERSION_1 | FEATURE_RING_INDIRECT_DESC | FEATURE_DEVICE_THING | 1;
+        let device =
+            VirtioDevice::new(FakeTransport::new(true, offered), FEATURE_DEVICE_THING, 2).unwrap();
+        let expected = FEATURE_VERSION_1 | FEATURE_RING_INDIRECT_DESC | FEATURE_DEVICE_THING;
+        assert_eq!(device.features(), expected);
+        assert_eq!(device.transport.driver_features, expected);
+        assert_eq!(device.transport.queues_set_up, 2);
+        assert!(device.transport.status.contains(
+            Status::ACKNOWLEDGE | Status::DRIVER | Status::FEATURES_OK | Status::DRIVER_OK
+        ));
+        assert_eq!(device.queues[0].size(), MAX_QUEUE_SIZE);
+        assert_eq!(device.interrupt(), InterruptStatus::QUEUE);
+    }
+
+    #[test_case]
+    fn test_failures() {
+        // Modern devices have to offer VERSION_1
+        let err = VirtioDevice::new(FakeTransport::new(true, 0), 0, 1).err();
+        assert_eq!(err, Some(Err::FeaturesRejected));
+        let err = VirtioDevice::new(FakeTransport::new(false, 0), 0, 3).err();
+        assert_eq!(err, Some(Err::NoSuchQueue(2)));
+    }
+
+    #[test_case]
+    fn test_failure_resets_device() {
+        let mut transport = FakeTransport::new(false, 0);
+        transport.refused_queue = Some(1);
+        let statuses = transport.statuses.clone();
+        let err = VirtioDevice::new(transport, 0, 2).err();
+        assert_eq!(err, Some(Err::BadQueueSize(1024)));
+        // Queue 0 was set up, so the device is told we gave up and then reset
+        let statuses = statuses.lock();
+        let last = &statuses[statuses.len() - 2..];
+        assert!(last[0].contains(Status::FAILED));
+        assert_eq!(last[1], Status::empty());
+    }
+}
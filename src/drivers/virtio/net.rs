@@ -0,0 +1,196 @@
+use core::ops::Range;
+
+use super::queue::Buffer;
+use super::transport::Transport;
+use super::{Err, VirtioDevice, FEATURE_VERSION_1};
+use crate::memory::{self, physical_to_virtual};
+
+// A virtio network card: a receive queue we keep stocked with buffers for the device to fill with
+// frames, and a transmit queue we send frames on. Each frame is prefixed with a virtio_net_hdr,
+// which we leave zeroed since we don't ask for checksum or segmentation offload.
+// Only built with the `net` feature.
+// TODO: nothing finds the card until there's PCI enumeration, and there's no network stack above
+// this yet; both will drive it by polling `receive` and `send`
+
+const FEATURE_MAC: u64 = 1 << 5;
+
+const RECEIVE: u16 = 0;
+const TRANSMIT: u16 = 1;
+
+// An Ethernet frame, without the FCS the device adds
+pub const MTU: usize = 1514;
+const BUFFER_SIZE: usize = 2048;
+const RECEIVE_BUFFERS: usize = 16;
+
+// Physical memory from memory::allocate_dma
+struct Dma(Range<usize>);
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        memory::deallocate_dma(self.0.clone());
+    }
+}
+
+pub struct VirtioNet<T: Transport> {
+    // Before `buffers`, so the device is reset before they're freed
+    device: VirtioDevice<T>,
+    // RECEIVE_BUFFERS receive buffers, then the one we transmit from
+    buffers: Dma,
+    mac: [u8; 6],
+    // virtio_net_hdr's size: 10 bytes, or 12 for modern devices
+    header: usize,
+    // The token each receive buffer was last offered with
+    receiving: [u16; RECEIVE_BUFFERS],
+    // The transmit buffer's token, while the device has it
+    sending: Option<u16>,
+}
+
+impl<T: Transport> VirtioNet<T> {
+    pub fn new(transport: T) -> Result<Self, Err> {
+        let device = VirtioDevice::new(transport, FEATURE_MAC, 2)?;
+        let buffers = memory::allocate_dma((RECEIVE_BUFFERS + 1) * BUFFER_SIZE)
+            .map_err(|_| Err::OutOfMemory)?;
+        let mut mac = [0; 6];
+        if device.has_feature(FEATURE_MAC) {
+            device.read_config(0, &mut mac);
+        }
+        let header = match device.has_feature(FEATURE_VERSION_1) {
+            true => 12,
+            false => 10,
+        };
+        let mut net = VirtioNet {
+            device,
+            buffers: Dma(buffers),
+            mac,
+            header,
+            receiving: [0; RECEIVE_BUFFERS],
+            sending: None,
+        };
+        for index in 0..RECEIVE_BUFFERS {
+            net.offer(index)?;
+        }
+        Ok(net)
+    }
+
+    // All zeroes if the device doesn't say
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    // Physical
+    fn buffer(&self, index: usize) -> usize {
+        self.buffers.0.start + index * BUFFER_SIZE
+    }
+
+    // Hand receive buffer `index` to the device to fill
+    fn offer(&mut self, index: usize) -> Result<(), Err> {
+        let buffer = Buffer {
+            address: self.buffer(index),
+            length: BUFFER_SIZE as u32,
+            device_writable: true,
+        };
+        self.receiving[index] = self.device.submit(RECEIVE, &[buffer])?;
+        Ok(())
+    }
+
+    // Copy the next received frame into `frame`, returning its length (truncated to fit), or None
+    // if nothing has arrived
+    pub fn receive(&mut self, frame: &mut [u8]) -> Option<usize> {
+        let completion = self.device.pop_used(RECEIVE)?;
+        let index = self
+            .receiving
+            .iter()
+            .position(|&token| token == completion.token)?;
+        let length = (completion.length as usize)
+            .saturating_sub(self.header)
+            .min(frame.len());
+        let data = physical_to_virtual(self.buffer(index) + self.header) as *const u8;
+        frame[..length].copy_from_slice(unsafe { core::slice::from_raw_parts(data, length) });
+        // Can't fail, since its descriptor was just freed
+        let _ = self.offer(index);
+        Some(length)
+    }
+
+    // Offer a frame to the device. There's one transmit buffer, so this is QueueFull until the
+    // device is done with the last frame.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), Err> {
+        if frame.len() > MTU {
+            return Err(Err::TooLarge(frame.len()));
+        }
+        while let Some(completion) = self.device.pop_used(TRANSMIT) {
+            if self.sending == Some(completion.token) {
+                self.sending = None;
+            }
+        }
+        if self.sending.is_some() {
+            return Err(Err::QueueFull);
+        }
+        let address = self.buffer(RECEIVE_BUFFERS);
+        unsafe {
+            let buffer = physical_to_virtual(address) as *mut u8;
+            buffer.write_bytes(0, self.header);
+            buffer
+                .add(self.header)
+                .copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+        }
+        let buffer = Buffer {
+            address,
+            length: (self.header + frame.len()) as u32,
+            device_writable: false,
+        };
+        self.sending = Some(self.device.submit(TRANSMIT, &[buffer])?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::FakeTransport;
+    use super::*;
+
+    #[test_case]
+    fn test_receive_and_send() {
+        let offered = FEATURE_VERSION_1 | FEATURE_MAC;
+        let mut net = VirtioNet::new(FakeTransport::new(true, offered)).unwrap();
+        // FakeTransport's configuration reads as 0xab
+        assert_eq!(net.mac(), [0xab; 6]);
+        let receive_free = net.device.queues[RECEIVE as usize].free_descriptors();
+        assert_eq!(receive_free, 256 - RECEIVE_BUFFERS as u16);
+
+        let mut frame = [0; MTU];
+        assert_eq!(net.receive(&mut frame), None);
+        // Play the device: a 60 byte frame arrives in the third buffer
+        unsafe {
+            let data = physical_to_virtual(net.buffer(2) + 12) as *mut u8;
+            data.write_bytes(7, 60);
+        }
+        net.device.queues[RECEIVE as usize].complete(net.receiving[2], 12 + 60);
+        assert_eq!(net.receive(&mut frame), Some(60));
+        assert!(frame[..60].iter().all(|&byte| byte == 7));
+        // And the buffer went straight back to the device
+        let queue = &net.device.queues[RECEIVE as usize];
+        assert_eq!(queue.free_descriptors(), receive_free);
+
+        net.send(&[1; 60]).unwrap();
+        let sent = unsafe {
+            core::slice::from_raw_parts(
+                physical_to_virtual(net.buffer(RECEIVE_BUFFERS)) as *const u8,
+                12 + 60,
+            )
+        };
+        assert!(sent[..12].iter().all(|&byte| byte == 0));
+        assert!(sent[12..].iter().all(|&byte| byte == 1));
+        assert_eq!(net.send(&[2; 60]), Err(Err::QueueFull));
+        net.device.queues[TRANSMIT as usize].complete(net.sending.unwrap(), 0);
+        net.send(&[2; 60]).unwrap();
+        assert_eq!(net.send(&[0; MTU + 1]), Err(Err::TooLarge(MTU + 1)));
+    }
+
+    #[test_case]
+    fn test_legacy_header() {
+        let mut net = VirtioNet::new(FakeTransport::new(false, 0)).unwrap();
+        assert_eq!(net.mac(), [0; 6]);
+        assert_eq!(net.header, 10);
+        net.send(&[1; 14]).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{fence, Ordering};
+
+use super::Err;
+use crate::memory::{self, physical_to_virtual, PAGE_SIZE};
+
+// A split virtqueue: a descriptor table the driver fills in, an available ring where it offers
+// chains of descriptors to the device, and a used ring where the device hands them back. All three
+// live in one DMA allocation, laid out the way legacy devices require (the used ring on its own
+// page), which modern devices are happy with too.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-240006
+//
+// Chains of INDIRECT_MIN or more buffers go in an indirect table when the device supports it, so a
+// large request only takes up one descriptor in the ring.
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+const DESCRIPTOR_INDIRECT: u16 = 4;
+
+const INDIRECT_MIN: usize = 4;
+// One page of descriptors
+const MAX_INDIRECT: usize = PAGE_SIZE / core::mem::size_of::<Descriptor>();
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+// Physical memory for the device to read (a request) or write (a response)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: usize,
+    pub length: u32,
+    pub device_writable: bool,
+}
+
+// A chain the device is done with: the token `add` returned, and how much it wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    pub token: u16,
+    pub length: u32,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    // Physical, from memory::allocate_dma
+    memory: Range<usize>,
+    indirect: bool,
+    // Unused descriptors are linked through `next`
+    free_head: u16,
+    free_count: u16,
+    // Our copy of the available ring's idx, and how far we've read the used ring
+    avail_index: u16,
+    last_used: u16,
+    // Indirect tables, by the head descriptor pointing at them
+    indirect_tables: Vec<Option<Range<usize>>>,
+}
+
+// Offsets of the available and used rings, and the total size
+fn layout(size: u16) -> (usize, usize, usize) {
+    let size = size as usize;
+    let avail = size * core::mem::size_of::<Descriptor>();
+    let used = (avail + 6 + 2 * size).next_multiple_of(PAGE_SIZE);
+    (
+        avail,
+        used,
+        used + (6 + 8 * size).next_multiple_of(PAGE_SIZE),
+    )
+}
+
+impl Virtqueue {
+    pub fn new(index: u16, size: u16, indirect: bool) -> Result<Self, Err> {
+        if !size.is_power_of_two() {
+            return Err(Err::BadQueueSize(size));
+        }
+        let (_, _, length) = layout(size);
+        let memory = memory::allocate_dma(length).map_err(|_| Err::OutOfMemory)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            indirect,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used: 0,
+            indirect_tables: (0..size).map(|_| None).collect(),
+        };
+        for i in 0..size {
+            queue.write_descriptor(
+                i,
+                Descriptor {
+                    next: i.wrapping_add(1),
+                    ..Default::default()
+                },
+            );
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    // Physical addresses, for the transport to hand the device
+    pub fn descriptor_table(&self) -> usize {
+        self.memory.start
+    }
+
+    pub fn driver_area(&self) -> usize {
+        self.memory.start + layout(self.size).0
+    }
+
+    pub fn device_area(&self) -> usize {
+        self.memory.start + layout(self.size).1
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        let table = physical_to_virtual(self.descriptor_table()) as *mut Descriptor;
+        unsafe { table.add((index % self.size) as usize) }
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        unsafe { self.descriptor(index).read_volatile() }
+    }
+
+    fn write_descriptor(&self, index: u16, descriptor: Descriptor) {
+        unsafe { self.descriptor(index).write_volatile(descriptor) }
+    }
+
+    // The available ring's idx, then the ring itself
+    fn avail(&self) -> *mut u16 {
+        (physical_to_virtual(self.driver_area()) + 2) as *mut u16
+    }
+
+    // The used ring's idx, then (id, length) pairs
+    fn used(&self) -> *mut u16 {
+        (physical_to_virtual(self.device_area()) + 2) as *mut u16
+    }
+
+    // Offer a chain of buffers to the device, returning the token its completion will carry. The
+    // transport still has to notify the device, see VirtioDevice::submit.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, Err> {
+        if buffers.is_empty() {
+            return Err(Err::NoBuffers);
+        }
+        let head = if self.indirect && buffers.len() >= INDIRECT_MIN {
+            self.add_indirect(buffers)?
+        } else {
+            self.add_chain(buffers, 0)?
+        };
+        unsafe {
+            let ring = self.avail().add(1);
+            ring.add((self.avail_index % self.size) as usize)
+                .write_volatile(head);
+            // The device mustn't see the new idx before the descriptors and ring entry
+            fence(Ordering::SeqCst);
+            self.avail_index = self.avail_index.wrapping_add(1);
+            self.avail().write_volatile(self.avail_index);
+        }
+        Ok(head)
+    }
+
+    fn add_chain(&mut self, buffers: &[Buffer], extra_flags: u16) -> Result<u16, Err> {
+        if buffers.len() > self.free_count as usize {
+            return Err(Err::QueueFull);
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next_free = self.read_descriptor(index).next;
+            let last = i == buffers.len() - 1;
+            let mut flags = extra_flags;
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            if !last {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            self.write_descriptor(
+                index,
+                Descriptor {
+                    address: buffer.address as u64,
+                    length: buffer.length,
+                    flags,
+                    next: if last { 0 } else { next_free },
+                },
+            );
+            index = next_free;
+        }
+        self.free_head = index;
+        self.free_count -= buffers.len() as u16;
+        Ok(head)
+    }
+
+    fn add_indirect(&mut self, buffers: &[Buffer]) -> Result<u16, Err> {
+        if buffers.len() > MAX_INDIRECT {
+            return Err(Err::TooManyBuffers(buffers.len()));
+        }
+        if self.free_count == 0 {
+            return Err(Err::QueueFull);
+        }
+        let length = buffers.len() * core::mem::size_of::<Descriptor>();
+        let table = memory::allocate_dma(length).map_err(|_| Err::OutOfMemory)?;
+        let entries = physical_to_virtual(table.start) as *mut Descriptor;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i == buffers.len() - 1;
+            let mut flags = if last { 0 } else { DESCRIPTOR_NEXT };
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            let descriptor = Descriptor {
+                address: buffer.address as u64,
+                length: buffer.length,
+                flags,
+                next: if last { 0 } else { i as u16 + 1 },
+            };
+            unsafe { entries.add(i).write_volatile(descriptor) };
+        }
+        let table_buffer = Buffer {
+            address: table.start,
+            length: length as u32,
+            device_writable: false,
+        };
+        let head = self.add_chain(&[table_buffer], DESCRIPTOR_INDIRECT)?;
+        self.indirect_tables[head as usize] = Some(table);
+        Ok(head)
+    }
+
+    // The next chain the device has finished with, if any. Its descriptors are free again.
+    pub fn pop_used(&mut self) -> Option<Completion> {
+        let used_index = unsafe { self.used().read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        // Don't read the element before the idx that covers it
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            (self.used().add(1) as *mut u32).add(2 * (self.last_used % self.size) as usize)
+        };
+        let (id, length) = unsafe { (element.read_volatile(), element.add(1).read_volatile()) };
+        self.last_used = self.last_used.wrapping_add(1);
+        self.free_chain(id as u16);
+        Some(Completion {
+            token: id as u16,
+            length,
+        })
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        if let Some(table) = self.indirect_tables[head as usize].take() {
+            memory::deallocate_dma(table);
+        }
+        let mut tail = head;
+        let mut count = 1;
+        loop {
+            let descriptor = self.read_descriptor(tail);
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            tail = descriptor.next;
+            count += 1;
+        }
+        let descriptor = self.read_descriptor(tail);
+        self.write_descriptor(
+            tail,
+            Descriptor {
+                next: self.free_head,
+                ..descriptor
+            },
+        );
+        self.free_head = head;
+        self.free_count += count;
+    }
+}
+
+// Only once the device has been reset, see VirtioDevice's Drop
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        for table in self.indirect_tables.iter_mut().filter_map(Option::take) {
+            memory::deallocate_dma(table);
+        }
+        memory::deallocate_dma(self.memory.clone());
+    }
+}
+
+#[cfg(test)]
+impl Virtqueue {
+    // Play the device: hand `token` back having written `length` bytes
+    pub fn complete(&self, token: u16, length: u32) {
+        unsafe {
+            let index = self.used().read_volatile();
+            let element = (self.used().add(1) as *mut u32).add(2 * (index % self.size) as usize);
+            element.write_volatile(token as u32);
+            element.add(1).write_volatile(length);
+            self.used().write_volatile(index.wrapping_add(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buffers(count: usize) -> Vec<Buffer> {
+        (0..count)
+            .map(|i| Buffer {
+                address: 0x10_0000 + i * 0x1000,
+                length: 512,
+                device_writable: i == count - 1,
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn test_chain_round_trip() {
+        let mut queue = Virtqueue::new(0, 8, false).unwrap();
+        let token = queue.add(&buffers(3)).unwrap();
+        assert_eq!(queue.free_descriptors(), 5);
+        let head = queue.read_descriptor(token);
+        assert_eq!(head.flags, DESCRIPTOR_NEXT);
+        assert_eq!(unsafe { queue.avail().read_volatile() }, 1);
+        assert_eq!(queue.pop_used(), None);
+        queue.complete(token, 42);
+        assert_eq!(queue.pop_used(), Some(Completion { token, length: 42 }));
+        assert_eq!(queue.free_descriptors(), 8);
+        assert_eq!(queue.add(&buffers(9)), Err(Err::QueueFull));
+        assert_eq!(queue.add(&[]), Err(Err::NoBuffers));
+    }
+
+    #[test_case]
+    fn test_indirect_takes_one_descriptor() {
+        let mut queue = Virtqueue::new(0, 8, true).unwrap();
+        let token = queue.add(&buffers(20)).unwrap();
+        assert_eq!(queue.free_descriptors(), 7);
+        let head = queue.read_descriptor(token);
+        assert_eq!(head.flags, DESCRIPTOR_INDIRECT);
+        assert_eq!(
+            head.length as usize,
+            20 * core::mem::size_of::<Descriptor>()
+        );
+        let table = physical_to_virtual(head.address as usize) as *const Descriptor;
+        let last = unsafe { table.add(19).read_volatile() };
+        assert_eq!(last.flags, DESCRIPTOR_WRITE);
+        queue.complete(token, 512);
+        assert_eq!(queue.pop_used().map(|c| c.token), Some(token));
+        assert_eq!(queue.free_descriptors(), 8);
+        assert!(queue.indirect_tables.iter().all(Option::is_none));
+    }
+}
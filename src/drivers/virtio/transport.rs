@@ -0,0 +1,257 @@
+use core::ops::Range;
+
+use super::queue::Virtqueue;
+use super::{Err, Status};
+use crate::arch::port::{Port, PortValue};
+use crate::error::KResult;
+use crate::memory::{self, PAGE_SIZE};
+use crate::mmio::{Mmio, ReadOnly, VolatileCell};
+use crate::register_block;
+
+// How a virtio device's registers are reached. Everything above this (feature negotiation, queue
+// setup, VirtioDevice) is the same whichever transport the device uses.
+//
+// TODO: there's no PCI enumeration yet, so nothing finds these devices. Once there is, a legacy
+// device (transitional, 0x1000..0x103f) is its I/O BAR 0, and a modern one (0x1040..) is found from
+// its vendor capabilities.
+
+pub trait Transport {
+    // Virtio 1.0 and up, rather than the legacy 0.9.5 interface
+    fn is_modern(&self) -> bool;
+    fn device_features(&mut self) -> u64;
+    fn set_driver_features(&mut self, features: u64);
+    fn status(&self) -> Status;
+    // Writing an empty status resets the device
+    fn set_status(&mut self, status: Status);
+    // 0 if the queue doesn't exist
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+    fn setup_queue(&mut self, queue: &Virtqueue) -> Result<(), Err>;
+    fn notify(&self, queue: u16);
+    // Reading it acknowledges the interrupt
+    fn interrupt_status(&self) -> u8;
+    // The device specific configuration, eg. a block device's capacity
+    fn read_config(&self, offset: usize, buffer: &mut [u8]);
+}
+
+// Legacy virtio over PCI: the registers are in I/O space, followed by the device configuration
+// (MSI-X isn't supported, so it always starts at 0x14)
+pub struct Legacy {
+    base: u16,
+}
+
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+impl Legacy {
+    // Safety: there must be a legacy virtio device's registers at `base`
+    pub unsafe fn new(base: u16) -> Self {
+        Legacy { base }
+    }
+
+    fn port<T: PortValue>(&self, offset: u16) -> Port<T> {
+        Port::new(self.base + offset)
+    }
+}
+
+impl Transport for Legacy {
+    fn is_modern(&self) -> bool {
+        false
+    }
+
+    // Only 32 bits of them
+    fn device_features(&mut self) -> u64 {
+        unsafe { self.port::<u32>(LEGACY_DEVICE_FEATURES).read() as u64 }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        unsafe { self.port(LEGACY_DRIVER_FEATURES).write(features as u32) }
+    }
+
+    fn status(&self) -> Status {
+        Status::from_bits_truncate(unsafe { self.port(LEGACY_STATUS).read() })
+    }
+
+    fn set_status(&mut self, status: Status) {
+        unsafe { self.port(LEGACY_STATUS).write(status.bits()) }
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        unsafe {
+            self.port(LEGACY_QUEUE_SELECT).write(queue);
+            self.port(LEGACY_QUEUE_SIZE).read()
+        }
+    }
+
+    // The device decides the size, and finds the rings from one page number
+    fn setup_queue(&mut self, queue: &Virtqueue) -> Result<(), Err> {
+        let size = self.max_queue_size(queue.index());
+        if queue.size() != size {
+            return Err(Err::BadQueueSize(queue.size()));
+        }
+        let page = queue.descriptor_table() / PAGE_SIZE;
+        let page = u32::try_from(page).map_err(|_| Err::OutOfMemory)?;
+        unsafe { self.port(LEGACY_QUEUE_ADDRESS).write(page) };
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        unsafe { self.port(LEGACY_QUEUE_NOTIFY).write(queue) }
+    }
+
+    fn interrupt_status(&self) -> u8 {
+        unsafe { self.port(LEGACY_ISR).read() }
+    }
+
+    fn read_config(&self, offset: usize, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = unsafe { self.port(LEGACY_CONFIG + (offset + i) as u16).read() };
+        }
+    }
+}
+
+register_block! {
+    // virtio_pci_common_cfg
+    pub struct CommonConfig {
+        0x00 => pub device_feature_select: VolatileCell<u32>,
+        0x04 => pub device_feature: ReadOnly<u32>,
+        0x08 => pub driver_feature_select: VolatileCell<u32>,
+        0x0c => pub driver_feature: VolatileCell<u32>,
+        0x10 => pub msix_config: VolatileCell<u16>,
+        0x12 => pub num_queues: ReadOnly<u16>,
+        0x14 => pub device_status: VolatileCell<u8>,
+        0x15 => pub config_generation: ReadOnly<u8>,
+        0x16 => pub queue_select: VolatileCell<u16>,
+        0x18 => pub queue_size: VolatileCell<u16>,
+        0x1a => pub queue_msix_vector: VolatileCell<u16>,
+        0x1c => pub queue_enable: VolatileCell<u16>,
+        0x1e => pub queue_notify_off: ReadOnly<u16>,
+        0x20 => pub queue_desc: VolatileCell<u64>,
+        0x28 => pub queue_driver: VolatileCell<u64>,
+        0x30 => pub queue_device: VolatileCell<u64>,
+    }
+}
+
+// We never use more than this many queues (net uses 3, blk 1)
+const MAX_QUEUES: usize = 16;
+
+// Physical locations of a modern device's structures, from its PCI capabilities
+#[derive(Debug, Clone)]
+pub struct ModernRegions {
+    pub common: usize,
+    pub notify: Range<usize>,
+    pub notify_multiplier: u32,
+    pub isr: usize,
+    pub device: Range<usize>,
+}
+
+pub struct Modern {
+    common: Mmio<CommonConfig>,
+    isr: Mmio<ReadOnly<u8>>,
+    // Virtual
+    notify: usize,
+    notify_multiplier: u32,
+    notify_offsets: [u16; MAX_QUEUES],
+    device: Range<usize>,
+}
+
+impl Modern {
+    // Safety: `regions` must be a modern virtio device's, as its capabilities describe them
+    pub unsafe fn new(regions: ModernRegions) -> KResult<Self> {
+        let virtual_range = |range: &Range<usize>| {
+            memory::physical_to_virtual(range.start)..memory::physical_to_virtual(range.end)
+        };
+        let (notify, device) = (
+            virtual_range(&regions.notify),
+            virtual_range(&regions.device),
+        );
+        memory::set_uncached(notify.start, notify.end)?;
+        memory::set_uncached(device.start, device.end)?;
+        Ok(Modern {
+            common: Mmio::map(regions.common)?,
+            isr: Mmio::map(regions.isr)?,
+            notify: notify.start,
+            notify_multiplier: regions.notify_multiplier,
+            notify_offsets: [0; MAX_QUEUES],
+            device,
+        })
+    }
+}
+
+impl Transport for Modern {
+    fn is_modern(&self) -> bool {
+        true
+    }
+
+    // Read 32 bits at a time through the select register
+    fn device_features(&mut self) -> u64 {
+        let mut features = 0;
+        for half in 0..2 {
+            self.common.device_feature_select.write(half);
+            features |= (self.common.device_feature.read() as u64) << (32 * half);
+        }
+        features
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        for half in 0..2 {
+            self.common.driver_feature_select.write(half);
+            self.common
+                .driver_feature
+                .write((features >> (32 * half)) as u32);
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status::from_bits_truncate(self.common.device_status.read())
+    }
+
+    fn set_status(&mut self, status: Status) {
+        self.common.device_status.write(status.bits())
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        if queue as usize >= MAX_QUEUES || queue >= self.common.num_queues.read() {
+            return 0;
+        }
+        self.common.queue_select.write(queue);
+        self.common.queue_size.read()
+    }
+
+    fn setup_queue(&mut self, queue: &Virtqueue) -> Result<(), Err> {
+        if self.max_queue_size(queue.index()) == 0 {
+            return Err(Err::NoSuchQueue(queue.index()));
+        }
+        let common = &self.common;
+        common.queue_size.write(queue.size());
+        common.queue_desc.write(queue.descriptor_table() as u64);
+        common.queue_driver.write(queue.driver_area() as u64);
+        common.queue_device.write(queue.device_area() as u64);
+        self.notify_offsets[queue.index() as usize] = common.queue_notify_off.read();
+        common.queue_enable.write(1);
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        let offset = self.notify_offsets[queue as usize] as usize * self.notify_multiplier as usize;
+        unsafe { ((self.notify + offset) as *mut u16).write_volatile(queue) }
+    }
+
+    fn interrupt_status(&self) -> u8 {
+        self.isr.read()
+    }
+
+    fn read_config(&self, offset: usize, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let address = self.device.start + offset + i;
+            assert!(address < self.device.end, "virtio config read out of range");
+            *byte = unsafe { (address as *const u8).read_volatile() };
+        }
+    }
+}
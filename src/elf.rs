@@ -0,0 +1,235 @@
+use core::mem::size_of;
+
+// Minimal ELF64 parsing; just enough to inspect the kernel image the bootloader left in memory,
+// and to load user programs (see task::process).
+// Reference: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub file_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    pub section_header_size: u16,
+    pub section_header_count: u16,
+    pub section_name_index: u16,
+}
+
+pub const FILE_TYPE_EXECUTABLE: u16 = 2;
+pub const MACHINE_X86_64: u16 = 62;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+pub const SEGMENT_TYPE_LOAD: u32 = 1;
+
+pub const SEGMENT_FLAG_EXECUTE: u32 = 0x1;
+pub const SEGMENT_FLAG_WRITE: u32 = 0x2;
+pub const SEGMENT_FLAG_READ: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SectionHeader {
+    pub name: u32,
+    pub section_type: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub address_align: u64,
+    pub entry_size: u64,
+}
+
+pub const SECTION_FLAG_WRITE: u64 = 0x1;
+pub const SECTION_FLAG_ALLOC: u64 = 0x2;
+pub const SECTION_FLAG_EXECINSTR: u64 = 0x4;
+
+impl SectionHeader {
+    pub fn is_alloc(&self) -> bool {
+        self.flags & SECTION_FLAG_ALLOC != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & SECTION_FLAG_WRITE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & SECTION_FLAG_EXECINSTR != 0
+    }
+}
+
+pub const SECTION_TYPE_SYMTAB: u32 = 2;
+pub const SECTION_TYPE_RELA: u32 = 4;
+
+pub const SEGMENT_TYPE_LOAD: u32 = 1;
+
+// A relocation with an explicit addend. In an executable `offset` is a virtual address.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Rela {
+    pub offset: u64,
+    pub info: u64,
+    pub addend: i64,
+}
+
+impl Rela {
+    pub fn relocation_type(&self) -> u32 {
+        self.info as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Symbol {
+    pub name: u32,
+    pub info: u8,
+    pub other: u8,
+    pub section_index: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+const SYMBOL_TYPE_FUNC: u8 = 2;
+
+impl Symbol {
+    pub fn is_function(&self) -> bool {
+        self.info & 0xf == SYMBOL_TYPE_FUNC
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        (self.value..self.value + self.size.max(1)).contains(&address)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Err {
+    BadMagic,
+    UnsupportedClass,
+    Truncated,
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Err> {
+        if data.len() < size_of::<FileHeader>() {
+            return Err(Err::Truncated);
+        }
+        if data[..4] != MAGIC {
+            return Err(Err::BadMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(Err::UnsupportedClass);
+        }
+        Ok(ElfFile { data })
+    }
+
+    pub fn header(&self) -> &'a FileHeader {
+        // Safety: length was checked in `new`, and FileHeader is plain old data
+        unsafe { &*(self.data.as_ptr() as *const FileHeader) }
+    }
+
+    // Reads a T at `offset`, or None if it would read past the end of the image
+    fn read<T>(&self, offset: usize) -> Option<&'a T> {
+        if offset.checked_add(size_of::<T>())? > self.data.len() {
+            return None;
+        }
+        Some(unsafe { &*(self.data.as_ptr().add(offset) as *const T) })
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = &'a ProgramHeader> + '_ {
+        let header = self.header();
+        (0..header.program_header_count as usize).filter_map(move |i| {
+            self.read(
+                header.program_header_offset as usize + i * header.program_header_size as usize,
+            )
+        })
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = &'a SectionHeader> + '_ {
+        let header = self.header();
+        (0..header.section_header_count as usize).filter_map(move |i| {
+            self.read(
+                header.section_header_offset as usize + i * header.section_header_size as usize,
+            )
+        })
+    }
+
+    // The bytes a segment loads from the file; memory past them up to memory_size is zeroed
+    pub fn segment_data(&self, segment: &ProgramHeader) -> Option<&'a [u8]> {
+        let start = segment.offset as usize;
+        let end = start.checked_add(segment.file_size as usize)?;
+        self.data.get(start..end)
+    }
+
+    pub fn section_data(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        let start = section.offset as usize;
+        let end = start.checked_add(section.size as usize)?;
+        self.data.get(start..end)
+    }
+
+    // Reads a nul-terminated string at `offset` within the string table `section`
+    pub fn string_at(&self, section: &SectionHeader, offset: usize) -> Option<&'a str> {
+        let table = self.section_data(section)?;
+        let bytes = table.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    pub fn section_name(&self, section: &SectionHeader) -> Option<&'a str> {
+        let names = self
+            .sections()
+            .nth(self.header().section_name_index as usize)?;
+        self.string_at(names, section.name as usize)
+    }
+
+    // (symbol, name) for each entry in .symtab. Empty if the image was stripped.
+    pub fn symbols(&self) -> impl Iterator<Item = (&'a Symbol, &'a str)> + '_ {
+        let symtab = self
+            .sections()
+            .find(|s| s.section_type == SECTION_TYPE_SYMTAB);
+        let strtab = symtab.and_then(|s| self.sections().nth(s.link as usize));
+        let count = symtab.map_or(0, |s| s.size as usize / size_of::<Symbol>());
+        (0..count).filter_map(move |i| {
+            let symbol: &Symbol = self.read(symtab?.offset as usize + i * size_of::<Symbol>())?;
+            let name = self.string_at(strtab?, symbol.name as usize)?;
+            Some((symbol, name))
+        })
+    }
+
+    // Entries of every loaded SHT_RELA section, ie. the dynamic relocations of a PIE. Empty for
+    // a fixed position executable.
+    pub fn relocations(&self) -> impl Iterator<Item = &'a Rela> + '_ {
+        self.sections()
+            .filter(|s| s.section_type == SECTION_TYPE_RELA && s.is_alloc())
+            .flat_map(move |s| {
+                (0..s.size as usize / size_of::<Rela>())
+                    .filter_map(move |i| self.read(s.offset as usize + i * size_of::<Rela>()))
+            })
+    }
+}
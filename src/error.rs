@@ -0,0 +1,108 @@
+use alloc::string::String;
+use core::fmt;
+
+// Errors that carry their context up the stack. Every KError has a code for callers to match on,
+// and optionally a static message saying what was being done and a formatted detail string with
+// the specifics (addresses, device names), so whatever finally logs or panics on it can say more
+// than "Err(())".
+//
+// Details are formatted onto the heap, so don't add them where the heap might not be usable:
+// anywhere under the page allocator's lock (the heap grows through it), or in interrupt handlers.
+// Static messages are always fine.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // No physical memory left (in the zones asked for)
+    OutOfMemory,
+    // No virtual address range big enough
+    OutOfAddressSpace,
+    PageNotPresent,
+    // Mapped by a 2MiB or 1GiB page, so there's no l1 entry
+    HugePage,
+    AlreadyMapped,
+    // The device is there, but didn't behave
+    DeviceNotResponding,
+    Unsupported,
+    InvalidArgument,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KError {
+    code: ErrorCode,
+    message: Option<&'static str>,
+    detail: Option<String>,
+}
+
+pub type KResult<T> = Result<T, KError>;
+
+impl KError {
+    pub const fn new(code: ErrorCode) -> Self {
+        KError {
+            code,
+            message: None,
+            detail: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: &'static str) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    // Allocates, see above
+    pub fn with_detail(mut self, detail: fmt::Arguments) -> Self {
+        self.detail = Some(alloc::format!("{}", detail));
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn message(&self) -> Option<&'static str> {
+        self.message
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+impl From<ErrorCode> for KError {
+    fn from(code: ErrorCode) -> Self {
+        KError::new(code)
+    }
+}
+
+// eg. "OutOfMemory: allocating a page table (zone Dma)"
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.code)?;
+        if let Some(message) = self.message {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn test_error_display() {
+        let err = KError::new(ErrorCode::DeviceNotResponding)
+            .with_message("probing serial port")
+            .with_detail(format_args!("COM{}", 2));
+        assert_eq!(err.code(), ErrorCode::DeviceNotResponding);
+        assert_eq!(
+            err.to_string(),
+            "DeviceNotResponding: probing serial port (COM2)"
+        );
+        assert_eq!(KError::from(ErrorCode::HugePage).to_string(), "HugePage");
+    }
+}
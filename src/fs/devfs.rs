@@ -0,0 +1,117 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::file::{Err, File};
+use super::vfs::Filesystem;
+use crate::print;
+
+// Device nodes, opened as /dev/<name> (see fs::vfs). Drivers register theirs with `register`; the
+// console and null devices are always there.
+
+struct Node {
+    name: &'static str,
+    file: Arc<dyn File>,
+}
+
+lazy_static! {
+    static ref NODES: Mutex<Vec<Node>> = Mutex::new(Vec::from([
+        Node {
+            name: "console",
+            file: Arc::new(Console::new()),
+        },
+        Node {
+            name: "null",
+            file: Arc::new(Null),
+        },
+    ]));
+}
+
+pub fn register(name: &'static str, file: Arc<dyn File>) {
+    crate::without_interrupt! {{
+        NODES.lock().push(Node { name, file });
+    }}
+}
+
+// A node by its name under /dev
+pub fn lookup(name: &str) -> Result<Arc<dyn File>, Err> {
+    crate::without_interrupt! {{
+        NODES
+            .lock()
+            .iter()
+            .find(|node| node.name == name)
+            .map(|node| node.file.clone())
+            .ok_or(Err::NotFound)
+    }}
+}
+
+// Mounted at /dev
+pub struct Devfs;
+
+impl Filesystem for Devfs {
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, Err> {
+        lookup(path)
+    }
+}
+
+// Reads are whole lines from the console's line editor, handed out in as many reads as the
+// caller's buffer needs; writes are printed
+struct Console {
+    pending: Mutex<VecDeque<u8>>,
+}
+
+impl Console {
+    fn new() -> Self {
+        Console {
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl File for Console {
+    fn read(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+        let empty = crate::without_interrupt! {{ self.pending.lock().is_empty() }};
+        if empty && !buffer.is_empty() {
+            // Blocks, so not under our lock
+            let line = crate::console::line::read_line();
+            crate::without_interrupt! {{
+                let mut pending = self.pending.lock();
+                pending.extend(line.bytes());
+                pending.push_back(b'\n');
+            }}
+        }
+        crate::without_interrupt! {{
+            let mut pending = self.pending.lock();
+            let count = buffer.len().min(pending.len());
+            for (byte, pending) in buffer.iter_mut().zip(pending.drain(..count)) {
+                *byte = pending;
+            }
+            Ok(count)
+        }}
+    }
+
+    fn write(&self, _offset: u64, buffer: &[u8]) -> Result<usize, Err> {
+        print!("{}", alloc::string::String::from_utf8_lossy(buffer));
+        Ok(buffer.len())
+    }
+}
+
+// Reads as empty, swallows writes
+struct Null;
+
+impl File for Null {
+    fn read(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Err> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, buffer: &[u8]) -> Result<usize, Err> {
+        Ok(buffer.len())
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(0)
+    }
+}
@@ -0,0 +1,566 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::file::{self, File};
+use super::page_cache::{self, Backing, Key, Page};
+use super::vfs::{self, Filesystem};
+use crate::memory::PAGE_SIZE;
+
+// Read-only ext2, enough to read files out of an image made with mke2fs: the superblock, group
+// descriptors, inodes (through direct and indirect block pointers) and directories.
+// Reference: https://www.nongnu.org/ext2-doc/ext2.html
+//
+// The filesystem is read from an in-memory image (eg. a ramdisk); there's no block device layer
+// yet. `mount` puts an image in the VFS, and files opened through it are read via the page cache.
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const MAGIC: u16 = 0xef53;
+pub const ROOT_INODE: u32 = 2;
+// Inode size before revision 1 filesystems made it configurable
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+// Incompatible features we can read: just the file type in directory entries. Anything else
+// (compression, journals needing recovery, extents, 64 bit block numbers, ...) changes the on-disk
+// format in ways we'd misread.
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    BadMagic,
+    UnsupportedFeature(u32),
+    Truncated,
+    // An inode or block number outside the filesystem
+    OutOfRange,
+    NotFound,
+    NotADirectory,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub reserved_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub log_fragment_size: u32,
+    pub blocks_per_group: u32,
+    pub fragments_per_group: u32,
+    pub inodes_per_group: u32,
+    pub mount_time: u32,
+    pub write_time: u32,
+    pub mount_count: u16,
+    pub max_mount_count: u16,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub minor_revision: u16,
+    pub last_check: u32,
+    pub check_interval: u32,
+    pub creator_os: u32,
+    pub revision: u32,
+    pub default_reserved_uid: u16,
+    pub default_reserved_gid: u16,
+    // Revision 1 onwards
+    pub first_inode: u32,
+    pub inode_size: u16,
+    pub block_group: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct GroupDescriptor {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+    pub pad: u16,
+    pub reserved: [u8; 12],
+}
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Inode {
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u32,
+    pub access_time: u32,
+    pub change_time: u32,
+    pub modification_time: u32,
+    pub deletion_time: u32,
+    pub gid: u16,
+    pub links_count: u16,
+    // In 512 byte sectors, not filesystem blocks
+    pub sectors: u32,
+    pub flags: u32,
+    pub os_specific: u32,
+    pub block: [u32; 15],
+    pub generation: u32,
+    pub file_acl: u32,
+    // The top half of the size for regular files, with the large_file feature
+    pub size_high: u32,
+    pub fragment_address: u32,
+    pub os_specific_2: [u8; 12],
+}
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct DirEntryHeader {
+    inode: u32,
+    record_length: u16,
+    name_length: u8,
+    // Only with the filetype feature; the inode has it anyway
+    _file_type: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub name: String,
+}
+
+pub struct Ext2<'a> {
+    data: &'a [u8],
+    superblock: Superblock,
+    block_size: usize,
+}
+
+impl<'a> Ext2<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Err> {
+        let superblock: Superblock = read(data, SUPERBLOCK_OFFSET).ok_or(Err::Truncated)?;
+        if superblock.magic != MAGIC {
+            return Err(Err::BadMagic);
+        }
+        let unsupported = superblock.feature_incompat & !SUPPORTED_INCOMPAT;
+        if superblock.revision > 0 && unsupported != 0 {
+            return Err(Err::UnsupportedFeature(unsupported));
+        }
+        // Blocks are 1KiB << log_block_size; anything past 64KiB is corrupt
+        if superblock.log_block_size > 6 || superblock.inodes_per_group == 0 {
+            return Err(Err::OutOfRange);
+        }
+        Ok(Ext2 {
+            data,
+            superblock,
+            block_size: 1024 << superblock.log_block_size,
+        })
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn inode_size(&self) -> usize {
+        match self.superblock.revision {
+            0 => GOOD_OLD_INODE_SIZE as usize,
+            _ => self.superblock.inode_size as usize,
+        }
+    }
+
+    fn block(&self, number: u32) -> Result<&'a [u8], Err> {
+        if number >= self.superblock.blocks_count {
+            return Err(Err::OutOfRange);
+        }
+        let start = number as usize * self.block_size;
+        self.data
+            .get(start..start + self.block_size)
+            .ok_or(Err::Truncated)
+    }
+
+    fn group_descriptor(&self, group: u32) -> Result<GroupDescriptor, Err> {
+        // The table starts in the block after the superblock
+        let table = (self.superblock.first_data_block as usize + 1) * self.block_size;
+        let offset = table + group as usize * size_of::<GroupDescriptor>();
+        read(self.data, offset).ok_or(Err::Truncated)
+    }
+
+    pub fn inode(&self, number: u32) -> Result<Inode, Err> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(Err::OutOfRange);
+        }
+        let index = number - 1;
+        let group = self.group_descriptor(index / self.superblock.inodes_per_group)?;
+        let offset = group.inode_table as usize * self.block_size
+            + (index % self.superblock.inodes_per_group) as usize * self.inode_size();
+        read(self.data, offset).ok_or(Err::Truncated)
+    }
+
+    pub fn size(&self, inode: &Inode) -> u64 {
+        let large_file = self.superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE != 0;
+        match inode.is_file() && large_file {
+            true => (inode.size_high as u64) << 32 | inode.size as u64,
+            false => inode.size as u64,
+        }
+    }
+
+    // Entry `index` of the block of block numbers `block`
+    fn indirect(&self, block: u32, index: usize) -> Result<u32, Err> {
+        match block {
+            0 => Ok(0),
+            block => read(self.block(block)?, index * size_of::<u32>()).ok_or(Err::Truncated),
+        }
+    }
+
+    // The filesystem block holding block `index` of the inode's data. 0 for a hole.
+    fn data_block(&self, inode: &Inode, index: usize) -> Result<u32, Err> {
+        let per_block = self.block_size / size_of::<u32>();
+        let mut index = index;
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.block[index]);
+        }
+        index -= DIRECT_BLOCKS;
+        if index < per_block {
+            return self.indirect(inode.block[INDIRECT_BLOCK], index);
+        }
+        index -= per_block;
+        if index < per_block * per_block {
+            let block = self.indirect(inode.block[DOUBLE_INDIRECT_BLOCK], index / per_block)?;
+            return self.indirect(block, index % per_block);
+        }
+        index -= per_block * per_block;
+        let block = self.indirect(
+            inode.block[TRIPLE_INDIRECT_BLOCK],
+            index / (per_block * per_block),
+        )?;
+        let block = self.indirect(block, index / per_block % per_block)?;
+        self.indirect(block, index % per_block)
+    }
+
+    // Read from `offset` into `buffer`, returning how many bytes were read (0 at the end of file)
+    pub fn read(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+        let size = self.size(inode);
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = buffer.len().min((size - offset) as usize);
+        let mut done = 0;
+        while done < length {
+            let position = offset as usize + done;
+            let within = position % self.block_size;
+            let count = (self.block_size - within).min(length - done);
+            let destination = &mut buffer[done..done + count];
+            match self.data_block(inode, position / self.block_size)? {
+                0 => destination.fill(0),
+                block => destination.copy_from_slice(&self.block(block)?[within..within + count]),
+            }
+            done += count;
+        }
+        Ok(length)
+    }
+
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>, Err> {
+        if !dir.is_dir() {
+            return Err(Err::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        let blocks = (self.size(dir) as usize).div_ceil(self.block_size);
+        for index in 0..blocks {
+            let block = match self.data_block(dir, index)? {
+                0 => continue,
+                block => self.block(block)?,
+            };
+            let mut offset = 0;
+            while offset + size_of::<DirEntryHeader>() <= block.len() {
+                let header: DirEntryHeader = read(block, offset).ok_or(Err::Truncated)?;
+                if header.record_length == 0 {
+                    return Err(Err::Truncated);
+                }
+                // Unused entries have inode 0
+                if header.inode != 0 {
+                    let name_start = offset + size_of::<DirEntryHeader>();
+                    let name = block
+                        .get(name_start..name_start + header.name_length as usize)
+                        .ok_or(Err::Truncated)?;
+                    entries.push(DirEntry {
+                        inode: header.inode,
+                        name: String::from_utf8_lossy(name).into_owned(),
+                    });
+                }
+                offset += header.record_length as usize;
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn lookup(&self, dir: &Inode, name: &str) -> Result<u32, Err> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.inode)
+            .ok_or(Err::NotFound)
+    }
+
+    // Inode number of an absolute path, eg. "/etc/hostname"
+    pub fn resolve(&self, path: &str) -> Result<u32, Err> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(ROOT_INODE, |number, part| {
+                self.lookup(&self.inode(number)?, part)
+            })
+    }
+}
+
+impl From<Err> for file::Err {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::NotFound | Err::NotADirectory => file::Err::NotFound,
+            // Not an image we can mount
+            Err::BadMagic | Err::UnsupportedFeature(_) => file::Err::InvalidArgument,
+            Err::Truncated | Err::OutOfRange => file::Err::Io,
+        }
+    }
+}
+
+// A mounted image. Its pages are evicted from the page cache once it's unmounted and the last of
+// its files is closed.
+struct Volume {
+    fs: Ext2<'static>,
+    owner: u32,
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        page_cache::evict(self.owner);
+    }
+}
+
+struct Mount(Arc<Volume>);
+
+impl Filesystem for Mount {
+    // Regular files only; directories are listed with read_dir
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, file::Err> {
+        let fs = &self.0.fs;
+        let number = fs.resolve(path)?;
+        let inode = fs.inode(number)?;
+        if !inode.is_file() {
+            return Err(file::Err::Unsupported);
+        }
+        Ok(Arc::new(Ext2File {
+            volume: self.0.clone(),
+            number,
+            size: fs.size(&inode),
+            inode,
+        }))
+    }
+}
+
+struct Ext2File {
+    volume: Arc<Volume>,
+    number: u32,
+    inode: Inode,
+    size: u64,
+}
+
+impl File for Ext2File {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, file::Err> {
+        let owner = self.volume.owner;
+        page_cache::read(owner, self.number as u64, self.size, offset, buffer, self)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    fn page(&self, index: u64) -> Result<Arc<Page>, file::Err> {
+        let key = Key {
+            owner: self.volume.owner,
+            object: self.number as u64,
+            index,
+        };
+        page_cache::page(key, self)
+    }
+}
+
+impl Backing for Ext2File {
+    fn read_page(&self, index: u64, page: &mut [u8]) -> Result<(), file::Err> {
+        let offset = index * PAGE_SIZE as u64;
+        self.volume.fs.read(&self.inode, offset, page)?;
+        Ok(())
+    }
+}
+
+// Mount the image in `data` at `path`, eg. a ramdisk at "/"
+pub fn mount(path: &str, data: &'static [u8]) -> Result<(), file::Err> {
+    let volume = Volume {
+        fs: Ext2::new(data)?,
+        owner: page_cache::new_owner(),
+    };
+    vfs::mount(path, Arc::new(Mount(Arc::new(volume))))
+}
+
+// Copies a T out of `data` at `offset`, which needn't be aligned
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let bytes = data.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    const BLOCK_SIZE: usize = 1024;
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_dir_entry(image: &mut [u8], offset: usize, inode: u32, length: u16, name: &str) {
+        put_u32(image, offset, inode);
+        put_u16(image, offset + 4, length);
+        image[offset + 6] = name.len() as u8;
+        image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    // 1KiB blocks: superblock in 1, group descriptors in 2, inode table in 5-8, the root directory
+    // in 9 and /hello's data in 10
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 16 * BLOCK_SIZE];
+        let superblock = SUPERBLOCK_OFFSET;
+        put_u32(&mut image, superblock, 32);
+        put_u32(&mut image, superblock + 4, 16);
+        put_u32(&mut image, superblock + 20, 1);
+        put_u32(&mut image, superblock + 32, 8192);
+        put_u32(&mut image, superblock + 40, 32);
+        put_u16(&mut image, superblock + 56, MAGIC);
+        put_u32(&mut image, superblock + 76, 1);
+        put_u16(&mut image, superblock + 88, 128);
+        put_u32(&mut image, superblock + 96, INCOMPAT_FILETYPE);
+        put_u32(&mut image, 2 * BLOCK_SIZE + 8, 5);
+
+        let inode = |number: usize| 5 * BLOCK_SIZE + (number - 1) * 128;
+        put_u16(&mut image, inode(2), MODE_DIRECTORY | 0o755);
+        put_u32(&mut image, inode(2) + 4, BLOCK_SIZE as u32);
+        put_u32(&mut image, inode(2) + 40, 9);
+        put_u16(&mut image, inode(12), MODE_REGULAR | 0o644);
+        put_u32(&mut image, inode(12) + 4, 6);
+        put_u32(&mut image, inode(12) + 40, 10);
+
+        let root = 9 * BLOCK_SIZE;
+        put_dir_entry(&mut image, root, 2, 12, ".");
+        put_dir_entry(&mut image, root + 12, 2, 12, "..");
+        put_dir_entry(&mut image, root + 24, 12, BLOCK_SIZE as u16 - 24, "hello");
+        image[10 * BLOCK_SIZE..10 * BLOCK_SIZE + 6].copy_from_slice(b"world\n");
+        image
+    }
+
+    #[test_case]
+    fn test_ext2_read_file() {
+        let image = image();
+        let fs = Ext2::new(&image).unwrap();
+        assert_eq!(fs.block_size(), BLOCK_SIZE);
+        let number = fs.resolve("/hello").unwrap();
+        assert_eq!(number, 12);
+        let file = fs.inode(number).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(fs.read(&file, 0, &mut buffer), Ok(6));
+        assert_eq!(&buffer[..6], b"world\n");
+        assert_eq!(fs.read(&file, 3, &mut buffer), Ok(3));
+        assert_eq!(&buffer[..3], b"ld\n");
+        assert_eq!(fs.read(&file, 6, &mut buffer), Ok(0));
+    }
+
+    #[test_case]
+    fn test_ext2_directories() {
+        let image = image();
+        let fs = Ext2::new(&image).unwrap();
+        let root = fs.inode(ROOT_INODE).unwrap();
+        let names: Vec<String> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, [".", "..", "hello"]);
+        assert_eq!(fs.resolve("/missing"), Err(Err::NotFound));
+        assert_eq!(fs.resolve("/hello/world"), Err(Err::NotADirectory));
+        assert_eq!(fs.resolve("/"), Ok(ROOT_INODE));
+    }
+
+    #[test_case]
+    fn test_ext2_mounted_files_read_through_page_cache() {
+        let image = alloc::boxed::Box::leak(image().into_boxed_slice());
+        let volume = Arc::new(Volume {
+            fs: Ext2::new(image).unwrap(),
+            owner: page_cache::new_owner(),
+        });
+        let owner = volume.owner;
+        vfs::mount("/ext2_test", Arc::new(Mount(volume))).unwrap();
+        let file = vfs::open("/ext2_test/hello").unwrap();
+        assert_eq!(file.size(), Some(6));
+        let mut buffer = [0; 16];
+        assert_eq!(file.read(0, &mut buffer), Ok(6));
+        assert_eq!(&buffer[..6], b"world\n");
+        assert_eq!(page_cache::cached(owner), 1);
+        assert_eq!(file.read(2, &mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"rld\n");
+        assert_eq!(page_cache::cached(owner), 1);
+        assert_eq!(&file.page(0).unwrap().bytes()[..6], b"world\n");
+        assert!(vfs::open("/ext2_test/missing").err() == Some(file::Err::NotFound));
+        assert!(vfs::open("/ext2_test/").err() == Some(file::Err::Unsupported));
+
+        // The open file keeps the volume (and its pages) after unmounting
+        vfs::unmount("/ext2_test").unwrap();
+        assert!(vfs::open("/ext2_test/hello").err() == Some(file::Err::NotFound));
+        assert_eq!(page_cache::cached(owner), 1);
+        drop(file);
+        assert_eq!(page_cache::cached(owner), 0);
+    }
+
+    #[test_case]
+    fn test_ext2_mount_rejects_bad_images() {
+        let image = alloc::boxed::Box::leak(vec![0; 16 * BLOCK_SIZE].into_boxed_slice());
+        assert_eq!(mount("/ext2_bad", image), Err(file::Err::InvalidArgument));
+        assert_eq!(vfs::unmount("/ext2_bad"), Err(file::Err::NotFound));
+    }
+
+    #[test_case]
+    fn test_ext2_rejects_unsupported() {
+        let mut image = image();
+        // Extents, ie. ext4
+        put_u32(&mut image, SUPERBLOCK_OFFSET + 96, INCOMPAT_FILETYPE | 0x40);
+        assert_eq!(Ext2::new(&image).err(), Some(Err::UnsupportedFeature(0x40)));
+        put_u16(&mut image, SUPERBLOCK_OFFSET + 56, 0);
+        assert_eq!(Ext2::new(&image).err(), Some(Err::BadMagic));
+    }
+}
@@ -0,0 +1,363 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::file::{Err, File};
+use super::{devfs, vfs};
+use crate::memory::usercopy;
+use crate::sync::Mutex;
+use crate::syscall::{self, Syscall};
+use crate::task::scheduler::{self, TaskId};
+use crate::{print, println};
+
+// File descriptors: each task has a table of small integers naming the files it has open, and
+// the open/read/write/seek/close syscalls work on the current task's table. Every task starts
+// with stdin, stdout and stderr on the console, except processes, which start with copies of
+// their parent's (see `inherit`). Paths are opened through the mount table (see fs::vfs).
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+const MAX_FILES: usize = 16;
+// The most a read or write syscall moves at once, through a kernel buffer. Callers loop for more,
+// as they must for any short read or write.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+// A file and our position in it. Shared by descriptors for the same open (eg. stdin/out/err).
+struct OpenFile {
+    file: Arc<dyn File>,
+    // A sleeping lock, since it's held across (possibly blocking) reads and writes
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    fn new(file: Arc<dyn File>) -> Arc<Self> {
+        Arc::new(OpenFile {
+            file,
+            offset: Mutex::new(0),
+        })
+    }
+}
+
+// Kept by the scheduler, with each task's other state
+#[derive(Clone)]
+pub struct FileTable {
+    files: [Option<Arc<OpenFile>>; MAX_FILES],
+}
+
+impl FileTable {
+    pub(crate) fn new() -> Self {
+        let mut files = [(); MAX_FILES].map(|_| None);
+        if let Ok(console) = devfs::lookup("console") {
+            files[STDIN..=STDERR].fill(Some(OpenFile::new(console)));
+        }
+        FileTable { files }
+    }
+
+    // The lowest free descriptor
+    fn insert(&mut self, file: Arc<OpenFile>) -> Result<usize, Err> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Err::TooManyFiles)?;
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    fn get(&self, fd: usize) -> Result<Arc<OpenFile>, Err> {
+        self.files
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(Err::BadFileDescriptor)
+    }
+
+    fn remove(&mut self, fd: usize) -> Result<Arc<OpenFile>, Err> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Err::BadFileDescriptor)
+    }
+}
+
+fn with_files<R>(f: impl FnOnce(&mut FileTable) -> Result<R, Err>) -> Result<R, Err> {
+    scheduler::with_files(scheduler::current_id(), f).unwrap_or(Err(Err::BadFileDescriptor))
+}
+
+pub fn open(path: &str) -> Result<usize, Err> {
+    install(vfs::open(path)?)
+}
+
+// Give the current task a descriptor for a file that has no path, eg. a pipe
+pub fn install(file: Arc<dyn File>) -> Result<usize, Err> {
+    let file = OpenFile::new(file);
+    with_files(|files| files.insert(file))
+}
+
+// Give `child`, a task that hasn't run yet, the current task's descriptors. They share the open
+// files, offsets included, eg. so a process can hand a pipe to one it spawns.
+pub fn inherit(child: TaskId) {
+    let table = scheduler::with_files(scheduler::current_id(), |files| files.clone());
+    // Tasks without a table (eg. the bootstrap task) leave the child with the console
+    if let Some(table) = table {
+        let replaced = scheduler::with_files(child, |files| core::mem::replace(files, table));
+        // Dropped once the scheduler's lock is released
+        drop(replaced);
+    }
+}
+
+// The file behind a descriptor, eg. for a mapping of it to hold on to (see fs::mmap)
+pub fn file(fd: usize) -> Result<Arc<dyn File>, Err> {
+    Ok(with_files(|files| files.get(fd))?.file.clone())
+}
+
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, Err> {
+    let open = with_files(|files| files.get(fd))?;
+    let mut offset = open.offset.lock();
+    let count = open.file.read(*offset, buffer)?;
+    *offset += count as u64;
+    Ok(count)
+}
+
+pub fn write(fd: usize, buffer: &[u8]) -> Result<usize, Err> {
+    let open = with_files(|files| files.get(fd))?;
+    let mut offset = open.offset.lock();
+    let count = open.file.write(*offset, buffer)?;
+    *offset += count as u64;
+    Ok(count)
+}
+
+// Returns the new offset
+pub fn seek(fd: usize, offset: i64, whence: Whence) -> Result<u64, Err> {
+    let open = with_files(|files| files.get(fd))?;
+    let size = open.file.size().ok_or(Err::NotSeekable)?;
+    let mut current = open.offset.lock();
+    let base = match whence {
+        Whence::Set => 0,
+        Whence::Current => *current,
+        Whence::End => size,
+    };
+    let new = base as i128 + offset as i128;
+    if new < 0 || new > u64::MAX as i128 {
+        return Err(Err::InvalidArgument);
+    }
+    *current = new as u64;
+    Ok(*current)
+}
+
+pub fn close(fd: usize) -> Result<(), Err> {
+    // The file is closed once its last descriptor is
+    with_files(|files| files.remove(fd)).map(drop)
+}
+
+// The whole of a file, eg. a font or image for the kernel itself to use
+pub fn read_all(path: &str) -> Result<Vec<u8>, Err> {
+    let fd = open(path)?;
+    let mut data = Vec::new();
+    let mut buffer = [0; 512];
+    let result = loop {
+        match read(fd, &mut buffer) {
+            Ok(0) => break Ok(data),
+            Ok(length) => data.extend_from_slice(&buffer[..length]),
+            Err(err) => break Err(err),
+        }
+    };
+    close(fd)?;
+    result
+}
+
+// open(path, path_length) -> fd
+fn sys_open(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    Ok(open(&syscall::path_arg(args[0], args[1])?)? as u64)
+}
+
+// read(fd, buffer, length) -> bytes read, at most MAX_TRANSFER
+fn sys_read(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let address = args[1] as usize;
+    let length = args[2].min(MAX_TRANSFER as u64) as usize;
+    // Checked first, since whatever's read can't be put back
+    usercopy::check_writable(address, length)?;
+    let mut buffer = vec![0; length];
+    let count = read(args[0] as usize, &mut buffer)?;
+    usercopy::copy_to_user(address, &buffer[..count])?;
+    Ok(count as u64)
+}
+
+// write(fd, buffer, length) -> bytes written, at most MAX_TRANSFER
+fn sys_write(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let length = args[2].min(MAX_TRANSFER as u64);
+    let buffer = syscall::bytes_arg(args[1], length, MAX_TRANSFER)?;
+    Ok(write(args[0] as usize, &buffer)? as u64)
+}
+
+// seek(fd, offset, whence) -> new offset, whence being 0 (set), 1 (current) or 2 (end)
+fn sys_seek(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let whence = match args[2] {
+        0 => Whence::Set,
+        1 => Whence::Current,
+        2 => Whence::End,
+        _ => return Err(syscall::Err::InvalidArgument),
+    };
+    Ok(seek(args[0] as usize, args[1] as i64, whence)?)
+}
+
+// close(fd)
+fn sys_close(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    close(args[0] as usize)?;
+    Ok(0)
+}
+
+// cat <path>...
+fn cat_command(args: &[&str]) {
+    if args.is_empty() {
+        println!("usage: cat <path>...");
+    }
+    for path in args {
+        let fd = match open(path) {
+            Ok(fd) => fd,
+            Err(err) => {
+                println!("cat: {}: {:?}", path, err);
+                continue;
+            }
+        };
+        let mut buffer = [0; 256];
+        loop {
+            match read(fd, &mut buffer) {
+                Ok(0) => break,
+                Ok(count) => print!(
+                    "{}",
+                    alloc::string::String::from_utf8_lossy(&buffer[..count])
+                ),
+                Err(err) => {
+                    println!("cat: {}: {:?}", path, err);
+                    break;
+                }
+            }
+        }
+        let _ = close(fd);
+    }
+}
+
+pub fn init() {
+    crate::shell::register("cat", "print files", cat_command);
+    syscall::register(Syscall::Open, sys_open);
+    syscall::register(Syscall::Read, sys_read);
+    syscall::register(Syscall::Write, sys_write);
+    syscall::register(Syscall::Seek, sys_seek);
+    syscall::register(Syscall::Close, sys_close);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::{AddressSpace, PROCESS_START};
+    use crate::memory::page_table::PageTableFlags;
+    use crate::memory::PAGE_SIZE;
+
+    struct Bytes(&'static [u8]);
+
+    impl File for Bytes {
+        fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+            let data = self.0.get(offset as usize..).unwrap_or(&[]);
+            let count = buffer.len().min(data.len());
+            buffer[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+
+        fn size(&self) -> Option<u64> {
+            Some(self.0.len() as u64)
+        }
+    }
+
+    #[test_case]
+    fn test_read_seek_close() {
+        devfs::register("fd_test", Arc::new(Bytes(b"hello, world")));
+        let fd = open("/dev/fd_test").unwrap();
+        assert!(fd > STDERR);
+        let mut buffer = [0; 5];
+        assert_eq!(read(fd, &mut buffer), Ok(5));
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(seek(fd, -5, Whence::End), Ok(7));
+        assert_eq!(read(fd, &mut buffer), Ok(5));
+        assert_eq!(&buffer, b"world");
+        assert_eq!(read(fd, &mut buffer), Ok(0));
+        assert_eq!(seek(fd, -1, Whence::Set), Err(Err::InvalidArgument));
+        assert_eq!(write(fd, b"nope"), Err(Err::Unsupported));
+        assert_eq!(close(fd), Ok(()));
+        assert_eq!(close(fd), Err(Err::BadFileDescriptor));
+        assert_eq!(open("/dev/missing"), Err(Err::NotFound));
+    }
+
+    #[test_case]
+    fn test_inherit() {
+        devfs::register("fd_inherit_test", Arc::new(Bytes(b"shared")));
+        let fd = open("/dev/fd_inherit_test").unwrap();
+        let child = scheduler::spawn("fd_inherit_child", || ()).unwrap();
+        inherit(child);
+        let mine = with_files(|files| files.get(fd)).unwrap();
+        let theirs = scheduler::with_files(child, |files| files.get(fd)).unwrap();
+        // The same open file, so reads by either move both along
+        assert!(Arc::ptr_eq(&mine, &theirs.unwrap()));
+        close(fd).unwrap();
+        assert!(scheduler::with_files(child, |files| files.get(STDOUT).is_ok()).unwrap());
+    }
+
+    #[test_case]
+    fn test_file_syscalls() {
+        devfs::register("fd_syscall_test", Arc::new(Bytes(b"read by a syscall")));
+        let mut address_space = AddressSpace::new().unwrap();
+        let user = PageTableFlags::USER_ACCESSIBLE;
+        address_space.map(PROCESS_START, user).unwrap();
+        address_space
+            .map(PROCESS_START + PAGE_SIZE, user | PageTableFlags::WRITABLE)
+            .unwrap();
+        let path = b"/dev/fd_syscall_test";
+        let message = b"written through sys_write\n";
+        address_space.write(PROCESS_START, path).unwrap();
+        address_space.write(PROCESS_START + 64, message).unwrap();
+        let (path, message) = (PROCESS_START as u64, PROCESS_START as u64 + 64);
+        let buffer = (PROCESS_START + PAGE_SIZE) as u64;
+        address_space.enter(|| {
+            let call = |syscall: Syscall, args: [u64; 3]| {
+                syscall::dispatch(syscall as usize, [args[0], args[1], args[2], 0, 0, 0])
+            };
+            assert_eq!(call(Syscall::Write, [STDOUT as u64, message, 26]), Ok(26));
+            let fd = call(Syscall::Open, [path, 20, 0]).unwrap();
+            assert_eq!(call(Syscall::Seek, [fd, 8, 0]), Ok(8));
+            assert_eq!(call(Syscall::Read, [fd, buffer, 64]), Ok(9));
+            let mut read = [0; 9];
+            usercopy::copy_from_user(&mut read, buffer as usize).unwrap();
+            assert_eq!(&read, b"a syscall");
+            // Reads aren't let into read-only or kernel memory, and nothing's read
+            assert_eq!(call(Syscall::Seek, [fd, 0, 0]), Ok(0));
+            assert_eq!(
+                call(Syscall::Read, [fd, path, 4]),
+                Err(syscall::Err::BadAddress)
+            );
+            let kernel = read.as_mut_ptr() as u64;
+            assert_eq!(
+                call(Syscall::Read, [fd, kernel, 4]),
+                Err(syscall::Err::BadAddress)
+            );
+            assert_eq!(call(Syscall::Seek, [fd, 0, 1]), Ok(0));
+            assert_eq!(call(Syscall::Close, [fd, 0, 0]), Ok(0));
+            assert_eq!(
+                call(Syscall::Seek, [STDOUT as u64, 0, 0]),
+                Err(syscall::Err::InvalidArgument)
+            );
+            assert_eq!(
+                call(Syscall::Close, [MAX_FILES as u64, 0, 0]),
+                Err(syscall::Err::BadFileDescriptor)
+            );
+        });
+    }
+}
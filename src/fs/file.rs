@@ -0,0 +1,62 @@
+use alloc::sync::Arc;
+
+use super::page_cache::Page;
+use crate::syscall;
+
+// Anything that can be opened: device nodes, and files on mounted filesystems (see fs::vfs).
+// Files don't keep a position; the offset is per open file (see fs::fd), so two opens of the same
+// file read independently.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    NotFound,
+    BadFileDescriptor,
+    TooManyFiles,
+    // Streams (eg. the console) have no position to seek to
+    NotSeekable,
+    // eg. writing to a read-only file
+    Unsupported,
+    InvalidArgument,
+    // Writing to a pipe nobody can read
+    BrokenPipe,
+    // No memory to cache the file's data in
+    OutOfMemory,
+    // The file's data couldn't be read or written
+    Io,
+}
+
+pub trait File: Send + Sync {
+    // Returns how many bytes were read, 0 at the end of the file
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err>;
+
+    fn write(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, Err> {
+        Err(Err::Unsupported)
+    }
+
+    // None for streams, which ignore the offset and can't seek
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    // Page `index` of the file from the page cache, for files that can be memory-mapped (see
+    // fs::mmap)
+    fn page(&self, _index: u64) -> Result<Arc<Page>, Err> {
+        Err(Err::Unsupported)
+    }
+}
+
+impl From<Err> for syscall::Err {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::NotFound => syscall::Err::NotFound,
+            Err::BadFileDescriptor => syscall::Err::BadFileDescriptor,
+            Err::TooManyFiles => syscall::Err::TooManyFiles,
+            Err::BrokenPipe => syscall::Err::BrokenPipe,
+            Err::OutOfMemory => syscall::Err::OutOfMemory,
+            Err::Io => syscall::Err::Io,
+            Err::NotSeekable | Err::Unsupported | Err::InvalidArgument => {
+                syscall::Err::InvalidArgument
+            }
+        }
+    }
+}
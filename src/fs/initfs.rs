@@ -0,0 +1,39 @@
+use alloc::sync::Arc;
+
+use super::file::{Err, File};
+use super::vfs::Filesystem;
+
+// The programs built into the kernel image, mounted at /bin. They're assembled from user/ by
+// tools/build_user.sh, which has to be rerun after changing them.
+
+static PROGRAMS: [(&str, &[u8]); 2] = [
+    ("init", include_bytes!("../../user/bin/init")),
+    ("hello", include_bytes!("../../user/bin/hello")),
+];
+
+pub struct Initfs;
+
+struct Program(&'static [u8]);
+
+impl File for Program {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+        let data = self.0.get(offset as usize..).unwrap_or(&[]);
+        let count = buffer.len().min(data.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+}
+
+impl Filesystem for Initfs {
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, Err> {
+        let (_, data) = PROGRAMS
+            .iter()
+            .find(|(name, _)| *name == path)
+            .ok_or(Err::NotFound)?;
+        Ok(Arc::new(Program(data)))
+    }
+}
@@ -0,0 +1,390 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::fd;
+use super::file::{Err, File};
+use super::page_cache::Page;
+use crate::memory::page_table::PageTableFlags;
+use crate::memory::{self, PageFaultError, PAGE_SIZE};
+use crate::syscall::{self, Syscall};
+
+// Memory-mapped files. mmap reserves address space for part of a file without mapping anything
+// in it; the first touch of each page faults, and the page fault handler maps the file's page
+// cache page there. Writes land in the page cache page itself, so reads of the file see them
+// straight away, and msync (or munmap) writes the dirty pages back to the file.
+//
+// Only files read through the page cache can be mapped (see File::page), and every mapping is
+// shared: there's no copy on write for private ones. Mappings are made in kernel memory, which
+// processes can't touch; they can't map files into their own address space yet.
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+
+struct Mapping {
+    range: Range<usize>,
+    file: Arc<dyn File>,
+    // Index in the file of the first page
+    first_page: u64,
+    writable: bool,
+    // Faulted in so far, by address. Held so they stay cached while they're mapped.
+    pages: BTreeMap<usize, Arc<Page>>,
+}
+
+impl Mapping {
+    fn file_page(&self, page: usize) -> u64 {
+        self.first_page + ((page - self.range.start) / PAGE_SIZE) as u64
+    }
+}
+
+// A page to write back
+struct Dirty {
+    file: Arc<dyn File>,
+    index: u64,
+    page: Arc<Page>,
+}
+
+impl Dirty {
+    // Stops at the end of the file, rather than growing it to a whole page
+    fn write_back(&self) -> Result<(), Err> {
+        let offset = self.index * PAGE_SIZE as u64;
+        let size = self.file.size().unwrap_or(0);
+        let length = size.saturating_sub(offset).min(PAGE_SIZE as u64) as usize;
+        match self.file.write(offset, &self.page.bytes()[..length])? {
+            written if written == length => Ok(()),
+            _ => Err(Err::Io),
+        }
+    }
+}
+
+static MAPPINGS: Mutex<Vec<Mapping>> = Mutex::new(Vec::new());
+
+fn page_align(length: usize) -> Option<usize> {
+    Some(length.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+// Map `length` bytes of `file` from `offset` (which must be page aligned), returning where
+pub fn mmap(file: Arc<dyn File>, offset: u64, length: usize, prot: u64) -> Result<usize, Err> {
+    let size = page_align(length).ok_or(Err::InvalidArgument)?;
+    let unaligned = offset % PAGE_SIZE as u64 != 0;
+    if unaligned || size == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(Err::InvalidArgument);
+    }
+    let first_page = offset / PAGE_SIZE as u64;
+    // Refused here, rather than on the first fault, if the file isn't page cached
+    file.page(first_page)?;
+    let writable = prot & PROT_WRITE != 0;
+    // Read-only files refuse writes, even of nothing, and there'd be no writing the pages back
+    if writable {
+        file.write(offset, &[])?;
+    }
+    let range = memory::reserve(size).map_err(|_| Err::OutOfMemory)?;
+    let start = range.start;
+    crate::without_interrupt! {{
+        MAPPINGS.lock().push(Mapping {
+            range,
+            file,
+            first_page,
+            writable,
+            pages: BTreeMap::new(),
+        });
+    }}
+    Ok(start)
+}
+
+// Called by the page fault handler. True if the fault was on a page of a mapping that's not been
+// touched yet, which is now mapped; anything else (eg. writing to a read-only mapping) is a real
+// fault.
+pub fn handle_fault(address: usize, error: PageFaultError) -> bool {
+    if error.intersects(PageFaultError::PRESENT | PageFaultError::INSTRUCTION_FETCH) {
+        return false;
+    }
+    let page = address & !(PAGE_SIZE - 1);
+    crate::without_interrupt! {{
+        let mut mappings = MAPPINGS.lock();
+        let mapping = match mappings.iter_mut().find(|m| m.range.contains(&address)) {
+            Some(mapping) => mapping,
+            None => return false,
+        };
+        if error.contains(PageFaultError::WRITE) && !mapping.writable {
+            return false;
+        }
+        let cached = match mapping.file.page(mapping.file_page(page)) {
+            Ok(cached) => cached,
+            Err(_) => return false,
+        };
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        if mapping.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if memory::map_frame(page, cached.frame(), flags).is_err() {
+            return false;
+        }
+        mapping.pages.insert(page, cached);
+        true
+    }}
+}
+
+// A page that fails to write (and every one after it) is marked dirty again, if it's still mapped
+fn write_back(dirty: Vec<(usize, Dirty)>) -> Result<(), Err> {
+    let mut result = Ok(());
+    for (page, dirty) in dirty {
+        if result.is_ok() {
+            result = dirty.write_back();
+        }
+        if result.is_err() {
+            memory::mark_dirty(page);
+        }
+    }
+    result
+}
+
+// Write the pages of [address, address + length) that have been written to back to the file.
+// The range must be within one mapping.
+pub fn msync(address: usize, length: usize) -> Result<(), Err> {
+    let end = address.checked_add(length).ok_or(Err::InvalidArgument)?;
+    let dirty = crate::without_interrupt! {{
+        let mappings = MAPPINGS.lock();
+        let mapping = mappings
+            .iter()
+            .find(|m| m.range.start <= address && end <= m.range.end)
+            .ok_or(Err::InvalidArgument)?;
+        let dirty: Vec<(usize, Dirty)> = mapping
+            .pages
+            .range(address & !(PAGE_SIZE - 1)..end)
+            .filter(|&(&page, _)| memory::take_dirty(page))
+            .map(|(&page, cached)| {
+                let dirty = Dirty {
+                    file: mapping.file.clone(),
+                    index: mapping.file_page(page),
+                    page: cached.clone(),
+                };
+                (page, dirty)
+            })
+            .collect();
+        dirty
+    }};
+    // Written without the lock, since files are free to block
+    write_back(dirty)
+}
+
+// Unmap a whole mapping, writing back whatever was written to it. `length` is what it was
+// mapped with.
+pub fn munmap(address: usize, length: usize) -> Result<(), Err> {
+    let size = page_align(length).ok_or(Err::InvalidArgument)?;
+    let mapping = crate::without_interrupt! {{
+        let mut mappings = MAPPINGS.lock();
+        let index = mappings
+            .iter()
+            .position(|m| m.range.start == address && m.range.len() == size);
+        let mapping = index.map(|index| mappings.swap_remove(index));
+        mapping
+    }};
+    let mapping = mapping.ok_or(Err::InvalidArgument)?;
+    let mut dirty = Vec::new();
+    for (&page, cached) in &mapping.pages {
+        let flags = memory::unmap_frame(page).unwrap_or(PageTableFlags::empty());
+        if flags.contains(PageTableFlags::DIRTY) {
+            let page_dirty = Dirty {
+                file: mapping.file.clone(),
+                index: mapping.file_page(page),
+                page: cached.clone(),
+            };
+            dirty.push((page, page_dirty));
+        }
+    }
+    memory::release(mapping.range.clone());
+    write_back(dirty)
+}
+
+// mmap(fd, offset, length, prot) -> address, prot being PROT_READ and/or PROT_WRITE
+fn sys_mmap(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let file = fd::file(args[0] as usize)?;
+    Ok(mmap(file, args[1], args[2] as usize, args[3])? as u64)
+}
+
+// msync(address, length)
+fn sys_msync(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    msync(args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+// munmap(address, length)
+fn sys_munmap(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    munmap(args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+pub fn init() {
+    syscall::register(Syscall::Mmap, sys_mmap);
+    syscall::register(Syscall::Msync, sys_msync);
+    syscall::register(Syscall::Munmap, sys_munmap);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::page_cache::{self, Backing, Key};
+    use crate::fs::{devfs, vfs};
+
+    fn present(address: usize) -> bool {
+        memory::page_flags(address).map_or(false, |flags| flags.contains(PageTableFlags::PRESENT))
+    }
+
+    // An in-memory file read through the page cache. Writes only go to `data`; they come from
+    // write back, when the cached page already has them.
+    struct Cached {
+        owner: u32,
+        data: Mutex<Vec<u8>>,
+        writable: bool,
+    }
+
+    impl Cached {
+        fn new(data: &[u8], writable: bool) -> Arc<Self> {
+            Arc::new(Cached {
+                owner: page_cache::new_owner(),
+                data: Mutex::new(Vec::from(data)),
+                writable,
+            })
+        }
+    }
+
+    impl Backing for Cached {
+        fn read_page(&self, index: u64, page: &mut [u8]) -> Result<(), Err> {
+            let data = self.data.lock();
+            let from = data.get(index as usize * PAGE_SIZE..).unwrap_or(&[]);
+            let count = from.len().min(PAGE_SIZE);
+            page[..count].copy_from_slice(&from[..count]);
+            Ok(())
+        }
+    }
+
+    impl File for Cached {
+        fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+            let size = self.data.lock().len() as u64;
+            page_cache::read(self.owner, 0, size, offset, buffer, self)
+        }
+
+        fn write(&self, offset: u64, buffer: &[u8]) -> Result<usize, Err> {
+            if !self.writable {
+                return Err(Err::Unsupported);
+            }
+            let mut data = self.data.lock();
+            let offset = offset as usize;
+            data[offset..offset + buffer.len()].copy_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn size(&self) -> Option<u64> {
+            Some(self.data.lock().len() as u64)
+        }
+
+        fn page(&self, index: u64) -> Result<Arc<Page>, Err> {
+            let key = Key {
+                owner: self.owner,
+                object: 0,
+                index,
+            };
+            page_cache::page(key, self)
+        }
+    }
+
+    #[test_case]
+    fn test_mapped_pages_fault_in_and_write_back() {
+        let mut contents = alloc::vec![0; PAGE_SIZE + 100];
+        contents[PAGE_SIZE..PAGE_SIZE + 5].copy_from_slice(b"hello");
+        let file = Cached::new(&contents, true);
+        let length = contents.len();
+        let address = mmap(file.clone(), 0, length, PROT_READ | PROT_WRITE).unwrap();
+        let second = address + PAGE_SIZE;
+        // Nothing's mapped until it's touched
+        assert!(!present(second));
+        let mapped = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, length) };
+        assert_eq!(&mapped[PAGE_SIZE..PAGE_SIZE + 5], b"hello");
+        assert!(present(second));
+        assert!(!present(address));
+
+        mapped[PAGE_SIZE..PAGE_SIZE + 5].copy_from_slice(b"HELLO");
+        // Reads through the page cache see it before it's written back
+        let mut buffer = [0; 5];
+        assert_eq!(file.read(PAGE_SIZE as u64, &mut buffer), Ok(5));
+        assert_eq!(&buffer, b"HELLO");
+        assert_eq!(&file.data.lock()[PAGE_SIZE..PAGE_SIZE + 5], b"hello");
+        assert_eq!(msync(address, length), Ok(()));
+        assert_eq!(&file.data.lock()[PAGE_SIZE..PAGE_SIZE + 5], b"HELLO");
+        assert!(!memory::take_dirty(second));
+
+        mapped[PAGE_SIZE + 99] = 1;
+        assert_eq!(munmap(address, length), Ok(()));
+        assert_eq!(file.data.lock()[PAGE_SIZE + 99], 1);
+        assert_eq!(file.data.lock().len(), length);
+        assert!(!present(second));
+        assert_eq!(munmap(address, length), Err(Err::InvalidArgument));
+        page_cache::evict(file.owner);
+    }
+
+    #[test_case]
+    fn test_mmap_refusals() {
+        let read_only = Cached::new(b"read only", false);
+        assert_eq!(
+            mmap(read_only.clone(), 0, 9, PROT_READ | PROT_WRITE),
+            Err(Err::Unsupported)
+        );
+        assert_eq!(
+            mmap(read_only.clone(), 1, 9, PROT_READ),
+            Err(Err::InvalidArgument)
+        );
+        assert_eq!(
+            mmap(read_only.clone(), 0, 0, PROT_READ),
+            Err(Err::InvalidArgument)
+        );
+        // No executable mappings
+        assert_eq!(
+            mmap(read_only.clone(), 0, 9, PROT_READ | 4),
+            Err(Err::InvalidArgument)
+        );
+        // Not page cached
+        let program = vfs::open("/bin/init").unwrap();
+        assert_eq!(mmap(program, 0, 9, PROT_READ), Err(Err::Unsupported));
+
+        // Writing to a read-only mapping is a real fault
+        let address = mmap(read_only.clone(), 0, 9, PROT_READ).unwrap();
+        assert!(!handle_fault(address, PageFaultError::WRITE));
+        assert!(handle_fault(address, PageFaultError::empty()));
+        assert!(!handle_fault(address, PageFaultError::PRESENT));
+        assert_eq!(unsafe { *(address as *const u8) }, b'r');
+        // Only whole mappings can be unmapped
+        assert_eq!(munmap(address + PAGE_SIZE, 9), Err(Err::InvalidArgument));
+        assert_eq!(msync(address, 2 * PAGE_SIZE), Err(Err::InvalidArgument));
+        assert_eq!(munmap(address, 9), Ok(()));
+        page_cache::evict(read_only.owner);
+    }
+
+    #[test_case]
+    fn test_mmap_syscalls() {
+        let file = Cached::new(b"mapped through a syscall", true);
+        devfs::register("mmap_test", file.clone());
+        let fd = fd::open("/dev/mmap_test").unwrap();
+        let mmap_args = [fd as u64, 0, 24, PROT_READ, 0, 0];
+        let address = syscall::dispatch(Syscall::Mmap as usize, mmap_args).unwrap();
+        // The mapping holds on to the file
+        fd::close(fd).unwrap();
+        let mapped = unsafe { core::slice::from_raw_parts(address as *const u8, 24) };
+        assert_eq!(mapped, b"mapped through a syscall");
+        let args = [address, 24, 0, 0, 0, 0];
+        assert_eq!(syscall::dispatch(Syscall::Msync as usize, args), Ok(0));
+        assert_eq!(syscall::dispatch(Syscall::Munmap as usize, args), Ok(0));
+        assert_eq!(
+            syscall::dispatch(Syscall::Munmap as usize, args),
+            Err(syscall::Err::InvalidArgument)
+        );
+        assert_eq!(
+            syscall::dispatch(Syscall::Mmap as usize, mmap_args),
+            Err(syscall::Err::BadFileDescriptor)
+        );
+        page_cache::evict(file.owner);
+    }
+}
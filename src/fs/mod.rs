@@ -0,0 +1,10 @@
+pub mod devfs;
+pub mod ext2;
+pub mod fd;
+pub mod file;
+pub mod initfs;
+pub mod mmap;
+pub mod page_cache;
+pub mod pipe;
+pub mod procfs;
+pub mod vfs;
@@ -0,0 +1,265 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::file::Err;
+use crate::memory::cache_registry::{self, Cache};
+use crate::memory::{self, physical_to_virtual, PAGE_SIZE};
+
+// File data, cached a page at a time in whole frames (so they can be mapped as they are, see
+// fs::mmap). A page is named by its owner (eg. a mounted filesystem), the object it's part of
+// (eg. an inode) and its index in the object. Files read through `read`, and pages that aren't
+// cached yet are filled in by the object's Backing. Pages nobody else holds are given back under
+// memory pressure (see memory::cache_registry), and the rest stay cached until their owner evicts
+// them.
+
+// Where an object's pages come from
+pub trait Backing {
+    // Fill in page `index` of the object. `page` starts out zeroed, which is what's left past the
+    // end of the object.
+    fn read_page(&self, index: u64, page: &mut [u8]) -> Result<(), Err>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Key {
+    pub owner: u32,
+    pub object: u64,
+    pub index: u64,
+}
+
+pub struct Page {
+    frame: usize,
+}
+
+impl Page {
+    // Physical address
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(physical_to_virtual(self.frame) as *const u8, PAGE_SIZE)
+        }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        memory::deallocate_frame(self.frame);
+    }
+}
+
+static PAGES: Mutex<BTreeMap<Key, Arc<Page>>> = Mutex::new(BTreeMap::new());
+static NEXT_OWNER: AtomicU32 = AtomicU32::new(0);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+// A new owner for pages, eg. for each mount
+pub fn new_owner() -> u32 {
+    NEXT_OWNER.fetch_add(1, Ordering::Relaxed)
+}
+
+// The page at `key`, read in from `backing` if it isn't cached
+pub fn page(key: Key, backing: &dyn Backing) -> Result<Arc<Page>, Err> {
+    let cached = crate::without_interrupt! {{
+        PAGES.lock().get(&key).cloned()
+    }};
+    if let Some(page) = cached {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(page);
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    let page = Page {
+        frame: memory::allocate_frame().map_err(|_| Err::OutOfMemory)?,
+    };
+    // Filled in before anyone else can see it
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(physical_to_virtual(page.frame) as *mut u8, PAGE_SIZE)
+    };
+    backing.read_page(key.index, bytes)?;
+    // Someone may have read the same page in meanwhile; everyone shares whichever got there
+    // first, and ours (if it lost) is freed once the lock is released
+    let page = Arc::new(page);
+    let cached = crate::without_interrupt! {{
+        let cached = PAGES.lock().entry(key).or_insert_with(|| page.clone()).clone();
+        cached
+    }};
+    Ok(cached)
+}
+
+// Read from `offset` of an object `size` bytes long, a page at a time, returning how many bytes
+// were read (0 at the end)
+pub fn read(
+    owner: u32,
+    object: u64,
+    size: u64,
+    offset: u64,
+    buffer: &mut [u8],
+    backing: &dyn Backing,
+) -> Result<usize, Err> {
+    if offset >= size {
+        return Ok(0);
+    }
+    let length = buffer.len().min((size - offset) as usize);
+    let mut done = 0;
+    while done < length {
+        let position = offset + done as u64;
+        let within = position as usize % PAGE_SIZE;
+        let count = (PAGE_SIZE - within).min(length - done);
+        let key = Key {
+            owner,
+            object,
+            index: position / PAGE_SIZE as u64,
+        };
+        let page = page(key, backing)?;
+        buffer[done..done + count].copy_from_slice(&page.bytes()[within..within + count]);
+        done += count;
+    }
+    Ok(length)
+}
+
+// Forget an owner's pages, eg. once it's unmounted. Any still held elsewhere are freed when
+// they're dropped.
+pub fn evict(owner: u32) {
+    let first = |owner| Key {
+        owner,
+        object: 0,
+        index: 0,
+    };
+    // Dropped once the lock is released, since freeing a page takes the page allocator's
+    let _evicted = crate::without_interrupt! {{
+        let mut pages = PAGES.lock();
+        let mut evicted = pages.split_off(&first(owner));
+        if let Some(next) = owner.checked_add(1) {
+            pages.append(&mut evicted.split_off(&first(next)));
+        }
+        evicted
+    }};
+}
+
+// How many of an owner's pages are cached
+pub fn cached(owner: u32) -> usize {
+    crate::without_interrupt! {{
+        PAGES
+            .lock()
+            .keys()
+            .filter(|key| key.owner == owner)
+            .count()
+    }}
+}
+
+// Pages only the cache holds, which shrinking gives back
+fn idle(pages: &BTreeMap<Key, Arc<Page>>) -> impl Iterator<Item = (&Key, &Arc<Page>)> {
+    pages
+        .iter()
+        .filter(|(_, page)| Arc::strong_count(page) == 1)
+}
+
+struct PageCache;
+
+impl Cache for PageCache {
+    fn size(&self) -> usize {
+        crate::without_interrupt! {{
+            idle(&PAGES.lock()).count() * PAGE_SIZE
+        }}
+    }
+
+    fn hits(&self) -> Option<(u64, u64)> {
+        Some((HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed)))
+    }
+
+    // Skipped while the cache is busy, or the page allocator is (we may be reclaiming for it)
+    fn shrink(&self, target_bytes: usize) -> usize {
+        let mut freed = 0;
+        while freed < target_bytes && memory::can_free_frames() {
+            let page = crate::without_interrupt! {{
+                let page = PAGES.try_lock().and_then(|mut pages| {
+                    let key = *idle(&pages).next()?.0;
+                    pages.remove(&key)
+                });
+                page
+            }};
+            match page {
+                Some(page) => drop(page),
+                None => break,
+            }
+            freed += PAGE_SIZE;
+        }
+        freed
+    }
+}
+
+static PAGE_CACHE: PageCache = PageCache;
+
+pub fn init() {
+    cache_registry::register("page cache", &PAGE_CACHE);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    // Page n is filled with byte n
+    struct Counting {
+        reads: AtomicUsize,
+    }
+
+    impl Backing for Counting {
+        fn read_page(&self, index: u64, page: &mut [u8]) -> Result<(), Err> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            page.fill(index as u8);
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn test_pages_are_read_once() {
+        let owner = new_owner();
+        let backing = Counting {
+            reads: AtomicUsize::new(0),
+        };
+        let size = 2 * PAGE_SIZE as u64 + 10;
+        let mut buffer = [0; 8];
+        // Straddles pages 0 and 1
+        let offset = PAGE_SIZE as u64 - 4;
+        assert_eq!(read(owner, 1, size, offset, &mut buffer, &backing), Ok(8));
+        assert_eq!(buffer, [0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(read(owner, 1, size, offset, &mut buffer, &backing), Ok(8));
+        assert_eq!(backing.reads.load(Ordering::Relaxed), 2);
+        // Short at the end of the object
+        assert_eq!(read(owner, 1, size, size - 2, &mut buffer, &backing), Ok(2));
+        assert_eq!(&buffer[..2], [2, 2]);
+        assert_eq!(read(owner, 1, size, size, &mut buffer, &backing), Ok(0));
+        assert_eq!(cached(owner), 3);
+        evict(owner);
+        assert_eq!(cached(owner), 0);
+    }
+
+    #[test_case]
+    fn test_shrink_keeps_pages_in_use() {
+        let owner = new_owner();
+        let backing = Counting {
+            reads: AtomicUsize::new(0),
+        };
+        let key = |index| Key {
+            owner,
+            object: 0,
+            index,
+        };
+        let held = page(key(0), &backing).unwrap();
+        page(key(1), &backing).unwrap();
+        // Other owners' idle pages may go first
+        while cached(owner) > 1 {
+            assert!(PAGE_CACHE.shrink(PAGE_SIZE) > 0);
+        }
+        assert_eq!(PAGE_CACHE.shrink(usize::MAX) % PAGE_SIZE, 0);
+        assert_eq!(cached(owner), 1);
+        assert!(Arc::ptr_eq(&page(key(0), &backing).unwrap(), &held));
+        assert_eq!(backing.reads.load(Ordering::Relaxed), 2);
+        evict(owner);
+    }
+}
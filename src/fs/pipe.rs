@@ -0,0 +1,241 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use super::fd;
+use super::file::{Err, File};
+use crate::memory::usercopy;
+use crate::sync::WaitQueue;
+use crate::syscall::{self, Syscall};
+
+// Pipes: a byte stream from a write end to a read end through a fixed size ring buffer. Reads
+// block until there's data, writes until there's room. Once every write end is closed, reads
+// drain what's left and then return 0; once every read end is closed, writes fail with
+// BrokenPipe.
+
+const CAPACITY: usize = 4096;
+
+struct Buffer {
+    data: Box<[u8; CAPACITY]>,
+    // Index of the oldest byte
+    head: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    buffer: spin::Mutex<Buffer>,
+    // Readers waiting for data, and writers waiting for room
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+pub struct ReadEnd(Arc<Pipe>);
+pub struct WriteEnd(Arc<Pipe>);
+
+pub fn pipe() -> (ReadEnd, WriteEnd) {
+    let pipe = Arc::new(Pipe {
+        buffer: spin::Mutex::new(Buffer {
+            data: Box::new([0; CAPACITY]),
+            head: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (ReadEnd(pipe.clone()), WriteEnd(pipe))
+}
+
+impl File for ReadEnd {
+    fn read(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        // With interrupts off, nobody can write between us seeing it empty and waiting
+        let count = crate::without_interrupt! {{
+            loop {
+                let mut pipe_buffer = pipe.buffer.lock();
+                if pipe_buffer.len > 0 {
+                    let count = buffer.len().min(pipe_buffer.len);
+                    for byte in buffer[..count].iter_mut() {
+                        *byte = pipe_buffer.data[pipe_buffer.head];
+                        pipe_buffer.head = (pipe_buffer.head + 1) % CAPACITY;
+                    }
+                    pipe_buffer.len -= count;
+                    break count;
+                }
+                if pipe_buffer.writers == 0 {
+                    break 0;
+                }
+                drop(pipe_buffer);
+                pipe.readable.wait();
+            }
+        }};
+        pipe.writable.wake_all();
+        Ok(count)
+    }
+}
+
+impl File for WriteEnd {
+    fn read(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Err> {
+        Err(Err::Unsupported)
+    }
+
+    // Blocks until all of `buffer` is written, unless the last reader goes away first
+    fn write(&self, _offset: u64, buffer: &[u8]) -> Result<usize, Err> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buffer.len() {
+            let wrote = crate::without_interrupt! {{
+                loop {
+                    let mut pipe_buffer = pipe.buffer.lock();
+                    if pipe_buffer.readers == 0 {
+                        break None;
+                    }
+                    let count = (buffer.len() - written).min(CAPACITY - pipe_buffer.len);
+                    if count > 0 {
+                        for &byte in &buffer[written..written + count] {
+                            let tail = (pipe_buffer.head + pipe_buffer.len) % CAPACITY;
+                            pipe_buffer.data[tail] = byte;
+                            pipe_buffer.len += 1;
+                        }
+                        break Some(count);
+                    }
+                    drop(pipe_buffer);
+                    pipe.writable.wait();
+                }
+            }};
+            match wrote {
+                Some(count) => written += count,
+                None if written > 0 => break,
+                None => return Err(Err::BrokenPipe),
+            }
+            pipe.readable.wake_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for ReadEnd {
+    fn drop(&mut self) {
+        crate::without_interrupt! {{
+            self.0.buffer.lock().readers -= 1;
+        }}
+        self.0.writable.wake_all();
+    }
+}
+
+impl Drop for WriteEnd {
+    fn drop(&mut self) {
+        crate::without_interrupt! {{
+            self.0.buffer.lock().writers -= 1;
+        }}
+        self.0.readable.wake_all();
+    }
+}
+
+// pipe(fds) -> 0, storing the read end's fd in fds[0] and the write end's in fds[1], as u32s
+fn sys_pipe(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let address = args[0] as usize;
+    usercopy::check_writable(address, 2 * core::mem::size_of::<u32>())?;
+    let (read_end, write_end) = pipe();
+    let read_fd = fd::install(Arc::new(read_end))?;
+    let write_fd = match fd::install(Arc::new(write_end)) {
+        Ok(write_fd) => write_fd,
+        Err(err) => {
+            fd::close(read_fd)?;
+            return Err(err.into());
+        }
+    };
+    let mut fds = [0; 8];
+    fds[..4].copy_from_slice(&(read_fd as u32).to_ne_bytes());
+    fds[4..].copy_from_slice(&(write_fd as u32).to_ne_bytes());
+    if let Err(err) = usercopy::copy_to_user(address, &fds) {
+        // Unmapped since the check; nobody knows the descriptors, so don't leave them open
+        fd::close(read_fd)?;
+        fd::close(write_fd)?;
+        return Err(err.into());
+    }
+    Ok(0)
+}
+
+pub fn init() {
+    syscall::register(Syscall::Pipe, sys_pipe);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::{AddressSpace, PROCESS_START};
+    use crate::memory::page_table::PageTableFlags;
+    use crate::task;
+
+    static WRITE_END: spin::Mutex<Option<WriteEnd>> = spin::Mutex::new(None);
+
+    fn writer_task() {
+        let write_end = WRITE_END.lock().take().unwrap();
+        assert_eq!(write_end.write(0, b"ping"), Ok(4));
+    }
+
+    #[test_case]
+    fn test_pipe_blocking_read_and_eof() {
+        let (read_end, write_end) = pipe();
+        *WRITE_END.lock() = Some(write_end);
+        task::spawn("pipe_writer", writer_task).unwrap();
+        let mut buffer = [0; 8];
+        // Blocks until the writer has run
+        assert_eq!(read_end.read(0, &mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"ping");
+        // The writer dropped its end on exit
+        task::yield_now();
+        assert_eq!(read_end.read(0, &mut buffer), Ok(0));
+    }
+
+    fn exiting_writer_task() {
+        let write_end = WRITE_END.lock().take().unwrap();
+        // Left open, for the scheduler to close when the task exits
+        fd::install(Arc::new(write_end)).unwrap();
+    }
+
+    #[test_case]
+    fn test_exiting_task_closes_its_pipe_ends() {
+        let (read_end, write_end) = pipe();
+        *WRITE_END.lock() = Some(write_end);
+        task::spawn("pipe_exiter", exiting_writer_task).unwrap();
+        let mut buffer = [0; 8];
+        // Blocks until the writer's files are closed, which has to wake us
+        assert_eq!(read_end.read(0, &mut buffer), Ok(0));
+    }
+
+    #[test_case]
+    fn test_pipe_syscall() {
+        let mut address_space = AddressSpace::new().unwrap();
+        let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        address_space.map(PROCESS_START, flags).unwrap();
+        let args = [PROCESS_START as u64, 0, 0, 0, 0, 0];
+        let mut fds = [0u8; 8];
+        address_space.enter(|| {
+            assert_eq!(syscall::dispatch(Syscall::Pipe as usize, args), Ok(0));
+            usercopy::copy_from_user(&mut fds, PROCESS_START).unwrap();
+        });
+        let fd = |bytes: &[u8]| u32::from_ne_bytes(bytes.try_into().unwrap()) as usize;
+        let (read_fd, write_fd) = (fd(&fds[..4]), fd(&fds[4..]));
+        assert_eq!(fd::write(write_fd, b"through the pipe"), Ok(16));
+        let mut buffer = [0; 32];
+        assert_eq!(fd::read(read_fd, &mut buffer), Ok(16));
+        assert_eq!(&buffer[..16], b"through the pipe");
+        fd::close(read_fd).unwrap();
+        assert_eq!(fd::write(write_fd, b"anyone?"), Err(Err::BrokenPipe));
+        fd::close(write_fd).unwrap();
+        // Nowhere to put the descriptors, so none are made
+        let kernel = [buffer.as_mut_ptr() as u64, 0, 0, 0, 0, 0];
+        assert_eq!(
+            syscall::dispatch(Syscall::Pipe as usize, kernel),
+            Err(syscall::Err::BadAddress)
+        );
+        assert_eq!(fd::close(read_fd), Err(Err::BadFileDescriptor));
+    }
+}
@@ -0,0 +1,179 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write;
+
+use super::file::{Err, File};
+use super::vfs::Filesystem;
+use crate::interrupt::{self, table::Exception};
+use crate::task::scheduler;
+
+// Kernel state as text files under /proc, eg. `cat /proc/meminfo`. Each file's text is generated
+// when it's opened, so reads (and seeks) through one open see a consistent snapshot; open it
+// again for fresh numbers.
+
+// Appends a file's text
+type Generator = fn(&mut String) -> core::fmt::Result;
+
+const FILES: [(&str, Generator); 7] = [
+    ("meminfo", meminfo),
+    ("alloc_sizes", alloc_sizes),
+    ("interrupts", interrupts),
+    ("uptime", uptime),
+    ("tasks", tasks),
+    ("config", config),
+    ("boot", boot),
+];
+
+struct Snapshot(String);
+
+impl File for Snapshot {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+        let data = self.0.as_bytes().get(offset as usize..).unwrap_or(&[]);
+        let count = buffer.len().min(data.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+}
+
+// A file by its name under /proc
+pub fn lookup(name: &str) -> Result<Arc<dyn File>, Err> {
+    let (_, generate) = FILES
+        .iter()
+        .find(|(file, _)| *file == name)
+        .ok_or(Err::NotFound)?;
+    let mut text = String::new();
+    generate(&mut text).map_err(|_| Err::InvalidArgument)?;
+    Ok(Arc::new(Snapshot(text)))
+}
+
+// Mounted at /proc
+pub struct Procfs;
+
+impl Filesystem for Procfs {
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, Err> {
+        lookup(path)
+    }
+}
+
+fn meminfo(text: &mut String) -> core::fmt::Result {
+    writeln!(text, "{}", crate::memory::stats())
+}
+
+fn config(text: &mut String) -> core::fmt::Result {
+    write!(text, "{}", crate::config::Table)
+}
+
+fn boot(text: &mut String) -> core::fmt::Result {
+    write!(text, "{}", crate::boot::Summary)
+}
+
+fn alloc_sizes(text: &mut String) -> core::fmt::Result {
+    writeln!(text, "{}", crate::memory::allocator::histogram::histogram())
+}
+
+// Exceptions which have been handled, then all hardware interrupts together
+fn interrupts(text: &mut String) -> core::fmt::Result {
+    const EXCEPTIONS: [Exception; 21] = [
+        Exception::DivideByZero,
+        Exception::Debug,
+        Exception::NonMaskableInterrupt,
+        Exception::Breakpoint,
+        Exception::Overflow,
+        Exception::BoundRangeExceeded,
+        Exception::InvalidOpcode,
+        Exception::DeviceNotAvailable,
+        Exception::DoubleFault,
+        Exception::CoprocessorSegmentOverrun,
+        Exception::InvalidTss,
+        Exception::SegmentNotPresent,
+        Exception::StackSegmentFault,
+        Exception::GeneralProtectionFault,
+        Exception::PageFault,
+        Exception::X87FloatingPoint,
+        Exception::AlignmentCheck,
+        Exception::MachineCheck,
+        Exception::SimdFloatingPoint,
+        Exception::Virtualization,
+        Exception::SecurityException,
+    ];
+    for exception in EXCEPTIONS {
+        let count = interrupt::exception_count(exception);
+        if count > 0 {
+            writeln!(text, "{:?}: {}", exception, count)?;
+        }
+    }
+    writeln!(text, "hardware: {}", interrupt::hardware_interrupts())
+}
+
+// Seconds since boot, and timer ticks
+fn uptime(text: &mut String) -> core::fmt::Result {
+    let uptime = crate::time::uptime();
+    writeln!(
+        text,
+        "{}.{:09} {}",
+        uptime.as_secs(),
+        uptime.subsec_nanos(),
+        crate::time::jiffies()
+    )
+}
+
+// Run time is in ticks, and `last` is when the task was last switched to
+fn tasks(text: &mut String) -> core::fmt::Result {
+    writeln!(
+        text,
+        "{:>4} {:<16} {:>8} {:<8} {:>10} {:>10} {:>10}",
+        "id", "name", "priority", "state", "ticks", "switches", "last"
+    )?;
+    for task in scheduler::tasks() {
+        writeln!(
+            text,
+            "{:>4} {:<16} {:>8} {:<8} {:>10} {:>10} {:>10}",
+            task.id.as_u64(),
+            task.name,
+            task.priority,
+            alloc::format!("{:?}", task.state),
+            task.accounting.run_ticks,
+            task.accounting.switches,
+            task.accounting.last_scheduled
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::fd;
+
+    fn read_file(path: &str) -> String {
+        let fd = fd::open(path).unwrap();
+        let mut text = alloc::vec::Vec::new();
+        let mut buffer = [0; 64];
+        loop {
+            match fd::read(fd, &mut buffer).unwrap() {
+                0 => break,
+                count => text.extend_from_slice(&buffer[..count]),
+            }
+        }
+        fd::close(fd).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test_case]
+    fn test_proc_files() {
+        assert!(read_file("/proc/meminfo").contains("kernel heap"));
+        assert!(read_file("/proc/alloc_sizes").contains("allocations"));
+        assert!(read_file("/proc/interrupts").contains("hardware: "));
+        assert!(read_file("/proc/tasks").contains("Running"));
+        assert!(read_file("/proc/config").contains("heap_size "));
+        assert!(read_file("/proc/boot").contains("Kernel"));
+        let uptime = read_file("/proc/uptime");
+        let seconds: f64 = uptime.split_whitespace().next().unwrap().parse().unwrap();
+        assert!(seconds > 0.0);
+        assert_eq!(fd::open("/proc/missing"), Err(Err::NotFound));
+    }
+}
@@ -0,0 +1,161 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::devfs::Devfs;
+use super::file::{Err, File};
+use super::initfs::Initfs;
+use super::procfs::Procfs;
+
+// The mount table: filesystems mounted at a directory, eg. initfs at /bin. A path is opened by the
+// filesystem mounted at its longest matching directory, with the rest of the path. initfs, devfs
+// and procfs are mounted from the start; anything else (eg. an ext2 image) is mounted with
+// `mount`.
+
+pub trait Filesystem: Send + Sync {
+    // `path` is relative to where the filesystem is mounted, eg. "init" for /bin/init
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, Err>;
+}
+
+struct Mount {
+    // Without a trailing '/', so the root is ""
+    point: String,
+    fs: Arc<dyn Filesystem>,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::from([
+        Mount {
+            point: String::from("/bin"),
+            fs: Arc::new(Initfs),
+        },
+        Mount {
+            point: String::from("/dev"),
+            fs: Arc::new(Devfs),
+        },
+        Mount {
+            point: String::from("/proc"),
+            fs: Arc::new(Procfs),
+        },
+    ]));
+}
+
+fn mount_point(path: &str) -> Result<&str, Err> {
+    match path.starts_with('/') {
+        true => Ok(path.trim_end_matches('/')),
+        false => Err(Err::InvalidArgument),
+    }
+}
+
+// The rest of `path`, if it's under `point`
+fn within<'a>(point: &str, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(point)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+// Mounting over an existing mount point is refused; unmount it first
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<(), Err> {
+    let point = mount_point(path)?;
+    crate::without_interrupt! {{
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.point == point) {
+            return Err(Err::InvalidArgument);
+        }
+        mounts.push(Mount {
+            point: String::from(point),
+            fs,
+        });
+    }}
+    Ok(())
+}
+
+// Files already open stay usable; the filesystem goes once the last of them is dropped
+pub fn unmount(path: &str) -> Result<(), Err> {
+    let point = mount_point(path)?;
+    let mount = crate::without_interrupt! {{
+        let mut mounts = MOUNTS.lock();
+        let index = mounts.iter().position(|mount| mount.point == point);
+        let mount = index.map(|index| mounts.remove(index));
+        mount
+    }};
+    // Dropped once the lock is released, in case it was the filesystem's last reference
+    mount.map(drop).ok_or(Err::NotFound)
+}
+
+pub fn open(path: &str) -> Result<Arc<dyn File>, Err> {
+    let found = crate::without_interrupt! {{
+        let found = MOUNTS
+            .lock()
+            .iter()
+            .filter_map(|mount| Some((mount, within(&mount.point, path)?)))
+            .max_by_key(|(mount, _)| mount.point.len())
+            .map(|(mount, rest)| (mount.fs.clone(), rest));
+        found
+    }};
+    let (fs, rest) = found.ok_or(Err::NotFound)?;
+    // Opened without the lock, since filesystems are free to block
+    fs.open(rest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Text(String);
+
+    impl File for Text {
+        fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Err> {
+            let data = self.0.as_bytes().get(offset as usize..).unwrap_or(&[]);
+            let count = buffer.len().min(data.len());
+            buffer[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+    }
+
+    // Opens anything, as a file holding the path it was opened with
+    struct Echo;
+
+    impl Filesystem for Echo {
+        fn open(&self, path: &str) -> Result<Arc<dyn File>, Err> {
+            Ok(Arc::new(Text(String::from(path))))
+        }
+    }
+
+    fn opened_as(path: &str) -> Result<String, Err> {
+        let file = open(path)?;
+        let mut buffer = [0; 64];
+        let count = file.read(0, &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer[..count]).into_owned())
+    }
+
+    #[test_case]
+    fn test_longest_mount_point_wins() {
+        assert_eq!(opened_as("/vfs_test/a"), Err(Err::NotFound));
+        mount("/vfs_test", Arc::new(Echo)).unwrap();
+        mount("/vfs_test/inner/", Arc::new(Echo)).unwrap();
+        assert_eq!(
+            mount("/vfs_test", Arc::new(Echo)),
+            Err(Err::InvalidArgument)
+        );
+        assert_eq!(mount("relative", Arc::new(Echo)), Err(Err::InvalidArgument));
+        assert_eq!(opened_as("/vfs_test/a/b").as_deref(), Ok("a/b"));
+        assert_eq!(opened_as("/vfs_test/inner/c").as_deref(), Ok("c"));
+        assert_eq!(opened_as("/vfs_test").as_deref(), Ok(""));
+        // Only whole directory names match
+        assert_eq!(opened_as("/vfs_tested"), Err(Err::NotFound));
+        // The existing mounts are still there
+        assert!(open("/bin/init").is_ok());
+        assert!(open("/dev/null").is_ok());
+        assert!(open("/proc/uptime").is_ok());
+        unmount("/vfs_test/inner").unwrap();
+        assert_eq!(opened_as("/vfs_test/inner/c").as_deref(), Ok("inner/c"));
+        unmount("/vfs_test").unwrap();
+        assert_eq!(unmount("/vfs_test"), Err(Err::NotFound));
+        assert_eq!(opened_as("/vfs_test/a"), Err(Err::NotFound));
+    }
+}
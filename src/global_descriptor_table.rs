@@ -1,27 +1,59 @@
 use lazy_static::lazy_static;
 
-// Cargo-culted from blog_os
-const STACK_SIZE: usize = 4096 * 5;
-
 use x86_64::instructions::segmentation::{Segment, CS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use core::ops::Range;
+
+use crate::config::INTERRUPT_STACK_SIZE;
+use crate::interrupt::{DOUBLE_FAULT_STACK, MACHINE_CHECK_STACK, NMI_STACK, PAGE_FAULT_STACK};
+use crate::task::stack::Stack;
+
+// Interrupt stack table (IST) stacks, for handlers that can't trust the stack they interrupted:
+// NMIs and machine checks can arrive anywhere, and faults can come from a stack that's overflowed
+// or corrupt.
+
+static mut DOUBLE_FAULT_STACK_MEMORY: [u8; INTERRUPT_STACK_SIZE] = [0; INTERRUPT_STACK_SIZE];
+
+// Address range of the double fault IST stack, eg. for checking that a handler is running on it
+pub fn double_fault_stack() -> Range<usize> {
+    let start = unsafe { DOUBLE_FAULT_STACK_MEMORY.as_ptr() } as usize;
+    start..start + INTERRUPT_STACK_SIZE
+}
+
+// The rest come out of the task stack pool, for good
+fn allocate_interrupt_stack() -> Range<usize> {
+    let mut stack = Stack::allocate().expect("No stacks left for the interrupt stack table");
+    let memory = unsafe { stack.memory() };
+    core::mem::forget(stack);
+    let start = memory.as_ptr() as usize;
+    start..start + memory.len()
+}
+
 lazy_static! {
+    // By IST index, as used with EntryOptions::stack. Index 0 means no stack switch.
+    static ref INTERRUPT_STACKS: [Range<usize>; 5] = {
+        let mut stacks = [0..0, 0..0, 0..0, 0..0, 0..0];
+        stacks[DOUBLE_FAULT_STACK] = double_fault_stack();
+        stacks[NMI_STACK] = allocate_interrupt_stack();
+        stacks[MACHINE_CHECK_STACK] = allocate_interrupt_stack();
+        if cfg!(feature = "page_fault_stack") {
+            stacks[PAGE_FAULT_STACK] = allocate_interrupt_stack();
+        }
+        stacks
+    };
     static ref TSS: TaskStateSegment = {
-        // Create a separate stack for handling double faults
-        // This prevents triple-faults on stack overflow, which would otherwise cause
-        // the double-fault handler to try to load outside a page and page fault
+        // The double fault stack prevents triple-faults on stack overflow, which would otherwise
+        // cause the double-fault handler to try to load outside a page and page fault
         let mut tss = TaskStateSegment::new();
-        // x86_64 crate TSS indexes ISTs by 0; my InterruptTable indexes by 1 (0 is no stack switch)
-        tss.interrupt_stack_table[crate::interrupt::DOUBLE_FAULT_STACK - 1] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+        for (index, stack) in INTERRUPT_STACKS.iter().enumerate().skip(1) {
+            // x86_64 crate TSS indexes ISTs by 0; my InterruptTable indexes by 1 (0 is no stack switch)
+            // stacks grow down, so point at the end
+            tss.interrupt_stack_table[index - 1] = VirtAddr::new(stack.end as u64);
+        }
         tss
     };
     static ref GDT: SegmentAccessibleGDT = {
@@ -30,18 +62,47 @@ lazy_static! {
         // Cargo-culting from blog_os and moving on for now
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // Ring 3 segments for user mode, see task::process
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         SegmentAccessibleGDT {
             gdt,
             code_selector,
             tss_selector,
+            user_code_selector,
+            user_data_selector,
         }
     };
 }
 
+// Address range of an IST stack, eg. interrupt::NMI_STACK
+pub fn interrupt_stack(index: usize) -> Range<usize> {
+    INTERRUPT_STACKS[index].clone()
+}
+
 struct SegmentAccessibleGDT {
     gdt: GlobalDescriptorTable,
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+// (code, stack) selectors to iretq into user mode with
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.user_code_selector, GDT.user_data_selector)
+}
+
+// The stack the CPU switches to when an interrupt or syscall arrives from user mode. The
+// scheduler points it at the top of each task's own stack as it switches to it.
+pub fn set_kernel_stack(top: usize) {
+    let tss = &*TSS as *const TaskStateSegment as *mut TaskStateSegment;
+    // Safety: the CPU only reads it on entry from user mode, which can't happen while we're
+    // running here, and nothing else writes it
+    unsafe {
+        core::ptr::addr_of_mut!((*tss).privilege_stack_table[0])
+            .write_volatile(VirtAddr::new(top as u64))
+    };
 }
 
 pub fn init() {
@@ -0,0 +1,206 @@
+use spin::Mutex;
+
+use super::{Color, Framebuffer};
+use crate::boot::KernelConfig;
+
+// The mouse pointer, drawn straight onto the framebuffer above whatever else is there. The
+// pixels under it are saved when it's drawn and put back when it moves, so nothing underneath
+// needs to know about it or redraw.
+// TODO: there's no PS/2 mouse driver or compositor yet. A mouse driver should call move_by with
+// each packet's movement, and a compositor should hide the cursor around drawing under it.
+
+// An arrow, with its hotspot at the top left. 'X' is outline, '.' is fill, and spaces are
+// transparent.
+const SPRITE: [&str; 19] = [
+    "X           ",
+    "XX          ",
+    "X.X         ",
+    "X..X        ",
+    "X...X       ",
+    "X....X      ",
+    "X.....X     ",
+    "X......X    ",
+    "X.......X   ",
+    "X........X  ",
+    "X.........X ",
+    "X..........X",
+    "X......XXXXX",
+    "X...X..X    ",
+    "X..XX..X    ",
+    "X.X  X..X   ",
+    "XX   X..X   ",
+    "X     X..X  ",
+    "      XXXX  ",
+];
+const WIDTH: usize = SPRITE[0].len();
+const HEIGHT: usize = SPRITE.len();
+
+fn sprite_pixel(x: usize, y: usize) -> Option<Color> {
+    match SPRITE[y].as_bytes()[x] {
+        b'X' => Some(Color::BLACK),
+        b'.' => Some(Color::WHITE),
+        _ => None,
+    }
+}
+
+struct Cursor {
+    framebuffer: Framebuffer,
+    x: usize,
+    y: usize,
+    // What was on the screen under the sprite, while it's drawn
+    saved: Option<[[Color; WIDTH]; HEIGHT]>,
+}
+
+impl Cursor {
+    fn new(framebuffer: Framebuffer) -> Self {
+        let (x, y) = (framebuffer.width() / 2, framebuffer.height() / 2);
+        Cursor {
+            framebuffer,
+            x,
+            y,
+            saved: None,
+        }
+    }
+
+    fn show(&mut self) {
+        if self.saved.is_some() {
+            return;
+        }
+        let mut saved = [[Color::BLACK; WIDTH]; HEIGHT];
+        for (row, saved_row) in saved.iter_mut().enumerate() {
+            for (column, saved) in saved_row.iter_mut().enumerate() {
+                let (x, y) = (self.x + column, self.y + row);
+                *saved = self.framebuffer.pixel(x, y);
+                if let Some(color) = sprite_pixel(column, row) {
+                    self.framebuffer.put_pixel(x, y, color);
+                }
+            }
+        }
+        self.saved = Some(saved);
+    }
+
+    fn hide(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            for (row, saved_row) in saved.iter().enumerate() {
+                for (column, &color) in saved_row.iter().enumerate() {
+                    self.framebuffer
+                        .put_pixel(self.x + column, self.y + row, color);
+                }
+            }
+        }
+    }
+
+    // Kept with its hotspot on the screen
+    fn move_to(&mut self, x: usize, y: usize) {
+        let visible = self.saved.is_some();
+        self.hide();
+        self.x = x.min(self.framebuffer.width().saturating_sub(1));
+        self.y = y.min(self.framebuffer.height().saturating_sub(1));
+        if visible {
+            self.show();
+        }
+    }
+
+    fn move_by(&mut self, dx: isize, dy: isize) {
+        let x = self.x.saturating_add_signed(dx);
+        let y = self.y.saturating_add_signed(dy);
+        self.move_to(x, y);
+    }
+}
+
+static CURSOR: Mutex<Option<Cursor>> = Mutex::new(None);
+
+// After the boot splash is done with the screen. Without a framebuffer there's no cursor.
+pub fn init(config: &KernelConfig) {
+    let info = match config.framebuffer {
+        Some(info) => info,
+        None => return,
+    };
+    // Safety: the splash is finished, and the console is VGA text
+    let mut cursor = match unsafe { Framebuffer::new(info) } {
+        Some(framebuffer) => Cursor::new(framebuffer),
+        None => return,
+    };
+    cursor.show();
+    crate::without_interrupt! {{
+        *CURSOR.lock() = Some(cursor);
+    }}
+}
+
+fn with_cursor(f: impl FnOnce(&mut Cursor)) {
+    crate::without_interrupt! {{
+        if let Some(cursor) = CURSOR.lock().as_mut() {
+            f(cursor);
+        }
+    }}
+}
+
+// Relative movement, as a mouse reports it. Positive y is down the screen.
+pub fn move_by(dx: isize, dy: isize) {
+    with_cursor(|cursor| cursor.move_by(dx, dy));
+}
+
+pub fn move_to(x: usize, y: usize) {
+    with_cursor(|cursor| cursor.move_to(x, y));
+}
+
+pub fn position() -> Option<(usize, usize)> {
+    crate::without_interrupt! {{
+        CURSOR.lock().as_ref().map(|cursor| (cursor.x, cursor.y))
+    }}
+}
+
+// Take the cursor off the screen, eg. while drawing under it, and put it back
+pub fn hide() {
+    with_cursor(Cursor::hide);
+}
+
+pub fn show() {
+    with_cursor(Cursor::show);
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::boot::FramebufferInfo;
+
+    const GREY: Color = Color::new(128, 128, 128);
+
+    #[test_case]
+    fn test_save_and_restore() {
+        let (width, height) = (40, 30);
+        let mut memory = vec![0u8; width * height * 4];
+        let info = FramebufferInfo {
+            address: 0,
+            width,
+            height,
+            stride: width,
+            bytes_per_pixel: 4,
+        };
+        let mut framebuffer = Framebuffer {
+            info,
+            base: memory.as_mut_ptr(),
+        };
+        framebuffer.fill(0, 0, width, height, GREY);
+        let mut cursor = Cursor::new(framebuffer);
+        cursor.move_to(2, 3);
+        cursor.show();
+        assert_eq!(cursor.framebuffer.pixel(2, 3), Color::BLACK);
+        assert_eq!(cursor.framebuffer.pixel(3, 5), Color::WHITE);
+        // Transparent
+        assert_eq!(cursor.framebuffer.pixel(4, 3), GREY);
+        cursor.move_by(100, -1);
+        assert_eq!((cursor.x, cursor.y), (width - 1, 2));
+        assert_eq!(cursor.framebuffer.pixel(2, 3), GREY);
+        assert_eq!(cursor.framebuffer.pixel(3, 5), GREY);
+        assert_eq!(cursor.framebuffer.pixel(width - 1, 2), Color::BLACK);
+        cursor.hide();
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(cursor.framebuffer.pixel(x, y), GREY);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use alloc::vec::Vec;
+
+use super::Color;
+use crate::fs::{fd, file};
+
+// Just enough image decoding for a boot splash: uncompressed 24 and 32 bit BMP, and binary
+// (P6) or plain (P3) PPM with 8 bit samples.
+// References: https://en.wikipedia.org/wiki/BMP_file_format, https://netpbm.sourceforge.net/doc/ppm.html
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    // Not a BMP or PPM, or a kind of one we don't decode (eg. compressed or paletted BMP)
+    Unsupported,
+    Truncated,
+    Malformed,
+    File(file::Err),
+}
+
+impl From<file::Err> for Err {
+    fn from(err: file::Err) -> Self {
+        Err::File(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // Rows top to bottom
+    pixels: Vec<Color>,
+}
+
+impl Image {
+    pub fn decode(data: &[u8]) -> Result<Self, Err> {
+        match data.get(..2) {
+            Some(b"BM") => decode_bmp(data),
+            Some(b"P6") | Some(b"P3") => decode_ppm(data),
+            _ => Err(Err::Unsupported),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+}
+
+pub fn load(path: &str) -> Result<Image, Err> {
+    Image::decode(&fd::read_all(path)?)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Err> {
+    let bytes = data.get(offset..offset + 2).ok_or(Err::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Err> {
+    let bytes = data.get(offset..offset + 4).ok_or(Err::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// No compression, ie. BI_RGB
+const BMP_COMPRESSION_NONE: u32 = 0;
+
+fn decode_bmp(data: &[u8]) -> Result<Image, Err> {
+    let pixels_offset = read_u32(data, 10)? as usize;
+    let width = read_u32(data, 18)? as i32;
+    // Rows are stored bottom to top, unless the height is negative
+    let height = read_u32(data, 22)? as i32;
+    let bits_per_pixel = read_u16(data, 28)?;
+    if read_u32(data, 30)? != BMP_COMPRESSION_NONE {
+        return Err(Err::Unsupported);
+    }
+    let bytes_per_pixel = match bits_per_pixel {
+        24 => 3,
+        32 => 4,
+        _ => return Err(Err::Unsupported),
+    };
+    if width <= 0 || height == 0 {
+        return Err(Err::Malformed);
+    }
+    let (width, rows) = (width as usize, height.unsigned_abs() as usize);
+    // Rows are padded to 4 bytes
+    let stride = width
+        .checked_mul(bytes_per_pixel)
+        .and_then(|row| row.checked_next_multiple_of(4))
+        .ok_or(Err::Malformed)?;
+    let end = stride
+        .checked_mul(rows)
+        .and_then(|size| size.checked_add(pixels_offset))
+        .ok_or(Err::Malformed)?;
+    let pixel_data = data.get(pixels_offset..end).ok_or(Err::Truncated)?;
+    let mut pixels = Vec::with_capacity(width * rows);
+    for y in 0..rows {
+        let row = match height > 0 {
+            true => rows - 1 - y,
+            false => y,
+        };
+        let row = &pixel_data[row * stride..][..width * bytes_per_pixel];
+        pixels.extend(
+            row.chunks_exact(bytes_per_pixel)
+                .map(|bgr| Color::new(bgr[2], bgr[1], bgr[0])),
+        );
+    }
+    Ok(Image {
+        width,
+        height: rows,
+        pixels,
+    })
+}
+
+// Whitespace separated header fields, skipping comments. Leaves `position` just past the
+// field, at the whitespace after it.
+fn ppm_field(data: &[u8], position: &mut usize) -> Result<usize, Err> {
+    loop {
+        match data.get(*position) {
+            Some(b'#') => {
+                while data.get(*position).map_or(false, |&byte| byte != b'\n') {
+                    *position += 1;
+                }
+            }
+            Some(byte) if byte.is_ascii_whitespace() => *position += 1,
+            Some(_) => break,
+            None => return Err(Err::Truncated),
+        }
+    }
+    let start = *position;
+    while data
+        .get(*position)
+        .map_or(false, |byte| byte.is_ascii_digit())
+    {
+        *position += 1;
+    }
+    core::str::from_utf8(&data[start..*position])
+        .unwrap()
+        .parse()
+        .map_err(|_| Err::Malformed)
+}
+
+fn decode_ppm(data: &[u8]) -> Result<Image, Err> {
+    let binary = data[1] == b'6';
+    let mut position = 2;
+    let width = ppm_field(data, &mut position)?;
+    let height = ppm_field(data, &mut position)?;
+    let max = ppm_field(data, &mut position)?;
+    if max == 0 || max > 255 {
+        return Err(Err::Unsupported);
+    }
+    let scale = |sample: usize| (sample.min(max) * 255 / max) as u8;
+    let samples = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or(Err::Malformed)?;
+    let samples: Vec<u8> = match binary {
+        // A single whitespace character separates the header from the samples
+        true => data
+            .get(position + 1..)
+            .and_then(|data| data.get(..samples))
+            .ok_or(Err::Truncated)?
+            .iter()
+            .map(|&sample| scale(sample as usize))
+            .collect(),
+        false => (0..samples)
+            .map(|_| ppm_field(data, &mut position).map(scale))
+            .collect::<Result<_, _>>()?,
+    };
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|rgb| Color::new(rgb[0], rgb[1], rgb[2]))
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RED: Color = Color::new(255, 0, 0);
+    const BLUE: Color = Color::new(0, 0, 255);
+    const WHITE: Color = Color::new(255, 255, 255);
+
+    // 2x2, bottom up: blue, white on the bottom row, red, red on the top
+    fn test_bmp(bits_per_pixel: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bits_per_pixel.to_le_bytes());
+        data.resize(54, 0);
+        for row in [[BLUE, WHITE], [RED, RED]] {
+            let start = data.len();
+            for color in row {
+                data.extend_from_slice(&[color.b, color.g, color.r]);
+                if bits_per_pixel == 32 {
+                    data.push(0xff);
+                }
+            }
+            data.resize(start + (data.len() - start).next_multiple_of(4), 0);
+        }
+        data
+    }
+
+    #[test_case]
+    fn test_decode_bmp() {
+        for bits_per_pixel in [24, 32] {
+            let image = Image::decode(&test_bmp(bits_per_pixel)).unwrap();
+            assert_eq!((image.width, image.height), (2, 2));
+            assert_eq!(image.pixel(0, 0), RED);
+            assert_eq!(image.pixel(0, 1), BLUE);
+            assert_eq!(image.pixel(1, 1), WHITE);
+        }
+        let mut data = test_bmp(24);
+        data.truncate(60);
+        assert_eq!(Image::decode(&data), Err(Err::Truncated));
+        assert_eq!(Image::decode(&test_bmp(8)), Err(Err::Unsupported));
+    }
+
+    #[test_case]
+    fn test_oversized_bmp() {
+        let mut data = test_bmp(32);
+        data[18..22].copy_from_slice(&i32::MAX.to_le_bytes());
+        data[22..26].copy_from_slice(&i32::MIN.to_le_bytes());
+        data[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Image::decode(&data), Err(Err::Truncated));
+    }
+
+    #[test_case]
+    fn test_oversized_ppm() {
+        // width * height overflows, and then width * height * 3
+        let data = b"P6 4294967296 4294967296 255\n\xff\x00\x00";
+        assert_eq!(Image::decode(data), Err(Err::Malformed));
+        let data = b"P6 6148914691236517206 1 255\n\xff\x00\x00";
+        assert_eq!(Image::decode(data), Err(Err::Malformed));
+        let data = b"P3 6148914691236517206 1 255\n255 0 0";
+        assert_eq!(Image::decode(data), Err(Err::Malformed));
+        // All of memory's worth of samples, which doesn't fit after the header
+        let data = b"P6 6148914691236517205 1 255\n\xff\x00\x00";
+        assert_eq!(Image::decode(data), Err(Err::Truncated));
+    }
+
+    #[test_case]
+    fn test_decode_ppm() {
+        let plain = Image::decode(b"P3\n# a comment\n2 1\n15\n15 0 0  0 0 15\n").unwrap();
+        assert_eq!((plain.width, plain.height), (2, 1));
+        assert_eq!(plain.pixel(0, 0), RED);
+        assert_eq!(plain.pixel(1, 0), BLUE);
+        let binary = Image::decode(b"P6 2 1 255\n\xff\x00\x00\x00\x00\xff").unwrap();
+        assert_eq!(binary, plain);
+        assert_eq!(Image::decode(b"P6 2 1 255\n\xff"), Err(Err::Truncated));
+        assert_eq!(Image::decode(b"GIF89a"), Err(Err::Unsupported));
+    }
+}
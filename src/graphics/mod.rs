@@ -0,0 +1,146 @@
+use crate::boot::FramebufferInfo;
+use crate::memory::{self, PAGE_SIZE};
+
+pub mod cursor;
+pub mod image;
+pub mod splash;
+
+use image::Image;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(255, 255, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+// A linear framebuffer the bootloader set up for us, reached through the physical memory map (so
+// one outside it, eg. above the last RAM the bootloader mapped, isn't used).
+// Pixels are assumed to be blue, green, red (and unused, at 4 bytes per pixel), which is what
+// firmware almost always gives; FramebufferInfo doesn't carry the channel layout yet.
+// Drawing outside the screen is clipped.
+pub struct Framebuffer {
+    info: FramebufferInfo,
+    base: *mut u8,
+}
+
+// Only ever reached through a lock (see splash and cursor)
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    // None if it isn't all in the physical memory map.
+    // Safety: `info` must describe a real framebuffer, that nothing else draws to
+    pub unsafe fn new(info: FramebufferInfo) -> Option<Self> {
+        if info.width > info.stride {
+            return None;
+        }
+        let size = info
+            .stride
+            .checked_mul(info.height)?
+            .checked_mul(info.bytes_per_pixel)?;
+        let end = info.address.checked_add(size)?;
+        let mut frames = (info.address & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE);
+        let mapped = frames.all(|frame| {
+            let page = memory::physical_to_virtual(frame);
+            memory::translate_virtual_address(page).ok() == Some(frame)
+        });
+        mapped.then(|| Framebuffer {
+            base: memory::physical_to_virtual(info.address) as *mut u8,
+            info,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x >= self.info.width || y >= self.info.height || self.info.bytes_per_pixel < 3 {
+            return;
+        }
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        unsafe {
+            let pixel = self.base.add(offset);
+            pixel.write_volatile(color.b);
+            pixel.add(1).write_volatile(color.g);
+            pixel.add(2).write_volatile(color.r);
+        }
+    }
+
+    // Black off the screen
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        if x >= self.info.width || y >= self.info.height || self.info.bytes_per_pixel < 3 {
+            return Color::BLACK;
+        }
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        unsafe {
+            let pixel = self.base.add(offset);
+            Color::new(
+                pixel.add(2).read_volatile(),
+                pixel.add(1).read_volatile(),
+                pixel.read_volatile(),
+            )
+        }
+    }
+
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        for y in y..(y + height).min(self.info.height) {
+            for x in x..(x + width).min(self.info.width) {
+                self.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    // With its top left corner at (x, y)
+    pub fn draw(&mut self, image: &Image, x: usize, y: usize) {
+        for row in 0..image.height {
+            for column in 0..image.width {
+                self.put_pixel(x + column, y + row, image.pixel(column, row));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(address: usize, width: usize, height: usize, stride: usize) -> FramebufferInfo {
+        FramebufferInfo {
+            address,
+            width,
+            height,
+            stride,
+            bytes_per_pixel: 4,
+        }
+    }
+
+    #[test_case]
+    fn test_framebuffer_must_be_mapped() {
+        let memory = memory::allocate_dma(2 * PAGE_SIZE).unwrap();
+        // Straddling the two pages
+        let address = memory.start + PAGE_SIZE - 64;
+        let framebuffer = unsafe { Framebuffer::new(info(address, 16, 31, 16)) };
+        let mut framebuffer = framebuffer.expect("RAM is in the physical memory map");
+        framebuffer.put_pixel(15, 30, Color::WHITE);
+        assert_eq!(framebuffer.pixel(15, 30), Color::WHITE);
+        // Far past any RAM QEMU gives us
+        assert!(unsafe { Framebuffer::new(info(1 << 45, 16, 16, 16)) }.is_none());
+        assert!(unsafe { Framebuffer::new(info(memory.start, 17, 16, 16)) }.is_none());
+        assert!(unsafe { Framebuffer::new(info(memory.start, 1, usize::MAX, 1)) }.is_none());
+        memory::deallocate_dma(memory);
+    }
+}
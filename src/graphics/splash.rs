@@ -0,0 +1,114 @@
+use spin::Mutex;
+
+use super::image::Image;
+use super::{Color, Framebuffer};
+use crate::boot::KernelConfig;
+use crate::println;
+
+// A boot splash: an image in the middle of the screen, with a progress bar under it that moves
+// as each boot stage finishes (see sos::init). It's taken down once boot is done and the console
+// has the screen to itself. Without a framebuffer, eg. in the VGA text mode bootloader 0.9 leaves
+// us in, it does nothing.
+// TODO: an image, from the initramfs once there is one. Until then set_image is for whoever
+// has one to hand, and the splash is just the progress bar.
+
+const BAR_WIDTH: usize = 256;
+const BAR_HEIGHT: usize = 8;
+const BAR_BORDER: usize = 1;
+// Between the image and the bar
+const GAP: usize = 16;
+
+struct Splash {
+    framebuffer: Framebuffer,
+    // Top of the progress bar's border
+    bar_top: usize,
+    // Stages done, out of how many
+    progress: (usize, usize),
+}
+
+static SPLASH: Mutex<Option<Splash>> = Mutex::new(None);
+
+impl Splash {
+    fn bar_left(&self) -> usize {
+        self.framebuffer.width().saturating_sub(BAR_WIDTH) / 2
+    }
+
+    fn draw_bar(&mut self) {
+        let (done, total) = self.progress;
+        let (left, top) = (self.bar_left(), self.bar_top);
+        self.framebuffer.fill(
+            left,
+            top,
+            BAR_WIDTH + 2 * BAR_BORDER,
+            BAR_HEIGHT + 2 * BAR_BORDER,
+            Color::WHITE,
+        );
+        let filled = BAR_WIDTH * done.min(total) / total.max(1);
+        let (left, top) = (left + BAR_BORDER, top + BAR_BORDER);
+        self.framebuffer
+            .fill(left, top, filled, BAR_HEIGHT, Color::WHITE);
+        self.framebuffer.fill(
+            left + filled,
+            top,
+            BAR_WIDTH - filled,
+            BAR_HEIGHT,
+            Color::BLACK,
+        );
+    }
+}
+
+pub fn init(config: &KernelConfig) {
+    let info = match config.framebuffer {
+        Some(info) => info,
+        None => return,
+    };
+    // Safety: the console is VGA text, so nothing else draws to the framebuffer
+    let mut framebuffer = match unsafe { Framebuffer::new(info) } {
+        Some(framebuffer) => framebuffer,
+        None => return println!("splash: framebuffer at {:#x} isn't mapped", info.address),
+    };
+    framebuffer.fill(0, 0, info.width, info.height, Color::BLACK);
+    let mut splash = Splash {
+        bar_top: info.height / 2,
+        progress: (0, 1),
+        framebuffer,
+    };
+    splash.draw_bar();
+    *SPLASH.lock() = Some(splash);
+}
+
+// Centered, with the progress bar moved down to just under it
+pub fn set_image(image: &Image) {
+    if let Some(splash) = SPLASH.lock().as_mut() {
+        let (width, height) = (splash.framebuffer.width(), splash.framebuffer.height());
+        let top = height.saturating_sub(image.height + GAP + BAR_HEIGHT) / 2;
+        let (left, bar_top) = (splash.bar_left(), splash.bar_top);
+        splash.framebuffer.fill(
+            left,
+            bar_top,
+            BAR_WIDTH + 2 * BAR_BORDER,
+            BAR_HEIGHT + 2 * BAR_BORDER,
+            Color::BLACK,
+        );
+        splash
+            .framebuffer
+            .draw(image, width.saturating_sub(image.width) / 2, top);
+        splash.bar_top = top + image.height + GAP;
+        splash.draw_bar();
+    }
+}
+
+pub fn progress(done: usize, total: usize) {
+    if let Some(splash) = SPLASH.lock().as_mut() {
+        splash.progress = (done, total);
+        splash.draw_bar();
+    }
+}
+
+// Hand the screen over to the console
+pub fn finish() {
+    if let Some(mut splash) = SPLASH.lock().take() {
+        let (width, height) = (splash.framebuffer.width(), splash.framebuffer.height());
+        splash.framebuffer.fill(0, 0, width, height, Color::BLACK);
+    }
+}
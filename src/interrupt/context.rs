@@ -0,0 +1,62 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Whether we're inside a hardware interrupt handler, for catching work that mustn't happen there
+// (see memory::allocator::audit). Handlers hold an `Entered` for as long as they run; nested
+// handlers (eg. an NMI inside the timer) just count deeper.
+// TODO: one per CPU once the APs are started
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Entered(());
+
+impl Entered {
+    #[inline]
+    pub fn enter() -> Self {
+        DEPTH.fetch_add(1, Ordering::Relaxed);
+        Entered(())
+    }
+}
+
+impl Drop for Entered {
+    #[inline]
+    fn drop(&mut self) {
+        DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn in_interrupt() -> bool {
+    DEPTH.load(Ordering::Relaxed) > 0
+}
+
+// A handler can switch tasks (eg. preemption from the timer), and the task it switches to may not
+// have been in one. The scheduler keeps each task's depth on its own stack across the switch.
+pub(crate) fn swap_depth(depth: usize) -> usize {
+    DEPTH.swap(depth, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_nested_entry() {
+        assert!(!in_interrupt());
+        {
+            let _outer = Entered::enter();
+            let inner = Entered::enter();
+            assert!(in_interrupt());
+            drop(inner);
+            assert!(in_interrupt());
+        }
+        assert!(!in_interrupt());
+    }
+
+    #[test_case]
+    fn test_swap_depth() {
+        let _entered = Entered::enter();
+        let depth = swap_depth(0);
+        assert_eq!(depth, 1);
+        assert!(!in_interrupt());
+        swap_depth(depth);
+        assert!(in_interrupt());
+    }
+}
@@ -0,0 +1,87 @@
+use core::fmt;
+
+// The error code pushed by exceptions that are about a segment selector: invalid TSS, segment
+// not present, stack segment fault and general protection fault. Zero when the exception isn't
+// about a particular selector, which is most general protection faults.
+// Reference: Intel SDM vol 3A, 6.13 "Error Code"
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    const EXTERNAL: u64 = 1;
+    const IDT: u64 = 1 << 1;
+    const LDT: u64 = 1 << 2;
+
+    pub fn new(error: u64) -> Self {
+        SelectorErrorCode(error)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+
+    // Whether it happened delivering an event from outside the program, eg. a hardware interrupt
+    pub fn external(&self) -> bool {
+        self.0 & Self::EXTERNAL != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 & Self::IDT != 0, self.0 & Self::LDT != 0) {
+            (true, _) => DescriptorTable::Idt,
+            (false, true) => DescriptorTable::Ldt,
+            (false, false) => DescriptorTable::Gdt,
+        }
+    }
+
+    // Entry index within the table; the vector number for the IDT
+    pub fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+}
+
+// eg. "GDT selector 0x10", "IDT vector 13 (external)"
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.table() {
+            DescriptorTable::Gdt => write!(f, "GDT selector {:#x}", self.index() << 3)?,
+            DescriptorTable::Ldt => write!(f, "LDT selector {:#x}", self.index() << 3 | 0x4)?,
+            DescriptorTable::Idt => write!(f, "IDT vector {}", self.index())?,
+        }
+        if self.external() {
+            write!(f, " (external)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn test_selector_error_code() {
+        let error = SelectorErrorCode::new(0x10);
+        assert_eq!(error.table(), DescriptorTable::Gdt);
+        assert_eq!(error.index(), 2);
+        assert!(!error.external());
+        assert_eq!(format!("{}", error), "GDT selector 0x10");
+        // Vector 13 through the IDT, during a hardware interrupt
+        let error = SelectorErrorCode::new(13 << 3 | 0x3);
+        assert_eq!(error.table(), DescriptorTable::Idt);
+        assert_eq!(format!("{}", error), "IDT vector 13 (external)");
+        assert_eq!(
+            format!("{}", SelectorErrorCode::new(0x2c)),
+            "LDT selector 0x2c"
+        );
+        assert!(SelectorErrorCode::new(0).is_null());
+    }
+}
@@ -1,38 +1,121 @@
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use lazy_static::lazy_static;
+use spin::Mutex;
 
+pub mod context;
+pub mod error_code;
+pub mod nmi;
 pub mod table;
+pub mod trigger;
 
-use crate::keyboard::{self, Key, KeyboardModifiers};
-use crate::memory::PageFaultError;
-use crate::{print, println};
-use table::{Handler, Interrupt, InterruptStackFrame, InterruptTable};
+use crate::keyboard;
+use crate::memory::{address_space, PageFaultError};
+use crate::println;
+use crate::smp::ipi;
+use crate::symbols::Symbolized;
+use crate::task::{process, scheduler};
+use error_code::SelectorErrorCode;
+use table::{
+    EntryOptions, Exception, Handler, InterruptStackFrame, InterruptTable, Irq, PrivilegeLevel,
+    Vector,
+};
+pub use trigger::trigger;
 
+// Interrupt stack table indexes, see global_descriptor_table
 pub const DOUBLE_FAULT_STACK: usize = 1;
+pub const NMI_STACK: usize = 2;
+pub const MACHINE_CHECK_STACK: usize = 3;
+// Only used with the page_fault_stack feature. Page faults nest (eg. a fault while reporting a
+// fault), and a nested fault on the same IST stack starts again from its top, overwriting the
+// outer handler's frames.
+pub const PAGE_FAULT_STACK: usize = 4;
 
-lazy_static! {
-    static ref INTERRUPT_TABLE: InterruptTable = {
-        let mut table = InterruptTable::empty();
-        table.set_handler(
-            Interrupt::DivideByZero,
-            Handler::Interrupt(divide_by_zero_handler),
-        );
-        table.set_handler(
-            Interrupt::Breakpoint,
-            Handler::Interrupt(breakpoint_handler),
-        );
-        table.set_handler(Interrupt::PageFault, Handler::Exception(page_fault_handler));
-        table
-            .set_handler(
-                Interrupt::DoubleFault,
-                Handler::Exception(double_fault_handler),
-            )
-            .set_stack(DOUBLE_FAULT_STACK as u8);
-        table.set_handler(Interrupt::Timer, Handler::Interrupt(timer_handler));
-        table.set_handler(Interrupt::Keyboard, Handler::Interrupt(keyboard_handler));
-        table
+// Lives in a static rather than being built once and frozen, so that vectors can be handed out
+// (and taken back) after boot, see rebuild_and_reload. The CPU reads it without the lock;
+// the lock only keeps two rebuilds from interleaving.
+static INTERRUPT_TABLE: Mutex<InterruptTable> = Mutex::new(InterruptTable::empty());
+
+fn build(table: &mut InterruptTable) {
+    table.set_handler(
+        Exception::DivideByZero,
+        Handler::Interrupt(divide_by_zero_handler),
+    );
+    table.set_handler_with_options(
+        Exception::NonMaskableInterrupt,
+        Handler::Interrupt(nmi::nmi_handler),
+        EntryOptions::new().stack(NMI_STACK as u8),
+    );
+    table.set_handler_with_options(
+        Exception::MachineCheck,
+        Handler::Interrupt(machine_check_handler),
+        EntryOptions::new().stack(MACHINE_CHECK_STACK as u8),
+    );
+    table.set_handler(
+        Exception::Breakpoint,
+        Handler::Interrupt(breakpoint_handler),
+    );
+    table.set_handler(Exception::Overflow, Handler::Interrupt(overflow_handler));
+    table.set_handler(
+        Exception::BoundRangeExceeded,
+        Handler::Interrupt(bound_range_handler),
+    );
+    table.set_handler(
+        Exception::InvalidOpcode,
+        Handler::Interrupt(invalid_opcode_handler),
+    );
+    let page_fault_stack = match cfg!(feature = "page_fault_stack") {
+        true => PAGE_FAULT_STACK,
+        false => 0,
     };
+    table.set_handler_with_options(
+        Exception::PageFault,
+        Handler::Exception(page_fault_handler),
+        EntryOptions::new().stack(page_fault_stack as u8),
+    );
+    table.set_handler_with_options(
+        Exception::DoubleFault,
+        Handler::Exception(double_fault_handler),
+        EntryOptions::new().stack(DOUBLE_FAULT_STACK as u8),
+    );
+    table.set_handler(
+        Exception::InvalidTss,
+        Handler::Exception(invalid_tss_handler),
+    );
+    table.set_handler(
+        Exception::SegmentNotPresent,
+        Handler::Exception(segment_not_present_handler),
+    );
+    table.set_handler(
+        Exception::StackSegmentFault,
+        Handler::Exception(stack_segment_fault_handler),
+    );
+    table.set_handler(
+        Exception::GeneralProtectionFault,
+        Handler::Exception(general_protection_fault_handler),
+    );
+    table.set_handler(Irq::Timer, Handler::Interrupt(timer_handler));
+    table.set_handler(Irq::Keyboard, Handler::Interrupt(keyboard_handler));
+    table.set_handler(Irq::Com1, Handler::Interrupt(com1_handler));
+    table.set_handler(Irq::Com2, Handler::Interrupt(com2_handler));
+    table.set_handler(
+        Vector::IPI_RESCHEDULE,
+        Handler::Interrupt(ipi::reschedule_handler),
+    );
+    table.set_handler(
+        Vector::IPI_TLB_SHOOTDOWN,
+        Handler::Interrupt(ipi::tlb_shootdown_handler),
+    );
+    table.set_handler(
+        Vector::IPI_HALT_FOR_PANIC,
+        Handler::Interrupt(ipi::halt_for_panic_handler),
+    );
+    // User mode (ring 3) may `int` into this one, see syscall
+    table.set_handler_with_options(
+        Vector::SYSCALL,
+        Handler::Assembly(crate::syscall::sos_syscall_entry),
+        EntryOptions::new().privilege(PrivilegeLevel::Ring3),
+    );
 }
 
 #[macro_export]
@@ -45,66 +128,247 @@ macro_rules! without_interrupt {
     }};
 }
 
-extern "x86-interrupt" fn divide_by_zero_handler(_: InterruptStackFrame) {
+// Hardware interrupts (timer, keyboard, serial) handled since boot
+static HARDWARE_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn hardware_interrupts() -> u64 {
+    HARDWARE_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+fn count_hardware_interrupt() {
+    HARDWARE_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Times each CPU exception vector has been handled (and survived), see selftest
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static EXCEPTIONS: [AtomicU64; 32] = [ZERO; 32];
+
+pub fn exception_count(exception: Exception) -> u64 {
+    EXCEPTIONS[exception as usize].load(Ordering::Relaxed)
+}
+
+fn count_exception(exception: Exception) {
+    EXCEPTIONS[exception as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// A fault in user mode is the process's problem, not the kernel's: it's killed, and this doesn't
+// return. Kernel faults are left to the caller.
+fn kill_user_fault(name: &str, frame: &InterruptStackFrame) {
+    if frame.from_user() {
+        println!(
+            "{} in user mode at {:#x}, killing task {}",
+            name,
+            frame.instruction_pointer(),
+            scheduler::current_id().as_u64()
+        );
+        process::exit(process::KILLED);
+    }
+}
+
+extern "x86-interrupt" fn divide_by_zero_handler(frame: InterruptStackFrame) {
+    kill_user_fault("Divide by zero", &frame);
     panic!("div0 :boom:");
 }
 
 extern "x86-interrupt" fn breakpoint_handler(_: InterruptStackFrame) {
+    count_exception(Exception::Breakpoint);
     println!("breakpoint");
 }
 
-extern "x86-interrupt" fn timer_handler(_: InterruptStackFrame) {
+// `into` and `bound` are invalid in long mode, so these only come from `int 4` / `int 5`, which
+// resume after the int
+extern "x86-interrupt" fn overflow_handler(_: InterruptStackFrame) {
+    count_exception(Exception::Overflow);
+}
+
+extern "x86-interrupt" fn bound_range_handler(_: InterruptStackFrame) {
+    count_exception(Exception::BoundRangeExceeded);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut frame: InterruptStackFrame) {
+    kill_user_fault("Invalid opcode", &frame);
+    // The self test's deliberate ud2 skips ahead instead
+    if let Some(resume) = crate::selftest::fixup(frame.instruction_pointer()) {
+        count_exception(Exception::InvalidOpcode);
+        unsafe { frame.set_instruction_pointer(resume) };
+        return;
+    }
+    crate::panic_screen::record_exception(Exception::InvalidOpcode as u8, 0, &frame);
+    let address = frame.instruction_pointer() as usize;
+    println!("{}", crate::debug::hexdump(address..address + 16));
+    panic!(
+        "invalid opcode at {}",
+        Symbolized(frame.instruction_pointer())
+    );
+}
+
+extern "x86-interrupt" fn timer_handler(frame: InterruptStackFrame) {
+    let _context = context::Entered::enter();
+    count_hardware_interrupt();
     // print!(".");
+    crate::profile::sample(frame.instruction_pointer());
+    // EOI first; tick callbacks may never return here (eg. panics, or switching tasks)
     unsafe {
         crate::pic8259::PIC
             .lock()
-            .notify_end_of_interrupt(Interrupt::Timer);
+            .notify_end_of_interrupt(Irq::Timer);
     };
+    crate::time::tick::hook();
 }
 
 extern "x86-interrupt" fn keyboard_handler(_: InterruptStackFrame) {
+    let _context = context::Entered::enter();
+    count_hardware_interrupt();
     without_interrupt! {{
-        let key = match keyboard::KEYBOARD.lock().read_scancode() {
-            Some((Key::Character(c, _), modifiers)) if !modifiers.contains(KeyboardModifiers::SHIFT) => Some(c),
-            Some((Key::Character(_, c), modifiers)) if modifiers.contains(KeyboardModifiers::SHIFT) => Some(c),
-            _ => None,
-        };
-        if let Some(c) = key {
-            print!("{}", c);
+        let event = keyboard::KEYBOARD.lock().read_scancode();
+        if let Some(event) = event {
+            keyboard::dispatch(event);
         }
     }}
-    // print!("k{}", Interrupt::Keyboard as u8);
     unsafe {
         crate::pic8259::PIC
             .lock()
-            .notify_end_of_interrupt(Interrupt::Keyboard);
+            .notify_end_of_interrupt(Irq::Keyboard);
     };
 }
 
-extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, error: u64) {
-    println!("Page fault?!");
+// Both handle two ports each, see serial::ComPort::interrupt
+extern "x86-interrupt" fn com1_handler(_: InterruptStackFrame) {
+    let _context = context::Entered::enter();
+    count_hardware_interrupt();
+    crate::serial::handle_interrupt(Irq::Com1);
+    unsafe {
+        crate::pic8259::PIC
+            .lock()
+            .notify_end_of_interrupt(Irq::Com1)
+    };
+}
+
+extern "x86-interrupt" fn com2_handler(_: InterruptStackFrame) {
+    let _context = context::Entered::enter();
+    count_hardware_interrupt();
+    crate::serial::handle_interrupt(Irq::Com2);
+    unsafe {
+        crate::pic8259::PIC
+            .lock()
+            .notify_end_of_interrupt(Irq::Com2)
+    };
+}
+
+extern "x86-interrupt" fn page_fault_handler(mut frame: InterruptStackFrame, error: u64) {
     let mut invalid_address: u64;
     unsafe {
         asm!("mov {}, cr2", out(reg) invalid_address, options(nomem, nostack, preserves_flags))
     };
+    // Kernel memory mapped since this task's page tables were made
+    if address_space::sync_kernel_entry(invalid_address as usize) {
+        return;
+    }
+    kill_user_fault("Page fault", &frame);
+    // Pages of memory-mapped files are read in when they're first touched
+    let cause = PageFaultError::from_error_code(error);
+    if crate::fs::mmap::handle_fault(invalid_address as usize, cause) {
+        return;
+    }
+    // Faults from memory::try_read and friends are expected, resume at their fixup
+    if let Some(resume) =
+        crate::memory::fault::fixup(frame.instruction_pointer(), invalid_address, error)
+    {
+        unsafe { frame.set_instruction_pointer(resume) };
+        return;
+    }
+    crate::panic_screen::record_exception(Exception::PageFault as u8, error, &frame);
+    println!("Page fault?!");
     println!(
-        "PAGE FAULT: Error({:#?}) / ({:#x}) -- {:#?}",
-        PageFaultError::from_bits_truncate(error as u32),
+        "PAGE FAULT: {} at {:#x} ({:?} memory), from {} -- {:#?}",
+        cause,
         invalid_address,
+        address_space::region(invalid_address as usize),
+        Symbolized(frame.instruction_pointer()),
         frame
     );
     panic!("page fault");
 }
 
+// Faults about a segment selector, mostly from loading a bad one (or iretq to one). With no
+// selector involved it's usually a general protection fault from a non-canonical address or a
+// privileged instruction.
+// Raised from user mode, they kill the process instead.
+fn selector_fault(exception: Exception, name: &str, frame: &InterruptStackFrame, error: u64) -> ! {
+    kill_user_fault(name, frame);
+    crate::panic_screen::record_exception(exception as u8, error, frame);
+    let error = SelectorErrorCode::new(error);
+    let at = Symbolized(frame.instruction_pointer());
+    match error.is_null() {
+        true => panic!("{} at {}", name, at),
+        false => panic!("{} referencing {} at {}", name, error, at),
+    }
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(frame: InterruptStackFrame, error: u64) {
+    selector_fault(Exception::InvalidTss, "invalid TSS", &frame, error);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(frame: InterruptStackFrame, error: u64) {
+    selector_fault(
+        Exception::SegmentNotPresent,
+        "segment not present",
+        &frame,
+        error,
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(frame: InterruptStackFrame, error: u64) {
+    selector_fault(
+        Exception::StackSegmentFault,
+        "stack segment fault",
+        &frame,
+        error,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(frame: InterruptStackFrame, error: u64) {
+    selector_fault(Exception::GeneralProtectionFault, "GP fault", &frame, error);
+}
+
+// Hardware says it's broken. Nothing to recover, but say what we can before panicking, without
+// locks since this can interrupt anything.
+extern "x86-interrupt" fn machine_check_handler(frame: InterruptStackFrame) {
+    crate::serial::force_print(format_args!(
+        "MACHINE CHECK at {}\n",
+        Symbolized(frame.instruction_pointer())
+    ));
+    panic!("machine check");
+}
+
 extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error: u64) {
-    println!("DOUBLE FAULT: Error({:#x}) -- {:#?}", error, frame);
+    crate::panic_screen::record_exception(Exception::DoubleFault as u8, error, &frame);
+    println!(
+        "DOUBLE FAULT: Error({:#x}) at {} -- {:#?}",
+        error,
+        Symbolized(frame.instruction_pointer()),
+        frame
+    );
     panic!("double fault");
 }
 
 pub fn init() {
     println!("Loading interrupt table!");
-    println!("{:#?}", INTERRUPT_TABLE[Interrupt::DoubleFault]);
-    INTERRUPT_TABLE.load();
+    rebuild_and_reload(build);
+    println!("{:#?}", INTERRUPT_TABLE.lock()[Exception::DoubleFault]);
+}
+
+// Change the interrupt table, eg. to give a driver a vector. Maskable interrupts are off while
+// `change` runs, so none of them can be delivered through a half written entry. NMIs still
+// can, so leave the NMI's entry alone once it's loaded.
+pub fn rebuild_and_reload(change: impl FnOnce(&mut InterruptTable)) {
+    crate::without_interrupt! {{
+        let mut table = INTERRUPT_TABLE.lock();
+        change(&mut table);
+        // Reloading is only needed the first time, but it's cheap, and it's the same table
+        // Safety: the table is a static, so it never moves
+        unsafe { table.load_unchecked() };
+    }}
 }
 
 #[inline]
@@ -141,10 +405,28 @@ impl Drop for DisableInterruptsGuard {
 
 #[cfg(test)]
 mod test {
-    use core::arch::asm;
+    use super::*;
+    use core::sync::atomic::AtomicBool;
 
     #[test_case]
     fn test_breakpoint() {
         unsafe { asm!("int3") };
     }
+
+    static DEBUG_RAISED: AtomicBool = AtomicBool::new(false);
+
+    extern "x86-interrupt" fn test_debug_handler(_: InterruptStackFrame) {
+        DEBUG_RAISED.store(true, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_rebuild_and_reload() {
+        rebuild_and_reload(|table| {
+            table.set_handler(Exception::Debug, Handler::Interrupt(test_debug_handler));
+        });
+        unsafe { asm!("int 1") };
+        assert!(DEBUG_RAISED.load(Ordering::Relaxed));
+        rebuild_and_reload(|table| table.clear_handler(Exception::Debug));
+        assert_eq!(INTERRUPT_TABLE.lock()[Exception::Debug].pointer(), 0);
+    }
 }
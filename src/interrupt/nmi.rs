@@ -0,0 +1,66 @@
+use core::fmt;
+
+use bitflags::bitflags;
+
+use super::table::InterruptStackFrame;
+use crate::arch::port::ReadOnlyPort;
+use crate::symbols::Symbolized;
+
+// Non-maskable interrupts: hardware errors (memory parity, I/O channel checks) and watchdogs.
+// The handler can't take any locks, since `cli` doesn't keep it out of whatever holds them, so it
+// reports straight to serial. The NMI mask is managed with the CMOS, see drivers::cmos.
+
+// What's behind an NMI, on PC chipsets
+const SYSTEM_CONTROL_PORT_A: ReadOnlyPort<u8> = ReadOnlyPort::new(0x92);
+const SYSTEM_CONTROL_PORT_B: ReadOnlyPort<u8> = ReadOnlyPort::new(0x61);
+
+bitflags! {
+    pub struct ControlA: u8 {
+        const WATCHDOG_TIMER = 1 << 4;
+    }
+}
+
+bitflags! {
+    pub struct ControlB: u8 {
+        const IO_CHANNEL_CHECK = 1 << 6;
+        const PARITY_ERROR = 1 << 7;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub control_a: u8,
+    pub control_b: u8,
+}
+
+impl Status {
+    pub fn read() -> Self {
+        unsafe {
+            Status {
+                control_a: SYSTEM_CONTROL_PORT_A.read(),
+                control_b: SYSTEM_CONTROL_PORT_B.read(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "port 0x92 = {:#04x} {:?}, port 0x61 = {:#04x} {:?}",
+            self.control_a,
+            ControlA::from_bits_truncate(self.control_a),
+            self.control_b,
+            ControlB::from_bits_truncate(self.control_b)
+        )
+    }
+}
+
+pub(super) extern "x86-interrupt" fn nmi_handler(frame: InterruptStackFrame) {
+    crate::serial::force_print(format_args!(
+        "NMI at {}: {}\n",
+        Symbolized(frame.instruction_pointer()),
+        Status::read()
+    ));
+}
@@ -15,6 +15,29 @@ pub struct InterruptStackFrame {
     stack_segment: u64,
 }
 
+impl InterruptStackFrame {
+    // Address of the instruction that was interrupted (or faulted)
+    pub fn instruction_pointer(&self) -> u64 {
+        self.instruction_pointer
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.stack_pointer
+    }
+
+    // Whether the CPU was in user mode (ring 3) when it was interrupted
+    pub fn from_user(&self) -> bool {
+        self.code_segment & 0x3 == 3
+    }
+
+    // Change where the interrupted code resumes after iretq.
+    // Safety: the frame is the CPU's actual interrupt frame (the x86-interrupt ABI passes it by
+    // reference in disguise), and `address` must be somewhere it's safe to continue.
+    pub unsafe fn set_instruction_pointer(&mut self, address: u64) {
+        core::ptr::write_volatile(&mut self.instruction_pointer, address);
+    }
+}
+
 bitflags! {
     pub struct EntryOptions: u16 {
         // if all 0, don't switch stacks, otherwis switch to stack 1-7
@@ -32,23 +55,83 @@ bitflags! {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeLevel {
+    Ring0 = 0,
+    Ring1 = 1,
+    Ring2 = 2,
+    Ring3 = 3,
+}
+
+// Built up from new(), eg.
+//     EntryOptions::new().stack(DOUBLE_FAULT_STACK as u8).privilege(PrivilegeLevel::Ring3)
 impl EntryOptions {
     const EMPTY: Self = {
         let mut options = EntryOptions::empty();
         options.bits |= 0x7 << 9;
         options
     };
+    const STACK_MASK: u16 = 0x7;
+    const PRIVILEGE_SHIFT: u16 = 13;
+
+    // Present, only raised by the kernel, interrupts disabled in the handler, no stack switch
+    pub const fn new() -> Self {
+        let mut options = Self::EMPTY;
+        options.bits |= Self::PRESENT.bits;
+        options
+    }
+
+    // Interrupt stack table index 1-7 to switch to, or 0 to stay on the current stack
+    pub fn stack(mut self, stack: u8) -> Self {
+        assert!(
+            stack <= 7,
+            "Interrupt stack table index {} out of range",
+            stack
+        );
+        self.bits = (self.bits & !Self::STACK_MASK) | stack as u16;
+        self
+    }
+
+    // The least privileged ring allowed to raise it with `int`. Hardware interrupts and
+    // exceptions ignore it.
+    pub fn privilege(mut self, level: PrivilegeLevel) -> Self {
+        self.bits =
+            (self.bits & !(0x3 << Self::PRIVILEGE_SHIFT)) | (level as u16) << Self::PRIVILEGE_SHIFT;
+        self
+    }
+
+    // Whether the handler starts with interrupts disabled (an interrupt gate) or left as they
+    // were (a trap gate)
+    pub fn disable_interrupts(mut self, disable: bool) -> Self {
+        self.set(Self::INTERRUPTS_ENABLED, !disable);
+        self
+    }
 
-    pub fn stack(&self) -> u8 {
-        (self.bits & 0x7) as u8
+    pub fn stack_index(&self) -> u8 {
+        (self.bits & Self::STACK_MASK) as u8
+    }
+
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        match (self.bits >> Self::PRIVILEGE_SHIFT) & 0x3 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
     }
 
     pub fn set_stack(&mut self, stack: u8) -> &mut Self {
-        self.bits = (self.bits ^ (self.bits & 0x7)) | stack as u16;
+        *self = self.stack(stack);
         self
     }
 }
 
+impl Default for EntryOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 fn get_current_code_segment() -> u16 {
     let segment: u16;
     unsafe { asm!("mov {0:x}, cs", out(reg) segment, options(nomem, nostack, preserves_flags)) };
@@ -67,6 +150,8 @@ fn get_current_code_segment() -> u16 {
 pub enum Handler {
     Interrupt(extern "x86-interrupt" fn(frame: InterruptStackFrame)),
     Exception(extern "x86-interrupt" fn(frame: InterruptStackFrame, error: u64)),
+    // Entry stubs which do their own saving and iretq, eg. syscall::sos_syscall_entry
+    Assembly(unsafe extern "C" fn()),
 }
 
 #[derive(Clone, Copy)]
@@ -81,7 +166,7 @@ pub struct TableEntry {
 }
 
 impl TableEntry {
-    pub fn empty() -> TableEntry {
+    pub const fn empty() -> TableEntry {
         TableEntry {
             pointer_low: 0,
             global_descriptor_table_selector: 0,
@@ -97,6 +182,7 @@ impl TableEntry {
         let pointer = match handler {
             Handler::Interrupt(fp) => fp as u64,
             Handler::Exception(fp) => fp as u64,
+            Handler::Assembly(fp) => fp as u64,
         };
         entry.pointer_low = pointer as u16;
         entry.pointer_middle = (pointer >> 16) as u16;
@@ -107,6 +193,10 @@ impl TableEntry {
         entry
     }
 
+    pub fn options(&self) -> EntryOptions {
+        self.options
+    }
+
     pub fn pointer(&self) -> u64 {
         self.pointer_low as u64
             | (self.pointer_middle as u64) << 16
@@ -124,9 +214,16 @@ impl fmt::Debug for TableEntry {
     }
 }
 
+// Interrupt vectors come in three kinds, each with its own type so they can't be mixed up:
+// - Exception: raised by the CPU itself, with vectors 0-31 all to themselves
+// - Irq: hardware interrupt lines on the PIC, at PIC_INTERRUPT_OFFSET and up
+// - Vector: any table index. Exceptions and IRQs convert into one, and anything else (IPIs, and
+//   IO APIC or MSI vectors later) is range checked to keep clear of the exceptions.
+
+// Vectors not listed are reserved
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
-pub enum Interrupt {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
     DivideByZero = 0,
     Debug = 1,
     NonMaskableInterrupt = 2,
@@ -142,16 +239,81 @@ pub enum Interrupt {
     StackSegmentFault = 12,
     GeneralProtectionFault = 13,
     PageFault = 14,
-    X87FloatingPoint = 15,
-    AlignmentCheck = 16,
-    MachineCheck = 17,
-    SimdFloatingPoint = 18,
-    Virtualization = 19,
-    SecurityException = 20,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+// By line number
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irq {
+    Timer = 0,
+    Keyboard = 1,
+    Cascade = 2,
+    Com2 = 3,
+    Com1 = 4,
+}
 
-    // Hardware interrupts
-    Timer = pic8259::PIC_INTERRUPT_OFFSET as isize,
-    Keyboard,
+impl Irq {
+    pub const fn line(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn vector(self) -> Vector {
+        Vector::device(pic8259::PIC_INTERRUPT_OFFSET + self.line())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Vector(u8);
+
+impl Vector {
+    // Below this they're all exceptions
+    pub const FIRST_DEVICE: u8 = 32;
+
+    // User mode's way into the kernel, see syscall
+    pub const SYSCALL: Vector = Vector::device(crate::syscall::VECTOR);
+
+    // Inter-processor interrupts, see smp::ipi
+    pub const IPI_RESCHEDULE: Vector = Vector::device(0xf0);
+    pub const IPI_TLB_SHOOTDOWN: Vector = Vector::device(0xf1);
+    pub const IPI_HALT_FOR_PANIC: Vector = Vector::device(0xf2);
+
+    // A vector for something other than an exception. Panics if it's one of theirs, which for
+    // constants like the ones above is a compile error.
+    pub const fn device(vector: u8) -> Vector {
+        match Self::try_device(vector) {
+            Some(vector) => vector,
+            None => panic!("Vectors 0-31 are reserved for exceptions"),
+        }
+    }
+
+    pub const fn try_device(vector: u8) -> Option<Vector> {
+        match vector >= Self::FIRST_DEVICE {
+            true => Some(Vector(vector)),
+            false => None,
+        }
+    }
+
+    pub const fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+impl From<Exception> for Vector {
+    fn from(exception: Exception) -> Self {
+        Vector(exception as u8)
+    }
+}
+
+impl From<Irq> for Vector {
+    fn from(irq: Irq) -> Self {
+        irq.vector()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -159,24 +321,52 @@ pub enum Interrupt {
 #[repr(align(16))]
 pub struct InterruptTable([TableEntry; 256]);
 
-impl Index<Interrupt> for InterruptTable {
+impl<V: Into<Vector>> Index<V> for InterruptTable {
     type Output = TableEntry;
-    fn index(&self, index: Interrupt) -> &TableEntry {
-        return &self.0[index as usize];
+    fn index(&self, index: V) -> &TableEntry {
+        &self.0[index.into().as_u8() as usize]
     }
 }
 
 impl InterruptTable {
-    pub fn empty() -> InterruptTable {
+    pub const fn empty() -> InterruptTable {
         InterruptTable([TableEntry::empty(); 256])
     }
 
-    pub fn set_handler(&mut self, interrupt: Interrupt, handler: Handler) -> &mut EntryOptions {
-        self.0[interrupt as usize] = TableEntry::new(handler);
-        &mut self.0[interrupt as usize].options
+    pub fn set_handler(
+        &mut self,
+        vector: impl Into<Vector>,
+        handler: Handler,
+    ) -> &mut EntryOptions {
+        let entry = &mut self.0[vector.into().as_u8() as usize];
+        *entry = TableEntry::new(handler);
+        &mut entry.options
+    }
+
+    // Like set_handler, with the options given up front
+    pub fn set_handler_with_options(
+        &mut self,
+        vector: impl Into<Vector>,
+        handler: Handler,
+        options: EntryOptions,
+    ) {
+        let mut entry = TableEntry::new(handler);
+        entry.options = options;
+        self.0[vector.into().as_u8() as usize] = entry;
+    }
+
+    // Back to not present, so the vector double faults (or general protection faults) if raised
+    pub fn clear_handler(&mut self, vector: impl Into<Vector>) {
+        self.0[vector.into().as_u8() as usize] = TableEntry::empty();
     }
 
     pub fn load(&'static self) {
+        // Safety: it's 'static
+        unsafe { self.load_unchecked() }
+    }
+
+    // Safety: the table has to stay where it is, and valid, for as long as it's loaded
+    pub unsafe fn load_unchecked(&self) {
         use core::mem::size_of;
 
         let pointer = TablePointer {
@@ -184,7 +374,7 @@ impl InterruptTable {
             table_raw_pointer: self as *const _ as u64,
         };
 
-        unsafe { asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags)) };
+        asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
     }
 }
 
@@ -195,3 +385,39 @@ pub struct TablePointer {
     table_limit: u16, // table size in bytes - 1
     table_raw_pointer: u64,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_entry_options() {
+        let options = EntryOptions::new();
+        assert_eq!(options.bits(), 0x8e00);
+        let options = options
+            .stack(3)
+            .privilege(PrivilegeLevel::Ring3)
+            .disable_interrupts(false);
+        assert_eq!(options.bits(), 0xef03);
+        assert_eq!(options.stack_index(), 3);
+        assert_eq!(options.privilege_level(), PrivilegeLevel::Ring3);
+        assert_eq!(
+            options.stack(0).privilege(PrivilegeLevel::Ring0).bits(),
+            0x8f00
+        );
+    }
+
+    #[test_case]
+    fn test_vectors() {
+        assert_eq!(Vector::from(Exception::MachineCheck).as_u8(), 18);
+        assert_eq!(Vector::from(Irq::Com1).as_u8(), 36);
+        assert_eq!(Vector::try_device(31), None);
+        assert_eq!(Vector::try_device(32), Some(Irq::Timer.vector()));
+        crate::assert_panics!(Vector::device(14), "reserved for exceptions");
+    }
+
+    #[test_case]
+    fn test_stack_out_of_range() {
+        crate::assert_panics!(EntryOptions::new().stack(8), "out of range");
+    }
+}
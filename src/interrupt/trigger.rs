@@ -0,0 +1,117 @@
+use core::arch::global_asm;
+
+use super::table::Vector;
+use super::INTERRUPT_TABLE;
+
+// Raising any interrupt vector in software, eg. so tests can run the timer or keyboard handler
+// when they choose rather than waiting for the hardware. `int` only takes its vector as an
+// immediate, so there's a stub per vector doing `int n; ret`, and trigger calls the right one.
+//
+// The handler runs exactly as it would for the real thing, except that exceptions don't get an
+// error code pushed, so the ones that expect one are refused. Handlers for hardware IRQs send
+// the PIC an end of interrupt, which is harmless with nothing in service.
+// TODO: a local APIC self-IPI instead, once there's a local APIC driver, so IRQ handlers see an
+// interrupt the interrupt controller actually delivered.
+
+const STUB_SIZE: usize = 4;
+
+// Vectors the CPU pushes an error code for
+const ERROR_CODE_VECTORS: [u8; 9] = [8, 10, 11, 12, 13, 14, 17, 21, 30];
+
+global_asm!(
+    ".global sos_trigger_stubs",
+    ".balign 16",
+    "sos_trigger_stubs:",
+    ".set sos_trigger_vector, 0",
+    ".rept 256",
+    // int imm8, then ret; spelled out so vector 3 isn't assembled as the one byte int3
+    "    .byte 0xcd, sos_trigger_vector, 0xc3",
+    "    .balign 4",
+    "    .set sos_trigger_vector, sos_trigger_vector + 1",
+    ".endr",
+);
+
+extern "C" {
+    static sos_trigger_stubs: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    // The CPU would push an error code for it, which `int` doesn't
+    ErrorCodeExpected(u8),
+    // Raising it would be a general protection fault instead
+    NoHandler(u8),
+}
+
+// Run the handler for `vector` now, on this CPU, as though it had been raised
+pub fn trigger(vector: impl Into<Vector>) -> Result<(), Err> {
+    let vector = vector.into();
+    let number = vector.as_u8();
+    if ERROR_CODE_VECTORS.contains(&number) {
+        return Err(Err::ErrorCodeExpected(number));
+    }
+    let installed = crate::without_interrupt! {{
+        INTERRUPT_TABLE.lock()[vector].pointer() != 0
+    }};
+    if !installed {
+        return Err(Err::NoHandler(number));
+    }
+    unsafe {
+        let stub = (&sos_trigger_stubs as *const u8).add(number as usize * STUB_SIZE);
+        let stub: extern "C" fn() = core::mem::transmute(stub);
+        stub();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::interrupt::rebuild_and_reload;
+    use crate::interrupt::table::{Exception, Handler, InterruptStackFrame, Irq};
+
+    // Not used by anything else
+    const TEST_VECTOR: Vector = Vector::device(0xe0);
+
+    static RAISED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "x86-interrupt" fn test_handler(_: InterruptStackFrame) {
+        RAISED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_trigger_installed_handler() {
+        RAISED.store(0, Ordering::Relaxed);
+        assert_eq!(
+            trigger(TEST_VECTOR),
+            Err(Err::NoHandler(TEST_VECTOR.as_u8()))
+        );
+        rebuild_and_reload(|table| {
+            table.set_handler(TEST_VECTOR, Handler::Interrupt(test_handler));
+        });
+        assert_eq!(trigger(TEST_VECTOR), Ok(()));
+        assert_eq!(trigger(TEST_VECTOR), Ok(()));
+        assert_eq!(RAISED.load(Ordering::Relaxed), 2);
+        rebuild_and_reload(|table| table.clear_handler(TEST_VECTOR));
+    }
+
+    #[test_case]
+    fn test_trigger_timer() {
+        let before = crate::time::jiffies();
+        crate::without_interrupt! {{
+            trigger(Irq::Timer).unwrap();
+        }}
+        assert!(crate::time::jiffies() > before);
+    }
+
+    #[test_case]
+    fn test_error_code_exceptions_refused() {
+        assert_eq!(
+            trigger(Exception::PageFault),
+            Err(Err::ErrorCodeExpected(14))
+        );
+        assert_eq!(trigger(Exception::Breakpoint), Ok(()));
+    }
+}
@@ -0,0 +1,168 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::sync::WaitQueue;
+
+// Message passing between tasks. A port is a kernel-managed queue of messages; any task holding
+// its id can send to it, and receivers block until a message arrives.
+//
+// Small messages are copied in with `send`. Large payloads can be handed over with
+// `send_buffer`, which moves the buffer itself into the queue without copying.
+// TODO: per-process handle tables once there are processes; for now port ids are global.
+
+// Messages queued on a port before senders get Err::Full
+const MAX_QUEUED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortId(u64);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Err {
+    NoSuchPort,
+    // The port was destroyed while we were waiting on it
+    Closed,
+    Full,
+}
+
+pub type Message = Box<[u8]>;
+
+struct Port {
+    messages: Mutex<VecDeque<Message>>,
+    closed: AtomicBool,
+    receivers: WaitQueue,
+}
+
+lazy_static! {
+    static ref PORTS: Mutex<BTreeMap<PortId, Arc<Port>>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_PORT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn port(id: PortId) -> Result<Arc<Port>, Err> {
+    crate::without_interrupt! {{
+        PORTS.lock().get(&id).cloned().ok_or(Err::NoSuchPort)
+    }}
+}
+
+pub fn port_create() -> PortId {
+    let id = PortId(NEXT_PORT_ID.fetch_add(1, Ordering::Relaxed));
+    let port = Arc::new(Port {
+        messages: Mutex::new(VecDeque::new()),
+        closed: AtomicBool::new(false),
+        receivers: WaitQueue::new(),
+    });
+    crate::without_interrupt! {{
+        PORTS.lock().insert(id, port);
+    }}
+    id
+}
+
+// Destroy a port, dropping any queued messages. Blocked receivers get Err::Closed.
+pub fn port_destroy(id: PortId) -> Result<(), Err> {
+    let port = crate::without_interrupt! {{
+        PORTS.lock().remove(&id).ok_or(Err::NoSuchPort)
+    }}?;
+    port.closed.store(true, Ordering::Release);
+    port.receivers.wake_all();
+    Ok(())
+}
+
+// Send a copy of `data`
+pub fn send(id: PortId, data: &[u8]) -> Result<(), Err> {
+    send_buffer(id, Box::from(data))
+}
+
+// Send `buffer` itself, without copying it
+pub fn send_buffer(id: PortId, buffer: Message) -> Result<(), Err> {
+    let port = port(id)?;
+    crate::without_interrupt! {{
+        let mut messages = port.messages.lock();
+        if messages.len() >= MAX_QUEUED {
+            return Err(Err::Full);
+        }
+        messages.push_back(buffer);
+    }}
+    port.receivers.wake_one();
+    Ok(())
+}
+
+// Take the next message if there is one, without blocking
+pub fn try_receive(id: PortId) -> Result<Option<Message>, Err> {
+    let port = port(id)?;
+    let message = crate::without_interrupt! {{
+        port.messages.lock().pop_front()
+    }};
+    Ok(message)
+}
+
+// Block until a message arrives
+pub fn receive(id: PortId) -> Result<Message, Err> {
+    let port = port(id)?;
+    // Interrupts stay off between finding the queue empty and sleeping, so a send from an
+    // interrupt handler can't be missed
+    crate::without_interrupt! {{
+        loop {
+            if let Some(message) = port.messages.lock().pop_front() {
+                return Ok(message);
+            }
+            if port.closed.load(Ordering::Acquire) {
+                return Err(Err::Closed);
+            }
+            port.receivers.wait();
+        }
+    }}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task;
+    use alloc::vec;
+
+    static PING: Mutex<Option<PortId>> = Mutex::new(None);
+    static PONG: Mutex<Option<PortId>> = Mutex::new(None);
+
+    fn echo_task() {
+        let (ping, pong) = (PING.lock().unwrap(), PONG.lock().unwrap());
+        while let Ok(message) = receive(ping) {
+            send_buffer(pong, message).unwrap();
+        }
+    }
+
+    #[test_case]
+    fn test_send_and_receive() {
+        let ping = port_create();
+        let pong = port_create();
+        *PING.lock() = Some(ping);
+        *PONG.lock() = Some(pong);
+        task::spawn("echo", echo_task).unwrap();
+        send(ping, b"hello").unwrap();
+        assert_eq!(&*receive(pong).unwrap(), b"hello");
+        // Big payloads move rather than copy
+        let buffer = vec![7u8; 8192].into_boxed_slice();
+        let address = buffer.as_ptr();
+        send_buffer(ping, buffer).unwrap();
+        assert_eq!(receive(pong).unwrap().as_ptr(), address);
+        // Closing the port wakes the echo task, which exits
+        port_destroy(ping).unwrap();
+        task::yield_now();
+        assert_eq!(try_receive(pong), Ok(None));
+        port_destroy(pong).unwrap();
+        assert_eq!(send(pong, b"gone"), Err(Err::NoSuchPort));
+    }
+
+    #[test_case]
+    fn test_port_full() {
+        let port = port_create();
+        for _ in 0..MAX_QUEUED {
+            send(port, &[0]).unwrap();
+        }
+        assert_eq!(send(port, &[0]), Err(Err::Full));
+        port_destroy(port).unwrap();
+    }
+}
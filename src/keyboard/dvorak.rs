@@ -77,7 +77,7 @@ pub static MAP: Dvorak = Dvorak([
     Key::LeftMeta,
     Key::LeftOption,
     Key::Character(' ', ' '),
-    Key::NotBound,
+    Key::CapsLock,
     Key::NotBound,
     Key::NotBound, // scancode = 60
     Key::NotBound,
@@ -88,8 +88,8 @@ pub static MAP: Dvorak = Dvorak([
     Key::NotBound,
     Key::NotBound,
     Key::NotBound,
-    Key::NotBound,
-    Key::NotBound, // scancode = 70
+    Key::NumLock,
+    Key::ScrollLock, // scancode = 70
     Key::NotBound,
     Key::NotBound,
     Key::NotBound,
@@ -2,6 +2,8 @@
 pub enum Key {
     NotBound,
     CapsLock,
+    NumLock,
+    ScrollLock,
     LeftControl,
     RightControl,
     LeftShift,
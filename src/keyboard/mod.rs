@@ -4,7 +4,10 @@ use bitflags::bitflags;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::serial::port_read_byte;
+use crate::arch::port::{Port, ReadOnlyPort};
+use crate::console::line;
+use crate::sync::Semaphore;
+use crate::task::scheduler::{self, HIGH_PRIORITY};
 
 mod dvorak;
 mod keys;
@@ -12,6 +15,22 @@ mod keys;
 pub use keys::Key;
 
 const PS2_KEYBOARD_PORT: u16 = 0x60;
+// Reading it gives the controller status, writing it sends the controller (not keyboard) commands
+const PS2_STATUS_PORT: ReadOnlyPort<u8> = ReadOnlyPort::new(0x64);
+const PS2_STATUS_OUTPUT_FULL: u8 = 1;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Keyboard commands and responses
+// Reference: https://wiki.osdev.org/PS/2_Keyboard#Commands
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_SET_TYPEMATIC: u8 = 0xF3;
+const RESPONSE_ACK: u8 = 0xFA;
+const RESPONSE_RESEND: u8 = 0xFE;
+const RESPONSE_ECHO: u8 = 0xEE;
+const COMMAND_RETRIES: usize = 3;
+// How long to poll the status port before giving up on the controller, and how often
+const PS2_TIMEOUT_MICROS: u64 = 100_000;
+const PS2_POLL_MICROS: u64 = 10;
 
 bitflags! {
     pub struct KeyboardModifiers: u8 {
@@ -19,16 +38,48 @@ bitflags! {
         const SHIFT = 1 << 1;
         const OPTION = 1 << 2;
         const META = 1 << 3;
+        // Lock states, toggled by their keys rather than held
+        const CAPS_LOCK = 1 << 4;
+        const NUM_LOCK = 1 << 5;
+        const SCROLL_LOCK = 1 << 6;
     }
 }
 
+// A key press, with the modifiers held at the time. Real ones come from read_scancode, and
+// synthetic ones (eg. from tests) go through inject, taking the same path from there.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub modifiers: KeyboardModifiers,
+}
+
+impl KeyEvent {
+    pub const fn new(key: Key, modifiers: KeyboardModifiers) -> Self {
+        KeyEvent { key, modifiers }
+    }
+
+    // Typing `c`, whatever the layout. '\n' is Enter.
+    pub const fn character(c: char) -> Self {
+        Self::new(Key::Character(c, c), KeyboardModifiers::empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    Timeout,
+    // The keyboard kept asking for the command to be resent
+    Resend,
+}
+
 pub trait KeycodeMap: Index<u8, Output = Key> {
     fn modifiers(&self, keycode: u8) -> KeyboardModifiers;
 }
 
 pub struct KeyboardState<'a> {
-    port: u16,
+    port: Port<u8>,
     modifiers: KeyboardModifiers,
+    // Lock keys currently held down, so key repeat doesn't toggle them again
+    held_locks: KeyboardModifiers,
     keymap: &'a dyn KeycodeMap,
 }
 
@@ -42,19 +93,129 @@ fn modifier(key: Key) -> KeyboardModifiers {
     }
 }
 
+fn lock(key: Key) -> KeyboardModifiers {
+    match key {
+        Key::CapsLock => KeyboardModifiers::CAPS_LOCK,
+        Key::NumLock => KeyboardModifiers::NUM_LOCK,
+        Key::ScrollLock => KeyboardModifiers::SCROLL_LOCK,
+        _ => KeyboardModifiers::empty(),
+    }
+}
+
+// The character a key produces. Caps lock only affects letters, and shift undoes it.
+pub fn character(key: Key, modifiers: KeyboardModifiers) -> Option<char> {
+    match key {
+        Key::Character(unshifted, shifted) => {
+            let mut shift = modifiers.contains(KeyboardModifiers::SHIFT);
+            if unshifted.is_ascii_alphabetic() && modifiers.contains(KeyboardModifiers::CAPS_LOCK) {
+                shift = !shift;
+            }
+            Some(if shift { shifted } else { unshifted })
+        }
+        _ => None,
+    }
+}
+
+// Set LEDs command data
+fn led_bits(modifiers: KeyboardModifiers) -> u8 {
+    [
+        KeyboardModifiers::SCROLL_LOCK,
+        KeyboardModifiers::NUM_LOCK,
+        KeyboardModifiers::CAPS_LOCK,
+    ]
+    .iter()
+    .enumerate()
+    .filter(|(_, lock)| modifiers.contains(**lock))
+    .fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+// Set typematic command data. The delay before repeating is 250-1000ms in steps of 250, and the
+// repeat rate goes from 0 (30Hz) down to 31 (2Hz).
+fn typematic_bits(delay_ms: u32, rate: u8) -> u8 {
+    let delay = (delay_ms.clamp(250, 1000) - 250 + 125) / 250;
+    (delay as u8) << 5 | rate.min(31)
+}
+
+unsafe fn wait_for_status(mask: u8, set: bool) -> Result<(), Err> {
+    for _ in 0..PS2_TIMEOUT_MICROS / PS2_POLL_MICROS {
+        if (PS2_STATUS_PORT.read() & mask != 0) == set {
+            return Ok(());
+        }
+        crate::time::delay_us(PS2_POLL_MICROS);
+    }
+    Err(Err::Timeout)
+}
+
 // TODO: we'll rethink the API once we have async/await as an event subscription
 impl<'a> KeyboardState<'a> {
     pub fn new(port: u16, keymap: &'a dyn KeycodeMap) -> KeyboardState<'a> {
         KeyboardState {
-            port,
+            port: Port::new(port),
             keymap,
             modifiers: KeyboardModifiers::empty(),
+            held_locks: KeyboardModifiers::empty(),
+        }
+    }
+
+    pub fn modifiers(&self) -> KeyboardModifiers {
+        self.modifiers
+    }
+
+    // Send a command byte, or a command's data byte, waiting for the keyboard to acknowledge it.
+    // The keyboard's responses arrive on the data port like scancodes do, so this must run with
+    // interrupts disabled (and read_scancode skips any stray ones).
+    fn send(&self, byte: u8) -> Result<(), Err> {
+        for _ in 0..COMMAND_RETRIES {
+            unsafe {
+                wait_for_status(PS2_STATUS_INPUT_FULL, false)?;
+                self.port.write(byte);
+            }
+            // Scancodes for keys pressed before the command may still be queued ahead of the
+            // response; they're dropped until the keyboard answers or goes quiet
+            loop {
+                let response = unsafe {
+                    wait_for_status(PS2_STATUS_OUTPUT_FULL, true)?;
+                    self.port.read()
+                };
+                match response {
+                    RESPONSE_ACK => return Ok(()),
+                    RESPONSE_RESEND => break,
+                    _ => (),
+                }
+            }
         }
+        Err(Err::Resend)
     }
-    pub fn read_scancode(&mut self) -> Option<(Key, KeyboardModifiers)> {
+
+    fn command(&self, command: u8, data: u8) -> Result<(), Err> {
+        crate::without_interrupt! {{
+            self.send(command)?;
+            self.send(data)
+        }}
+    }
+
+    // Light the keyboard LEDs to match the lock states. Waits on the keyboard with interrupts
+    // disabled, so the interrupt handler leaves it to led_task.
+    pub fn update_leds(&self) -> Result<(), Err> {
+        self.command(COMMAND_SET_LEDS, led_bits(self.modifiers))
+    }
+
+    pub fn set_typematic(&self, delay_ms: u32, rate: u8) -> Result<(), Err> {
+        self.command(COMMAND_SET_TYPEMATIC, typematic_bits(delay_ms, rate))
+    }
+
+    pub fn read_scancode(&mut self) -> Option<KeyEvent> {
         // Shouldn't ever be unsafe to read, but might be junky.
         // If that's not true, move unsafety to caller.
-        let scancode = unsafe { port_read_byte(self.port) };
+        let scancode = unsafe { self.port.read() };
+        self.handle_scancode(scancode)
+    }
+
+    fn handle_scancode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        // Replies to commands, not keys
+        if matches!(scancode, RESPONSE_ACK | RESPONSE_RESEND | RESPONSE_ECHO) {
+            return None;
+        }
         // Top bit is 1 for released, 0 for pressed, rest are keycode
         let released = (scancode >> 7) != 0;
         let keycode = scancode & 0x7F;
@@ -63,8 +224,19 @@ impl<'a> KeyboardState<'a> {
         if !modifier.is_empty() {
             self.modifiers.set(modifier, !released);
         }
+        let lock = lock(key);
+        if !lock.is_empty() {
+            if !released && !self.held_locks.contains(lock) {
+                self.modifiers.toggle(lock);
+                // Only once per batch of toggles, led_task reads the latest state
+                if LEDS_CHANGED.count() == 0 {
+                    LEDS_CHANGED.up();
+                }
+            }
+            self.held_locks.set(lock, !released);
+        }
         match released {
-            true => Some((key, self.modifiers)),
+            true => Some(KeyEvent::new(key, self.modifiers)),
             false => None,
         }
     }
@@ -82,3 +254,104 @@ lazy_static! {
     pub static ref KEYBOARD: Mutex<KeyboardState<'static>> =
         Mutex::new(KeyboardState::new(PS2_KEYBOARD_PORT, &dvorak::MAP));
 }
+
+// Upped by the interrupt handler when a lock key changes the LEDs
+static LEDS_CHANGED: Semaphore = Semaphore::new(0);
+
+fn led_task() {
+    loop {
+        LEDS_CHANGED.down();
+        // Best effort; the lock state is right even if the LEDs aren't
+        let _ = crate::without_interrupt! {{
+            KEYBOARD.lock().update_leds()
+        }};
+    }
+}
+
+pub fn init() {
+    scheduler::spawn_with_priority("keyboard_leds", led_task, HIGH_PRIORITY)
+        .expect("Failed to start the keyboard LED task");
+}
+
+// Hand a key press on to the console, from the keyboard interrupt or inject
+pub(crate) fn dispatch(event: KeyEvent) {
+    if let Some(input) = line::from_key(event.key, event.modifiers) {
+        line::feed(input);
+    }
+}
+
+// Feed a synthetic key press through the same path as real ones, eg. for testing the shell or
+// line editing without a keyboard. Interrupts are disabled while it's handled, as for a real one.
+pub fn inject(event: KeyEvent) {
+    crate::without_interrupt! {{
+        dispatch(event);
+    }}
+}
+
+// Type `text`, one character at a time
+pub fn inject_str(text: &str) {
+    text.chars().map(KeyEvent::character).for_each(inject);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_character_with_caps_lock() {
+        let a = Key::Character('a', 'A');
+        let one = Key::Character('1', '!');
+        let caps = KeyboardModifiers::CAPS_LOCK;
+        let shift = KeyboardModifiers::SHIFT;
+        assert_eq!(character(a, KeyboardModifiers::empty()), Some('a'));
+        assert_eq!(character(a, caps), Some('A'));
+        assert_eq!(character(a, caps | shift), Some('a'));
+        assert_eq!(character(one, caps), Some('1'));
+        assert_eq!(character(one, caps | shift), Some('!'));
+        assert_eq!(character(Key::CapsLock, caps), None);
+    }
+
+    #[test_case]
+    fn test_command_data() {
+        assert_eq!(led_bits(KeyboardModifiers::empty()), 0);
+        assert_eq!(led_bits(KeyboardModifiers::CAPS_LOCK), 0b100);
+        assert_eq!(
+            led_bits(KeyboardModifiers::SCROLL_LOCK | KeyboardModifiers::NUM_LOCK),
+            0b011
+        );
+        assert_eq!(typematic_bits(250, 0), 0);
+        assert_eq!(typematic_bits(500, 31), 0b0111111);
+        assert_eq!(typematic_bits(5000, 100), 0b1111111);
+    }
+
+    #[test_case]
+    fn test_lock_key_defers_leds() {
+        // Caps lock in scancode set 1, pressed then released
+        const CAPS_LOCK: u8 = 0x3A;
+        while LEDS_CHANGED.try_down() {}
+        let mut keyboard = KeyboardState::new(PS2_KEYBOARD_PORT, &dvorak::MAP);
+        keyboard.handle_scancode(CAPS_LOCK);
+        keyboard.handle_scancode(CAPS_LOCK | 0x80);
+        assert!(keyboard.modifiers().contains(KeyboardModifiers::CAPS_LOCK));
+        assert_eq!(LEDS_CHANGED.count(), 1);
+        // Pressing it again before led_task runs doesn't queue another update
+        keyboard.handle_scancode(CAPS_LOCK);
+        assert!(!keyboard.modifiers().contains(KeyboardModifiers::CAPS_LOCK));
+        assert_eq!(LEDS_CHANGED.count(), 1);
+        while LEDS_CHANGED.try_down() {}
+    }
+
+    #[test_case]
+    fn test_injected_keys_reach_the_console() {
+        line::set_mode(line::Mode::Cooked);
+        inject_str("shelp");
+        inject(KeyEvent::new(
+            Key::Character('w', 'W'),
+            KeyboardModifiers::CONTROL,
+        ));
+        inject_str("echo hk");
+        inject(KeyEvent::new(Key::Backspace, KeyboardModifiers::empty()));
+        inject_str("i\n");
+        assert_eq!(line::read_line(), "echo hi");
+    }
+}
@@ -14,81 +14,134 @@
 
 extern crate alloc;
 
+pub mod acpi;
+pub mod arch;
+pub mod backtrace;
+pub mod boot;
 pub mod collections;
+pub mod config;
+pub mod console;
+pub mod cpu;
+pub mod debug;
+pub mod driver;
+pub mod drivers;
+pub mod elf;
+pub mod error;
+pub mod fs;
 pub mod global_descriptor_table;
+#[cfg(feature = "framebuffer")]
+pub mod graphics;
 pub mod interrupt;
+pub mod ipc;
 pub mod keyboard;
 pub mod memory;
+pub mod mmio;
+pub mod panic_screen;
+pub mod panicking;
 pub mod pic8259;
+pub mod power;
+pub mod profile;
+pub mod pstore;
+pub mod selftest;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod symbols;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod testing;
+pub mod time;
+pub mod unwind;
 pub mod vga_buffer;
-
-use core::panic::PanicInfo;
+pub mod watchdog;
 
 use bootloader::BootInfo;
 
+// Boot happens in stages, each depending on the ones before it. Optional subsystems (see the
+// features in Cargo.toml) register within their stage, so leaving one out doesn't reorder the rest.
 pub fn init(boot_info: &'static BootInfo) {
-    memory::init(boot_info);
-    global_descriptor_table::init();
-    interrupt::init();
-    pic8259::init();
-}
-
-const IOBASE_PORT: u16 = 0xF4;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum QemuExitStatus {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub trait Testable {
-    fn run(&self) -> ();
+    init_early(boot_info);
+    let stages: [fn(); 4] = [init_cpu, init_services, init_tasks, init_late];
+    for (_done, stage) in stages.iter().enumerate() {
+        #[cfg(feature = "framebuffer")]
+        graphics::splash::progress(_done + 1, stages.len() + 1);
+        stage();
+    }
+    #[cfg(feature = "framebuffer")]
+    {
+        graphics::splash::finish();
+        graphics::cursor::init(boot::config());
+    }
 }
 
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
+// Output, the boot info and memory: everything after this can print and allocate
+fn init_early(boot_info: &'static BootInfo) {
+    // First, so early_print! works if anything below fails
+    serial::early_init();
+    let config = boot::init(boot_info);
+    console::init(config);
+    // Before anything reads a tunable, starting with the heap size
+    crate::config::init(config.cmdline.as_str());
+    memory::init(config);
+    #[cfg(feature = "framebuffer")]
+    graphics::splash::init(config);
+    // Once there's a heap to format with
+    pstore::init(config);
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) -> ! {
-    serial_println!("Running {} tests", tests.len());
-    tests.iter().for_each(|test| test.run());
-    test_runner_exit(QemuExitStatus::Success);
+// Descriptor tables, CPU features and interrupt handlers
+fn init_cpu() {
+    global_descriptor_table::init();
+    cpu::init();
+    // Before any drivers, some of which need short delays
+    time::delay::calibrate();
+    interrupt::init();
+    #[cfg(feature = "smp")]
+    smp::ipi::init();
 }
 
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    test_runner_exit(QemuExitStatus::Failed);
+// Shell commands, syscalls, tick callbacks and other registrations
+fn init_services() {
+    watchdog::init();
+    shell::init();
+    debug::init();
+    selftest::init();
+    power::init();
+    task::scheduler::init();
+    task::signal::init();
+    sync::futex::init();
+    fs::fd::init();
+    fs::pipe::init();
+    fs::page_cache::init();
+    sync::spin_lock::init();
 }
 
-pub fn test_runner_exit(status: QemuExitStatus) -> ! {
-    // Write status to IOBASE port
-    // exit status will be (status << 1 | 1)
-    unsafe { serial::port_write_byte(IOBASE_PORT, status as u8) };
-    panic!("Test runner failed to exit");
+// Background tasks and drivers
+fn init_tasks() {
+    task::idle::init();
+    task::process::init();
+    fs::mmap::init();
+    memory::scrub::init();
+    driver::init();
+    keyboard::init();
+    // After driver::init, which sets the tick rate they use
+    memory::cache_registry::init();
+    console::status_bar::init();
+    console::task_manager::init();
 }
 
-#[cfg(test)]
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    test_panic_handler(info);
+fn init_late() {
+    memory::protect_kernel();
+    #[cfg(feature = "debug-alloc")]
+    {
+        let report = memory::allocator::validate();
+        assert!(report.is_ok(), "Heap inconsistent after boot: {}", report);
+    }
 }
 
-#[cfg(test)]
-bootloader::entry_point!(test_kernel_main);
+pub use power::QemuExitStatus;
+pub use testing::{test_panic_handler, test_runner, test_runner_exit, Testable};
 
 #[cfg(test)]
-fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
-    init(boot_info);
-    test_main();
-    loop {}
-}
+crate::kernel_test_main!();
@@ -17,8 +17,21 @@ use sos::{print, println};
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    if sos::panicking::enter() == sos::panicking::Nesting::Nested {
+        sos::panicking::emergency(info);
+    }
+    sos::panicking::resume_catch(info);
+    sos::pstore::save_panic(info);
+    // Best effort, we're panicking either way
+    let _ = sos::smp::ipi::broadcast(sos::smp::ipi::IpiKind::HaltForPanic);
+    // Before the panic screen replaces it
+    sos::console::screenshot();
+    if !cfg!(debug_assertions) {
+        sos::panic_screen::show(info);
+    }
     println!("{}", info);
-    loop {}
+    println!("{}", sos::backtrace::Backtrace::capture());
+    sos::power::halt();
 }
 
 #[cfg(test)]
@@ -122,8 +135,19 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
+    match sos::task::process::spawn("/bin/init", &[]) {
+        Ok(init) => println!("init exited with {:?}", sos::task::process::wait(init)),
+        Err(err) => println!("Couldn't start /bin/init: {:?}", err),
+    }
+
     // panic!("Kernel shutdown");
-    loop {}
+    sos::console::line::start_serial_input();
+    sos::task::spawn("shell", sos::shell::task).expect("Failed to start the shell");
+    sos::watchdog::register("kernel_main", 5 * sos::time::ticks_per_second());
+    loop {
+        sos::watchdog::pet("kernel_main");
+        sos::task::idle::idle();
+    }
 }
 
 #[test_case]
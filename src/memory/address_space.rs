@@ -0,0 +1,528 @@
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::page_table::{l1, l2, l3, l4, PageTableFlags};
+use super::{physical_to_virtual, PAGE_ALLOCATOR, PAGE_SIZE};
+
+// How virtual memory is split between user space and the kernel: user space gets the lower half
+// of the canonical address space and the kernel the upper half, so one l4 table can carry both,
+// with the kernel's half shared between every process.
+//
+// The bootloader still puts the kernel image and its stack in the lower half, but everything the
+// kernel allocates for itself comes from the upper half, see PageAllocator::init.
+// TODO: link the kernel into the upper half once we load it ourselves
+//
+// Address spaces for user processes (see task::process) each get their own l4 table: a copy of
+// the kernel's entries, so the kernel runs unchanged on any process's page tables, plus one entry
+// of its own (PROCESS_SLOT) for the process's code, data and stack. Only pages in that slot are
+// user accessible, and they're freed along with the address space, except frames it only borrows
+// (see map_frame).
+//
+// The kernel's entries are copied when an address space is made. The page allocator can add l4
+// entries to the kernel's table later on, which a copy made earlier won't have; the page fault
+// handler copies them over the first time they're touched (see sync_kernel_entry).
+
+// Page 0 stays unmapped so null pointers fault
+pub const USER_START: usize = PAGE_SIZE;
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+pub const KERNEL_START: usize = 0xFFFF_8000_0000_0000;
+// The last l4 entry is kept back, so kernel ranges can be exclusive without overflowing
+pub const KERNEL_END: usize = 0xFFFF_FF80_0000_0000;
+
+const _: () = assert!(USER_START < USER_END && USER_END <= KERNEL_START);
+const _: () = assert!(KERNEL_START < KERNEL_END);
+const _: () = assert!(USER_START % PAGE_SIZE == 0 && KERNEL_END % PAGE_SIZE == 0);
+
+// The kernel's own fixed areas (so far just the heap) sit at offsets from a base picked at boot,
+// see memory::init, rather than at hardcoded addresses: the bootloader maps physical memory
+// wherever it likes, and KASLR will want to move them anyway. The base is always in the kernel
+// half; this is just where it starts out.
+pub const DEFAULT_KERNEL_BASE: usize = KERNEL_START;
+pub const KERNEL_HEAP_OFFSET: usize = 0x4444_0000;
+// Each base gets a whole l4 entry to itself
+pub const KERNEL_BASE_ALIGN: usize = 1 << 39;
+
+static KERNEL_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_KERNEL_BASE);
+
+const _: () = assert!(DEFAULT_KERNEL_BASE % KERNEL_BASE_ALIGN == 0);
+
+pub fn kernel_base() -> usize {
+    KERNEL_BASE.load(Ordering::Relaxed)
+}
+
+// Only before the kernel heap is mapped; everything placed relative to the old base would be lost
+pub(in crate::memory) fn set_kernel_base(base: usize) {
+    assert!(
+        base % KERNEL_BASE_ALIGN == 0,
+        "Kernel base {:#x} isn't l4 aligned",
+        base
+    );
+    KERNEL_BASE.store(base, Ordering::Relaxed);
+}
+
+pub fn kernel_heap_start() -> usize {
+    kernel_base() + KERNEL_HEAP_OFFSET
+}
+
+pub const PROCESS_SLOT: usize = 255;
+const L4_ENTRY_SIZE: usize = 1 << 39;
+// The last slot of the lower half, well clear of what the bootloader maps
+pub const PROCESS_START: usize = PROCESS_SLOT * L4_ENTRY_SIZE;
+pub const PROCESS_END: usize = PROCESS_START + L4_ENTRY_SIZE;
+
+const _: () = assert!(USER_START <= PROCESS_START && PROCESS_END <= USER_END);
+
+// Intermediate tables allow everything; the leaf entries say what the process can actually do
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+// Physical address of the l4 table we booted with, which kernel tasks run on
+static KERNEL_L4: Once<usize> = Once::new();
+// Held by every address space, tables included
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    OutOfMemory,
+    // Outside PROCESS_START..PROCESS_END
+    OutOfRange(usize),
+    NotMapped(usize),
+    // Already mapped to a frame from map_frame, or mapped at all for map_frame
+    AlreadyMapped(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    User,
+    Kernel,
+    // In the hole between the halves (or one of the pages kept back); never mapped
+    Unused,
+}
+
+pub fn region(address: usize) -> Region {
+    if (USER_START..USER_END).contains(&address) {
+        Region::User
+    } else if (KERNEL_START..KERNEL_END).contains(&address) {
+        Region::Kernel
+    } else {
+        Region::Unused
+    }
+}
+
+pub fn is_user_addr(address: usize) -> bool {
+    region(address) == Region::User
+}
+
+pub fn is_kernel_addr(address: usize) -> bool {
+    region(address) == Region::Kernel
+}
+
+// Whether all of [address, address + length) is user space. Empty ranges are, wherever they are.
+pub fn is_user_range(address: usize, length: usize) -> bool {
+    match address.checked_add(length) {
+        _ if length == 0 => true,
+        Some(end) => is_user_addr(address) && end <= USER_END,
+        None => false,
+    }
+}
+
+pub fn is_kernel_range(range: &Range<usize>) -> bool {
+    is_kernel_addr(range.start) && range.end <= KERNEL_END
+}
+
+pub fn kernel_page_table() -> u64 {
+    *KERNEL_L4
+        .get()
+        .expect("Address spaces used before memory::init") as u64
+}
+
+// Frames used by processes, page tables included
+pub fn frames() -> usize {
+    FRAMES.load(Ordering::Relaxed)
+}
+
+fn indices(address: usize) -> [usize; 4] {
+    [
+        (address >> (9 * 3) + 12) & 0x1FF,
+        (address >> (9 * 2) + 12) & 0x1FF,
+        (address >> (9 * 1) + 12) & 0x1FF,
+        (address >> (9 * 0) + 12) & 0x1FF,
+    ]
+}
+
+// Returns the frame's physical address. The page allocator hands out frames already zeroed.
+fn allocate_zeroed_frame() -> Result<usize, Err> {
+    let frame = crate::without_interrupt! {{
+        let frame = PAGE_ALLOCATOR.lock().allocate_frame();
+        frame
+    }};
+    let frame = frame.map_err(|_| Err::OutOfMemory)?.as_mut_ptr() as usize;
+    FRAMES.fetch_add(1, Ordering::Relaxed);
+    Ok(frame)
+}
+
+fn deallocate_frame(frame: usize) {
+    let frame = NonNull::slice_from_raw_parts(NonNull::new(frame as *mut u8).unwrap(), PAGE_SIZE);
+    crate::without_interrupt! {{
+        PAGE_ALLOCATOR.lock().deallocate_frame(frame);
+    }}
+    FRAMES.fetch_sub(1, Ordering::Relaxed);
+}
+
+// Point `entry` at a new empty table, unless it already has one
+macro_rules! ensure_table {
+    ($entry:expr, $level:ident) => {
+        if !$entry.present() {
+            let frame = allocate_zeroed_frame()?;
+            $entry = $level::PageTableEntry::new(frame | TABLE_FLAGS.bits() as usize);
+        }
+    };
+}
+
+pub struct AddressSpace {
+    // Physical address of the l4 table
+    l4: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, Err> {
+        let address_space = AddressSpace {
+            l4: allocate_zeroed_frame()?,
+        };
+        let kernel = unsafe {
+            &*(physical_to_virtual(kernel_page_table() as usize) as *const l4::PageTable)
+        };
+        let table = address_space.table();
+        for (index, entry) in kernel.iter().enumerate() {
+            if index != PROCESS_SLOT {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(address_space)
+    }
+
+    // What to load into CR3 to run in this address space
+    pub fn page_table(&self) -> u64 {
+        self.l4 as u64
+    }
+
+    // Run `f` on this address space's page tables, eg. to make syscalls on a process's behalf
+    // from a kernel test. `f` mustn't block, since the scheduler would switch back to the task's
+    // own page tables.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        crate::without_interrupt! {{
+            let (kernel, flags) = Cr3::read();
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.l4 as u64));
+            unsafe { Cr3::write(frame, flags) };
+            let result = f();
+            unsafe { Cr3::write(kernel, flags) };
+            result
+        }}
+    }
+
+    fn table(&self) -> &'static mut l4::PageTable {
+        unsafe { &mut *(physical_to_virtual(self.l4) as *mut l4::PageTable) }
+    }
+
+    fn check(address: usize) -> Result<usize, Err> {
+        match (PROCESS_START..PROCESS_END).contains(&address) {
+            true => Ok(address),
+            false => Err(Err::OutOfRange(address)),
+        }
+    }
+
+    // The l1 entry for `page`, making the tables on the way to it
+    fn entry(&mut self, page: usize) -> Result<&'static mut l1::PageTableEntry, Err> {
+        let [l4_index, l3_index, l2_index, l1_index] = indices(Self::check(page)?);
+        let l4_table = self.table();
+        ensure_table!(l4_table[l4_index], l4);
+        let l3_table = &mut *l4_table[l4_index];
+        ensure_table!(l3_table[l3_index], l3);
+        let l2_table = &mut *l3_table[l3_index];
+        ensure_table!(l2_table[l2_index], l2);
+        Ok(&mut (*l2_table[l2_index])[l1_index])
+    }
+
+    // Map a zeroed page at `page` with `flags` (PRESENT is implied). If it's already mapped, it
+    // keeps its contents and gets the union of the permissions: writable or executable if either
+    // mapping is.
+    pub fn map(&mut self, page: usize, flags: PageTableFlags) -> Result<(), Err> {
+        let entry = self.entry(page)?;
+        let flags = flags | PageTableFlags::PRESENT;
+        if entry.present() && entry.flags().contains(PageTableFlags::BORROWED) {
+            return Err(Err::AlreadyMapped(page));
+        } else if entry.present() {
+            let old = entry.flags();
+            let mut merged = old | flags;
+            if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            entry.set_flags(merged);
+        } else {
+            let frame = allocate_zeroed_frame()?;
+            *entry = l1::PageTableEntry::new(frame | flags.bits() as usize);
+        }
+        Ok(())
+    }
+
+    // Map `frame`, which belongs to someone else (eg. a page the kernel shares with every
+    // process), at `page` with `flags`. It isn't freed with the address space, so it has to outlive
+    // it, and `page` can't be mapped already.
+    pub fn map_frame(
+        &mut self,
+        page: usize,
+        frame: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), Err> {
+        let entry = self.entry(page)?;
+        if entry.present() {
+            return Err(Err::AlreadyMapped(page));
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::BORROWED;
+        *entry = l1::PageTableEntry::new(frame | flags.bits() as usize);
+        Ok(())
+    }
+
+    // Physical address of the frame mapped at `page`
+    fn frame(&self, page: usize) -> Result<usize, Err> {
+        let [l4_index, l3_index, l2_index, l1_index] = indices(Self::check(page)?);
+        let not_mapped = |_| Err::NotMapped(page);
+        let l3_table = self.table()[l4_index].try_deref_mut().map_err(not_mapped)?;
+        let l2_table = l3_table[l3_index].try_deref_mut().map_err(not_mapped)?;
+        let l1_table = l2_table[l2_index].try_deref_mut().map_err(not_mapped)?;
+        let entry = &l1_table[l1_index];
+        match entry.present() {
+            true => Ok(entry.pointer()),
+            false => Err(Err::NotMapped(page)),
+        }
+    }
+
+    // Copy `data` to `address`, which must already be mapped. The address space needn't be the
+    // one we're running on; the copy goes through the physical memory mapping.
+    pub fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Err> {
+        let mut written = 0;
+        while written < data.len() {
+            let address = address + written;
+            let offset = address % PAGE_SIZE;
+            let count = (PAGE_SIZE - offset).min(data.len() - written);
+            let frame = self.frame(address - offset)?;
+            let destination = physical_to_virtual(frame + offset) as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(data[written..].as_ptr(), destination, count) };
+            written += count;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees the process slot's pages and tables; the kernel's entries are only borrowed, as are
+    // frames from map_frame
+    fn drop(&mut self) {
+        let owned = |entry: &&l1::PageTableEntry| {
+            entry.present() && !entry.flags().contains(PageTableFlags::BORROWED)
+        };
+        let slot = &self.table()[PROCESS_SLOT];
+        if let Ok(l3_table) = slot.deref() {
+            for l3_entry in l3_table.iter().filter(|entry| entry.present()) {
+                for l2_entry in l3_entry.iter().filter(|entry| entry.present()) {
+                    for l1_entry in l2_entry.iter().filter(owned) {
+                        deallocate_frame(l1_entry.pointer());
+                    }
+                    deallocate_frame(l2_entry.pointer());
+                }
+                deallocate_frame(l3_entry.pointer());
+            }
+            deallocate_frame(slot.pointer());
+        }
+        deallocate_frame(self.l4);
+    }
+}
+
+// If `address` faulted because the current page tables are missing one of the kernel's l4 entries,
+// added since they were copied, copy it over and return true so the access can be retried
+pub fn sync_kernel_entry(address: usize) -> bool {
+    let index = indices(address)[0];
+    let kernel_l4 = match KERNEL_L4.get() {
+        Some(&kernel_l4) if index != PROCESS_SLOT => kernel_l4,
+        _ => return false,
+    };
+    let kernel = unsafe { &*(physical_to_virtual(kernel_l4) as *const l4::PageTable) };
+    let current = unsafe { l4::PageTable::get() };
+    if current[index].present() || !kernel[index].present() {
+        return false;
+    }
+    current[index] = kernel[index].clone();
+    true
+}
+
+pub(super) fn init() {
+    let l4 = Cr3::read().0.start_address().as_u64() as usize;
+    let table = unsafe { &*(physical_to_virtual(l4) as *const l4::PageTable) };
+    assert!(
+        !table[PROCESS_SLOT].present(),
+        "The bootloader mapped {:#x}, where processes go",
+        PROCESS_START
+    );
+    KERNEL_L4.call_once(|| l4);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_map_and_write() {
+        let mut address_space = AddressSpace::new().unwrap();
+        let page = PROCESS_START + 5 * PAGE_SIZE;
+        let user = PageTableFlags::USER_ACCESSIBLE;
+        address_space
+            .map(page, user | PageTableFlags::NO_EXECUTE)
+            .unwrap();
+        address_space.map(page + PAGE_SIZE, user).unwrap();
+        // Straddling the two pages
+        address_space.write(page + PAGE_SIZE - 2, b"abcd").unwrap();
+        let frame = address_space.frame(page).unwrap();
+        let bytes = unsafe { &*(physical_to_virtual(frame) as *const [u8; PAGE_SIZE]) };
+        assert_eq!(&bytes[PAGE_SIZE - 2..], b"ab");
+        assert_eq!(bytes[0], 0);
+        // Mapping it again keeps the data, and takes the union of the permissions
+        address_space
+            .map(page, user | PageTableFlags::WRITABLE)
+            .unwrap();
+        assert_eq!(&bytes[PAGE_SIZE - 2..], b"ab");
+        let [_, l3_index, l2_index, l1_index] = indices(page);
+        let entry = &address_space.table()[PROCESS_SLOT][l3_index][l2_index][l1_index];
+        assert_eq!(
+            entry.flags() & (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+            PageTableFlags::WRITABLE
+        );
+        assert_eq!(
+            address_space.write(page + 2 * PAGE_SIZE, b"x"),
+            Err(Err::NotMapped(page + 2 * PAGE_SIZE))
+        );
+        assert_eq!(
+            address_space.map(0x1000, user),
+            Err(Err::OutOfRange(0x1000))
+        );
+    }
+
+    #[test_case]
+    fn test_drop_frees_frames() {
+        let before = frames();
+        let mut address_space = AddressSpace::new().unwrap();
+        for page in (PROCESS_START..PROCESS_START + 4 * PAGE_SIZE).step_by(PAGE_SIZE) {
+            address_space
+                .map(page, PageTableFlags::USER_ACCESSIBLE)
+                .unwrap();
+        }
+        // The l4 table, 3 intermediate tables and the pages
+        assert_eq!(frames() - before, 8);
+        drop(address_space);
+        assert_eq!(frames(), before);
+    }
+
+    #[test_case]
+    fn test_map_frame() {
+        let frame = crate::memory::allocate_frame().unwrap();
+        unsafe { *(physical_to_virtual(frame) as *mut u8) = 42 };
+        let before = frames();
+        let mut address_space = AddressSpace::new().unwrap();
+        let user = PageTableFlags::USER_ACCESSIBLE;
+        address_space.map_frame(PROCESS_START, frame, user).unwrap();
+        assert_eq!(address_space.frame(PROCESS_START), Ok(frame));
+        let read = address_space.enter(|| unsafe { *(PROCESS_START as *const u8) });
+        assert_eq!(read, 42);
+        // Neither mapping can take over the other's page
+        assert_eq!(
+            address_space.map(PROCESS_START, user | PageTableFlags::WRITABLE),
+            Err(Err::AlreadyMapped(PROCESS_START))
+        );
+        address_space.map(PROCESS_START + PAGE_SIZE, user).unwrap();
+        assert_eq!(
+            address_space.map_frame(PROCESS_START + PAGE_SIZE, frame, user),
+            Err(Err::AlreadyMapped(PROCESS_START + PAGE_SIZE))
+        );
+        // Only the tables and the page from map count, and only they are freed
+        assert_eq!(frames() - before, 5);
+        drop(address_space);
+        assert_eq!(frames(), before);
+        assert_eq!(unsafe { *(physical_to_virtual(frame) as *const u8) }, 42);
+        crate::memory::deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn test_enter() {
+        let mut address_space = AddressSpace::new().unwrap();
+        let user = PageTableFlags::USER_ACCESSIBLE;
+        address_space.map(PROCESS_START, user).unwrap();
+        address_space.write(PROCESS_START, b"mine").unwrap();
+        let read = address_space.enter(|| unsafe { *(PROCESS_START as *const [u8; 4]) });
+        assert_eq!(&read, b"mine");
+        assert_eq!(Cr3::read().0.start_address().as_u64(), kernel_page_table());
+    }
+
+    #[test_case]
+    fn test_kernel_shared() {
+        let address_space = AddressSpace::new().unwrap();
+        let kernel = unsafe {
+            &*(physical_to_virtual(kernel_page_table() as usize) as *const l4::PageTable)
+        };
+        for index in (0..512).filter(|&index| index != PROCESS_SLOT) {
+            assert_eq!(
+                address_space.table()[index].pointer(),
+                kernel[index].pointer()
+            );
+        }
+        assert!(!address_space.table()[PROCESS_SLOT].present());
+    }
+
+    #[test_case]
+    fn test_kernel_entries_added_later() {
+        let page = crate::memory::reserve(PAGE_SIZE).unwrap().start;
+        let frame = crate::memory::allocate_frame().unwrap();
+        unsafe { *(physical_to_virtual(frame) as *mut u8) = 42 };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        crate::memory::map_frame(page, frame, flags).unwrap();
+        // As if the kernel had mapped the page's l4 entry after the address space was made
+        let address_space = AddressSpace::new().unwrap();
+        let index = indices(page)[0];
+        address_space.table()[index] = l4::PageTableEntry::new(0);
+        let read = address_space.enter(|| unsafe { core::ptr::read_volatile(page as *const u8) });
+        assert_eq!(read, 42);
+        assert!(address_space.table()[index].present());
+        crate::memory::unmap_frame(page);
+        crate::memory::release(page..page + PAGE_SIZE);
+        crate::memory::deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn test_address_regions() {
+        assert_eq!(region(0), Region::Unused);
+        assert_eq!(region(0x40_0000), Region::User);
+        assert_eq!(region(USER_END - 1), Region::User);
+        assert_eq!(region(USER_END), Region::Unused);
+        assert_eq!(region(KERNEL_START), Region::Kernel);
+        assert_eq!(region(usize::MAX), Region::Unused);
+        assert!(is_user_range(USER_END - 8, 8));
+        assert!(!is_user_range(USER_END - 8, 16));
+        assert!(!is_user_range(usize::MAX - 8, 16));
+        assert!(is_user_range(usize::MAX, 0));
+    }
+
+    #[test_case]
+    fn test_kernel_heap_follows_base() {
+        assert_eq!(kernel_heap_start(), kernel_base() + KERNEL_HEAP_OFFSET);
+        assert_eq!(kernel_base() % KERNEL_BASE_ALIGN, 0);
+        assert!(is_kernel_range(
+            &(kernel_base()..kernel_base() + KERNEL_BASE_ALIGN)
+        ));
+        assert!(crate::memory::allocator::kernel_heap().contains(&kernel_heap_start()));
+    }
+}
@@ -0,0 +1,262 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use spin::Mutex;
+
+use super::call_site::{self, CALLER_DEPTH};
+use crate::backtrace;
+use crate::serial_println;
+use crate::symbols::Symbolized;
+
+// Heap usage by allocation site, for hunting leaks. Enabled with `--features alloc_track`.
+//
+// The global allocator records every live allocation along with its callers in a fixed size
+// hash table (which can't itself use the heap), and `report` attributes live bytes to the first
+// caller outside of the allocation machinery.
+
+const MAX_TRACKED: usize = 1024;
+const MAX_SITES: usize = 64;
+const REPORT_ROWS: usize = 20;
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    address: usize,
+    size: usize,
+    callers: [u64; CALLER_DEPTH],
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Empty,
+    Deleted,
+    Used(Allocation),
+}
+
+// Open addressing with linear probing, keyed by address
+struct Table {
+    slots: [Slot; MAX_TRACKED],
+    // Allocations we had no room to record
+    untracked: usize,
+}
+
+impl Table {
+    fn probe(address: usize) -> impl Iterator<Item = usize> {
+        let hash = (address >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (0..MAX_TRACKED).map(move |i| hash.wrapping_add(i) % MAX_TRACKED)
+    }
+
+    fn insert(&mut self, allocation: Allocation) {
+        let slots = &self.slots;
+        match Self::probe(allocation.address).find(|&i| !matches!(slots[i], Slot::Used(_))) {
+            Some(i) => self.slots[i] = Slot::Used(allocation),
+            None => self.untracked += 1,
+        }
+    }
+
+    fn remove(&mut self, address: usize) {
+        for i in Self::probe(address) {
+            match self.slots[i] {
+                Slot::Empty => return,
+                Slot::Used(allocation) if allocation.address == address => {
+                    self.slots[i] = Slot::Deleted;
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn find(&self, address: usize) -> Option<&Allocation> {
+        for i in Self::probe(address) {
+            match &self.slots[i] {
+                Slot::Empty => return None,
+                Slot::Used(allocation) if allocation.address == address => return Some(allocation),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    fn allocations(&self) -> impl Iterator<Item = &Allocation> {
+        self.slots.iter().filter_map(|slot| match slot {
+            Slot::Used(allocation) => Some(allocation),
+            _ => None,
+        })
+    }
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    slots: [Slot::Empty; MAX_TRACKED],
+    untracked: 0,
+});
+
+// Wraps the real global allocator, recording allocations as they pass through
+pub struct TrackingAllocator<A: GlobalAlloc + 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc + 'static> TrackingAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        TrackingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let mut callers = [0; CALLER_DEPTH];
+            backtrace::capture_into(&mut callers);
+            crate::without_interrupt! {{
+                TABLE.lock().insert(Allocation {
+                    address: ptr as usize,
+                    size: layout.size(),
+                    callers,
+                });
+            }}
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        crate::without_interrupt! {{
+            TABLE.lock().remove(ptr as usize);
+        }}
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+fn allocation_site(allocation: &Allocation) -> u64 {
+    call_site::site(&allocation.callers)
+}
+
+// Where a live allocation was made, if it's tracked
+pub fn site_of(address: usize) -> Option<u64> {
+    crate::without_interrupt! {{
+        TABLE.lock().find(address).map(allocation_site)
+    }}
+}
+
+// Every tracked live allocation's address, size and site, or None if the table is locked (eg.
+// when validating from inside the allocator)
+pub fn try_for_each(mut f: impl FnMut(usize, usize, u64)) -> Option<()> {
+    crate::without_interrupt! {{
+        let table = TABLE.try_lock()?;
+        for allocation in table.allocations() {
+            f(allocation.address, allocation.size, allocation_site(allocation));
+        }
+        Some(())
+    }}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    pub address: u64,
+    pub allocations: usize,
+    pub live_bytes: usize,
+}
+
+pub struct Sites {
+    // Sorted by live bytes, largest first
+    sites: [Option<Site>; MAX_SITES],
+    pub allocations: usize,
+    pub live_bytes: usize,
+    pub untracked: usize,
+    // Allocations whose site didn't fit in `sites`
+    pub other_bytes: usize,
+}
+
+impl Sites {
+    pub fn iter(&self) -> impl Iterator<Item = &Site> {
+        self.sites.iter().flatten()
+    }
+}
+
+// Live allocations aggregated by allocation site. Doesn't allocate, so it's safe to call with
+// the heap in any state (and doesn't perturb what it's measuring).
+pub fn sites() -> Sites {
+    let mut sites = Sites {
+        sites: [None; MAX_SITES],
+        allocations: 0,
+        live_bytes: 0,
+        untracked: 0,
+        other_bytes: 0,
+    };
+    crate::without_interrupt! {{
+        let table = TABLE.lock();
+        sites.untracked = table.untracked;
+        for allocation in table.allocations() {
+            sites.allocations += 1;
+            sites.live_bytes += allocation.size;
+            let address = allocation_site(allocation);
+            let slot = sites
+                .sites
+                .iter()
+                .position(|site| matches!(site, Some(site) if site.address == address))
+                .or_else(|| sites.sites.iter().position(|site| site.is_none()));
+            match slot {
+                Some(i) => {
+                    let site = sites.sites[i].get_or_insert(Site {
+                        address,
+                        allocations: 0,
+                        live_bytes: 0,
+                    });
+                    site.allocations += 1;
+                    site.live_bytes += allocation.size;
+                }
+                None => sites.other_bytes += allocation.size,
+            }
+        }
+    }}
+    sites
+        .sites
+        .sort_unstable_by_key(|site| core::cmp::Reverse(site.map_or(0, |site| site.live_bytes)));
+    sites
+}
+
+// Print the allocation sites holding the most live heap memory
+pub fn report() {
+    let sites = sites();
+    serial_println!(
+        "alloc_track: {} live allocations, {} bytes ({} untracked)",
+        sites.allocations,
+        sites.live_bytes,
+        sites.untracked
+    );
+    for site in sites.iter().take(REPORT_ROWS) {
+        serial_println!(
+            "{:>10} bytes {:>6} allocs  {}",
+            site.live_bytes,
+            site.allocations,
+            Symbolized(site.address)
+        );
+    }
+    if sites.other_bytes > 0 {
+        serial_println!("{:>10} bytes in other sites", sites.other_bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    fn live_bytes_here() -> usize {
+        let here = test_allocation_tracked as usize as u64;
+        sites()
+            .iter()
+            .filter(|site| {
+                crate::symbols::lookup(site.address).map_or(false, |s| s.address == here)
+            })
+            .map(|site| site.live_bytes)
+            .sum()
+    }
+
+    #[test_case]
+    fn test_allocation_tracked() {
+        let before = live_bytes_here();
+        let boxed = Box::new([0u8; 100]);
+        assert_eq!(live_bytes_here(), before + 100);
+        drop(boxed);
+        assert_eq!(live_bytes_here(), before);
+    }
+}
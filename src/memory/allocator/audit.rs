@@ -0,0 +1,28 @@
+use alloc::alloc::Layout;
+
+use super::call_site::{self, CALLER_DEPTH};
+use crate::backtrace;
+use crate::interrupt::{self, context};
+use crate::symbols::Symbolized;
+
+// Allocating from an interrupt handler. Enabled with `--features alloc_audit`.
+//
+// The code a handler interrupted may be holding the heap's lock, and then the handler spins on
+// it forever. That only happens when the timing is just wrong, so an audit build panics on every
+// allocation or free from a handler, naming the caller, rather than waiting for the deadlock.
+
+#[inline(always)]
+pub fn check(what: &str, layout: Layout) {
+    // Handlers run with interrupts disabled, so the flag only needs reading when they are
+    if interrupt::are_interrupts_enabled() || !context::in_interrupt() {
+        return;
+    }
+    let mut callers = [0; CALLER_DEPTH];
+    backtrace::capture_into(&mut callers);
+    panic!(
+        "{} of {} bytes in interrupt context, from {}",
+        what,
+        layout.size(),
+        Symbolized(call_site::site(&callers))
+    );
+}
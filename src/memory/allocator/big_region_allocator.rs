@@ -18,7 +18,10 @@ unsafe impl MutAllocator for BigRegionAllocator {
         PAGE_ALLOCATOR
             .lock()
             .allocate(layout.size())
-            .or(Err(AllocError))
+            .map_err(|failure| {
+                super::record_failure(failure);
+                AllocError
+            })
     }
 
     unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
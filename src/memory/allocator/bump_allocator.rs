@@ -3,6 +3,7 @@ use core::alloc::AllocError;
 use core::ptr::NonNull;
 
 use super::bootstrap_allocator::MutAllocator;
+use super::validate::{Issue, Report};
 
 // TODO this should go somewhere better
 // Assumes that align is a power of 2
@@ -27,9 +28,36 @@ impl BumpAllocator {
         }
     }
 
+    // Point an allocator with nothing allocated at a different (mapped) range
+    pub unsafe fn set_heap(&mut self, start: usize, size: usize) {
+        assert_eq!(self.allocations, 0, "Moving a heap with live allocations");
+        *self = Self::new(start, size);
+    }
+
     pub fn upper_bound(&self) -> usize {
         self.heap_start + self.heap_size
     }
+
+    pub fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    pub fn heap_range(&self) -> core::ops::Range<usize> {
+        self.heap_start..self.upper_bound()
+    }
+
+    pub fn validate(&self, report: &mut Report) {
+        if self.next < self.heap_start || self.next > self.upper_bound() {
+            report.push(Issue::HeapPointerOutOfBounds {
+                next: self.next,
+                heap: self.heap_range(),
+            });
+        }
+    }
 }
 
 unsafe impl MutAllocator for BumpAllocator {
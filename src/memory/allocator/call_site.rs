@@ -0,0 +1,37 @@
+use crate::symbols;
+
+// Who an allocation is for: the first return address on the stack that isn't part of making the
+// allocation. Shared by alloc_track and audit.
+
+// Deep enough to get out of Box/Vec/RawVec/__rust_alloc in unoptimized builds
+pub const CALLER_DEPTH: usize = 8;
+
+// Functions in these modules are part of making an allocation, not a reason for one
+const MACHINERY_PREFIXES: [&str; 4] = ["alloc::", "core::", "__rust", "__rg_"];
+// Neither are implementations of the allocator traits, eg. our own global allocator
+const MACHINERY_TRAITS: &str = " as core::alloc::";
+
+fn is_machinery(address: u64) -> bool {
+    match symbols::resolve(address) {
+        Some(name) => {
+            // Trait impls look like `<alloc::vec::Vec<T> as ...>::method`
+            let name = name.trim_start_matches('<');
+            name.contains(MACHINERY_TRAITS)
+                || MACHINERY_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+        }
+        None => false,
+    }
+}
+
+// The first caller that isn't part of the allocator itself, from return addresses captured with
+// backtrace::capture_into
+pub fn site(callers: &[u64]) -> u64 {
+    let callers = callers.iter().copied().filter(|&c| c != 0);
+    callers
+        .clone()
+        .find(|&caller| !is_machinery(caller))
+        .or_else(|| callers.last())
+        .unwrap_or(0)
+}
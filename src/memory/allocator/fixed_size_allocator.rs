@@ -1,3 +1,4 @@
+use crate::memory::allocator::validate::{Issue, Report};
 use crate::{collections::DoublyLinkedList, memory::allocator::bootstrap_allocator::MutAllocator};
 use core::{
     alloc::{AllocError, Allocator, Layout},
@@ -168,6 +169,20 @@ impl<const S: usize, PA: Allocator + Clone, SA: Allocator + Clone> SlabAllocator
         result
     }
 
+    // Slabs on the full list should have every bit set, and slabs on the available list shouldn't
+    pub fn validate(&self, report: &mut Report) {
+        let lists = [(&self.available, false), (&self.full, true)];
+        for (list, full) in lists {
+            for slab in list.iter().filter(|slab| slab.full() != full) {
+                report.push(Issue::SlabBitmapMismatch {
+                    slab: slab.data as *mut u8 as usize,
+                    full,
+                    allocated_bits: slab.allocated_bit_indices,
+                });
+            }
+        }
+    }
+
     pub fn deallocate(&mut self, ptr: NonNull<[u8; S]>) {
         // Need to be able to map ptr back to its slab somehow, but I don't know how :(
         // Ideas:
@@ -0,0 +1,60 @@
+use crate::memory::{physical_to_virtual, PAGE_SIZE};
+
+// How many pages map each physical frame, so that a frame shared between mappings (copy-on-write,
+// shared memory, the page cache) only goes back to the allocator once the last of them is gone.
+// One u16 per frame, indexed by frame number. The table lives in frames taken straight from pmem,
+// since the heap can't grow while the page allocator is locked.
+
+pub struct FrameRefCount {
+    counts: &'static mut [u16],
+}
+
+impl FrameRefCount {
+    pub fn new() -> Self {
+        FrameRefCount { counts: &mut [] }
+    }
+
+    // Bytes of table needed to count `frames` frames
+    pub fn table_size(frames: usize) -> usize {
+        frames * core::mem::size_of::<u16>()
+    }
+
+    // Safety: `table` must be the physical address of table_size(frames) bytes nothing else uses
+    pub unsafe fn init(&mut self, table: usize, frames: usize) {
+        let counts = physical_to_virtual(table) as *mut u16;
+        counts.write_bytes(0, frames);
+        self.counts = core::slice::from_raw_parts_mut(counts, frames);
+    }
+
+    fn index(&self, frame: usize) -> usize {
+        let index = frame / PAGE_SIZE;
+        assert!(
+            index < self.counts.len(),
+            "Frame {:#x} is past the end of physical memory",
+            frame
+        );
+        index
+    }
+
+    pub fn get(&self, frame: usize) -> u16 {
+        self.counts[self.index(frame)]
+    }
+
+    // Returns the new count
+    pub fn increment(&mut self, frame: usize) -> u16 {
+        let index = self.index(frame);
+        self.counts[index] = self.counts[index]
+            .checked_add(1)
+            .unwrap_or_else(|| panic!("Too many mappings of frame {:#x}", frame));
+        self.counts[index]
+    }
+
+    // Returns the new count; the frame is free once it's zero
+    pub fn decrement(&mut self, frame: usize) -> u16 {
+        let index = self.index(frame);
+        self.counts[index] = self.counts[index]
+            .checked_sub(1)
+            .unwrap_or_else(|| panic!("Unmapped frame {:#x} more times than it was mapped", frame));
+        self.counts[index]
+    }
+}
@@ -0,0 +1,95 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// How many heap allocations of each size the kernel makes, in power of two buckets: the data for
+// choosing MetaAllocator's size classes. Counted on every allocation since boot, see
+// `cat /proc/alloc_sizes`. With alloc_redzone the sizes include the guard bytes.
+
+// Bucket i counts sizes above 2^(i-1), up to and including 2^i (and bucket 0 sizes 0 and 1)
+const BUCKETS: usize = usize::BITS as usize + 1;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; BUCKETS] = [ZERO; BUCKETS];
+
+fn bucket(size: usize) -> usize {
+    match size {
+        0 | 1 => 0,
+        size => (usize::BITS - (size - 1).leading_zeros()) as usize,
+    }
+}
+
+#[inline]
+pub fn record(size: usize) {
+    COUNTS[bucket(size)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+}
+
+impl Histogram {
+    // Allocations of at most `size` bytes, for the bucket `size` falls in
+    pub fn count(&self, size: usize) -> u64 {
+        self.counts[bucket(size)]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+pub fn histogram() -> Histogram {
+    Histogram {
+        counts: COUNTS.each_ref().map(|count| count.load(Ordering::Relaxed)),
+    }
+}
+
+// Only the buckets anything fell into
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().max(1);
+        write!(
+            f,
+            "{:>12} {:>12} {:>8}",
+            "size <=", "allocations", "percent"
+        )?;
+        for (i, &count) in self.counts.iter().enumerate() {
+            if count > 0 {
+                let size = 1u128 << i;
+                write!(
+                    f,
+                    "\n{:>12} {:>12} {:>7}%",
+                    size,
+                    count,
+                    count * 100 / total
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[test_case]
+    fn test_buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(4), 2);
+        assert_eq!(bucket(4097), 13);
+        assert_eq!(bucket(usize::MAX), BUCKETS - 1);
+    }
+
+    #[test_case]
+    fn test_allocations_counted() {
+        let before = histogram().count(3000);
+        let boxed = Box::new([0u8; 3000]);
+        assert!(histogram().count(3000) > before);
+        drop(boxed);
+    }
+}
@@ -0,0 +1,280 @@
+use core::alloc::{AllocError, Allocator, Layout};
+use core::fmt;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::memory::cache_registry::Cache;
+use crate::smp::{self, MAX_CPUS};
+
+// Per-CPU caches of free objects for one size class, in front of a shared allocator (eg. a slab)
+// so that most allocations and frees never touch its lock.
+//
+// Each CPU has a "magazine" of recently freed objects. Allocations pop from it and frees push to
+// it; only when it runs empty (or full) do we go to the backing allocator, and then for a batch
+// of objects at a time.
+
+const MAGAZINE_SIZE: usize = 32;
+const BATCH_SIZE: usize = MAGAZINE_SIZE / 2;
+
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+// Only ever touched by the CPU it belongs to, with interrupts disabled
+unsafe impl Send for Magazine {}
+
+const EMPTY_MAGAZINE: Mutex<Magazine> = Mutex::new(Magazine {
+    objects: [null_mut(); MAGAZINE_SIZE],
+    count: 0,
+});
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Batches moved between the magazines and the backing allocator
+    pub refills: u64,
+    pub flushes: u64,
+}
+
+impl CacheStats {
+    pub fn hit_percent(&self) -> u64 {
+        match self.hits + self.misses {
+            0 => 0,
+            total => self.hits * 100 / total,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}% hit rate ({} hits, {} misses), {} refills, {} flushes",
+            self.hit_percent(),
+            self.hits,
+            self.misses,
+            self.refills,
+            self.flushes
+        )
+    }
+}
+
+pub struct MagazineCache<A: Allocator> {
+    // Every object in the cache has this layout
+    layout: Layout,
+    magazines: [Mutex<Magazine>; MAX_CPUS],
+    backing: A,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    refills: AtomicU64,
+    flushes: AtomicU64,
+}
+
+impl<A: Allocator> MagazineCache<A> {
+    pub const fn new(layout: Layout, backing: A) -> Self {
+        MagazineCache {
+            layout,
+            magazines: [EMPTY_MAGAZINE; MAX_CPUS],
+            backing,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            refills: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    fn fits(&self, layout: Layout) -> bool {
+        layout.size() <= self.layout.size() && layout.align() <= self.layout.align()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            refills: self.refills.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+        }
+    }
+
+    // Return every cached object to the backing allocator
+    pub fn drain(&self) -> usize {
+        self.magazines
+            .iter()
+            .map(|magazine| crate::without_interrupt! {{ self.empty(&mut magazine.lock()) }})
+            .sum()
+    }
+
+    // Return a magazine's objects to the backing allocator, returning how many bytes that was
+    fn empty(&self, magazine: &mut Magazine) -> usize {
+        let drained = magazine.count;
+        while magazine.count > 0 {
+            magazine.count -= 1;
+            let object = magazine.objects[magazine.count];
+            unsafe {
+                self.backing
+                    .deallocate(NonNull::new_unchecked(object), self.layout)
+            };
+        }
+        drained * self.layout.size()
+    }
+
+    // Top up an empty magazine from the backing allocator. Returns false if it couldn't get any.
+    fn refill(&self, magazine: &mut Magazine) -> bool {
+        self.refills.fetch_add(1, Ordering::Relaxed);
+        while magazine.count < BATCH_SIZE {
+            match self.backing.allocate(self.layout) {
+                Ok(object) => {
+                    magazine.objects[magazine.count] = object.as_mut_ptr();
+                    magazine.count += 1;
+                }
+                Err(AllocError) => break,
+            }
+        }
+        magazine.count > 0
+    }
+
+    // Send half of a full magazine back to the backing allocator
+    fn flush(&self, magazine: &mut Magazine) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        for _ in 0..BATCH_SIZE {
+            magazine.count -= 1;
+            let object = magazine.objects[magazine.count];
+            unsafe {
+                self.backing
+                    .deallocate(NonNull::new_unchecked(object), self.layout)
+            };
+        }
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for MagazineCache<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.fits(layout) {
+            return self.backing.allocate(layout);
+        }
+        let object = crate::without_interrupt! {{
+            let mut magazine = self.magazines[smp::current_cpu() as usize].lock();
+            if magazine.count > 0 {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                if !self.refill(&mut magazine) {
+                    return Err(AllocError);
+                }
+            }
+            magazine.count -= 1;
+            magazine.objects[magazine.count]
+        }};
+        let object = core::ptr::slice_from_raw_parts_mut(object, self.layout.size());
+        Ok(unsafe { NonNull::new_unchecked(object) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if !self.fits(layout) {
+            return self.backing.deallocate(ptr, layout);
+        }
+        crate::without_interrupt! {{
+            let mut magazine = self.magazines[smp::current_cpu() as usize].lock();
+            if magazine.count == MAGAZINE_SIZE {
+                self.flush(&mut magazine);
+            }
+            magazine.objects[magazine.count] = ptr.as_ptr();
+            magazine.count += 1;
+        }}
+    }
+}
+
+// Whatever's cached, regardless of the target: magazines are small
+impl<A: Allocator + Sync> Cache for MagazineCache<A> {
+    fn size(&self) -> usize {
+        let cached: usize = self
+            .magazines
+            .iter()
+            .map(|magazine| crate::without_interrupt! {{ magazine.lock().count }})
+            .sum();
+        cached * self.layout.size()
+    }
+
+    fn hits(&self) -> Option<(u64, u64)> {
+        let stats = self.stats();
+        Some((stats.hits, stats.misses))
+    }
+
+    // Magazines in use are skipped, since we may be reclaiming for their own refill
+    fn shrink(&self, _target_bytes: usize) -> usize {
+        self.magazines
+            .iter()
+            .map(|magazine| {
+                crate::without_interrupt! {{
+                    let drained = match magazine.try_lock() {
+                        Some(mut magazine) => self.empty(&mut magazine),
+                        None => 0,
+                    };
+                    drained
+                }}
+            })
+            .sum()
+    }
+}
+
+impl<A: Allocator> Drop for MagazineCache<A> {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::alloc::Global;
+
+    #[test_case]
+    fn test_freed_objects_are_reused() {
+        let cache = MagazineCache::new(Layout::new::<[u64; 4]>(), Global);
+        let first = cache.allocate(Layout::new::<[u64; 4]>()).unwrap();
+        assert_eq!(cache.stats().misses, 1);
+        unsafe { cache.deallocate(first.as_non_null_ptr(), Layout::new::<[u64; 4]>()) };
+        let second = cache.allocate(Layout::new::<u64>()).unwrap();
+        // Last in, first out
+        assert_eq!(first.as_mut_ptr(), second.as_mut_ptr());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.refills), (1, 1, 1));
+        unsafe { cache.deallocate(second.as_non_null_ptr(), Layout::new::<u64>()) };
+    }
+
+    #[test_case]
+    fn test_full_magazine_flushes_batch() {
+        let layout = Layout::new::<u64>();
+        let cache = MagazineCache::new(layout, Global);
+        let mut objects = [NonNull::dangling(); MAGAZINE_SIZE + 1];
+        for object in objects.iter_mut() {
+            *object = cache.allocate(layout).unwrap().as_non_null_ptr();
+        }
+        for object in objects {
+            unsafe { cache.deallocate(object, layout) };
+        }
+        // The last refill left some cached, so we filled up part way through freeing
+        assert_eq!(cache.stats().flushes, 1);
+        assert_eq!(cache.drain(), MAGAZINE_SIZE * layout.size());
+    }
+
+    #[test_case]
+    fn test_shrink_skips_busy_magazine() {
+        let layout = Layout::new::<u64>();
+        let cache = MagazineCache::new(layout, Global);
+        let object = cache.allocate(layout).unwrap();
+        unsafe { cache.deallocate(object.as_non_null_ptr(), layout) };
+        let cached = Cache::size(&cache);
+        assert!(cached > 0);
+        crate::without_interrupt! {{
+            let _busy = cache.magazines[smp::current_cpu() as usize].lock();
+            assert_eq!(Cache::shrink(&cache, usize::MAX), 0);
+        }}
+        assert_eq!(Cache::shrink(&cache, usize::MAX), cached);
+        assert_eq!(Cache::size(&cache), 0);
+    }
+}
@@ -1,4 +1,4 @@
-use core::{alloc::Allocator, ptr::NonNull};
+use core::{alloc::Allocator, ops::Range, ptr::NonNull};
 
 use hashbrown::HashMap;
 
@@ -15,7 +15,10 @@ use super::{
 // - Probably unreasonable to allow more than one?
 pub struct MetaAllocator<A: Allocator + Clone> {
     // fixed_size_allocators: [dyn FixedSizeAllocator; _];
-    fixed_size_lookup_table: [NonNull<dyn Allocator>; 512],
+    // Each size class should be a magazine::MagazineCache in front of its shared slab allocator,
+    // so most small allocations don't contend on the slab's lock
+    // None routes that size to big_region_allocator
+    fixed_size_lookup_table: [Option<NonNull<dyn Allocator>>; 512],
     big_region_allocator: Locked<BigRegionAllocator>,
     // TODO: allocator for blocks between say 512 and 4096 bytes
 
@@ -27,31 +30,76 @@ pub struct MetaAllocator<A: Allocator + Clone> {
     // backup_allocator: A
 
     // pointers to allocators for a given memory region
-    // - keys are vmem pointers >> 20, in other words 1 pointer per l1 page (2MB of vmem)
+    // - keys are vmem pointers >> REGION_SHIFT, in other words 1 pointer per 1MB of vmem
     // - values are pointers to the unique allocator responsible for that vmem range
     // - on deallocate, we use this hash to determine the correct allocator to route to
     responsible_allocators: HashMap<usize, NonNull<dyn Allocator>, SimpleBuildHasher, A>,
 }
 
+// Which allocator owns a pointer is tracked per 1MB of address space
+const REGION_SHIFT: usize = 20;
+
+impl<A: Allocator + Clone> MetaAllocator<A> {
+    // Everything goes to the big region allocator until size classes are set up. The bookkeeping
+    // hash map allocates from `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        MetaAllocator {
+            fixed_size_lookup_table: [None; 512],
+            big_region_allocator: Locked::new(BigRegionAllocator),
+            responsible_allocators: HashMap::with_hasher_in(SimpleBuildHasher::default(), alloc),
+        }
+    }
+
+    // Route allocations whose size is in `sizes` to `allocator`.
+    // Safety: allocator must outlive self, and must only hand out memory in regions it was given
+    // with add_region, or deallocating will route to the wrong allocator.
+    pub unsafe fn set_size_class(
+        &mut self,
+        sizes: Range<usize>,
+        allocator: NonNull<dyn Allocator>,
+    ) {
+        for size in sizes {
+            self.fixed_size_lookup_table[size] = Some(allocator);
+        }
+    }
+
+    // Route deallocations in `region` to `allocator`. Each 1MB of address space can only belong to
+    // one allocator.
+    // Safety: allocator must outlive self.
+    pub unsafe fn add_region(&mut self, region: Range<usize>, allocator: NonNull<dyn Allocator>) {
+        if region.is_empty() {
+            return;
+        }
+        for key in (region.start >> REGION_SHIFT)..=((region.end - 1) >> REGION_SHIFT) {
+            let previous = self.responsible_allocators.insert(key, allocator);
+            assert!(
+                previous.is_none(),
+                "{:#x} is already managed by another allocator",
+                key << REGION_SHIFT
+            );
+        }
+    }
+}
+
 unsafe impl<A: Allocator + Clone> MutAllocator for MetaAllocator<A> {
     fn allocate(
         &mut self,
         layout: core::alloc::Layout,
     ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
-        let allocator = if layout.size() < self.fixed_size_lookup_table.len() {
-            let ptr = self.fixed_size_lookup_table[layout.size()];
-            unsafe { ptr.as_ref() }
-        } else {
-            &self.big_region_allocator
-        };
-        allocator.allocate(layout)
+        match self.fixed_size_lookup_table.get(layout.size()) {
+            Some(Some(ptr)) => unsafe { ptr.as_ref() }.allocate(layout),
+            _ => self.big_region_allocator.allocate(layout),
+        }
     }
 
     unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: core::alloc::Layout) {
-        // TODO: >>20 should probably be encoded / abstracted a _little_ bit
-        // TODO: probably if it's not known assume it's the big_region_allocator
-        //       rather than trying to store hash lookups for all of those?
-        let responsible_allocator = self.responsible_allocators[&(ptr.addr().get() >> 20)].as_ref();
-        responsible_allocator.deallocate(ptr, layout);
+        // Big regions aren't in the hash, anything not known is assumed to be one of theirs
+        match self
+            .responsible_allocators
+            .get(&(ptr.addr().get() >> REGION_SHIFT))
+        {
+            Some(allocator) => allocator.as_ref().deallocate(ptr, layout),
+            None => self.big_region_allocator.deallocate(ptr, layout),
+        }
     }
 }
@@ -1,20 +1,51 @@
+#[cfg(feature = "alloc_track")]
+pub mod alloc_track;
+#[cfg(feature = "alloc_audit")]
+pub mod audit;
 pub mod big_region_allocator;
 pub mod bootstrap_allocator;
 pub mod bump_allocator;
+#[cfg(any(feature = "alloc_track", feature = "alloc_audit"))]
+mod call_site;
 pub mod fixed_size_allocator;
+pub mod frame_ref_count;
+pub mod histogram;
+pub mod magazine;
 pub mod meta_allocator;
+pub mod object_cache;
 pub mod page_allocator;
+#[cfg(feature = "alloc_redzone")]
+pub mod redzone;
 pub mod resource_allocator;
+pub mod validate;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use bump_allocator::BumpAllocator;
+use spin::Mutex;
 
 use self::bootstrap_allocator::Locked;
+use self::resource_allocator::Exhausted;
+use self::validate::{Issue, Report};
+
+use super::address_space;
+use super::frame_allocator::{self, Reserved};
+use super::oom::ReclaimingAllocator;
+use super::page_table::{self, PageTableFlags};
+use super::{HUGE_PAGE_SIZE, PAGE_SIZE};
+use crate::config::{self, Tunable};
+use crate::error::{ErrorCode, KError};
 
-use super::page_table;
-use super::PAGE_SIZE;
+// Starts at address_space::kernel_heap_start, which is only settled in memory::init. The size is
+// the heap_size tunable, in whole pages.
+fn heap_size() -> usize {
+    config::get(Tunable::HeapSize) & !(PAGE_SIZE - 1)
+}
 
-const KERNEL_HEAP_START: usize = 0x4444_4444_0000;
-const KERNEL_HEAP_SIZE: usize = 100 * 1024;
+// How the kernel heap ended up mapped, see init_kernel_heap
+static HEAP_HUGE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static HEAP_SMALL_PAGES: AtomicUsize = AtomicUsize::new(0);
 
 // TODO:
 // - MetaAllocator
@@ -33,15 +64,232 @@ const KERNEL_HEAP_SIZE: usize = 100 * 1024;
 // - FixedSizeAllocator
 // - Remove `allocator` from module names
 
-#[global_allocator]
 static ALLOCATOR: Locked<BumpAllocator> = {
-    let alloc = unsafe { BumpAllocator::new(KERNEL_HEAP_START, KERNEL_HEAP_SIZE) };
+    // Empty until init_kernel_heap knows where the heap goes
+    let alloc = unsafe { BumpAllocator::new(0, 0) };
     Locked::new(alloc)
 };
 
+#[cfg_attr(not(feature = "alloc_track"), global_allocator)]
+static RECLAIMING_ALLOCATOR: ReclaimingAllocator<Locked<BumpAllocator>> =
+    ReclaimingAllocator::new(&ALLOCATOR);
+
+#[cfg(feature = "alloc_track")]
+#[cfg_attr(not(feature = "alloc_redzone"), global_allocator)]
+static TRACKING_ALLOCATOR: alloc_track::TrackingAllocator<
+    ReclaimingAllocator<Locked<BumpAllocator>>,
+> = alloc_track::TrackingAllocator::new(&RECLAIMING_ALLOCATOR);
+
+#[cfg(feature = "alloc_redzone")]
+#[global_allocator]
+static REDZONE_ALLOCATOR: redzone::RedzoneAllocator<
+    alloc_track::TrackingAllocator<ReclaimingAllocator<Locked<BumpAllocator>>>,
+> = redzone::RedzoneAllocator::new(&TRACKING_ALLOCATOR);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocFailure {
+    OutOfVirtualSpace,
+    OutOfPhysicalFrames,
+    // There's enough free in total, but no single free range is big enough
+    Fragmented { largest_free: usize },
+    MappingFailed(page_table::Err),
+}
+
+impl AllocFailure {
+    // `out_of_space` if there really isn't enough left, otherwise Fragmented
+    pub fn from_exhausted(exhausted: Exhausted, out_of_space: AllocFailure) -> Self {
+        match exhausted.is_fragmented() {
+            true => AllocFailure::Fragmented {
+                largest_free: exhausted.largest_free,
+            },
+            false => out_of_space,
+        }
+    }
+}
+
+impl fmt::Display for AllocFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocFailure::OutOfVirtualSpace => write!(f, "out of virtual address space"),
+            AllocFailure::OutOfPhysicalFrames => write!(f, "out of physical frames"),
+            AllocFailure::Fragmented { largest_free } => write!(
+                f,
+                "fragmented, largest free range is {} bytes",
+                largest_free
+            ),
+            AllocFailure::MappingFailed(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+impl From<page_table::Err> for AllocFailure {
+    fn from(err: page_table::Err) -> Self {
+        match err {
+            page_table::Err::OutOfFrames => AllocFailure::OutOfPhysicalFrames,
+            err => AllocFailure::MappingFailed(err),
+        }
+    }
+}
+
+impl From<AllocFailure> for KError {
+    fn from(failure: AllocFailure) -> Self {
+        match failure {
+            AllocFailure::OutOfVirtualSpace => KError::new(ErrorCode::OutOfAddressSpace),
+            AllocFailure::OutOfPhysicalFrames => KError::new(ErrorCode::OutOfMemory),
+            AllocFailure::Fragmented { .. } => {
+                KError::new(ErrorCode::OutOfAddressSpace).with_message("fragmented")
+            }
+            AllocFailure::MappingFailed(err) => KError::from(err),
+        }
+    }
+}
+
+// The most recent failure behind an AllocError, for alloc_error_handler, which only gets the layout
+static LAST_FAILURE: Mutex<Option<AllocFailure>> = Mutex::new(None);
+
+pub(super) fn record_failure(failure: AllocFailure) {
+    crate::without_interrupt! {{
+        *LAST_FAILURE.lock() = Some(failure);
+    }}
+}
+
+// Only reached once the OOM reclaimers (see memory::oom) have failed to free enough memory
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    // A corrupted allocator is a much more interesting failure than a full one
+    crate::serial_println!("{}", validate());
+    match LAST_FAILURE.try_lock().and_then(|failure| *failure) {
+        Some(failure) => panic!("allocation error: {:?}: {}\n{}", layout, failure, stats()),
+        None => panic!("allocation error: {:?}\n{}", layout, stats()),
+    }
+}
+
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub allocations: usize,
+    // 2MiB and 4KiB pages backing the heap
+    pub huge_pages: usize,
+    pub small_pages: usize,
+}
+
+pub struct RegionStats {
+    pub free: usize,
+    pub allocated: usize,
+}
+
+// Memory usage across the allocators. Like `validate`, never allocates, and leaves out anything
+// whose lock is held.
+pub struct Stats {
+    pub heap: Option<HeapStats>,
+    pub virtual_memory: Option<RegionStats>,
+    pub physical_memory: Option<RegionStats>,
+    // Physical memory the page allocator never got
+    pub reserved: Option<Reserved>,
+}
+
+pub fn stats() -> Stats {
+    let heap = ALLOCATOR.value.try_lock().map(|heap| HeapStats {
+        size: heap_size(),
+        used: heap.used(),
+        allocations: heap.allocations(),
+        huge_pages: HEAP_HUGE_PAGES.load(Ordering::Relaxed),
+        small_pages: HEAP_SMALL_PAGES.load(Ordering::Relaxed),
+    });
+    let (virtual_memory, physical_memory) = match super::PAGE_ALLOCATOR.try_lock() {
+        Some(page_allocator) => {
+            let ((vfree, vallocated), (pfree, pallocated)) = page_allocator.usage();
+            (
+                Some(RegionStats {
+                    free: vfree,
+                    allocated: vallocated,
+                }),
+                Some(RegionStats {
+                    free: pfree,
+                    allocated: pallocated,
+                }),
+            )
+        }
+        None => (None, None),
+    };
+    Stats {
+        heap,
+        virtual_memory,
+        physical_memory,
+        reserved: frame_allocator::reserved(),
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory stats:")?;
+        match &self.heap {
+            Some(heap) => write!(
+                f,
+                "\n  kernel heap: {} / {} bytes used, {} live allocations, {} 2MiB + {} 4KiB pages",
+                heap.used, heap.size, heap.allocations, heap.huge_pages, heap.small_pages
+            )?,
+            None => write!(f, "\n  kernel heap: locked")?,
+        }
+        let regions = [
+            ("virtual memory", &self.virtual_memory),
+            ("physical memory", &self.physical_memory),
+        ];
+        for (name, region) in regions {
+            match region {
+                Some(region) => write!(
+                    f,
+                    "\n  {}: {} bytes free, {} bytes allocated",
+                    name, region.free, region.allocated
+                )?,
+                None => write!(f, "\n  {}: locked", name)?,
+            }
+        }
+        if let Some(reserved) = &self.reserved {
+            write!(
+                f,
+                "\n  reserved: {} KiB ({})",
+                reserved.total() / 1024,
+                reserved
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Walk the allocators' bookkeeping and the kernel heap mappings looking for inconsistencies.
+// Doesn't allocate, so it's safe to call when the heap is exhausted. Allocators whose locks are
+// held (eg. we failed an allocation while inside one of them) are skipped rather than deadlocking.
+pub fn validate() -> Report {
+    let mut report = Report::new();
+    match ALLOCATOR.value.try_lock() {
+        Some(heap) => heap.validate(&mut report),
+        None => report.skip("kernel heap"),
+    }
+    match super::PAGE_ALLOCATOR.try_lock() {
+        Some(page_allocator) => page_allocator.validate(&mut report),
+        None => report.skip("page allocator"),
+    }
+    #[cfg(feature = "alloc_redzone")]
+    redzone::validate(&mut report);
+    for page in kernel_heap().step_by(PAGE_SIZE) {
+        match super::page_flags(page) {
+            Ok(flags) if !flags.contains(PageTableFlags::PRESENT) => {
+                report.push(Issue::HeapPageNotMapped { page })
+            }
+            Ok(flags) if !flags.contains(PageTableFlags::WRITABLE) => {
+                report.push(Issue::HeapPageNotWritable { page })
+            }
+            Ok(_) => (),
+            Err(_) => report.push(Issue::HeapPageNotMapped { page }),
+        }
+    }
+    report
+}
+
+pub(in crate::memory) fn kernel_heap() -> core::ops::Range<usize> {
+    let start = address_space::kernel_heap_start();
+    start..start + heap_size()
 }
 
 // Safety: This function maps pages to frames yielded by next_frame.
@@ -54,13 +302,80 @@ pub unsafe fn init_kernel_heap(next_frame: &mut dyn FnMut() -> usize) {
 fn init_kernel_heap_unsafe(next_frame: &mut dyn FnMut() -> usize) {
     // TODO: kernel logs
     crate::println!("Initializing kernel heap");
-    let kernel_heap_pages =
-        (KERNEL_HEAP_START..KERNEL_HEAP_START + KERNEL_HEAP_SIZE).step_by(PAGE_SIZE);
     let page_table = unsafe { page_table::l4::PageTable::get() };
-    for page in kernel_heap_pages {
-        match unsafe { page_table.map_if_unmapped(page, next_frame) } {
-            Ok(()) => (),
+    let heap = kernel_heap();
+    let (mut page, end) = (heap.start, heap.end);
+    while page < end {
+        if page % HUGE_PAGE_SIZE == 0 && page + HUGE_PAGE_SIZE <= end {
+            map_huge_or_fallback(page_table, page, next_frame);
+            page += HUGE_PAGE_SIZE;
+        } else {
+            match unsafe { page_table.map_if_unmapped(page, next_frame) } {
+                Ok(()) => HEAP_SMALL_PAGES.fetch_add(1, Ordering::Relaxed),
+                Err(err) => panic!("Failed to map kernel heap: {:#?}", err),
+            };
+            page += PAGE_SIZE;
+        }
+    }
+    unsafe { ALLOCATOR.value.lock().set_heap(heap.start, heap_size()) };
+}
+
+// Map a 2MiB stretch of heap with a single huge page if the next 512 frames happen to be an
+// aligned contiguous run, to save TLB entries. Otherwise map them as 4KiB pages, so the frames
+// aren't wasted either way.
+fn map_huge_or_fallback(
+    page_table: &mut page_table::l4::PageTable,
+    page: usize,
+    next_frame: &mut dyn FnMut() -> usize,
+) {
+    let mut frames = [0; HUGE_PAGE_SIZE / PAGE_SIZE];
+    frames.iter_mut().for_each(|frame| *frame = next_frame());
+    let contiguous = frames[0] % HUGE_PAGE_SIZE == 0
+        && frames
+            .iter()
+            .enumerate()
+            .all(|(i, &frame)| frame == frames[0] + i * PAGE_SIZE);
+    if contiguous {
+        match unsafe { page_table.map_huge_if_unmapped(page, frames[0], next_frame) } {
+            Ok(()) => HEAP_HUGE_PAGES.fetch_add(1, Ordering::Relaxed),
+            Err(err) => panic!("Failed to map kernel heap: {:#?}", err),
+        };
+        return;
+    }
+    // Page tables come out of the frames we already took too
+    let mut taken = frames.into_iter();
+    let mut next = || match taken.next() {
+        Some(frame) => frame,
+        None => next_frame(),
+    };
+    for page in (page..page + HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+        match unsafe { page_table.map_if_unmapped(page, &mut next) } {
+            Ok(()) => HEAP_SMALL_PAGES.fetch_add(1, Ordering::Relaxed),
             Err(err) => panic!("Failed to map kernel heap: {:#?}", err),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_validate_allocators() {
+        let report = validate();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test_case]
+    fn test_heap_page_counts_cover_heap() {
+        let heap = stats().heap.unwrap();
+        let mapped = heap.huge_pages * HUGE_PAGE_SIZE + heap.small_pages * PAGE_SIZE;
+        assert!(mapped >= heap_size());
+    }
+
+    crate::bench_case! {
+        fn bench_small_alloc(b: &mut Bencher) {
+            b.iter(|| alloc::boxed::Box::new(0u64));
         }
     }
 }
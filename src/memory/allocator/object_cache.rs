@@ -0,0 +1,279 @@
+use alloc::alloc::Global;
+use alloc::vec::Vec;
+use core::alloc::{Allocator, Layout};
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::memory::cache_registry::Cache;
+
+// Typed object caches, after Bonwick's slab allocator: objects are constructed once, when their
+// slab first hands them out, and keep their constructed state across frees. Whoever frees an
+// object leaves it in a state the next user can take as freshly constructed (eg. an empty list
+// with its capacity still allocated), and expensive setup only happens once per object.
+//
+// Objects are only destroyed (dropped) when their slab is, once every object in it is free. Caches
+// registered with memory::cache_registry give their empty slabs back under memory pressure.
+//
+// Like SlabAllocatorBlock, each slab is 64 objects tracked by a bitmap.
+
+const SLAB_OBJECTS: usize = 64;
+
+struct Slab<T> {
+    objects: NonNull<[MaybeUninit<T>; SLAB_OBJECTS]>,
+    allocated: u64,
+    // Objects that have been through the constructor, and not dropped since
+    constructed: u64,
+}
+
+// Only reached through its cache's lock
+unsafe impl<T: Send> Send for Slab<T> {}
+
+impl<T> Slab<T> {
+    const LAYOUT: Layout = Layout::new::<[MaybeUninit<T>; SLAB_OBJECTS]>();
+
+    fn new() -> Option<Self> {
+        let objects = Global.allocate(Self::LAYOUT).ok()?;
+        Some(Slab {
+            objects: objects.cast(),
+            allocated: 0,
+            constructed: 0,
+        })
+    }
+
+    fn object(&self, index: usize) -> *mut T {
+        self.objects.as_ptr().cast::<T>().wrapping_add(index)
+    }
+
+    fn index_of(&self, object: *mut T) -> Option<usize> {
+        let start = self.objects.as_ptr() as usize;
+        let offset = (object as usize).checked_sub(start)?;
+        Some(offset / core::mem::size_of::<T>().max(1)).filter(|&index| index < SLAB_OBJECTS)
+    }
+
+    fn is_full(&self) -> bool {
+        self.allocated == u64::MAX
+    }
+
+    // A free object, and whether it still needs constructing
+    fn take(&mut self) -> (*mut T, bool) {
+        let index = self.allocated.trailing_ones() as usize;
+        self.allocated |= 1 << index;
+        let constructed = self.constructed & 1 << index != 0;
+        self.constructed |= 1 << index;
+        (self.object(index), !constructed)
+    }
+
+    // Drop its objects and free it. Every object must be free.
+    unsafe fn destroy(self) {
+        debug_assert_eq!(self.allocated, 0);
+        for index in 0..SLAB_OBJECTS {
+            if self.constructed & 1 << index != 0 {
+                self.object(index).drop_in_place();
+            }
+        }
+        Global.deallocate(self.objects.cast(), Self::LAYOUT);
+    }
+}
+
+pub struct ObjectCache<T> {
+    ctor: fn() -> T,
+    slabs: Mutex<Vec<Slab<T>>>,
+    // Objects handed out already constructed, and ones that needed constructing
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<T: Send> ObjectCache<T> {
+    pub const fn new(ctor: fn() -> T) -> Self {
+        ObjectCache {
+            ctor,
+            slabs: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // None if there's no memory for a new slab
+    pub fn alloc(&self) -> Option<CacheBox<'_, T>> {
+        let (object, construct) = crate::without_interrupt! {{
+            let mut slabs = self.slabs.lock();
+            let index = match slabs.iter().position(|slab| !slab.is_full()) {
+                Some(index) => index,
+                None => {
+                    slabs.try_reserve(1).ok()?;
+                    slabs.push(Slab::new()?);
+                    slabs.len() - 1
+                }
+            };
+            slabs[index].take()
+        }};
+        // Outside the lock, since constructors may well allocate
+        match construct {
+            true => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                unsafe { object.write((self.ctor)()) };
+            }
+            false => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Some(CacheBox {
+            cache: self,
+            object: unsafe { NonNull::new_unchecked(object) },
+        })
+    }
+
+    fn free(&self, object: *mut T) {
+        crate::without_interrupt! {{
+            let mut slabs = self.slabs.lock();
+            let (slab, index) = slabs
+                .iter_mut()
+                .find_map(|slab| {
+                    let index = slab.index_of(object)?;
+                    Some((slab, index))
+                })
+                .expect("freed object not from this cache");
+            slab.allocated &= !(1 << index);
+        }}
+    }
+
+    // Destroy slabs with nothing allocated from them until `target_bytes` have been freed,
+    // returning how much was. Skipped if the cache is locked, eg. when it's the one whose
+    // allocation failed. T's destructor mustn't use this cache.
+    pub fn shrink(&self, target_bytes: usize) -> usize {
+        let mut slabs = match self.slabs.try_lock() {
+            Some(slabs) => slabs,
+            None => return 0,
+        };
+        shed_empty(&mut slabs, target_bytes)
+    }
+}
+
+fn shed_empty<T>(slabs: &mut Vec<Slab<T>>, target_bytes: usize) -> usize {
+    let mut freed = 0;
+    let mut i = 0;
+    while i < slabs.len() && freed < target_bytes {
+        if slabs[i].allocated == 0 {
+            unsafe { slabs.swap_remove(i).destroy() };
+            freed += Slab::<T>::LAYOUT.size();
+        } else {
+            i += 1;
+        }
+    }
+    freed
+}
+
+impl<T> Drop for ObjectCache<T> {
+    fn drop(&mut self) {
+        // Nothing can be allocated, since CacheBoxes borrow the cache
+        shed_empty(self.slabs.get_mut(), usize::MAX);
+    }
+}
+
+// A cached object, returned to its cache (still constructed) when dropped
+pub struct CacheBox<'a, T: Send> {
+    cache: &'a ObjectCache<T>,
+    object: NonNull<T>,
+}
+
+impl<T: Send> Deref for CacheBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T: Send> DerefMut for CacheBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: Send> Drop for CacheBox<'_, T> {
+    fn drop(&mut self) {
+        self.cache.free(self.object.as_ptr());
+    }
+}
+
+impl<T: Send> Cache for ObjectCache<T> {
+    fn size(&self) -> usize {
+        let slabs = crate::without_interrupt! {{ self.slabs.lock().len() }};
+        slabs * Slab::<T>::LAYOUT.size()
+    }
+
+    fn hits(&self) -> Option<(u64, u64)> {
+        Some((
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn shrink(&self, target_bytes: usize) -> usize {
+        ObjectCache::shrink(self, target_bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(u64);
+
+    impl Counted {
+        fn new() -> Self {
+            CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+            Counted(7)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn test_objects_keep_constructed_state() {
+        CONSTRUCTED.store(0, Ordering::Relaxed);
+        DROPPED.store(0, Ordering::Relaxed);
+        let cache = ObjectCache::new(Counted::new);
+        let mut object = cache.alloc().unwrap();
+        assert_eq!(object.0, 7);
+        object.0 = 8;
+        let address = &*object as *const Counted;
+        drop(object);
+        let object = cache.alloc().unwrap();
+        assert_eq!(&*object as *const Counted, address);
+        assert_eq!(object.0, 8);
+        assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), 1);
+        drop(object);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+        drop(cache);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn test_shed_only_empty_slabs() {
+        let cache = ObjectCache::new(Counted::new);
+        let objects: Vec<_> = (0..SLAB_OBJECTS + 1)
+            .map(|_| cache.alloc().unwrap())
+            .collect();
+        assert_eq!(cache.shrink(usize::MAX), 0);
+        let mut objects = objects.into_iter();
+        // The first slab's objects
+        objects.by_ref().take(SLAB_OBJECTS).for_each(drop);
+        assert_eq!(cache.shrink(usize::MAX), Slab::<Counted>::LAYOUT.size());
+        drop(objects);
+        assert_eq!(cache.shrink(usize::MAX), Slab::<Counted>::LAYOUT.size());
+        assert_eq!(cache.shrink(usize::MAX), 0);
+    }
+}
@@ -1,35 +1,141 @@
 use core::ops::Range;
 use core::ptr::NonNull;
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
-
+use super::frame_ref_count::FrameRefCount;
 use super::resource_allocator::ResourceAllocator;
+use super::validate::Report;
+use super::AllocFailure;
+use crate::boot::{MemoryMap, RegionKind};
+use crate::memory::address_space::{self, KERNEL_END, KERNEL_START};
 use crate::memory::page_table;
 use crate::memory::page_table::l4;
+use crate::memory::page_table::PageTableFlags;
+use crate::memory::scrub;
 use crate::memory::PAGE_SIZE;
 
+// Physical memory zones, for devices that can only address low memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    // Below 16MiB, for legacy ISA DMA
+    Dma,
+    // Below 4GiB, for 32 bit devices
+    Low,
+    Normal,
+}
+
+const ZONES: usize = 3;
+const DMA_ZONE_END: usize = 16 * 1024 * 1024;
+const LOW_ZONE_END: usize = 4 * 1024 * 1024 * 1024;
+
+impl Zone {
+    pub const ALL: [Zone; ZONES] = [Zone::Dma, Zone::Low, Zone::Normal];
+    // Ordinary allocations take the least constrained memory first
+    const DEFAULT_ORDER: [Zone; ZONES] = [Zone::Normal, Zone::Low, Zone::Dma];
+
+    pub fn range(&self) -> Range<usize> {
+        match self {
+            Zone::Dma => 0..DMA_ZONE_END,
+            Zone::Low => DMA_ZONE_END..LOW_ZONE_END,
+            Zone::Normal => LOW_ZONE_END..usize::MAX,
+        }
+    }
+
+    pub fn containing(address: usize) -> Zone {
+        match address {
+            a if a < DMA_ZONE_END => Zone::Dma,
+            a if a < LOW_ZONE_END => Zone::Low,
+            _ => Zone::Normal,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Zone::Dma => "pmem dma",
+            Zone::Low => "pmem low",
+            Zone::Normal => "pmem normal",
+        }
+    }
+}
+
+// The parts of `range` in each zone
+fn split_by_zone(range: Range<usize>) -> impl Iterator<Item = (Zone, Range<usize>)> {
+    Zone::ALL.into_iter().filter_map(move |zone| {
+        let zone_range = zone.range();
+        let start = range.start.max(zone_range.start);
+        let end = range.end.min(zone_range.end);
+        (start < end).then(|| (zone, start..end))
+    })
+}
+
 pub struct PageAllocator {
     l4_table: &'static mut l4::PageTable,
     vmem: ResourceAllocator<PAGE_SIZE>,
-    pmem: ResourceAllocator<PAGE_SIZE>,
+    // Indexed by Zone
+    pmem: [ResourceAllocator<PAGE_SIZE>; ZONES],
+    // Mappings of each frame made by allocate/share
+    refcounts: FrameRefCount,
+}
+
+fn allocate_frame_from(
+    pmem: &mut [ResourceAllocator<PAGE_SIZE>; ZONES],
+    zones: &[Zone],
+) -> Result<usize, AllocFailure> {
+    zones
+        .iter()
+        .find_map(|&zone| pmem[zone as usize].fast_allocate(1).ok())
+        .map(|range| range.start)
+        .ok_or(AllocFailure::OutOfPhysicalFrames)
 }
 
-const L4_PAGE_SIZE: usize = 1 << 8 << 8 << 8 << 12;
+// Everything handed out of the page allocator is zeroed, so nobody sees the last owner's data
+fn next_zeroed_frame(
+    pmem: &mut [ResourceAllocator<PAGE_SIZE>; ZONES],
+) -> Result<usize, AllocFailure> {
+    if let Some(frame) = scrub::take_clean_frame() {
+        return Ok(frame);
+    }
+    let frame = allocate_frame_from(pmem, &Zone::DEFAULT_ORDER)?;
+    unsafe { scrub::zero_frame(frame) };
+    Ok(frame)
+}
 
+const L4_PAGE_SIZE: usize = 1 << 9 << 9 << 9 << 12;
+
+fn l4_index(address: usize) -> usize {
+    address / L4_PAGE_SIZE % 512
+}
+
+// Sign extended, so upper half entries are canonical
 fn l4_page_range(entry_index: usize) -> Range<usize> {
-    entry_index * L4_PAGE_SIZE..(entry_index + 1) * L4_PAGE_SIZE
+    let start = match entry_index {
+        0..=255 => entry_index * L4_PAGE_SIZE,
+        _ => entry_index * L4_PAGE_SIZE | 0xFFFF_0000_0000_0000,
+    };
+    start..start + L4_PAGE_SIZE
+}
+
+// Kernel half l4 entries with nothing mapped under them; the lower half is for user space
+fn unmapped_l4_ranges(l4_table: &l4::PageTable) -> impl Iterator<Item = Range<usize>> + '_ {
+    let first = l4_index(KERNEL_START);
+    l4_table
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(l4_index(KERNEL_END) - first)
+        .filter(|(_, entry)| !entry.present())
+        .map(|(index, _)| l4_page_range(index))
 }
 
 impl PageAllocator {
     pub fn new() -> Self {
         let vmem: ResourceAllocator<PAGE_SIZE> = ResourceAllocator::new();
-        let pmem: ResourceAllocator<PAGE_SIZE> = ResourceAllocator::new();
+        let pmem = [(); ZONES].map(|_| ResourceAllocator::new());
         let l4_table = unsafe { l4::PageTable::get() };
         PageAllocator {
             l4_table,
             vmem,
             pmem,
+            refcounts: FrameRefCount::new(),
         }
     }
 
@@ -37,30 +143,41 @@ impl PageAllocator {
         // Add any non-present l4 pages as available for vmem allocation.
         // If this isn't sufficient, we can go deeper, but iirc only 4 l4 pages are mapped
         // by the bootloader (and maybe 1 more by us for the bootstrap allocator?)
-        let l4 = page_table::l4::PageTable::get();
-        l4.iter()
-            .enumerate()
-            .filter(|(_, e)| e.present())
-            .for_each(|(i, _)| self.vmem.add(l4_page_range(i)));
+        for range in unmapped_l4_ranges(page_table::l4::PageTable::get()) {
+            self.vmem.add(range);
+        }
 
-        // Add all physical memory regions to the pmem allocator.
+        // Add all physical memory regions to the pmem allocator of their zone(s).
         // Assume all used_frames come from the front. We guarantee this with our bootstrap
         // allocator, which iterates over frames in sorted order from MemoryMap.
         let mut to_drop = used_frames;
-        let usable_regions = memory_map
-            .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        let usable_regions = memory_map.iter().filter(|r| r.kind == RegionKind::Usable);
         for region in usable_regions {
-            let start = region.range.start_frame_number as usize;
-            let end = region.range.start_frame_number as usize;
+            let frames = region.frames();
+            let (start, end) = (frames.start, frames.end);
             if end - start > to_drop {
-                self.pmem
-                    .add((start + to_drop) * PAGE_SIZE..end * PAGE_SIZE);
+                let range = (start + to_drop) * PAGE_SIZE..end * PAGE_SIZE;
+                for (zone, range) in split_by_zone(range) {
+                    self.pmem[zone as usize].add(range);
+                }
                 to_drop = 0;
             } else {
                 to_drop -= end - start;
             }
         }
+
+        let frames = memory_map
+            .iter()
+            .filter(|r| r.kind == RegionKind::Usable)
+            .map(|r| r.frames().end)
+            .max()
+            .unwrap_or(0);
+        let table_size = FrameRefCount::table_size(frames);
+        let table = Zone::DEFAULT_ORDER
+            .iter()
+            .find_map(|&zone| self.pmem[zone as usize].fast_allocate(table_size).ok())
+            .expect("No room for the frame reference counts");
+        self.refcounts.init(table.start, frames);
     }
 
     // Allocate should allocate contiguous blocks of virtual memory, backed
@@ -76,36 +193,182 @@ impl PageAllocator {
     // pub fn resize();
     // pub fn to_disk();
 
-    pub fn allocate_frame(&mut self) -> Result<NonNull<[u8]>, ()> {
+    // Prefers the normal zone, only dipping into low memory once it runs out. Zeroed, preferably
+    // ahead of time by the scrubber.
+    pub fn allocate_frame(&mut self) -> Result<NonNull<[u8]>, AllocFailure> {
         // self.allocate_frames(1)
-        let start = self.pmem.fast_allocate(1)?.start as *mut u8;
+        let start = next_zeroed_frame(&mut self.pmem)? as *mut u8;
         Ok(unsafe { NonNull::new_unchecked(start as *mut [u8; PAGE_SIZE]) })
     }
-    // pub fn allocate_frames(&mut self, frames: usize) -> Result<NonNull<[u8]>, ()> {
-    //     let start = self.pmem.fast_allocate(frames)?.start as *mut u8;
-    //     Ok(unsafe { NonNull::new_unchecked(start) })
-    // }
-    pub fn allocate(&mut self, size: usize) -> Result<NonNull<[u8]>, ()> {
-        let range = self.vmem.fast_allocate(size)?;
-        unsafe {
-            // TODO: propagate page allocation error
-            let next_frame =
-                &mut || self.pmem.fast_allocate(1).unwrap().start as *const () as usize;
-            for page in range.clone().step_by(PAGE_SIZE) {
-                self.l4_table
-                    .map_if_unmapped(page, next_frame)
-                    .or(Err(()))?;
+
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Result<NonNull<[u8]>, AllocFailure> {
+        let start = allocate_frame_from(&mut self.pmem, &[zone])?;
+        unsafe { scrub::zero_frame(start) };
+        Ok(unsafe { NonNull::new_unchecked(start as *mut [u8; PAGE_SIZE]) })
+    }
+
+    pub fn deallocate_frame(&mut self, frame: NonNull<[u8]>) {
+        self.deallocate_unzeroed_frame(frame.as_mut_ptr() as usize);
+    }
+
+    // For the scrubber, which does the zeroing itself
+    pub(in crate::memory) fn allocate_unzeroed_frame(&mut self) -> Result<usize, AllocFailure> {
+        allocate_frame_from(&mut self.pmem, &Zone::DEFAULT_ORDER)
+    }
+
+    pub(in crate::memory) fn deallocate_unzeroed_frame(&mut self, frame: usize) {
+        self.pmem[Zone::containing(frame) as usize].release(frame..frame + PAGE_SIZE);
+    }
+
+    // Physically contiguous, zeroed frames covering `size` bytes, eg. for a device to DMA to.
+    // Returns their physical range, which is what deallocate_contiguous wants back.
+    pub fn allocate_contiguous(&mut self, size: usize) -> Result<Range<usize>, AllocFailure> {
+        let range = Zone::DEFAULT_ORDER
+            .iter()
+            .find_map(|&zone| self.pmem[zone as usize].fast_allocate(size).ok())
+            .ok_or(AllocFailure::OutOfPhysicalFrames)?;
+        for frame in range.clone().step_by(PAGE_SIZE) {
+            unsafe { scrub::zero_frame(frame) };
+        }
+        Ok(range)
+    }
+
+    pub fn deallocate_contiguous(&mut self, range: Range<usize>) {
+        self.pmem[Zone::containing(range.start) as usize].release(range);
+    }
+
+    pub fn allocate(&mut self, size: usize) -> Result<NonNull<[u8]>, AllocFailure> {
+        let range = self.allocate_virtual(size)?;
+        for page in range.clone().step_by(PAGE_SIZE) {
+            if let Err(failure) = self.map_zeroed(page) {
+                self.abandon(range, page);
+                return Err(failure);
             }
-        };
+        }
         // page_table::l4::PageTable::
         Ok(unsafe {
             NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(
                 range.start as *mut u8,
-                range.end,
+                range.end - range.start,
             ))
         })
     }
 
+    // Map the frames behind the pages of [ptr, ptr + size) a second time, at a new address. Either
+    // mapping can be deallocated first; the frames are freed along with the last of them. Every
+    // source page must be mapped.
+    pub fn share(&mut self, ptr: *mut u8, size: usize) -> Result<NonNull<[u8]>, AllocFailure> {
+        let source = ptr as usize;
+        let range = self.allocate_virtual(size)?;
+        for (page, source_page) in range
+            .clone()
+            .step_by(PAGE_SIZE)
+            .zip((source..).step_by(PAGE_SIZE))
+        {
+            if let Err(failure) = self.map_shared(page, source_page) {
+                self.abandon(range, page);
+                return Err(failure);
+            }
+        }
+        Ok(unsafe {
+            NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(
+                range.start as *mut u8,
+                range.end - range.start,
+            ))
+        })
+    }
+
+    // Mappings of `frame` made through this allocator
+    pub fn mappings(&self, frame: usize) -> u16 {
+        self.refcounts.get(frame)
+    }
+
+    fn allocate_virtual(&mut self, size: usize) -> Result<Range<usize>, AllocFailure> {
+        self.vmem.fast_allocate(size).map_err(|exhausted| {
+            AllocFailure::from_exhausted(exhausted, AllocFailure::OutOfVirtualSpace)
+        })
+    }
+
+    // Map a fresh zeroed frame at `page`
+    fn map_zeroed(&mut self, page: usize) -> Result<(), AllocFailure> {
+        let frame = next_zeroed_frame(&mut self.pmem)?;
+        self.map(page, frame).map_err(|failure| {
+            self.pmem[Zone::containing(frame) as usize].release(frame..frame + PAGE_SIZE);
+            failure
+        })
+    }
+
+    // Map the frame behind `source_page` at `page` too
+    fn map_shared(&mut self, page: usize, source_page: usize) -> Result<(), AllocFailure> {
+        let entry = self.l4_table.entry_mut(source_page)?;
+        if !entry.present() {
+            return Err(page_table::Err::PageNotPresent.into());
+        }
+        let frame = entry.pointer();
+        self.map(page, frame)
+    }
+
+    // Back out of an allocate or share that failed at `failed`: unmap the pages before it, and
+    // give back the virtual range
+    fn abandon(&mut self, range: Range<usize>, failed: usize) {
+        for page in (range.start..failed).step_by(PAGE_SIZE) {
+            self.unmap(page);
+        }
+        self.vmem.release(range);
+    }
+
+    fn map(&mut self, page: usize, frame: usize) -> Result<(), AllocFailure> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        self.map_borrowed(page, frame, flags)?;
+        self.refcounts.increment(frame);
+        Ok(())
+    }
+
+    // Virtual space with nothing mapped in it yet, eg. for a memory-mapped file's pages to be
+    // faulted into
+    pub fn reserve(&mut self, size: usize) -> Result<Range<usize>, AllocFailure> {
+        self.allocate_virtual(size)
+    }
+
+    // Give back a reserved range, once everything mapped in it has been unmapped
+    pub fn release(&mut self, range: Range<usize>) {
+        self.vmem.release(range);
+    }
+
+    // Map `frame` at `page` without counting it as one of our mappings: whoever owns the frame
+    // (eg. the page cache) keeps it alive until it's unmapped with unmap_borrowed
+    pub fn map_borrowed(
+        &mut self,
+        page: usize,
+        frame: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), AllocFailure> {
+        let pmem = &mut self.pmem;
+        // Running out for a page table comes back as AllocFailure::OutOfPhysicalFrames
+        let next_frame = &mut || next_zeroed_frame(pmem).ok();
+        unsafe { self.l4_table.map_to(page, frame, flags, next_frame) }?;
+        Ok(())
+    }
+
+    // The flags `page` was mapped with (eg. DIRTY), or None if it wasn't
+    pub fn unmap_borrowed(&mut self, page: usize) -> Option<PageTableFlags> {
+        let entry = self.l4_table.entry_mut(page).ok()?;
+        if !entry.present() {
+            return None;
+        }
+        let flags = entry.flags();
+        unsafe { self.l4_table.unmap(page) };
+        Some(flags)
+    }
+
+    fn unmap(&mut self, page: usize) {
+        let entry = unsafe { self.l4_table.unmap(page) };
+        let frame = entry.pointer();
+        if self.refcounts.decrement(frame) == 0 {
+            self.pmem[Zone::containing(frame) as usize].release(frame..frame + PAGE_SIZE);
+        }
+    }
+
     // unsafe fn map_page(&mut self, page: usize) {
     //     self.l4_table
     //         .map_if_unmapped(page, &mut || self.next_frame().unwrap());
@@ -114,15 +377,159 @@ impl PageAllocator {
     //     Ok(self.allocate_frame()?.as_ptr() as *const () as usize)
     // }
 
+    // ((free, allocated) virtual bytes, (free, allocated) physical bytes)
+    pub fn usage(&self) -> ((usize, usize), (usize, usize)) {
+        let pmem = Zone::ALL.iter().fold((0, 0), |(free, allocated), &zone| {
+            let (zone_free, zone_allocated) = self.zone_usage(zone);
+            (free + zone_free, allocated + zone_allocated)
+        });
+        (self.vmem.usage(), pmem)
+    }
+
+    // (free, allocated) physical bytes in `zone`
+    pub fn zone_usage(&self, zone: Zone) -> (usize, usize) {
+        self.pmem[zone as usize].usage()
+    }
+
+    pub fn validate(&self, report: &mut Report) {
+        self.vmem.validate("vmem", report);
+        for zone in Zone::ALL {
+            self.pmem[zone as usize].validate(zone.name(), report);
+        }
+    }
+
     pub fn deallocate(&mut self, ptr: *mut u8, size: usize) {
         let start = ptr as usize;
         let range = start..start + size;
         self.vmem.release(range.clone());
         for page in range.step_by(PAGE_SIZE) {
-            let entry = unsafe { self.l4_table.unmap(page) };
-            let ptr = entry.pointer();
-            self.pmem.release(ptr..ptr + PAGE_SIZE);
+            self.unmap(page);
         }
     }
     // pub fn allocate_frames();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_split_by_zone() {
+        let mut parts = split_by_zone(0x0080_0000..0x0180_0000);
+        assert_eq!(parts.next(), Some((Zone::Dma, 0x0080_0000..DMA_ZONE_END)));
+        assert_eq!(parts.next(), Some((Zone::Low, DMA_ZONE_END..0x0180_0000)));
+        assert_eq!(parts.next(), None);
+    }
+
+    #[test_case]
+    fn test_allocate_frame_in_zone() {
+        let mut page_allocator = crate::memory::PAGE_ALLOCATOR.lock();
+        let frame = page_allocator.allocate_frame_in(Zone::Dma).unwrap();
+        assert_eq!(Zone::containing(frame.as_mut_ptr() as usize), Zone::Dma);
+        page_allocator.deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn test_shared_frames_outlive_first_mapping() {
+        let mut page_allocator = crate::memory::PAGE_ALLOCATOR.lock();
+        let original = page_allocator.allocate(PAGE_SIZE).unwrap().as_mut_ptr();
+        unsafe { *original = 42 };
+        let shared = page_allocator
+            .share(original, PAGE_SIZE)
+            .unwrap()
+            .as_mut_ptr();
+        let frame = page_allocator
+            .l4_table
+            .entry_mut(shared as usize)
+            .unwrap()
+            .pointer();
+        assert_eq!(page_allocator.mappings(frame), 2);
+
+        page_allocator.deallocate(original, PAGE_SIZE);
+        assert_eq!(page_allocator.mappings(frame), 1);
+        assert_eq!(unsafe { *shared }, 42);
+        page_allocator.deallocate(shared, PAGE_SIZE);
+        assert_eq!(page_allocator.mappings(frame), 0);
+    }
+
+    #[test_case]
+    fn test_share_unmapped_page_fails_cleanly() {
+        let mut page_allocator = crate::memory::PAGE_ALLOCATOR.lock();
+        let original = page_allocator.allocate(2 * PAGE_SIZE).unwrap().as_mut_ptr();
+        // Leave a hole where the second page was
+        let hole = original as usize + PAGE_SIZE;
+        page_allocator.unmap(hole);
+        let frame = page_allocator
+            .l4_table
+            .entry_mut(original as usize)
+            .unwrap()
+            .pointer();
+        let vmem = page_allocator.vmem.usage();
+
+        let result = page_allocator.share(original, 2 * PAGE_SIZE);
+        assert_eq!(
+            result.err(),
+            Some(AllocFailure::MappingFailed(page_table::Err::PageNotPresent))
+        );
+        // The first page's second mapping was undone, and no address space leaked
+        assert_eq!(page_allocator.mappings(frame), 1);
+        assert_eq!(page_allocator.vmem.usage(), vmem);
+
+        page_allocator.deallocate(original, PAGE_SIZE);
+        page_allocator.vmem.release(hole..hole + PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn test_map_without_table_frames_fails() {
+        let _page_allocator = crate::memory::PAGE_ALLOCATOR.lock();
+        let l4_table = unsafe { l4::PageTable::get() };
+        let page = unmapped_l4_ranges(l4_table).next().unwrap().start;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let result = unsafe { l4_table.map_to(page, 0, flags, &mut || None) };
+        assert_eq!(result, Err(page_table::Err::OutOfFrames));
+        assert!(!l4_table[l4_index(page)].present());
+        assert_eq!(
+            AllocFailure::from(result.unwrap_err()),
+            AllocFailure::OutOfPhysicalFrames
+        );
+    }
+
+    // text, heap, the physical memory mapping, and the stack
+    fn kernel_addresses() -> [usize; 4] {
+        let stack = 0u8;
+        [
+            kernel_addresses as fn() -> [usize; 4] as usize,
+            crate::memory::allocator::kernel_heap().start,
+            crate::memory::physical_memory_offset(),
+            &stack as *const u8 as usize,
+        ]
+    }
+
+    #[test_case]
+    fn test_unmapped_l4_ranges_skip_kernel() {
+        let l4_table = unsafe { l4::PageTable::get() };
+        let mut ranges = unmapped_l4_ranges(l4_table).peekable();
+        assert!(ranges.peek().is_some());
+        for range in ranges {
+            assert!(address_space::is_kernel_range(&range));
+            for address in kernel_addresses() {
+                assert!(!range.contains(&address), "{:#x} in {:#x?}", address, range);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_allocate_avoids_kernel_l4_entries() {
+        let mut page_allocator = crate::memory::PAGE_ALLOCATOR.lock();
+        for size in [PAGE_SIZE, 3 * PAGE_SIZE, 16 * PAGE_SIZE] {
+            let allocation = page_allocator.allocate(size).unwrap();
+            assert_eq!(allocation.len(), size);
+            let start = allocation.as_mut_ptr() as usize;
+            assert!(address_space::is_kernel_addr(start));
+            for address in kernel_addresses() {
+                assert_ne!(l4_index(start), l4_index(address));
+            }
+            page_allocator.deallocate(allocation.as_mut_ptr(), size);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+
+use super::alloc_track;
+use super::validate::{Issue, Report};
+
+// Guard bytes around every heap allocation, for catching buffer overruns (and underruns), eg. a
+// driver writing one descriptor too many into a DMA buffer. Enabled with
+// `--features alloc_redzone`, which brings alloc_track along to say who made the allocation.
+//
+// Each allocation gets a block laid out as
+//   | header | canary ... | the allocation | canary ... |
+// with the header recording where the allocation is, so `validate` can check every live block
+// alloc_track knows of, and free checks the block it's given before handing it back.
+// The overhead shows up in alloc_track's live bytes.
+
+const REDZONE: usize = 16;
+// Not zero or 0xff, which an overrun is most likely to write
+const CANARY: u8 = 0xfd;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Header {
+    // From the start of the block
+    offset: usize,
+    size: usize,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+// The block for an allocation, and where the allocation starts in it
+fn block_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(core::mem::align_of::<Header>());
+    let offset = (HEADER_SIZE + REDZONE).next_multiple_of(align);
+    let size = offset.checked_add(layout.size())?.checked_add(REDZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+// The block's header, if it and the canaries either side of the allocation are intact
+unsafe fn check(block: *const u8, block_size: usize) -> Option<Header> {
+    let header = block.cast::<Header>().read();
+    let end = header.offset.checked_add(header.size)?;
+    if header.offset < HEADER_SIZE + REDZONE || end.checked_add(REDZONE)? > block_size {
+        return None;
+    }
+    let intact = |mut range: Range<usize>| range.all(|i| block.add(i).read() == CANARY);
+    match intact(HEADER_SIZE..header.offset) && intact(end..block_size) {
+        true => Some(header),
+        false => None,
+    }
+}
+
+// Layered above TrackingAllocator (see allocator::REDZONE_ALLOCATOR), so alloc_track sees and
+// records whole blocks, redzones included
+pub struct RedzoneAllocator<A: GlobalAlloc + 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc + 'static> RedzoneAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        RedzoneAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for RedzoneAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (block_layout, offset) = match block_layout(layout) {
+            Some(block) => block,
+            None => return core::ptr::null_mut(),
+        };
+        let block = self.inner.alloc(block_layout);
+        if block.is_null() {
+            return block;
+        }
+        let size = layout.size();
+        block.cast::<Header>().write(Header { offset, size });
+        block
+            .add(HEADER_SIZE)
+            .write_bytes(CANARY, offset - HEADER_SIZE);
+        block
+            .add(offset + size)
+            .write_bytes(CANARY, block_layout.size() - offset - size);
+        block.add(offset)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (block_layout, offset) = block_layout(layout).unwrap();
+        let block = ptr.sub(offset);
+        let expected = Header {
+            offset,
+            size: layout.size(),
+        };
+        if check(block, block_layout.size()) != Some(expected) {
+            let block = block as usize;
+            let issue = Issue::RedzoneCorrupted {
+                block,
+                size: block_layout.size(),
+                site: alloc_track::site_of(block).unwrap_or(0),
+            };
+            panic!("{}", issue);
+        }
+        self.inner.dealloc(block, block_layout);
+    }
+}
+
+// Check the canaries of every live allocation alloc_track has room to know about
+pub fn validate(report: &mut Report) {
+    let checked = alloc_track::try_for_each(|block, size, site| {
+        if unsafe { check(block as *const u8, size) }.is_none() {
+            report.push(Issue::RedzoneCorrupted { block, size, site });
+        }
+    });
+    if checked.is_none() {
+        report.skip("heap redzones");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    #[test_case]
+    fn test_overrun_detected() {
+        let mut boxed = Box::new([0u8; 24]);
+        let end = unsafe { boxed.as_mut_ptr().add(boxed.len()) };
+        unsafe { end.write(0) };
+        let report = super::super::validate();
+        let here = test_overrun_detected as usize as u64;
+        let found = report.issues().any(|issue| match issue {
+            Issue::RedzoneCorrupted { site, .. } => {
+                crate::symbols::lookup(*site).map_or(false, |s| s.address == here)
+            }
+            _ => false,
+        });
+        // Put it back, or dropping the box panics
+        unsafe { end.write(CANARY) };
+        assert!(found, "{}", report);
+        drop(boxed);
+        let report = super::super::validate();
+        assert!(report.is_ok(), "{}", report);
+    }
+}
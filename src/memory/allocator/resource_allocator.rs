@@ -7,6 +7,8 @@ use hashbrown::HashMap;
 use crate::collections::hash_map::SimpleBuildHasher;
 use crate::collections::{DoublyLinkedList, DoublyLinkedListNode};
 
+use super::validate::{Issue, Report};
+
 /// Based on the VMem resource allocator design described in
 /// https://www.usenix.org/legacy/publications/library/proceedings/usenix01/full_papers/bonwick/bonwick.pdf
 
@@ -71,6 +73,21 @@ impl<A: Allocator + Clone> SegmentPtr<A> {
     }
 }
 
+// Why fast_allocate failed. If `free` covers `size` there's room in total, just not in one piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exhausted {
+    // Rounded up to the quantum
+    pub size: usize,
+    pub free: usize,
+    pub largest_free: usize,
+}
+
+impl Exhausted {
+    pub fn is_fragmented(&self) -> bool {
+        self.free >= self.size
+    }
+}
+
 // Pick M to be floor(log2(max(value)))
 // - so for instance if you want to have 2^16 process IDs, choose 16
 pub struct ResourceAllocator<
@@ -208,20 +225,40 @@ impl<const Q: usize, const M: usize, A: Allocator + Clone> ResourceAllocator<Q,
         }
     }
 
-    pub fn fast_allocate(&mut self, size: usize) -> Result<Range<usize>, ()> {
+    pub fn fast_allocate(&mut self, size: usize) -> Result<Range<usize>, Exhausted> {
         // ignore alignment for now
         // TODO: special case for zero-sized allocations?
-        let mut segment_ptr = self.fast_find_segment(size)?;
-        segment_ptr.segment_mut().freelist_ptr = None;
-        // split the segment if we can and put the new split in the associated freelist
         let qsize = size.div_ceil(Q).max(1);
         let alloc_size = qsize * Q;
+        let mut segment_ptr = match self.fast_find_segment(size) {
+            Ok(segment_ptr) => segment_ptr,
+            Err(()) => return Err(self.exhausted(alloc_size)),
+        };
+        segment_ptr.segment_mut().freelist_ptr = None;
+        // split the segment if we can and put the new split in the associated freelist
         self.try_split_segment(&mut segment_ptr, alloc_size);
         let range = segment_ptr.segment().range.clone();
         self.allocated_segments.insert(range.start, segment_ptr);
         Ok(range)
     }
 
+    // Only walks the segments once allocation has already failed
+    fn exhausted(&self, size: usize) -> Exhausted {
+        let (free, _) = self.usage();
+        let largest_free = self
+            .segments
+            .iter()
+            .filter(|segment| !segment.is_allocated())
+            .map(|segment| segment.size())
+            .max()
+            .unwrap_or(0);
+        Exhausted {
+            size,
+            free,
+            largest_free,
+        }
+    }
+
     fn try_split_segment(&mut self, segment_ptr: &mut SegmentPtr<A>, alloc_size: usize) {
         let segment = segment_ptr.segment_mut();
         let leftover_size = segment.size() - alloc_size;
@@ -236,6 +273,103 @@ impl<const Q: usize, const M: usize, A: Allocator + Clone> ResourceAllocator<Q,
         }
     }
 
+    // (range, allocated) for every segment, in address order
+    pub fn segments(&self) -> impl Iterator<Item = (Range<usize>, bool)> + '_ {
+        self.segments
+            .iter()
+            .map(|segment| (segment.range.clone(), segment.is_allocated()))
+    }
+
+    // (free, allocated) totals across all segments
+    pub fn usage(&self) -> (usize, usize) {
+        self.segments
+            .iter()
+            .fold((0, 0), |(free, allocated), segment| {
+                match segment.is_allocated() {
+                    true => (free, allocated + segment.size()),
+                    false => (free + segment.size(), allocated),
+                }
+            })
+    }
+
+    // Check segment ordering and coalescing, and that the freelists and allocation table agree
+    // with the segment list.
+    pub fn validate(&self, name: &'static str, report: &mut Report) {
+        let mut free_segments = 0;
+        let mut allocated_segments = 0;
+        let mut prev: Option<&Segment<A>> = None;
+        for segment in self.segments.iter() {
+            if let Some(prev) = prev {
+                if prev.range.end > segment.range.start {
+                    report.push(Issue::SegmentsOutOfOrder {
+                        allocator: name,
+                        first: prev.range.clone(),
+                        second: segment.range.clone(),
+                    });
+                } else if prev.can_join(segment) && !prev.is_allocated() && !segment.is_allocated()
+                {
+                    report.push(Issue::FreeSegmentsNotCoalesced {
+                        allocator: name,
+                        first: prev.range.clone(),
+                        second: segment.range.clone(),
+                    });
+                }
+            }
+            if segment.is_allocated() {
+                allocated_segments += 1;
+                let tracked = self
+                    .allocated_segments
+                    .get(&segment.range.start)
+                    .map_or(false, |ptr| core::ptr::eq(ptr.segment(), segment));
+                if !tracked {
+                    report.push(Issue::AllocatedSegmentUntracked {
+                        allocator: name,
+                        range: segment.range.clone(),
+                    });
+                }
+            } else {
+                free_segments += 1;
+            }
+            prev = Some(segment);
+        }
+        if allocated_segments != self.allocated_segments.len() {
+            report.push(Issue::AllocatedCountMismatch {
+                allocator: name,
+                allocated_segments,
+                tracked: self.allocated_segments.len(),
+            });
+        }
+
+        let mut freelist_entries = 0;
+        for (freelist_idx, freelist) in self.freelists.iter().enumerate() {
+            for segment_ptr in freelist.iter() {
+                freelist_entries += 1;
+                let segment = segment_ptr.segment();
+                if segment.is_allocated() {
+                    report.push(Issue::AllocatedSegmentInFreelist {
+                        allocator: name,
+                        range: segment.range.clone(),
+                        freelist: freelist_idx,
+                    });
+                } else if self.segment_freelist_idx(segment) != freelist_idx {
+                    report.push(Issue::FreeSegmentInWrongFreelist {
+                        allocator: name,
+                        range: segment.range.clone(),
+                        freelist: freelist_idx,
+                        expected: self.segment_freelist_idx(segment),
+                    });
+                }
+            }
+        }
+        if free_segments != freelist_entries {
+            report.push(Issue::FreelistCountMismatch {
+                allocator: name,
+                free_segments,
+                freelist_entries,
+            });
+        }
+    }
+
     pub fn release(&mut self, range: Range<usize>) {
         // Panic if we're given a range we didn't allocate
         let mut segment_ptr = self.allocated_segments.remove(&range.start).unwrap();
@@ -345,4 +479,35 @@ mod test {
         let _r2 = ra.fast_allocate(10).unwrap();
         assert!(ra.fast_allocate(1).is_err());
     }
+
+    #[test_case]
+    fn resource_allocator_fragmented() {
+        let mut ra = ResourceAllocator::<2>::new();
+        ra.add(0..8);
+        ra.add(10..18);
+        let exhausted = ra.fast_allocate(12).unwrap_err();
+        assert_eq!(exhausted.largest_free, 8);
+        assert!(exhausted.is_fragmented());
+        let _r1 = ra.fast_allocate(8).unwrap();
+        let exhausted = ra.fast_allocate(12).unwrap_err();
+        assert_eq!(exhausted.free, 8);
+        assert!(!exhausted.is_fragmented());
+    }
+
+    #[test_case]
+    fn validate_resource_allocator() {
+        let mut ra = ResourceAllocator::<2>::new();
+        ra.add(0..64);
+        ra.add(100..132);
+        let r1 = ra.fast_allocate(6).unwrap();
+        let r2 = ra.fast_allocate(30).unwrap();
+        let mut report = Report::new();
+        ra.validate("test", &mut report);
+        assert!(report.is_ok(), "{}", report);
+        ra.release(r1);
+        ra.release(r2);
+        let mut report = Report::new();
+        ra.validate("test", &mut report);
+        assert!(report.is_ok(), "{}", report);
+    }
 }
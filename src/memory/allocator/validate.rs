@@ -0,0 +1,225 @@
+use core::fmt;
+use core::ops::Range;
+
+use crate::symbols::Symbolized;
+
+// Consistency checking for the allocators' internal structures.
+// The report is fixed size, since we want to be able to produce one when the heap is exhausted.
+
+const MAX_ISSUES: usize = 16;
+const MAX_SKIPPED: usize = 4;
+
+#[derive(Debug, Clone)]
+pub enum Issue {
+    SegmentsOutOfOrder {
+        allocator: &'static str,
+        first: Range<usize>,
+        second: Range<usize>,
+    },
+    FreeSegmentsNotCoalesced {
+        allocator: &'static str,
+        first: Range<usize>,
+        second: Range<usize>,
+    },
+    FreeSegmentInWrongFreelist {
+        allocator: &'static str,
+        range: Range<usize>,
+        freelist: usize,
+        expected: usize,
+    },
+    AllocatedSegmentInFreelist {
+        allocator: &'static str,
+        range: Range<usize>,
+        freelist: usize,
+    },
+    FreelistCountMismatch {
+        allocator: &'static str,
+        free_segments: usize,
+        freelist_entries: usize,
+    },
+    AllocatedSegmentUntracked {
+        allocator: &'static str,
+        range: Range<usize>,
+    },
+    AllocatedCountMismatch {
+        allocator: &'static str,
+        allocated_segments: usize,
+        tracked: usize,
+    },
+    HeapPointerOutOfBounds {
+        next: usize,
+        heap: Range<usize>,
+    },
+    HeapPageNotMapped {
+        page: usize,
+    },
+    HeapPageNotWritable {
+        page: usize,
+    },
+    SlabBitmapMismatch {
+        slab: usize,
+        full: bool,
+        allocated_bits: u64,
+    },
+    // Written over the guard bytes around an allocation, see allocator::redzone
+    RedzoneCorrupted {
+        block: usize,
+        size: usize,
+        site: u64,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::SegmentsOutOfOrder {
+                allocator,
+                first,
+                second,
+            } => write!(
+                f,
+                "{}: segments out of order or overlapping: {:#x?} then {:#x?}",
+                allocator, first, second
+            ),
+            Issue::FreeSegmentsNotCoalesced {
+                allocator,
+                first,
+                second,
+            } => write!(
+                f,
+                "{}: adjacent free segments not coalesced: {:#x?} and {:#x?}",
+                allocator, first, second
+            ),
+            Issue::FreeSegmentInWrongFreelist {
+                allocator,
+                range,
+                freelist,
+                expected,
+            } => write!(
+                f,
+                "{}: free segment {:#x?} in freelist {}, expected {}",
+                allocator, range, freelist, expected
+            ),
+            Issue::AllocatedSegmentInFreelist {
+                allocator,
+                range,
+                freelist,
+            } => write!(
+                f,
+                "{}: allocated segment {:#x?} in freelist {}",
+                allocator, range, freelist
+            ),
+            Issue::FreelistCountMismatch {
+                allocator,
+                free_segments,
+                freelist_entries,
+            } => write!(
+                f,
+                "{}: {} free segments but {} freelist entries",
+                allocator, free_segments, freelist_entries
+            ),
+            Issue::AllocatedSegmentUntracked { allocator, range } => write!(
+                f,
+                "{}: allocated segment {:#x?} missing from allocation table",
+                allocator, range
+            ),
+            Issue::AllocatedCountMismatch {
+                allocator,
+                allocated_segments,
+                tracked,
+            } => write!(
+                f,
+                "{}: {} allocated segments but {} in allocation table",
+                allocator, allocated_segments, tracked
+            ),
+            Issue::HeapPointerOutOfBounds { next, heap } => write!(
+                f,
+                "kernel heap: next allocation {:#x} outside of heap {:#x?}",
+                next, heap
+            ),
+            Issue::HeapPageNotMapped { page } => {
+                write!(f, "kernel heap: page {:#x} not mapped", page)
+            }
+            Issue::HeapPageNotWritable { page } => {
+                write!(f, "kernel heap: page {:#x} not writable", page)
+            }
+            Issue::SlabBitmapMismatch {
+                slab,
+                full,
+                allocated_bits,
+            } => write!(
+                f,
+                "slab {:#x}: on {} list with allocation bitmap {:#066b}",
+                slab,
+                if *full { "full" } else { "available" },
+                allocated_bits
+            ),
+            Issue::RedzoneCorrupted { block, size, site } => write!(
+                f,
+                "kernel heap: redzone of block {:#x} ({} bytes) overwritten, allocated by {}",
+                block,
+                size,
+                Symbolized(*site)
+            ),
+        }
+    }
+}
+
+pub struct Report {
+    issues: [Option<Issue>; MAX_ISSUES],
+    count: usize,
+    // Structures we couldn't check, eg. because their lock was held
+    skipped: [Option<&'static str>; MAX_SKIPPED],
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            issues: [(); MAX_ISSUES].map(|_| None),
+            count: 0,
+            skipped: [None; MAX_SKIPPED],
+        }
+    }
+
+    pub fn push(&mut self, issue: Issue) {
+        if let Some(slot) = self.issues.get_mut(self.count) {
+            *slot = Some(issue);
+        }
+        // Keep counting past MAX_ISSUES so the report can say how many were dropped
+        self.count += 1;
+    }
+
+    pub fn skip(&mut self, name: &'static str) {
+        if let Some(slot) = self.skipped.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(name);
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn issues(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().flatten()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            write!(f, "allocator validation: ok")?;
+        } else {
+            write!(f, "allocator validation: {} issue(s)", self.count)?;
+        }
+        for issue in self.issues() {
+            write!(f, "\n  {}", issue)?;
+        }
+        if self.count > MAX_ISSUES {
+            write!(f, "\n  ... and {} more", self.count - MAX_ISSUES)?;
+        }
+        for name in self.skipped.iter().flatten() {
+            write!(f, "\n  {}: locked, not checked", name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+use super::oom;
+use crate::println;
+use crate::sync::Semaphore;
+use crate::task::scheduler;
+use crate::time::{self, tick};
+
+// Memory held for speed rather than need (object caches, magazines, ...), and the one place to
+// ask for it back. Caches register here, and are shrunk in registration order:
+// - when an allocation fails, by an OOM reclaimer (see memory::oom)
+// - when the kernel heap is getting full, by the memory pressure task, checked once a second
+// The `caches` shell command lists them.
+
+const MAX_CACHES: usize = 16;
+// Heap usage, in percent, that counts as pressure, and what to shrink back down to
+const PRESSURE_PERCENT: usize = 90;
+const TARGET_PERCENT: usize = 75;
+
+pub trait Cache: Sync {
+    // Bytes held that shrinking could give back
+    fn size(&self) -> usize;
+    // Hits and misses, for caches that count them
+    fn hits(&self) -> Option<(u64, u64)> {
+        None
+    }
+    // Free at least `target_bytes` if possible, returning how many bytes were freed
+    fn shrink(&self, target_bytes: usize) -> usize;
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: &'static str,
+    cache: &'static dyn Cache,
+}
+
+// Fixed size so shrinking works when the heap is full
+static CACHES: Mutex<[Option<Entry>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+// Upped by the tick when it's time to check the heap
+static CHECK: Semaphore = Semaphore::new(0);
+
+// Registering an existing name replaces it
+pub fn register(name: &'static str, cache: &'static dyn Cache) {
+    crate::without_interrupt! {{
+        let mut caches = CACHES.lock();
+        let index = caches
+            .iter()
+            .position(|c| matches!(c, Some(c) if c.name == name))
+            .or_else(|| caches.iter().position(|c| c.is_none()));
+        match index {
+            Some(index) => caches[index] = Some(Entry { name, cache }),
+            None => panic!("Too many caches registering {}", name),
+        }
+    }}
+}
+
+pub fn unregister(name: &'static str) {
+    crate::without_interrupt! {{
+        CACHES
+            .lock()
+            .iter_mut()
+            .filter(|c| matches!(c, Some(c) if c.name == name))
+            .for_each(|c| *c = None);
+    }}
+}
+
+// Copied out, so caches are free to (un)register while being shrunk
+fn caches() -> [Option<Entry>; MAX_CACHES] {
+    crate::without_interrupt! {{
+        let caches = *CACHES.lock();
+        caches
+    }}
+}
+
+// Shrink caches in order until `target_bytes` have been freed, returning how much was
+pub fn shrink(target_bytes: usize) -> usize {
+    let mut freed = 0;
+    for entry in caches().iter().flatten() {
+        if freed >= target_bytes {
+            break;
+        }
+        freed += entry.cache.shrink(target_bytes - freed);
+    }
+    freed
+}
+
+fn reclaim(layout: Layout) -> usize {
+    shrink(layout.size())
+}
+
+// How far the kernel heap is over the pressure mark, in bytes to get it back to the target
+fn heap_excess() -> usize {
+    match super::stats().heap {
+        Some(heap) if heap.used * 100 >= heap.size * PRESSURE_PERCENT => {
+            heap.used - heap.size * TARGET_PERCENT / 100
+        }
+        _ => 0,
+    }
+}
+
+fn pressure_task() {
+    loop {
+        CHECK.down();
+        match heap_excess() {
+            0 => (),
+            excess => {
+                shrink(excess);
+            }
+        }
+    }
+}
+
+fn tick_check(_now: u64) {
+    if CHECK.count() == 0 {
+        CHECK.up();
+    }
+}
+
+fn caches_command(_args: &[&str]) {
+    println!("{:<20} {:>10} {:>10}", "cache", "bytes", "hit rate");
+    for entry in caches().iter().flatten() {
+        match entry.cache.hits() {
+            Some((hits, misses)) => println!(
+                "{:<20} {:>10} {:>9}%",
+                entry.name,
+                entry.cache.size(),
+                hits * 100 / (hits + misses).max(1)
+            ),
+            None => println!("{:<20} {:>10} {:>10}", entry.name, entry.cache.size(), "-"),
+        }
+    }
+}
+
+// After driver::init, so the tick rate is the one the PIT ends up with
+pub fn init() {
+    oom::register("caches", reclaim);
+    scheduler::spawn("memory_pressure", pressure_task)
+        .expect("Failed to start the memory pressure task");
+    // Nothing allocates while the CPU idles
+    tick::register_deferrable("memory_pressure", time::ticks_per_second(), tick_check);
+    crate::shell::register(
+        "caches",
+        "list caches, their size and hit rate",
+        caches_command,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TestCache {
+        size: AtomicUsize,
+    }
+
+    impl Cache for TestCache {
+        fn size(&self) -> usize {
+            self.size.load(Ordering::Relaxed)
+        }
+
+        fn shrink(&self, target_bytes: usize) -> usize {
+            let size = self.size();
+            let freed = target_bytes.min(size);
+            self.size.store(size - freed, Ordering::Relaxed);
+            freed
+        }
+    }
+
+    static FIRST: TestCache = TestCache {
+        size: AtomicUsize::new(100),
+    };
+    static SECOND: TestCache = TestCache {
+        size: AtomicUsize::new(100),
+    };
+
+    #[test_case]
+    fn test_shrink_in_order_until_target() {
+        // Empty the caches registered at boot (eg. the page cache), which come ahead of these
+        shrink(usize::MAX);
+        register("test_first", &FIRST);
+        register("test_second", &SECOND);
+        assert!(shrink(150) >= 150);
+        assert_eq!(FIRST.size(), 0);
+        assert_eq!(SECOND.size(), 50);
+        unregister("test_first");
+        unregister("test_second");
+        assert!(caches()
+            .iter()
+            .flatten()
+            .all(|c| !c.name.starts_with("test_")));
+    }
+}
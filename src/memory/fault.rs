@@ -0,0 +1,157 @@
+use core::arch::global_asm;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::PageFaultError;
+
+// Reading memory that may not be mapped, without panicking.
+//
+// Accesses go through small assembly routines whose faulting instructions are listed in a fixup
+// table. When page_fault_handler sees a fault at one of those instructions it records the fault
+// and resumes at the matching fixup, which returns an error instead.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultInfo {
+    // Non-canonical addresses raise a general protection fault rather than a page fault,
+    // so we refuse them up front
+    NonCanonical(usize),
+    PageFault {
+        address: usize,
+        error: PageFaultError,
+    },
+}
+
+// probe_copy(destination: rdi, source: rsi, length: rdx) -> rax: 0 on success, 1 on fault
+global_asm!(
+    ".global sos_probe_copy",
+    "sos_probe_copy:",
+    "    cld",
+    "    mov rcx, rdx",
+    ".global sos_probe_copy_access",
+    "sos_probe_copy_access:",
+    "    rep movsb",
+    "    xor eax, eax",
+    "    ret",
+    ".global sos_probe_copy_fixup",
+    "sos_probe_copy_fixup:",
+    "    mov eax, 1",
+    "    ret",
+);
+
+extern "C" {
+    fn sos_probe_copy(destination: *mut u8, source: *const u8, length: usize) -> u64;
+    static sos_probe_copy_access: u8;
+    static sos_probe_copy_fixup: u8;
+}
+
+struct Fixup {
+    // Address of an instruction which is allowed to fault
+    instruction: u64,
+    // Where to resume if it does
+    resume: u64,
+}
+
+fn fixups() -> [Fixup; 1] {
+    unsafe {
+        [Fixup {
+            instruction: &sos_probe_copy_access as *const u8 as u64,
+            resume: &sos_probe_copy_fixup as *const u8 as u64,
+        }]
+    }
+}
+
+static FAULT_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static FAULT_ERROR: AtomicU32 = AtomicU32::new(0);
+
+// Called from page_fault_handler. Returns where to resume execution if the fault happened at an
+// instruction with a fixup, recording the fault for the probe to report.
+pub(crate) fn fixup(instruction_pointer: u64, address: u64, error: u64) -> Option<u64> {
+    let fixup = fixups()
+        .into_iter()
+        .find(|fixup| fixup.instruction == instruction_pointer)?;
+    FAULT_ADDRESS.store(address, Ordering::Relaxed);
+    FAULT_ERROR.store(error as u32, Ordering::Relaxed);
+    Some(fixup.resume)
+}
+
+fn is_canonical(address: usize) -> bool {
+    // Bits 48-63 must all match bit 47
+    let high = address >> 47;
+    high == 0 || high == (1 << 17) - 1
+}
+
+// Copy `length` bytes, reporting a fault on either side instead of panicking.
+// Safety: faults are caught, but a successful copy can still clobber anything at `destination`.
+pub(crate) unsafe fn probe_copy(
+    destination: *mut u8,
+    source: *const u8,
+    length: usize,
+) -> Result<(), FaultInfo> {
+    for address in [source as usize, destination as usize] {
+        let last_byte = address.wrapping_add(length.max(1) - 1);
+        if !is_canonical(address) || !is_canonical(last_byte) || last_byte < address {
+            return Err(FaultInfo::NonCanonical(address));
+        }
+    }
+    // Keep an interrupt handler's own probe from clobbering the recorded fault before we read it
+    crate::without_interrupt! {{
+        if sos_probe_copy(destination, source, length) != 0 {
+            return Err(FaultInfo::PageFault {
+                address: FAULT_ADDRESS.load(Ordering::Relaxed) as usize,
+                error: PageFaultError::from_bits_truncate(FAULT_ERROR.load(Ordering::Relaxed)),
+            });
+        }
+    }}
+    Ok(())
+}
+
+// Read a T from `address`, or report why we couldn't.
+// T should be plain old data (integers, arrays of them, ...); any bit pattern may come back.
+pub fn try_read<T: Copy>(address: usize) -> Result<T, FaultInfo> {
+    let mut value = MaybeUninit::<T>::uninit();
+    unsafe {
+        probe_copy(
+            value.as_mut_ptr() as *mut u8,
+            address as *const u8,
+            size_of::<T>(),
+        )?;
+        Ok(value.assume_init())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_try_read_mapped() {
+        let value: u64 = 0x1234_5678_9abc_def0;
+        let address = &value as *const u64 as usize;
+        assert_eq!(try_read::<u64>(address), Ok(value));
+        assert_eq!(try_read::<[u8; 2]>(address), Ok([0xf0, 0xde]));
+    }
+
+    #[test_case]
+    fn test_try_read_unmapped() {
+        // Nothing is mapped this far into the lower half
+        let address = 0x7000_0000_0000;
+        match try_read::<u32>(address) {
+            Err(FaultInfo::PageFault {
+                address: fault,
+                error,
+            }) => {
+                assert_eq!(fault, address);
+                assert!(!error.contains(PageFaultError::PRESENT));
+            }
+            other => panic!("Expected a page fault, got {:?}", other),
+        }
+    }
+
+    #[test_case]
+    fn test_try_read_non_canonical() {
+        assert_eq!(
+            try_read::<u8>(0x8000_0000_0000),
+            Err(FaultInfo::NonCanonical(0x8000_0000_0000))
+        );
+    }
+}
@@ -1,6 +1,9 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+
+use spin::Once;
 
 use super::PAGE_SIZE;
+use crate::boot::{MemoryMap, RegionKind};
 
 // I need to think about this more carefully. We really want the page allocator to have access
 // to some unsize data structures to be able to manage and reclaim pages, and eg. eventually try
@@ -19,9 +22,98 @@ pub(in crate::memory) fn usable_frames(
 ) -> impl Iterator<Item = usize> {
     memory_map
         .iter()
-        .filter(|r| r.region_type == MemoryRegionType::Usable)
-        .flat_map(|r| (r.range.start_frame_number..r.range.end_frame_number))
-        .map(|frame_number| frame_number as usize * PAGE_SIZE)
+        .filter(|r| r.kind == RegionKind::Usable)
+        .flat_map(|r| r.frames())
+        .map(|frame_number| frame_number * PAGE_SIZE)
+}
+
+// Physical memory that the page allocator never sees, in bytes, by what's in it. Tallied once
+// from the memory map when memory::init hands the rest to the page allocator, so memory::stats
+// can say where RAM went.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Reserved {
+    pub kernel_image: usize,
+    // The bootloader's, that we're still running on
+    pub page_tables: usize,
+    pub boot_stacks: usize,
+    // Boot info and anything else the bootloader kept
+    pub bootloader: usize,
+    pub modules: usize,
+    // ACPI tables and ranges the firmware reserved
+    pub firmware: usize,
+    pub bad: usize,
+    // Usable frames the kernel heap was bootstrapped from, before the page allocator was up
+    pub early_heap: usize,
+}
+
+static RESERVED: Once<Reserved> = Once::new();
+
+fn tally(memory_map: &MemoryMap, early_heap_frames: usize) -> Reserved {
+    let mut reserved = Reserved {
+        early_heap: early_heap_frames * PAGE_SIZE,
+        ..Reserved::default()
+    };
+    for region in memory_map.iter() {
+        let total = match region.kind {
+            RegionKind::Usable => continue,
+            RegionKind::Kernel => &mut reserved.kernel_image,
+            RegionKind::PageTable => &mut reserved.page_tables,
+            RegionKind::BootStack => &mut reserved.boot_stacks,
+            RegionKind::Bootloader => &mut reserved.bootloader,
+            RegionKind::Module => &mut reserved.modules,
+            RegionKind::AcpiReclaimable | RegionKind::AcpiNvs | RegionKind::Reserved => {
+                &mut reserved.firmware
+            }
+            RegionKind::BadMemory => &mut reserved.bad,
+        };
+        *total += region.end - region.start;
+    }
+    reserved
+}
+
+pub(in crate::memory) fn account(memory_map: &MemoryMap, early_heap_frames: usize) {
+    RESERVED.call_once(|| tally(memory_map, early_heap_frames));
+}
+
+// None until memory::init
+pub fn reserved() -> Option<Reserved> {
+    RESERVED.get().copied()
+}
+
+impl Reserved {
+    pub fn total(&self) -> usize {
+        self.kernel_image
+            + self.page_tables
+            + self.boot_stacks
+            + self.bootloader
+            + self.modules
+            + self.firmware
+            + self.bad
+            + self.early_heap
+    }
+}
+
+// eg. "kernel image 1024 KiB, boot page tables 20 KiB, ..."
+impl fmt::Display for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            ("kernel image", self.kernel_image),
+            ("boot page tables", self.page_tables),
+            ("boot stacks", self.boot_stacks),
+            ("bootloader", self.bootloader),
+            ("modules", self.modules),
+            ("firmware", self.firmware),
+            ("bad", self.bad),
+            ("early heap", self.early_heap),
+        ];
+        for (i, (name, bytes)) in parts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {} KiB", name, bytes / 1024)?;
+        }
+        Ok(())
+    }
 }
 
 // impl FrameAllocator {
@@ -34,3 +126,37 @@ pub(in crate::memory) fn usable_frames(
 //     fn allocate_page(&mut self) -> u64 {}
 //     fn deallocate_page(&mut self, page: u64) {}
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_tally() {
+        let mut map = MemoryMap::new();
+        map.push(0x1000, 0x9_f000, RegionKind::Usable).unwrap();
+        map.push(0x9_f000, 0x10_0000, RegionKind::Reserved).unwrap();
+        map.push(0x10_0000, 0x20_0000, RegionKind::Kernel).unwrap();
+        map.push(0x20_0000, 0x20_5000, RegionKind::PageTable)
+            .unwrap();
+        map.push(0x20_5000, 0x20_7000, RegionKind::BootStack)
+            .unwrap();
+        map.push(0x20_7000, 0x80_0000, RegionKind::Usable).unwrap();
+        let reserved = tally(&map, 3);
+        assert_eq!(
+            reserved,
+            Reserved {
+                kernel_image: 0x10_0000,
+                page_tables: 0x5000,
+                boot_stacks: 0x2000,
+                firmware: 0x6_1000,
+                early_heap: 0x3000,
+                ..Reserved::default()
+            }
+        );
+        assert_eq!(
+            reserved.total(),
+            0x10_0000 + 0x5000 + 0x2000 + 0x6_1000 + 0x3000
+        );
+    }
+}
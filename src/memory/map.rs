@@ -0,0 +1,152 @@
+use alloc::format;
+use core::ops::Range;
+
+use super::page_table::{self, PageTableFlags};
+use super::{translate_virtual_address, PAGE_SIZE};
+use crate::arch::{Arch, Mmu};
+use crate::boot::{MemoryMap, RegionKind};
+use crate::println;
+
+// The bootloader's memory map as a table, and an audit of where the kernel put its own structures:
+// the kernel heap's frames must be usable RAM, every page table must be usable RAM or one the
+// bootloader built, and device memory must stay out of the RAM the page allocator hands out.
+// Anything else means we're scribbling over the kernel image, a boot stack or firmware tables, or
+// handing out a device's registers as a free frame, so it fails the boot.
+
+const VGA_TEXT_BUFFER: Range<usize> = 0xb8000..0xb8000 + 80 * 25 * 2;
+
+// Regions page tables may live in: ours come from usable RAM, the bootloader's from its own
+fn page_table_memory(kind: RegionKind) -> bool {
+    use RegionKind::*;
+    matches!(kind, Usable | PageTable | Bootloader)
+}
+
+fn region_kind_at(memory_map: &MemoryMap, address: usize) -> Option<RegionKind> {
+    memory_map
+        .iter()
+        .find(|r| r.range().contains(&address))
+        .map(|r| r.kind)
+}
+
+// Every page table frame reachable from the root table
+fn for_each_page_table(f: &mut dyn FnMut(usize)) {
+    let is_table = |flags: PageTableFlags| !flags.contains(PageTableFlags::HUGE_PAGE);
+    f(Arch::page_table_root());
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    for l4_entry in l4_table.iter().filter(|e| e.present()) {
+        f(l4_entry.pointer());
+        let l3_table = match l4_entry.deref() {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+        for l3_entry in l3_table
+            .iter()
+            .filter(|e| e.present() && is_table(e.flags()))
+        {
+            f(l3_entry.pointer());
+            let l2_table = match l3_entry.deref() {
+                Ok(table) => table,
+                Err(_) => continue,
+            };
+            l2_table
+                .iter()
+                .filter(|e| e.present() && is_table(e.flags()))
+                .for_each(|l2_entry| f(l2_entry.pointer()));
+        }
+    }
+}
+
+// Returns how many problems were found, printing each
+fn audit(memory_map: &MemoryMap) -> usize {
+    let mut problems = 0;
+    let mut check_frame = |what: &str, frame: usize, allowed: fn(RegionKind) -> bool| {
+        let kind = region_kind_at(memory_map, frame);
+        if !kind.map_or(false, allowed) {
+            println!(
+                "memory map: {} frame {:#x} is in {:?} memory",
+                what, frame, kind
+            );
+            problems += 1;
+        }
+    };
+
+    for page in super::allocator::kernel_heap().step_by(PAGE_SIZE) {
+        // Unmapped heap pages are allocator::validate's to report
+        if let Ok(frame) = translate_virtual_address(page) {
+            check_frame("kernel heap", frame, |kind| kind == RegionKind::Usable);
+        }
+    }
+    for_each_page_table(&mut |frame| check_frame("page table", frame, page_table_memory));
+
+    let mut mmio = [Some(VGA_TEXT_BUFFER), None];
+    if let Some(framebuffer) = crate::boot::framebuffer() {
+        let size = framebuffer.stride * framebuffer.height * framebuffer.bytes_per_pixel;
+        mmio[1] = Some(framebuffer.address..framebuffer.address + size);
+    }
+    for window in mmio.into_iter().flatten() {
+        let overlapping = memory_map.iter().filter(|r| {
+            r.kind == RegionKind::Usable && r.start < window.end && window.start < r.end
+        });
+        for region in overlapping {
+            println!(
+                "memory map: MMIO window {:#x?} overlaps usable RAM {:#x}..{:#x}",
+                window, region.start, region.end
+            );
+            problems += 1;
+        }
+    }
+    problems
+}
+
+// Print the memory map, then audit it. Needs the heap and the page allocator up.
+pub fn report_map() {
+    let config = crate::boot::config();
+    let memory_map = crate::boot::memory_map();
+    println!("memory map from {}", config.protocol);
+    println!("{:<34} {:<16} {:>10}", "physical memory", "type", "size");
+    for region in memory_map.iter() {
+        let (start, end) = (region.start, region.end);
+        println!(
+            "{:#016x}..{:#016x} {:<16} {:>6} KiB",
+            start,
+            end,
+            format!("{:?}", region.kind),
+            (end - start) / 1024
+        );
+    }
+    let problems = audit(memory_map);
+    assert!(
+        problems == 0,
+        "Memory map audit found {} problem(s)",
+        problems
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_page_table_in_kernel_memory() {
+        let memory_map = crate::boot::memory_map();
+        let clean = audit(memory_map);
+        // The same map, but with the root page table's frame in the kernel image
+        let root = Arch::page_table_root();
+        let mut bad_map = MemoryMap::new();
+        for region in memory_map.iter() {
+            if !region.range().contains(&root) {
+                bad_map.push(region.start, region.end, region.kind).unwrap();
+                continue;
+            }
+            bad_map.push(region.start, root, region.kind).unwrap();
+            bad_map
+                .push(root, root + PAGE_SIZE, RegionKind::Kernel)
+                .unwrap();
+            bad_map
+                .push(root + PAGE_SIZE, region.end, region.kind)
+                .unwrap();
+        }
+        assert_eq!(region_kind_at(&bad_map, root), Some(RegionKind::Kernel));
+        assert_eq!(audit(&bad_map), clean + 1);
+    }
+}
@@ -1,30 +1,54 @@
+use core::fmt;
+use core::ops::Range;
+
 use bitflags::bitflags;
-use bootloader::BootInfo;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
+pub mod address_space;
 pub mod allocator;
+pub mod cache_registry;
+pub mod fault;
 pub mod frame_allocator;
+pub mod map;
+pub mod oom;
 pub mod page_table;
+pub mod relocate;
+pub mod scrub;
+pub mod tlb;
+pub mod usercopy;
 
+use crate::arch::{Arch, Mmu};
+use crate::boot::{self, KernelConfig, RegionKind};
+use crate::elf::ElfFile;
+use crate::error::{KError, KResult};
 use allocator::page_allocator::PageAllocator;
-use page_table::Err;
+pub use allocator::{stats, Stats};
+pub use fault::{try_read, FaultInfo};
+pub use map::report_map;
+use page_table::{Err, PageTableFlags};
 
-const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+const GIGANTIC_PAGE_SIZE: usize = 1024 * 1024 * 1024;
 
-lazy_static! {
-    static ref _PHYSICAL_MEMORY_OFFSET: Mutex<usize> = Mutex::new(0);
-    static ref PHYSICAL_MEMORY_OFFSET: usize = *_PHYSICAL_MEMORY_OFFSET.lock();
-}
+// Physical range of the kernel ELF file, which the bootloader leaves loaded in memory
+static KERNEL_IMAGE: Once<(usize, usize)> = Once::new();
 
 lazy_static! {
     static ref PAGE_ALLOCATOR: Mutex<PageAllocator> = Mutex::new(PageAllocator::new());
 }
 
-pub fn init(boot_info: &'static BootInfo) {
-    // This is done exactly once, before anyone has accessed PHYSICAL_MEMORY_OFFSET,
-    // creating an immutable value we can set at runtime.
-    *_PHYSICAL_MEMORY_OFFSET.lock() = boot_info.physical_memory_offset as usize;
+pub fn init(config: &'static KernelConfig) {
+    if let Some(region) = config
+        .memory_map
+        .iter()
+        .find(|r| r.kind == RegionKind::Kernel)
+    {
+        KERNEL_IMAGE.call_once(|| (region.start, region.end));
+    }
+    relocate::init();
+    address_space::set_kernel_base(free_kernel_base());
     // available_frames is a global bootstrap of physical memory pages.
     // - On first iteration of frame_allocator::usable_frames, every frame is guaranteed to be unused
     //   physical memory and safe to map to pages.
@@ -35,7 +59,7 @@ pub fn init(boot_info: &'static BootInfo) {
     // - Any frames yielded must either be semantically &'static, or be manually passed to
     //   FRAME_ALLOCATOR.dealloc(frame) so that it may reuse them.
     //   - I should eventually find a way to encode this in the type system
-    let mut available_frames = frame_allocator::usable_frames(&boot_info.memory_map);
+    let mut available_frames = frame_allocator::usable_frames(&config.memory_map);
     let mut allocated_frames: usize = 0;
     unsafe {
         allocator::init_kernel_heap(&mut || {
@@ -48,8 +72,21 @@ pub fn init(boot_info: &'static BootInfo) {
     // Now that the bootstrap allocator is initialized, we can start doing more complicated things!
     // Let's initialize our arena-based page allocator.
     unsafe {
-        (*PAGE_ALLOCATOR.lock()).init(&boot_info.memory_map, allocated_frames);
+        (*PAGE_ALLOCATOR.lock()).init(&config.memory_map, allocated_frames);
     };
+    frame_allocator::account(&config.memory_map, allocated_frames);
+    address_space::init();
+    report_map();
+}
+
+// The first l4 entry in the kernel half that the bootloader left empty, so the kernel heap can't
+// land on its physical memory mapping, or our image or stack
+fn free_kernel_base() -> usize {
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    (address_space::KERNEL_START..address_space::KERNEL_END)
+        .step_by(address_space::KERNEL_BASE_ALIGN)
+        .find(|&base| !l4_table[base / address_space::KERNEL_BASE_ALIGN % 512].present())
+        .expect("No free l4 entry for the kernel heap")
 }
 
 bitflags! {
@@ -67,11 +104,60 @@ bitflags! {
     }
 }
 
+impl PageFaultError {
+    pub fn from_error_code(error: u64) -> Self {
+        Self::from_bits_truncate(error as u32)
+    }
+}
+
+// eg. "write to a present page in user mode"
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match (
+            self.contains(Self::INSTRUCTION_FETCH),
+            self.contains(Self::WRITE),
+        ) {
+            (true, _) => "instruction fetch from",
+            (false, true) => "write to",
+            (false, false) => "read from",
+        };
+        let page = match self.contains(Self::PRESENT) {
+            true => "a present",
+            false => "a non-present",
+        };
+        let mode = match self.contains(Self::USER_MODE) {
+            true => "user",
+            false => "kernel",
+        };
+        write!(f, "{} {} page in {} mode", access, page, mode)?;
+        let reasons = [
+            (
+                Self::RESERVED_WRITE,
+                "reserved bit set in a page table entry",
+            ),
+            (Self::PROTECTION_KEY, "protection key violation"),
+            (Self::SHADOW_STACK, "shadow stack access"),
+            (Self::SOFTWARE_GUARD_EXTENSION, "SGX access violation"),
+        ];
+        for (flag, reason) in reasons {
+            if self.contains(flag) {
+                write!(f, ", {}", reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 // Doesn't need to be unsafe because casting the pointer to anything
 // is already unsafe
 #[inline]
-fn physical_to_virtual(address: usize) -> usize {
-    address + *PHYSICAL_MEMORY_OFFSET
+pub(crate) fn physical_to_virtual(address: usize) -> usize {
+    address + physical_memory_offset()
+}
+
+#[inline]
+fn physical_memory_offset() -> usize {
+    boot::config().physical_memory_offset
 }
 
 // I actually really like the x86_64 VirtAddr/PhysAddr types, TODO to
@@ -85,13 +171,202 @@ pub fn translate_virtual_address(address: usize) -> Result<usize, Err> {
     ];
     let l4_table = unsafe { page_table::l4::PageTable::get() };
     let l3_table = l4_table[l4_index].deref()?;
-    let l2_table = l3_table[l3_index].deref()?;
-    let l1_table = l2_table[l2_index].deref()?;
+    let l3_entry = &l3_table[l3_index];
+    if l3_entry.present() && l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let frame = l3_entry.pointer() & !(GIGANTIC_PAGE_SIZE - 1);
+        return Ok(frame + (address & (GIGANTIC_PAGE_SIZE - 1)));
+    }
+    let l2_table = l3_entry.deref()?;
+    let l2_entry = &l2_table[l2_index];
+    if l2_entry.present() && l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        // Bit 12 of a huge page entry is PAT, not part of the address
+        let frame = l2_entry.pointer() & !(HUGE_PAGE_SIZE - 1);
+        return Ok(frame + (address & (HUGE_PAGE_SIZE - 1)));
+    }
+    let l1_table = l2_entry.deref()?;
     let l1_entry = &l1_table[l1_index];
     let _memory_block = l1_entry.deref()?;
     Ok(l1_entry.pointer() + (address & 0xFFF))
 }
 
+pub fn kernel_image() -> Option<ElfFile<'static>> {
+    let (start, end) = *KERNEL_IMAGE.get()?;
+    let data = unsafe {
+        core::slice::from_raw_parts(physical_to_virtual(start) as *const u8, end - start)
+    };
+    ElfFile::new(data).ok()
+}
+
+pub fn page_flags(address: usize) -> Result<PageTableFlags, Err> {
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    l4_table.leaf_flags(address)
+}
+
+// A zeroed frame for the kernel to keep data in, eg. the page cache. Returns its physical address;
+// the CPU gets at it through physical_to_virtual.
+pub fn allocate_frame() -> KResult<usize> {
+    PAGE_ALLOCATOR
+        .lock()
+        .allocate_frame()
+        .map(|frame| frame.as_mut_ptr() as usize)
+        .map_err(|failure| KError::from(failure).with_message("allocating a frame"))
+}
+
+pub fn deallocate_frame(frame: usize) {
+    PAGE_ALLOCATOR.lock().deallocate_unzeroed_frame(frame);
+}
+
+// False while the page allocator is locked. A reclaimer (see memory::oom) may be running inside
+// an allocation the page allocator is making, so it mustn't free frames then.
+pub fn can_free_frames() -> bool {
+    !PAGE_ALLOCATOR.is_locked()
+}
+
+// Virtual space to map pages into later, eg. for a memory-mapped file (see fs::mmap)
+pub fn reserve(size: usize) -> KResult<Range<usize>> {
+    PAGE_ALLOCATOR
+        .lock()
+        .reserve(size)
+        .map_err(|failure| KError::from(failure).with_message("reserving address space"))
+}
+
+// Everything mapped in the range must have been unmapped
+pub fn release(range: Range<usize>) {
+    PAGE_ALLOCATOR.lock().release(range);
+}
+
+// Map a frame someone else keeps alive (eg. a page cache page) at `page`, until unmap_frame
+pub fn map_frame(page: usize, frame: usize, flags: PageTableFlags) -> KResult<()> {
+    PAGE_ALLOCATOR
+        .lock()
+        .map_borrowed(page, frame, flags)
+        .map_err(KError::from)
+}
+
+// The flags `page` was mapped with (eg. DIRTY), or None if it wasn't mapped
+pub fn unmap_frame(page: usize) -> Option<PageTableFlags> {
+    PAGE_ALLOCATOR.lock().unmap_borrowed(page)
+}
+
+// Clear the dirty bit of a mapped page, returning whether it was set
+pub fn take_dirty(page: usize) -> bool {
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    match l4_table.entry_mut(page) {
+        Ok(entry) if entry.present() && entry.flags().contains(PageTableFlags::DIRTY) => {
+            entry.set_flags(entry.flags() - PageTableFlags::DIRTY);
+            tlb::flush(page);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Set the dirty bit again, eg. when writing a page back failed. Nothing if it isn't mapped.
+pub fn mark_dirty(page: usize) {
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    if let Ok(entry) = l4_table.entry_mut(page) {
+        if entry.present() {
+            entry.set_flags(entry.flags() | PageTableFlags::DIRTY);
+        }
+    }
+}
+
+// Physically contiguous, zeroed memory for a device to DMA to or from. Returns its physical range;
+// the CPU gets at it through physical_to_virtual.
+pub fn allocate_dma(size: usize) -> KResult<Range<usize>> {
+    PAGE_ALLOCATOR
+        .lock()
+        .allocate_contiguous(size)
+        .map_err(|failure| KError::from(failure).with_message("allocating DMA memory"))
+}
+
+pub fn deallocate_dma(range: Range<usize>) {
+    PAGE_ALLOCATOR.lock().deallocate_contiguous(range);
+}
+
+// Make writes to [start, end) write-combining, eg. a framebuffer: the CPU batches them into
+// bursts instead of sending each one to the device as an uncached write
+pub fn set_write_combining(start: usize, end: usize) -> KResult<()> {
+    set_cache_flags(
+        start,
+        end,
+        PageTableFlags::WRITE_COMBINING,
+        PageTableFlags::NO_CACHE,
+    )
+}
+
+// Make [start, end) uncached, eg. device registers: every read and write goes to the device, in
+// program order, rather than being served from or combined in the cache
+pub fn set_uncached(start: usize, end: usize) -> KResult<()> {
+    set_cache_flags(
+        start,
+        end,
+        PageTableFlags::NO_CACHE,
+        PageTableFlags::WRITE_COMBINING,
+    )
+}
+
+// Huge pages are refused (ErrorCode::HugePage) rather than split: their bit 7 is HUGE_PAGE, with
+// the PAT bit moved up to bit 12, so they'd need their own flags. Every page is checked before
+// any is changed, so a refused range is left as it was.
+fn set_cache_flags(
+    start: usize,
+    end: usize,
+    set: PageTableFlags,
+    clear: PageTableFlags,
+) -> KResult<()> {
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    let pages = || (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE);
+    for page in pages() {
+        l4_table.entry_mut(page)?;
+    }
+    for page in pages() {
+        let entry = l4_table.entry_mut(page)?;
+        entry.set_flags((entry.flags() - clear) | set);
+    }
+    tlb::flush_range(start, end);
+    Ok(())
+}
+
+// Remap the kernel's own sections with the least permissions they need: .text is read+execute,
+// .rodata is read-only, and only data/bss stay writable (and never executable). Stray writes
+// through wild pointers then fault immediately instead of silently corrupting code.
+pub fn protect_kernel() {
+    let image = kernel_image().expect("Kernel image not found in memory map");
+    unsafe { Arch::enforce_page_permissions() };
+
+    let l4_table = unsafe { page_table::l4::PageTable::get() };
+    let sections = || image.sections().filter(|s| s.is_alloc() && s.address != 0);
+    for section in sections() {
+        let start = section.address as usize & !(PAGE_SIZE - 1);
+        let end = (section.address + section.size) as usize;
+        for page in (start..end).step_by(PAGE_SIZE) {
+            // Sections can share a page, so the page gets the union of their permissions
+            let overlapping = || {
+                sections().filter(move |s| {
+                    (s.address as usize) < page + PAGE_SIZE && page < (s.address + s.size) as usize
+                })
+            };
+            let mut flags = PageTableFlags::PRESENT;
+            if overlapping().any(|s| s.is_writable()) {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !overlapping().any(|s| s.is_executable()) {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            match l4_table.entry_mut(page) {
+                Ok(entry) => {
+                    let preserved =
+                        entry.flags() & !(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+                    entry.set_flags(preserved | flags);
+                    tlb::flush(page);
+                }
+                Err(err) => panic!("Kernel page {:#x} not mapped: {:?}", page, err),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -101,11 +376,11 @@ mod test {
         assert_eq!(0xb8000, translate_virtual_address(0xb8000).unwrap());
     }
 
-    #[allow(dead_code)] //#[test_case]  // huge page support not implemented yet
+    #[test_case]
     fn test_physical_adress_offset_maps_to_0() {
         assert_eq!(
             0,
-            translate_virtual_address(*PHYSICAL_MEMORY_OFFSET).unwrap()
+            translate_virtual_address(physical_memory_offset()).unwrap()
         );
     }
 
@@ -117,6 +392,45 @@ mod test {
         }
     }
 
+    #[test_case]
+    fn test_write_combining_refuses_huge_pages() {
+        // The bootloader maps physical memory with 2MiB pages
+        let start = physical_memory_offset();
+        let flags = page_flags(start).unwrap();
+        assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+        assert!(set_write_combining(start, start + PAGE_SIZE).is_err());
+        assert_eq!(page_flags(start).unwrap(), flags);
+    }
+
+    #[test_case]
+    fn test_kernel_text_not_writable() {
+        let text = test_kernel_text_not_writable as fn() as usize;
+        let flags = page_flags(text).unwrap();
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn test_kernel_rodata_not_executable() {
+        static RODATA: [u8; 4] = [1, 2, 3, 4];
+        let flags = page_flags(&RODATA as *const _ as usize).unwrap();
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn test_page_fault_error_display() {
+        use alloc::format;
+        let error = PageFaultError::from_error_code(0x7);
+        assert_eq!(format!("{}", error), "write to a present page in user mode");
+        let error = PageFaultError::from_error_code(0x18);
+        assert_eq!(
+            format!("{}", error),
+            "instruction fetch from a non-present page in kernel mode, reserved bit set in a page \
+             table entry"
+        );
+    }
+
     // TODO: test invlpg for updated pages
     // TODO: huge pages
 }
@@ -0,0 +1,138 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+// Allocation failure policy. Subsystems holding memory they could give back (caches, scrollback,
+// ...) register a reclaim callback; when an allocation fails the callbacks run in registration
+// order, and the allocation is retried after each one that frees something. Only once they've
+// all come up empty does the allocation fail, and alloc_error_handler panics.
+
+const MAX_RECLAIMERS: usize = 8;
+
+// Given the failed allocation, free what memory we can and return how many bytes were released
+pub type ReclaimFn = fn(Layout) -> usize;
+
+#[derive(Clone, Copy)]
+struct Reclaimer {
+    name: &'static str,
+    reclaim: ReclaimFn,
+}
+
+// Fixed size so registration and reclaim work when the heap is full
+static RECLAIMERS: Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> = Mutex::new([None; MAX_RECLAIMERS]);
+
+// Set while reclaim callbacks run, so an allocation failing inside one doesn't recurse
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+pub fn register(name: &'static str, reclaim: ReclaimFn) {
+    crate::without_interrupt! {{
+        let mut reclaimers = RECLAIMERS.lock();
+        let reclaimer = Reclaimer { name, reclaim };
+        let index = reclaimers
+            .iter()
+            .position(|r| matches!(r, Some(r) if r.name == name))
+            .or_else(|| reclaimers.iter().position(|r| r.is_none()));
+        match index {
+            Some(index) => reclaimers[index] = Some(reclaimer),
+            None => panic!("Too many OOM reclaimers registering {}", name),
+        }
+    }}
+}
+
+pub fn unregister(name: &'static str) {
+    crate::without_interrupt! {{
+        RECLAIMERS
+            .lock()
+            .iter_mut()
+            .filter(|r| matches!(r, Some(r) if r.name == name))
+            .for_each(|r| *r = None);
+    }}
+}
+
+// Run reclaimers in order until `retry` succeeds, returning its result (or null)
+fn reclaim_and_retry(layout: Layout, mut retry: impl FnMut() -> *mut u8) -> *mut u8 {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return core::ptr::null_mut();
+    }
+    // Copy the list out so callbacks are free to (un)register
+    let reclaimers = crate::without_interrupt! {{
+        let reclaimers = *RECLAIMERS.lock();
+        reclaimers
+    }};
+    let mut ptr = core::ptr::null_mut();
+    for reclaimer in reclaimers.iter().flatten() {
+        if (reclaimer.reclaim)(layout) > 0 {
+            ptr = retry();
+            if !ptr.is_null() {
+                break;
+            }
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    ptr
+}
+
+// Wraps the real global allocator, running the reclaimers before giving up on an allocation
+pub struct ReclaimingAllocator<A: GlobalAlloc + 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc + 'static> ReclaimingAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        ReclaimingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for ReclaimingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "alloc_audit")]
+        super::allocator::audit::check("allocation", layout);
+        super::allocator::histogram::record(layout.size());
+        match self.inner.alloc(layout) {
+            ptr if ptr.is_null() => reclaim_and_retry(layout, || self.inner.alloc(layout)),
+            ptr => ptr,
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc_audit")]
+        super::allocator::audit::check("free", layout);
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn reclaim_nothing(_: Layout) -> usize {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+
+    fn reclaim_something(_: Layout) -> usize {
+        CALLS.fetch_add(10, Ordering::Relaxed);
+        64
+    }
+
+    #[test_case]
+    fn test_reclaimers_run_in_order_until_retry_succeeds() {
+        register("test_nothing", reclaim_nothing);
+        register("test_something", reclaim_something);
+        CALLS.store(0, Ordering::Relaxed);
+        let mut backing = 0u8;
+        let ptr = reclaim_and_retry(Layout::new::<u8>(), || &mut backing as *mut u8);
+        assert!(!ptr.is_null());
+        assert_eq!(CALLS.load(Ordering::Relaxed), 11);
+
+        // Nothing freed, so no retry and the allocation stays failed
+        unregister("test_something");
+        let ptr = reclaim_and_retry(Layout::new::<u8>(), || panic!("retried without reclaiming"));
+        assert!(ptr.is_null());
+        unregister("test_nothing");
+    }
+}
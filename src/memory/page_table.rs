@@ -1,11 +1,14 @@
-use core::arch::asm;
+use bitflags::bitflags;
 use core::fmt;
 use core::ops::{Deref, DerefMut, Index, IndexMut};
 use core::result::Result;
 use core::slice::{Iter, IterMut};
 
+use crate::arch::{Arch, Mmu};
+use crate::error::{ErrorCode, KError};
+
 macro_rules! page_table {
-    ($page_table_name:ident -> $points_to:ty) => {
+    ($page_table_name:ident -> $points_to:ty, zero_new: $zero_new:expr) => {
         pub mod $page_table_name {
             use super::*;
 
@@ -23,32 +26,72 @@ macro_rules! page_table {
                 }
 
                 pub fn set_not_present(&mut self) {
-                    self.0 |= 0x1;
+                    self.0 &= !0x1;
                 }
 
                 pub fn set_present(&mut self) {
-                    self.0 &= !0x1;
+                    self.0 |= 0x1;
                 }
 
                 pub fn present(&self) -> bool {
                     self.0 & 0x1 != 0
                 }
 
+                pub fn flags(&self) -> PageTableFlags {
+                    PageTableFlags::from_bits_truncate(self.0)
+                }
+
+                // Doesn't flush the TLB; callers changing a live mapping must memory::tlb::flush it
+                pub fn set_flags(&mut self, flags: PageTableFlags) {
+                    self.0 = (self.0 & !PageTableFlags::all().bits()) | flags.bits();
+                }
+
                 pub fn deref(&self) -> Result<&$points_to, Err> {
                     if !self.present() {
                         Err(Err::PageNotPresent)
                     } else {
-                        Ok(unsafe { &*(crate::memory::physical_to_virtual(self.pointer()) as *mut $points_to) })
+                        Ok(unsafe {
+                            &*(crate::memory::physical_to_virtual(self.pointer())
+                                as *mut $points_to)
+                        })
                     }
                 }
 
-                pub fn deref_mut_or_map(&mut self, next_frame: &mut dyn FnMut() -> usize) -> &mut $points_to {
+                pub fn try_deref_mut(&mut self) -> Result<&mut $points_to, Err> {
                     if !self.present() {
-                        // TODO: initialize frame to empty page table
-                        self.0 = next_frame() as u64 | 0x63; // TODO flags
+                        Err(Err::PageNotPresent)
+                    } else {
+                        Ok(unsafe {
+                            &mut *(crate::memory::physical_to_virtual(self.pointer())
+                                as *mut $points_to)
+                        })
+                    }
+                }
+
+                pub fn deref_mut_or_map(
+                    &mut self,
+                    next_frame: &mut dyn FnMut() -> usize,
+                ) -> &mut $points_to {
+                    // Can't fail, there's always a frame
+                    self.try_deref_mut_or_map(&mut || Some(next_frame()))
+                        .unwrap()
+                }
+
+                // Err::OutOfFrames if the table isn't there and next_frame has no frame for it
+                pub fn try_deref_mut_or_map(
+                    &mut self,
+                    next_frame: &mut dyn FnMut() -> Option<usize>,
+                ) -> Result<&mut $points_to, Err> {
+                    if !self.present() {
+                        let frame = next_frame().ok_or(Err::OutOfFrames)?;
+                        // A recycled frame's stale entries would map whatever they used to point at
+                        if $zero_new {
+                            unsafe { crate::memory::scrub::zero_frame(frame) };
+                        }
+                        self.0 = frame as u64 | 0x63; // TODO flags
                         crate::println!("Mapped page {:#?}", self);
                     }
-                    self.deref_mut()
+                    Ok(self.deref_mut())
                 }
             }
 
@@ -60,7 +103,9 @@ macro_rules! page_table {
                     if !self.present() {
                         panic!("Tried to dereference non-present page table");
                     }
-                    unsafe { &*(crate::memory::physical_to_virtual(self.pointer()) as *mut Self::Target) }
+                    unsafe {
+                        &*(crate::memory::physical_to_virtual(self.pointer()) as *mut Self::Target)
+                    }
                 }
             }
 
@@ -69,7 +114,10 @@ macro_rules! page_table {
                     if !self.present() {
                         panic!("Tried to dereference non-present page table");
                     }
-                    unsafe { &mut *(crate::memory::physical_to_virtual(self.pointer()) as *mut Self::Target) }
+                    unsafe {
+                        &mut *(crate::memory::physical_to_virtual(self.pointer())
+                            as *mut Self::Target)
+                    }
                 }
             }
 
@@ -88,7 +136,7 @@ macro_rules! page_table {
             impl Drop for PageTableEntry {
                 fn drop(&mut self) {
                     if self.present() {
-                        unsafe { asm!("invlpg [{}]", in(reg) self.0) };
+                        Arch::flush_page(self.0 as usize);
                     }
                 }
             }
@@ -132,19 +180,39 @@ macro_rules! page_table {
     };
 }
 
+bitflags! {
+    pub struct PageTableFlags: u64 {
+        const PRESENT = 1;
+        const WRITABLE = 1 << 1;
+        const USER_ACCESSIBLE = 1 << 2;
+        // PWT, which picks write-combining once cpu::init has set up the PAT (see
+        // cpu::PAGE_ATTRIBUTE_TABLE), or write-through without one
+        const WRITE_COMBINING = 1 << 3;
+        const NO_CACHE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        const HUGE_PAGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        // 9-11 available to the OS, 12-51 are the frame address, 52-62 available to the OS
+        // The frame belongs to someone else, so it isn't freed with the page table (see
+        // AddressSpace::map_frame)
+        const BORROWED = 1 << 9;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
 #[repr(align(4096))]
 pub struct Memory4KB([u8; 4096]);
 
-page_table!(l1 -> Memory4KB);
-page_table!(l2 -> l1::PageTable);
-page_table!(l3 -> l2::PageTable);
-page_table!(l4 -> l3::PageTable);
+// Leaf frames are zeroed (or not) by whoever allocates them, see memory::scrub
+page_table!(l1 -> Memory4KB, zero_new: false);
+page_table!(l2 -> l1::PageTable, zero_new: true);
+page_table!(l3 -> l2::PageTable, zero_new: true);
+page_table!(l4 -> l3::PageTable, zero_new: true);
 
 impl l4::PageTable {
     pub unsafe fn get() -> &'static mut Self {
-        let mut cr3: usize;
-        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
-        &mut *(crate::memory::physical_to_virtual(cr3 & !0xFFF) as *mut Self)
+        &mut *(crate::memory::physical_to_virtual(Arch::page_table_root()) as *mut Self)
     }
 
     // TODO: bigger page sizes
@@ -172,6 +240,123 @@ impl l4::PageTable {
         Ok(())
     }
 
+    // Map the page at `address` to `frame`, which may already be mapped elsewhere.
+    // Intermediate tables are allocated with next_frame, failing with Err::OutOfFrames if it
+    // runs out.
+    pub unsafe fn map_to(
+        &mut self,
+        address: usize,
+        frame: usize,
+        flags: PageTableFlags,
+        next_frame: &mut dyn FnMut() -> Option<usize>,
+    ) -> Result<(), Err> {
+        let [l4_index, l3_index, l2_index, l1_index] = [
+            (address >> (9 * 3) + 12) & 0x1FF,
+            (address >> (9 * 2) + 12) & 0x1FF,
+            (address >> (9 * 1) + 12) & 0x1FF,
+            (address >> (9 * 0) + 12) & 0x1FF,
+        ];
+        let l1_table = self[l4_index].try_deref_mut_or_map(next_frame)?[l3_index]
+            .try_deref_mut_or_map(next_frame)?[l2_index]
+            .try_deref_mut_or_map(next_frame)?;
+        let entry = &mut l1_table[l1_index];
+        if entry.present() {
+            return Err(Err::AlreadyMapped);
+        }
+        *entry = l1::PageTableEntry::new(frame | (flags | PageTableFlags::PRESENT).bits() as usize);
+        Ok(())
+    }
+
+    // The l1 entry mapping `address`, if all of the intermediate tables are present
+    pub fn entry_mut(&mut self, address: usize) -> Result<&mut l1::PageTableEntry, Err> {
+        let [l4_index, l3_index, l2_index, l1_index] = [
+            (address >> (9 * 3) + 12) & 0x1FF,
+            (address >> (9 * 2) + 12) & 0x1FF,
+            (address >> (9 * 1) + 12) & 0x1FF,
+            (address >> (9 * 0) + 12) & 0x1FF,
+        ];
+        let l3_table = self[l4_index].try_deref_mut()?;
+        if l3_table[l3_index]
+            .flags()
+            .contains(PageTableFlags::HUGE_PAGE)
+        {
+            return Err(Err::HugePage);
+        }
+        let l2_table = l3_table[l3_index].try_deref_mut()?;
+        if l2_table[l2_index]
+            .flags()
+            .contains(PageTableFlags::HUGE_PAGE)
+        {
+            return Err(Err::HugePage);
+        }
+        let l1_table = l2_table[l2_index].try_deref_mut()?;
+        Ok(&mut l1_table[l1_index])
+    }
+
+    // Flags of the entry that actually maps `address`, whether it's a 4KiB, 2MiB or 1GiB page
+    pub fn leaf_flags(&mut self, address: usize) -> Result<PageTableFlags, Err> {
+        let [l4_index, l3_index, l2_index] = [
+            (address >> (9 * 3) + 12) & 0x1FF,
+            (address >> (9 * 2) + 12) & 0x1FF,
+            (address >> (9 * 1) + 12) & 0x1FF,
+        ];
+        let l3_table = self[l4_index].try_deref_mut()?;
+        let l3_entry = &l3_table[l3_index];
+        if l3_entry.present() && l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(l3_entry.flags());
+        }
+        let l2_table = l3_table[l3_index].try_deref_mut()?;
+        let l2_entry = &l2_table[l2_index];
+        if l2_entry.present() && l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(l2_entry.flags());
+        }
+        Ok(self.entry_mut(address)?.flags())
+    }
+
+    // Leaf flags as the CPU applies them: a page is only writable or user accessible if every
+    // level of the walk allows it
+    pub fn effective_flags(&mut self, address: usize) -> Result<PageTableFlags, Err> {
+        let [l4_index, l3_index, l2_index] = [
+            (address >> (9 * 3) + 12) & 0x1FF,
+            (address >> (9 * 2) + 12) & 0x1FF,
+            (address >> (9 * 1) + 12) & 0x1FF,
+        ];
+        let leaf = self.leaf_flags(address)?;
+        let mut walk = self[l4_index].flags();
+        let l3_table = self[l4_index].try_deref_mut()?;
+        let l3_flags = l3_table[l3_index].flags();
+        walk &= l3_flags;
+        if !l3_flags.contains(PageTableFlags::HUGE_PAGE) {
+            walk &= l3_table[l3_index].try_deref_mut()?[l2_index].flags();
+        }
+        let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        Ok(leaf - (inherited - walk))
+    }
+
+    // Map the 2MiB page at `address` (which must be 2MiB aligned) to the 2MiB aligned `frame`.
+    // Intermediate tables are allocated with next_frame.
+    pub unsafe fn map_huge_if_unmapped(
+        &mut self,
+        address: usize,
+        frame: usize,
+        next_frame: &mut dyn FnMut() -> usize,
+    ) -> Result<(), Err> {
+        let [l4_index, l3_index, l2_index] = [
+            (address >> (9 * 3) + 12) & 0x1FF,
+            (address >> (9 * 2) + 12) & 0x1FF,
+            (address >> (9 * 1) + 12) & 0x1FF,
+        ];
+        let l2_table =
+            self[l4_index].deref_mut_or_map(next_frame)[l3_index].deref_mut_or_map(next_frame);
+        let entry = &mut l2_table[l2_index];
+        if !entry.present() {
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE;
+            *entry = l2::PageTableEntry::new(frame | flags.bits() as usize);
+        }
+        Ok(())
+    }
+
     pub unsafe fn unmap(&mut self, address: usize) -> l1::PageTableEntry {
         let [l4_index, l3_index, l2_index, l1_index] = [
             (address >> (9 * 3) + 12) & 0x1FF,
@@ -181,12 +366,29 @@ impl l4::PageTable {
         ];
         let entry = &mut self[l4_index][l3_index][l2_index][l1_index];
         entry.set_not_present();
-        entry.clone() // return an Entry so that if it's dropped it will invlpg
+        // The returned copy isn't present, so dropping it won't invlpg
+        crate::memory::tlb::flush(address);
+        entry.clone()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Err {
     PageNotPresent,
-    // Don't even have error cases yet for huge pages, we'll probably just fault :P
+    // Mapped by a 2MiB or 1GiB page, so there's no l1 entry
+    HugePage,
+    AlreadyMapped,
+    // Allocating a frame for an intermediate table failed
+    OutOfFrames,
+}
+
+impl From<Err> for KError {
+    fn from(err: Err) -> Self {
+        KError::new(match err {
+            Err::PageNotPresent => ErrorCode::PageNotPresent,
+            Err::HugePage => ErrorCode::HugePage,
+            Err::AlreadyMapped => ErrorCode::AlreadyMapped,
+            Err::OutOfFrames => ErrorCode::OutOfMemory,
+        })
+    }
 }
@@ -0,0 +1,211 @@
+use crate::elf::{ElfFile, SEGMENT_TYPE_LOAD};
+use crate::println;
+
+// Self-relocation, for a kernel linked as a position independent executable and loaded somewhere
+// other than where it was linked (eg. at a random address, for KASLR). PIE code reaches its own
+// code and data relative to rip, so the only fixups are absolute pointers stored in data (vtables,
+// &'static str in statics, ...): R_X86_64_RELATIVE entries in .rela.dyn, each of which stores the
+// load bias plus its addend at its (biased) address.
+//
+// TODO: bootloader 0.9 can't load a PIE, so the target still links a fixed position executable,
+// there are no dynamic relocations and the bias is always 0. A loader that does move us has to
+// get here before anything reads a pointer out of a static, ie. from its entry stub; memory::init
+// is just the earliest point the kernel image can be found today.
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    UnsupportedRelocation(u32),
+    // The relocation would write outside the kernel's loaded segments
+    OutOfImage(u64),
+}
+
+extern "C" {
+    // Every kernel binary's entry point, see bootloader::entry_point!
+    fn _start() -> !;
+}
+
+// How far from its linked address the kernel is running
+pub fn load_bias(image: &ElfFile) -> usize {
+    (_start as usize).wrapping_sub(image.header().entry as usize)
+}
+
+// Returns how many relocations were applied.
+// Safety: `image` must be the running kernel, with its data segments still writable.
+pub unsafe fn apply(image: &ElfFile, bias: usize) -> Result<usize, Err> {
+    if bias == 0 {
+        return Ok(0);
+    }
+    let mut applied = 0;
+    for rela in image.relocations() {
+        match rela.relocation_type() {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => (),
+            other => return Err(Err::UnsupportedRelocation(other)),
+        }
+        let in_image = image.program_headers().any(|segment| {
+            segment.segment_type == SEGMENT_TYPE_LOAD
+                && (segment.virtual_address..segment.virtual_address + segment.memory_size)
+                    .contains(&rela.offset)
+        });
+        if !in_image {
+            return Err(Err::OutOfImage(rela.offset));
+        }
+        let target = (rela.offset as usize).wrapping_add(bias) as *mut u64;
+        target.write_unaligned((bias as u64).wrapping_add(rela.addend as u64));
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+// Before the heap exists, so it mustn't allocate
+pub fn init() {
+    let image = match super::kernel_image() {
+        Some(image) => image,
+        None => return,
+    };
+    let bias = load_bias(&image);
+    match unsafe { apply(&image, bias) } {
+        Ok(0) => (),
+        Ok(count) => println!(
+            "Relocated the kernel by {:#x} ({} relocations)",
+            bias, count
+        ),
+        Err(err) => panic!("Failed to relocate the kernel: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{FileHeader, ProgramHeader, Rela, SectionHeader, SECTION_TYPE_RELA};
+    use core::mem::size_of;
+
+    // A made up image linked at LINKED: one 32 byte data segment, and a .rela.dyn
+    const LINKED: u64 = 0x20_0000;
+
+    #[repr(C)]
+    struct Image {
+        header: FileHeader,
+        segment: ProgramHeader,
+        section: SectionHeader,
+        relocations: [Rela; 3],
+    }
+
+    impl Image {
+        fn new(relocations: [Rela; 3]) -> Self {
+            let mut ident = [0; 16];
+            ident[..5].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2]);
+            Image {
+                header: FileHeader {
+                    ident,
+                    file_type: 3,
+                    machine: 0x3e,
+                    version: 1,
+                    entry: LINKED,
+                    program_header_offset: size_of::<FileHeader>() as u64,
+                    section_header_offset: (size_of::<FileHeader>() + size_of::<ProgramHeader>())
+                        as u64,
+                    flags: 0,
+                    header_size: size_of::<FileHeader>() as u16,
+                    program_header_size: size_of::<ProgramHeader>() as u16,
+                    program_header_count: 1,
+                    section_header_size: size_of::<SectionHeader>() as u16,
+                    section_header_count: 1,
+                    section_name_index: 0,
+                },
+                segment: ProgramHeader {
+                    segment_type: SEGMENT_TYPE_LOAD,
+                    flags: 6,
+                    offset: 0,
+                    virtual_address: LINKED,
+                    physical_address: LINKED,
+                    file_size: 32,
+                    memory_size: 32,
+                    align: 8,
+                },
+                section: SectionHeader {
+                    name: 0,
+                    section_type: SECTION_TYPE_RELA,
+                    flags: crate::elf::SECTION_FLAG_ALLOC,
+                    address: 0,
+                    offset: (size_of::<Image>() - size_of::<[Rela; 3]>()) as u64,
+                    size: size_of::<[Rela; 3]>() as u64,
+                    link: 0,
+                    info: 0,
+                    address_align: 8,
+                    entry_size: size_of::<Rela>() as u64,
+                },
+                relocations,
+            }
+        }
+
+        fn bytes(&self) -> &[u8] {
+            unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+        }
+    }
+
+    fn rela(offset: u64, relocation_type: u32, addend: i64) -> Rela {
+        Rela {
+            offset,
+            info: relocation_type as u64,
+            addend,
+        }
+    }
+
+    #[test_case]
+    fn test_relative_relocations_with_bias() {
+        // Where the data segment is "loaded"
+        let mut data = [0u64; 4];
+        let bias = (data.as_mut_ptr() as usize).wrapping_sub(LINKED as usize);
+        let image = Image::new([
+            rela(LINKED + 8, R_X86_64_RELATIVE, 0x10),
+            rela(0, R_X86_64_NONE, 0),
+            rela(LINKED + 24, R_X86_64_RELATIVE, -8),
+        ]);
+        let elf = ElfFile::new(image.bytes()).unwrap();
+        assert_eq!(elf.relocations().count(), 3);
+        assert_eq!(unsafe { apply(&elf, bias) }, Ok(2));
+        let bias = bias as u64;
+        assert_eq!(data, [0, bias + 0x10, 0, bias.wrapping_sub(8)]);
+    }
+
+    #[test_case]
+    fn test_bad_relocations() {
+        let mut data = [0u64; 4];
+        let bias = (data.as_mut_ptr() as usize).wrapping_sub(LINKED as usize);
+        // Just past the end of the segment
+        let image = Image::new([
+            rela(LINKED + 32, R_X86_64_RELATIVE, 0),
+            rela(0, R_X86_64_NONE, 0),
+            rela(0, R_X86_64_NONE, 0),
+        ]);
+        let elf = ElfFile::new(image.bytes()).unwrap();
+        assert_eq!(
+            unsafe { apply(&elf, bias) },
+            Err(Err::OutOfImage(LINKED + 32))
+        );
+        // R_X86_64_64, which a PIE shouldn't have
+        let image = Image::new([
+            rela(LINKED, 1, 0),
+            rela(0, R_X86_64_NONE, 0),
+            rela(0, R_X86_64_NONE, 0),
+        ]);
+        let elf = ElfFile::new(image.bytes()).unwrap();
+        assert_eq!(
+            unsafe { apply(&elf, bias) },
+            Err(Err::UnsupportedRelocation(1))
+        );
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test_case]
+    fn test_loaded_where_linked() {
+        let image = crate::memory::kernel_image().unwrap();
+        assert_eq!(load_bias(&image), 0);
+        assert_eq!(unsafe { apply(&image, 0) }, Ok(0));
+        assert_eq!(image.relocations().count(), 0);
+    }
+}
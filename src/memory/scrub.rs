@@ -0,0 +1,157 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::{physical_to_virtual, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::sync::Semaphore;
+use crate::task::scheduler::{self, IDLE_PRIORITY};
+use crate::task::signal;
+
+// Frame sanitization. Free frames hold whatever their last owner left in them, so nothing hands
+// one out without zeroing it first: new page tables are zeroed as they're installed (stale entries
+// would map whatever they used to point at), and frames the page allocator gives out are zeroed
+// on allocation. Zeroing costs a 4KiB write, so the scrubber task zeroes free frames ahead of time
+// whenever the idle task would otherwise halt, and allocations take those first.
+
+const CLEAN_FRAMES: usize = 64;
+
+// Pre-zeroed frames, still counted as allocated by the page allocator
+struct CleanFrames {
+    frames: [usize; CLEAN_FRAMES],
+    len: usize,
+}
+
+static CLEAN: Mutex<CleanFrames> = Mutex::new(CleanFrames {
+    frames: [0; CLEAN_FRAMES],
+    len: 0,
+});
+// Upped by the idle task when there's scrubbing to do
+static WORK: Semaphore = Semaphore::new(0);
+static SCRUBBED: AtomicU64 = AtomicU64::new(0);
+
+// Safety: `frame` must be a physical frame nobody else is using
+pub unsafe fn zero_frame(frame: usize) {
+    core::ptr::write_bytes(physical_to_virtual(frame) as *mut u8, 0, PAGE_SIZE);
+}
+
+pub fn take_clean_frame() -> Option<usize> {
+    crate::without_interrupt! {{
+        let mut clean = CLEAN.lock();
+        clean.len = clean.len.checked_sub(1)?;
+        Some(clean.frames[clean.len])
+    }}
+}
+
+pub fn clean_frames() -> usize {
+    crate::without_interrupt! {{
+        CLEAN.lock().len
+    }}
+}
+
+// Frames zeroed ahead of time since boot
+pub fn scrubbed() -> u64 {
+    SCRUBBED.load(Ordering::Relaxed)
+}
+
+// Zero one free frame into the clean pool. False if the pool is full or memory has run out.
+pub fn scrub_one() -> bool {
+    if clean_frames() >= CLEAN_FRAMES {
+        return false;
+    }
+    let frame = match PAGE_ALLOCATOR.lock().allocate_unzeroed_frame() {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    // Zeroed without holding any locks, allocations shouldn't wait on it
+    unsafe { zero_frame(frame) };
+    let pushed = crate::without_interrupt! {{
+        let mut clean = CLEAN.lock();
+        let len = clean.len;
+        if len < CLEAN_FRAMES {
+            clean.frames[len] = frame;
+            clean.len += 1;
+        }
+        len < CLEAN_FRAMES
+    }};
+    if !pushed {
+        PAGE_ALLOCATOR.lock().deallocate_unzeroed_frame(frame);
+        return false;
+    }
+    SCRUBBED.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+// Called by the idle task before halting; wakes the scrubber if the pool needs topping up.
+// Returns whether it did.
+pub fn kick() -> bool {
+    if clean_frames() >= CLEAN_FRAMES || WORK.count() > 0 {
+        return false;
+    }
+    WORK.up();
+    true
+}
+
+fn scrubber_task() {
+    loop {
+        WORK.down();
+        // Just above idle, so anything else that becomes ready gets the CPU back
+        while !scheduler::would_yield() && scrub_one() {}
+    }
+}
+
+pub fn init() {
+    let task = scheduler::spawn_with_priority("scrubber", scrubber_task, IDLE_PRIORITY + 1)
+        .expect("Failed to spawn frame scrubber");
+    signal::protect(task).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const MAX_FRAMES_UNTIL_REUSE: usize = 4 * CLEAN_FRAMES;
+
+    #[test_case]
+    fn test_scrubbed_frames_are_zero() {
+        while scrub_one() {}
+        let frame = take_clean_frame().unwrap();
+        let bytes = unsafe {
+            core::slice::from_raw_parts(physical_to_virtual(frame) as *const u8, PAGE_SIZE)
+        };
+        assert!(bytes.iter().all(|&b| b == 0));
+        PAGE_ALLOCATOR.lock().deallocate_unzeroed_frame(frame);
+    }
+
+    #[test_case]
+    fn test_allocated_frames_are_zero() {
+        // Allocated up front, since the heap may need the page allocator
+        let mut others = Vec::with_capacity(MAX_FRAMES_UNTIL_REUSE);
+        let mut page_allocator = PAGE_ALLOCATOR.lock();
+        // Dirty a frame, give it back, and make sure it doesn't come back dirty
+        let dirty = page_allocator.allocate_unzeroed_frame().unwrap();
+        unsafe { core::ptr::write_bytes(physical_to_virtual(dirty) as *mut u8, 0xAA, PAGE_SIZE) };
+        page_allocator.deallocate_unzeroed_frame(dirty);
+        // Clean frames from the scrubber come first, so keep going until it's the dirty one
+        let frame = loop {
+            let frame = page_allocator.allocate_frame().unwrap();
+            if frame.as_mut_ptr() as usize == dirty {
+                break frame;
+            }
+            assert!(
+                others.len() < MAX_FRAMES_UNTIL_REUSE,
+                "Freed frame {:#x} wasn't handed out again",
+                dirty
+            );
+            others.push(frame);
+        };
+        let bytes = unsafe {
+            core::slice::from_raw_parts(physical_to_virtual(dirty) as *const u8, PAGE_SIZE)
+        };
+        assert!(bytes.iter().all(|&b| b == 0));
+        page_allocator.deallocate_frame(frame);
+        others
+            .into_iter()
+            .for_each(|frame| page_allocator.deallocate_frame(frame));
+    }
+}
@@ -0,0 +1,167 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use super::PAGE_SIZE;
+use crate::arch::{Arch, Mmu};
+use crate::smp::ipi::{self, IpiKind};
+use crate::smp::{self, CpuId};
+use crate::time;
+
+// Past this many pages it's cheaper to flush everything than invlpg each one
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+// TLB shootdowns. Other CPUs may have the pages we just changed cached in their TLBs, so the
+// initiator publishes the range, sends each of them a TlbShootdown IPI, and waits for every one
+// to acknowledge that it's flushed before the old frames can be reused.
+const SHOOTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+// Ticks don't advance on this CPU while we wait with interrupts disabled, so also give up after
+// spinning this long
+const SHOOTDOWN_MAX_SPINS: u64 = 100_000_000;
+
+// Invalidate the TLB entry for the page containing `address`.
+// Needed after changing the flags or frame of a live mapping.
+#[inline]
+pub fn flush(address: usize) {
+    Arch::flush_page(address);
+}
+
+// Invalidate all non-global TLB entries
+pub fn flush_all() {
+    Arch::flush_all();
+}
+
+// Invalidate the pages overlapping [start, end) on this CPU only
+fn flush_local(start: usize, end: usize) {
+    let pages = (end.saturating_sub(start) + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages > FLUSH_ALL_THRESHOLD {
+        flush_all();
+    } else {
+        for page in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            flush(page);
+        }
+    }
+}
+
+// Only one shootdown at a time, since there's one published range
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_END: AtomicUsize = AtomicUsize::new(0);
+// Bit per CPU which hasn't yet acknowledged the current shootdown
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+// CPUs which failed to acknowledge a shootdown in time
+pub struct Stragglers(u64);
+
+impl fmt::Display for Stragglers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CPUs")?;
+        for cpu in (0..64).filter(|cpu| self.0 & 1 << cpu != 0) {
+            write!(f, " {}", cpu)?;
+        }
+        Ok(())
+    }
+}
+
+fn shoot_down(
+    start: usize,
+    end: usize,
+    cpus: impl Iterator<Item = CpuId>,
+    timeout: Duration,
+) -> Result<(), Stragglers> {
+    // Interrupts stay off while we hold the lock, so a shootdown can't be started on this CPU
+    // from an interrupt handler and deadlock against itself
+    crate::without_interrupt! {{
+        let _shootdown = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            // Whoever holds the lock may be waiting for us, and its IPI can't be delivered with
+            // interrupts off
+            if SHOOTDOWN_PENDING.load(Ordering::Acquire) & 1 << smp::current_cpu() != 0 {
+                shootdown();
+            }
+            core::hint::spin_loop();
+        };
+        SHOOTDOWN_START.store(start, Ordering::Relaxed);
+        SHOOTDOWN_END.store(end, Ordering::Relaxed);
+        let mut targets = 0;
+        for cpu in cpus {
+            // Set before sending, since the IPI may be handled before `send` returns
+            SHOOTDOWN_PENDING.fetch_or(1 << cpu, Ordering::Release);
+            if ipi::send(cpu, IpiKind::TlbShootdown).is_ok() {
+                targets |= 1 << cpu;
+            } else {
+                // Offline, so it has nothing cached
+                SHOOTDOWN_PENDING.fetch_and(!(1 << cpu), Ordering::Release);
+            }
+        }
+        if targets == 0 {
+            return Ok(());
+        }
+        let deadline = time::jiffies() + time::duration_to_ticks(timeout).max(1);
+        let mut spins = 0;
+        loop {
+            let pending = SHOOTDOWN_PENDING.load(Ordering::Acquire);
+            if pending == 0 {
+                return Ok(());
+            }
+            if time::jiffies() >= deadline || spins >= SHOOTDOWN_MAX_SPINS {
+                // Don't let a late acknowledgement confuse the next shootdown
+                SHOOTDOWN_PENDING.store(0, Ordering::Release);
+                return Err(Stragglers(pending));
+            }
+            spins += 1;
+            core::hint::spin_loop();
+        }
+    }}
+}
+
+// Invalidate the pages overlapping [start, end) on every CPU, after unmapping or changing them.
+// Panics if another CPU doesn't acknowledge, since it could still be using the old mapping.
+pub fn flush_range(start: usize, end: usize) {
+    flush_local(start, end);
+    if let Err(stragglers) = shoot_down(start, end, smp::other_cpus(), SHOOTDOWN_TIMEOUT) {
+        panic!(
+            "TLB shootdown of {:#x}..{:#x} timed out waiting for {}",
+            start, end, stragglers
+        );
+    }
+}
+
+// IPI handler for TLB shootdowns from other CPUs
+pub(crate) fn shootdown() {
+    flush_local(
+        SHOOTDOWN_START.load(Ordering::Relaxed),
+        SHOOTDOWN_END.load(Ordering::Relaxed),
+    );
+    SHOOTDOWN_PENDING.fetch_and(!(1 << smp::current_cpu()), Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_shootdown_acknowledged() {
+        // Shooting down ourselves takes the same path as another CPU would. Registered here too,
+        // since smp::ipi::init doesn't run without the smp feature.
+        ipi::register(IpiKind::TlbShootdown, shootdown);
+        let cpus = [smp::current_cpu()].into_iter();
+        assert!(shoot_down(0x1000, 0x3000, cpus, SHOOTDOWN_TIMEOUT).is_ok());
+    }
+
+    #[test_case]
+    fn test_shootdown_straggler() {
+        ipi::unregister(IpiKind::TlbShootdown);
+        let cpus = [smp::current_cpu()].into_iter();
+        let result = shoot_down(0x1000, 0x3000, cpus, Duration::from_millis(20));
+        ipi::register(IpiKind::TlbShootdown, shootdown);
+        match result {
+            Err(Stragglers(pending)) => assert_eq!(pending, 1 << smp::current_cpu()),
+            Ok(()) => panic!("Expected the shootdown to time out"),
+        }
+    }
+}
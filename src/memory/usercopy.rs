@@ -0,0 +1,132 @@
+use super::address_space;
+use super::fault::{self, FaultInfo};
+use super::page_table::{self, PageTableFlags};
+use super::PAGE_SIZE;
+use crate::syscall;
+
+// Copying to and from user memory for syscalls. User pointers are never trusted: the whole
+// range must be in user space (see memory::address_space) and mapped user accessible (and
+// writable, for copy_to_user) at every level of the page tables. The copy itself goes through
+// the page fault fixup mechanism (see memory::fault), so if the mapping changes underneath us we
+// get BadAddress rather than a kernel panic. Syscalls run on the calling process's page tables
+// (see memory::address_space), so the check is against its own mappings.
+
+// EFAULT: the range isn't (or stopped being) accessible to the user
+#[derive(Debug, PartialEq, Eq)]
+pub enum Err {
+    BadAddress(usize),
+}
+
+impl From<Err> for syscall::Err {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::BadAddress(_) => syscall::Err::BadAddress,
+        }
+    }
+}
+
+fn check_range(address: usize, length: usize, flags: PageTableFlags) -> Result<(), Err> {
+    if length == 0 {
+        return Ok(());
+    }
+    if !address_space::is_user_range(address, length) {
+        return Err(Err::BadAddress(address));
+    }
+    let end = address + length;
+    let page_table = unsafe { page_table::l4::PageTable::get() };
+    for page in (address & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        match page_table.effective_flags(page) {
+            Ok(page_flags) if page_flags.contains(flags | PageTableFlags::PRESENT) => (),
+            _ => return Err(Err::BadAddress(page.max(address))),
+        }
+    }
+    Ok(())
+}
+
+// Whether [address, address + length) is all mapped for the caller to read, eg. before the kernel
+// works on a word of user memory in place
+pub fn check_readable(address: usize, length: usize) -> Result<(), Err> {
+    check_range(address, length, PageTableFlags::USER_ACCESSIBLE)
+}
+
+// Whether [address, address + length) is all mapped for the caller to write, eg. before reading
+// something that can't be put back into it
+pub fn check_writable(address: usize, length: usize) -> Result<(), Err> {
+    check_range(
+        address,
+        length,
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+    )
+}
+
+fn fault_address(fault: FaultInfo) -> Err {
+    match fault {
+        FaultInfo::NonCanonical(address) => Err::BadAddress(address),
+        FaultInfo::PageFault { address, .. } => Err::BadAddress(address),
+    }
+}
+
+pub fn copy_from_user(destination: &mut [u8], source: usize) -> Result<(), Err> {
+    check_range(source, destination.len(), PageTableFlags::USER_ACCESSIBLE)?;
+    unsafe {
+        fault::probe_copy(
+            destination.as_mut_ptr(),
+            source as *const u8,
+            destination.len(),
+        )
+    }
+    .map_err(fault_address)
+}
+
+pub fn copy_to_user(destination: usize, source: &[u8]) -> Result<(), Err> {
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    check_range(destination, source.len(), flags)?;
+    unsafe { fault::probe_copy(destination as *mut u8, source.as_ptr(), source.len()) }
+        .map_err(fault_address)
+}
+
+// Copy a NUL terminated string of at most `destination.len()` bytes. Returns its length without
+// the NUL, or destination.len() if it didn't fit (in which case it isn't terminated).
+pub fn strncpy_from_user(destination: &mut [u8], source: usize) -> Result<usize, Err> {
+    for (i, byte) in destination.iter_mut().enumerate() {
+        // Byte at a time, so we don't touch (or fault on) anything past the NUL
+        copy_from_user(core::slice::from_mut(byte), source.wrapping_add(i))?;
+        if *byte == 0 {
+            return Ok(i);
+        }
+    }
+    Ok(destination.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::USER_END;
+
+    #[test_case]
+    fn test_kernel_memory_rejected() {
+        static SECRET: [u8; 8] = *b"kernel!!";
+        let mut buffer = [0; 8];
+        let address = SECRET.as_ptr() as usize;
+        assert_eq!(
+            copy_from_user(&mut buffer, address),
+            Err(Err::BadAddress(address))
+        );
+        assert_eq!(buffer, [0; 8]);
+        assert_eq!(
+            copy_to_user(address, b"clobber!"),
+            Err(Err::BadAddress(address))
+        );
+    }
+
+    #[test_case]
+    fn test_bad_ranges_rejected() {
+        let mut buffer = [0; 16];
+        assert!(copy_from_user(&mut buffer, 0x7000_0000_0000).is_err());
+        assert!(copy_from_user(&mut buffer, USER_END - 8).is_err());
+        assert!(copy_from_user(&mut buffer, usize::MAX - 8).is_err());
+        assert!(strncpy_from_user(&mut buffer, 0x7000_0000_0000).is_err());
+        // Empty copies never touch memory
+        assert_eq!(copy_from_user(&mut [], 0xdead_beef), Ok(()));
+    }
+}
@@ -0,0 +1,171 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::error::KResult;
+use crate::memory;
+
+// Memory mapped device registers, the MMIO counterpart to port.rs. A device's registers are
+// declared as a struct with register_block!, and an Mmio<Block> lays that struct over the device's
+// physical window. Every access through it is volatile, and each register's type says whether it
+// can be read, written or both, so eg. writing a status register doesn't compile.
+//
+// Unlike ports, register access itself is safe: the unsafe part is claiming that some memory is a
+// device's registers, in Mmio::map.
+
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> VolatileCell<T> {
+    pub const fn new(value: T) -> Self {
+        VolatileCell {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+
+    // Read, modify, write. Not atomic: the device may change the register in between.
+    #[inline]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+}
+
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(VolatileCell<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    #[inline]
+    pub fn read(&self) -> T {
+        self.0.read()
+    }
+}
+
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(VolatileCell<T>);
+
+impl<T: Copy> WriteOnly<T> {
+    #[inline]
+    pub fn write(&self, value: T) {
+        self.0.write(value)
+    }
+}
+
+// A #[repr(C)] register block, checked at compile time against the offsets the datasheet gives.
+// Gaps need explicit padding fields, eg.
+//
+// register_block! {
+//     pub struct Registers {
+//         0x00 => pub id: ReadOnly<u32>,
+//         0x04 => _reserved: [u32; 3],
+//         0x10 => pub control: VolatileCell<u32>,
+//         0x14 => pub doorbell: WriteOnly<u32>,
+//     }
+// }
+#[macro_export]
+macro_rules! register_block {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident {
+        $($offset:literal => $field_vis:vis $field:ident: $type:ty),* $(,)?
+    }) => {
+        $(#[$meta])*
+        #[repr(C)]
+        // Only ever laid over device memory, never built
+        #[allow(dead_code)]
+        $vis struct $name {
+            $($field_vis $field: $type),*
+        }
+        $crate::register_block!(@check $name [0] $($offset $type,)*);
+    };
+    (@check $name:ident [$at:expr]) => {};
+    (@check $name:ident [$at:expr] $offset:literal $type:ty, $($rest:tt)*) => {
+        // Both, or repr(C) would quietly pad the register to somewhere else
+        const _: () = assert!(
+            $offset == $at && $offset % core::mem::align_of::<$type>() == 0,
+            concat!(stringify!($name), ": misplaced register at ", stringify!($offset))
+        );
+        $crate::register_block!(@check $name [$at + core::mem::size_of::<$type>()] $($rest)*);
+    };
+}
+
+// A register block B over a device's MMIO window
+pub struct Mmio<B> {
+    registers: NonNull<B>,
+    block: PhantomData<B>,
+}
+
+// The registers are the device's, not any one CPU's
+unsafe impl<B> Send for Mmio<B> {}
+
+impl<B> Mmio<B> {
+    // Through the physical memory map, with caching turned off for the window.
+    // Safety: there must be registers laid out as B at `physical_address`, and nothing else may
+    // treat that memory as RAM.
+    pub unsafe fn map(physical_address: usize) -> KResult<Self> {
+        let address = memory::physical_to_virtual(physical_address);
+        memory::set_uncached(address, address + size_of::<B>())?;
+        Ok(Self::from_virtual(address))
+    }
+
+    // Safety: as for `map`, and `address` must already be mapped uncached (or be plain memory,
+    // eg. in tests)
+    pub unsafe fn from_virtual(address: usize) -> Self {
+        Mmio {
+            registers: NonNull::new(address as *mut B).expect("MMIO block at address 0"),
+            block: PhantomData,
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.registers.as_ptr() as usize
+    }
+}
+
+impl<B> Deref for Mmio<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        unsafe { self.registers.as_ref() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    register_block! {
+        struct TestRegisters {
+            0x00 => id: ReadOnly<u32>,
+            0x04 => _reserved: [u32; 1],
+            0x08 => control: VolatileCell<u64>,
+            0x10 => doorbell: WriteOnly<u16>,
+        }
+    }
+
+    #[test_case]
+    fn test_register_access() {
+        let mut memory = [0u64; 3];
+        memory[0] = 0x1234;
+        let registers: Mmio<TestRegisters> =
+            unsafe { Mmio::from_virtual(memory.as_mut_ptr() as usize) };
+        assert_eq!(registers.id.read(), 0x1234);
+        registers.control.write(0xf0);
+        registers.control.update(|control| control | 1);
+        assert_eq!(registers.control.read(), 0xf1);
+        registers.doorbell.write(7);
+        assert_eq!(memory[1], 0xf1);
+        assert_eq!(memory[2], 7);
+    }
+}
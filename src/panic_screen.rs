@@ -0,0 +1,247 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use spin::Mutex;
+
+use crate::backtrace::Backtrace;
+use crate::interrupt::table::InterruptStackFrame;
+use crate::serial;
+use crate::vga_buffer::{Color, Writer};
+
+// The release build panic screen. Besides the message and backtrace it prints a base64 blob of
+// the machine state (registers, the exception we were handling, raw return addresses) that's
+// small enough for a QR code, and can be copied out of the serial log and decoded offline
+// against the matching kernel binary.
+//
+// Blob layout, little endian: magic "SOSP", version u8, frame count u8, 2 bytes padding, then
+// Registers and ExceptionContext as u64s in declaration order, then the frames.
+
+const MAGIC: &[u8; 4] = b"SOSP";
+const VERSION: u8 = 1;
+const BLOB_FRAMES: usize = 16;
+const BLOB_SIZE: usize = 8 + 7 * 8 + 5 * 8 + BLOB_FRAMES * 8;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov {}, rsp",
+                "mov {}, rbp",
+                "pushfq",
+                "pop {}",
+                out(reg) registers.rsp,
+                out(reg) registers.rbp,
+                out(reg) registers.rflags,
+                options(preserves_flags)
+            );
+            asm!(
+                "mov {}, cr0",
+                "mov {}, cr2",
+                "mov {}, cr3",
+                "mov {}, cr4",
+                out(reg) registers.cr0,
+                out(reg) registers.cr2,
+                out(reg) registers.cr3,
+                out(reg) registers.cr4,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        registers
+    }
+
+    fn words(&self) -> [u64; 7] {
+        [
+            self.rsp,
+            self.rbp,
+            self.rflags,
+            self.cr0,
+            self.cr2,
+            self.cr3,
+            self.cr4,
+        ]
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rsp={:#018x} rbp={:#018x} rflags={:#x}\ncr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x}",
+            self.rsp, self.rbp, self.rflags, self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
+
+// The exception being handled when we panicked, if any
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionContext {
+    pub vector: u8,
+    pub error: u64,
+    pub instruction_pointer: u64,
+    pub stack_pointer: u64,
+}
+
+impl fmt::Display for ExceptionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "exception {} error={:#x} at rip={:#x} rsp={:#x}",
+            self.vector, self.error, self.instruction_pointer, self.stack_pointer
+        )
+    }
+}
+
+static EXCEPTION: Mutex<Option<ExceptionContext>> = Mutex::new(None);
+
+// Called by exception handlers before they panic
+pub(crate) fn record_exception(vector: u8, error: u64, frame: &InterruptStackFrame) {
+    if let Some(mut exception) = EXCEPTION.try_lock() {
+        *exception = Some(ExceptionContext {
+            vector,
+            error,
+            instruction_pointer: frame.instruction_pointer(),
+            stack_pointer: frame.stack_pointer(),
+        });
+    }
+}
+
+fn encode(
+    registers: &Registers,
+    exception: Option<ExceptionContext>,
+    frames: &[u64],
+) -> [u8; BLOB_SIZE] {
+    let mut blob = [0; BLOB_SIZE];
+    let frames = &frames[..frames.len().min(BLOB_FRAMES)];
+    blob[..4].copy_from_slice(MAGIC);
+    blob[4] = VERSION;
+    blob[5] = frames.len() as u8;
+    let exception_words = match exception {
+        Some(e) => [
+            1,
+            e.vector as u64,
+            e.error,
+            e.instruction_pointer,
+            e.stack_pointer,
+        ],
+        None => [0; 5],
+    };
+    let words = registers
+        .words()
+        .into_iter()
+        .chain(exception_words)
+        .chain(frames.iter().copied());
+    for (chunk, word) in blob[8..].chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    blob
+}
+
+// Standard base64 with padding, without allocating
+struct Base64<'a>(&'a [u8]);
+
+impl fmt::Display for Base64<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for chunk in self.0.chunks(3) {
+            let bytes = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => {
+                        f.write_char(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char)?
+                    }
+                    false => f.write_char('=')?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Report<'a> {
+    info: &'a PanicInfo<'a>,
+    registers: Registers,
+    exception: Option<ExceptionContext>,
+    backtrace: &'a Backtrace,
+    blob: &'a [u8],
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "*** KERNEL PANIC ***\n\n{}\n", self.info)?;
+        writeln!(f, "{}", self.registers)?;
+        if let Some(exception) = self.exception {
+            writeln!(f, "{}", exception)?;
+        }
+        writeln!(f, "\n{}\n", self.backtrace)?;
+        write!(f, "machine state:\n{}\n", Base64(self.blob))
+    }
+}
+
+pub fn show(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    let backtrace = Backtrace::capture();
+    let exception = EXCEPTION.try_lock().and_then(|exception| *exception);
+    let blob = encode(&registers, exception, backtrace.frames());
+    let report = Report {
+        info,
+        registers,
+        exception,
+        backtrace: &backtrace,
+        blob: &blob,
+    };
+    // Whoever panicked may be holding the console locks, so go around them
+    let mut screen = Writer::new();
+    screen.set_colors(Color::White, Color::Red);
+    screen.clear();
+    write!(screen, "{}", report).ok();
+    serial::force_print(format_args!("{}", report));
+    crate::power::halt();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn test_base64() {
+        assert_eq!(Base64(b"").to_string(), "");
+        assert_eq!(Base64(b"f").to_string(), "Zg==");
+        assert_eq!(Base64(b"fo").to_string(), "Zm8=");
+        assert_eq!(Base64(b"foobar").to_string(), "Zm9vYmFy");
+    }
+
+    #[test_case]
+    fn test_encode_blob() {
+        let registers = Registers {
+            rsp: 1,
+            ..Registers::default()
+        };
+        let blob = encode(&registers, None, &[0xdead_beef; 20]);
+        assert_eq!(&blob[..4], MAGIC);
+        assert_eq!(blob[5] as usize, BLOB_FRAMES);
+        assert_eq!(blob[8], 1);
+        let last_frame = &blob[BLOB_SIZE - 8..];
+        assert_eq!(last_frame, &0xdead_beef_u64.to_le_bytes());
+    }
+}
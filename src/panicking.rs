@@ -0,0 +1,232 @@
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::interrupt::are_interrupts_enabled;
+use crate::serial;
+use crate::smp::{self, MAX_CPUS};
+
+// Panic handlers print, and printing takes locks and runs arbitrary Display impls, any of which
+// can panic again (eg. a panic inside println! while holding WRITER). Without this the nested
+// panic would go through the same handler and recurse until the stack runs out.
+//
+// Panic handlers call `enter` first thing. A nested panic on the same CPU goes down `emergency`
+// instead, which touches no locks and no heap.
+//
+// The kernel is built with panic=abort, so there's no unwinding to recover from a panic with.
+// `catch_panic` gets the same effect the way setjmp/longjmp would: it saves the callee-saved
+// registers before running its closure, and a panic handler which finds a catch point armed jumps
+// straight back to it. Nothing on the abandoned frames is dropped, so memory they owned leaks and
+// any locks they held stay locked. That's fine for tests of panic paths, which is what it's for.
+
+const MAX_MESSAGE: usize = 256;
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static DEPTH: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+// Innermost catch_panic running on each CPU, each linking to the one it's nested in
+const NO_CATCH_POINT: AtomicPtr<CatchPoint> = AtomicPtr::new(ptr::null_mut());
+static CATCH_POINTS: [AtomicPtr<CatchPoint>; MAX_CPUS] = [NO_CATCH_POINT; MAX_CPUS];
+
+// sos_catch(registers: rdi, f: rsi, data: rdx) -> rax: 0 once f(data) returns, or 1 when
+// sos_throw(registers) jumps back. Saves rbx, rbp, r12-r15, then rsp and the return address.
+global_asm!(
+    ".global sos_catch",
+    "sos_catch:",
+    "    mov [rdi], rbx",
+    "    mov [rdi + 8], rbp",
+    "    mov [rdi + 16], r12",
+    "    mov [rdi + 24], r13",
+    "    mov [rdi + 32], r14",
+    "    mov [rdi + 40], r15",
+    "    lea rax, [rsp + 8]",
+    "    mov [rdi + 48], rax",
+    "    mov rax, [rsp]",
+    "    mov [rdi + 56], rax",
+    // Keep the stack 16 byte aligned across the call
+    "    sub rsp, 8",
+    "    mov rdi, rdx",
+    "    call rsi",
+    "    add rsp, 8",
+    "    xor eax, eax",
+    "    ret",
+    ".global sos_throw",
+    "sos_throw:",
+    "    mov rbx, [rdi]",
+    "    mov rbp, [rdi + 8]",
+    "    mov r12, [rdi + 16]",
+    "    mov r13, [rdi + 24]",
+    "    mov r14, [rdi + 32]",
+    "    mov r15, [rdi + 40]",
+    "    mov rsp, [rdi + 48]",
+    "    mov eax, 1",
+    "    jmp [rdi + 56]",
+);
+
+extern "C" {
+    fn sos_catch(registers: *mut [u64; 8], f: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+    fn sos_throw(registers: *const [u64; 8]) -> !;
+}
+
+// The formatted PanicInfo of a caught panic, truncated to MAX_MESSAGE bytes
+pub struct PanicMessage {
+    buffer: [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+impl PanicMessage {
+    pub fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.buffer[..self.len]) {
+            Ok(message) => message,
+            // Truncated in the middle of a character
+            Err(error) => core::str::from_utf8(&self.buffer[..error.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let length = s.len().min(MAX_MESSAGE - self.len);
+        self.buffer[self.len..self.len + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.len += length;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+struct CatchPoint {
+    registers: [u64; 8],
+    previous: *mut CatchPoint,
+    interrupts_enabled: bool,
+    message: PanicMessage,
+}
+
+fn catch_point() -> &'static AtomicPtr<CatchPoint> {
+    &CATCH_POINTS[smp::current_cpu() as usize]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nesting {
+    First,
+    Nested,
+}
+
+fn depth() -> &'static AtomicUsize {
+    &DEPTH[smp::current_cpu() as usize]
+}
+
+// Record that we're handling a panic on this CPU
+pub fn enter() -> Nesting {
+    match depth().fetch_add(1, Ordering::SeqCst) {
+        0 => Nesting::First,
+        _ => Nesting::Nested,
+    }
+}
+
+// Whether this CPU is in a panic handler
+pub fn is_panicking() -> bool {
+    depth().load(Ordering::SeqCst) > 0
+}
+
+// For panic handlers which carry on running afterwards, eg. after a should_panic test
+pub fn recovered() {
+    depth().store(0, Ordering::SeqCst);
+}
+
+// Write straight to the serial registers and halt. Formatting `info` can panic yet again (the
+// nested panic may come from the same Display impl), in which case we get back here one level
+// deeper and give up on formatting altogether.
+pub fn emergency(info: &PanicInfo) -> ! {
+    match depth().load(Ordering::SeqCst) {
+        depth if depth <= 2 => {
+            serial::force_print(format_args!("\npanicked while panicking: {}\n", info))
+        }
+        _ => serial::force_print(format_args!("\npanicked while reporting a nested panic\n")),
+    }
+    crate::power::halt();
+}
+
+// Run `f`, returning the panic message if it panics. See the top of the file for caveats.
+pub fn catch_panic<F: FnOnce()>(f: F) -> Result<(), PanicMessage> {
+    extern "C" fn call<F: FnOnce()>(data: *mut u8) {
+        let f = unsafe { (*(data as *mut Option<F>)).take().unwrap() };
+        f();
+    }
+    let mut f = Some(f);
+    let mut point = CatchPoint {
+        registers: [0; 8],
+        previous: catch_point().load(Ordering::SeqCst),
+        interrupts_enabled: are_interrupts_enabled(),
+        message: PanicMessage {
+            buffer: [0; MAX_MESSAGE],
+            len: 0,
+        },
+    };
+    catch_point().store(&mut point, Ordering::SeqCst);
+    let panicked = unsafe {
+        sos_catch(
+            &mut point.registers,
+            call::<F>,
+            &mut f as *mut Option<F> as *mut u8,
+        )
+    };
+    catch_point().store(point.previous, Ordering::SeqCst);
+    match panicked {
+        0 => Ok(()),
+        _ => Err(point.message),
+    }
+}
+
+// Called by panic handlers after `enter`. If a catch_panic is running on this CPU, records the
+// panic and resumes there instead of returning.
+pub fn resume_catch(info: &PanicInfo) {
+    let point = catch_point().load(Ordering::SeqCst);
+    if point.is_null() {
+        return;
+    }
+    let point = unsafe { &mut *point };
+    write!(point.message, "{}", info).ok();
+    recovered();
+    // The panic may have come from inside without_interrupt!, whose guard won't be dropped now
+    if point.interrupts_enabled {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+    unsafe { sos_throw(&point.registers) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_catch_panic() {
+        assert!(catch_panic(|| ()).is_ok());
+        let message = catch_panic(|| panic!("caught {}", 42)).unwrap_err();
+        assert!(message.as_str().contains("caught 42"), "{}", message);
+    }
+
+    #[test_case]
+    fn test_catch_panic_nested() {
+        let mut inner = None;
+        let outer = catch_panic(|| {
+            inner = Some(catch_panic(|| panic!("inner")));
+            panic!("outer");
+        });
+        assert!(inner.unwrap().unwrap_err().as_str().contains("inner"));
+        assert!(outer.unwrap_err().as_str().contains("outer"));
+        assert!(catch_point().load(Ordering::SeqCst).is_null());
+    }
+}
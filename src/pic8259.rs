@@ -1,9 +1,12 @@
 use core::arch::asm;
+use core::sync::atomic::{AtomicU16, Ordering};
 use spin::Mutex;
 
 use crate::{
-    interrupt::table::Interrupt,
-    serial::{port_read_byte, port_write_byte},
+    arch::port::{Port, WriteOnlyPort},
+    driver::{Device, Driver},
+    error::KResult,
+    interrupt::table::Irq,
 };
 
 pub const PIC_INTERRUPT_OFFSET: u8 = 32;
@@ -13,33 +16,52 @@ pub static PIC: Mutex<ChainedPIC> = Mutex::new(ChainedPIC::new(PIC_INTERRUPT_OFF
 const BASE_PIC_COMMAND_PORT: u16 = 0x20;
 const CHAINED_PIC_COMMAND_PORT: u16 = 0xA0;
 
-const WAIT_PORT: u16 = 0x80;
 const PIC_COMMAND_INIT: u8 = 0x11;
 const PIC_COMMAND_END_OF_INTERRUPT: u8 = 0x20;
 const PIC_MODE_8086: u8 = 0x01;
 
+// IRQ lines, ie. Irq::line
+pub const IRQ_LINES: u8 = 16;
+// Everything but these starts masked, whatever the firmware left, until a driver unmasks its line.
+// Drivers bound before the PIC (eg. the UARTs) can unmask theirs early, and init keeps them.
+// The chained PIC's interrupts come through the cascade line on the base PIC.
+const DEFAULT_UNMASKED: [Irq; 3] = [Irq::Timer, Irq::Keyboard, Irq::Cascade];
+
 pub fn init() {
     unsafe { PIC.lock().init() };
     // enable hardware interrupts
     unsafe { asm!("sti", options(nomem, nostack)) };
 }
 
-// Comment shamelessly taken from crate pic8259.
-// We need to add a delay between writes to our PICs, especially on
-// older motherboards.  But we don't necessarily have any kind of
-// timers yet, because most of them require interrupts.  Various
-// older versions of Linux and other PC operating systems have
-// worked around this by writing garbage data to port 0x80, which
-// allegedly takes long enough to make everything work on most
-// hardware.  Here, `wait` is a closure.
-unsafe fn wait() {
-    port_write_byte(WAIT_PORT, 0);
+struct Pic;
+
+impl Driver for Pic {
+    fn probe(&self, device: &Device) -> bool {
+        device.name == "i8259"
+    }
+
+    fn init(&self, _device: &Device) -> KResult<()> {
+        init();
+        Ok(())
+    }
+
+    fn shutdown(&self, _device: &Device) {
+        unsafe { asm!("cli", options(nomem, nostack)) };
+    }
+}
+
+crate::register_driver!(PIC_DRIVER, Pic);
+
+// Older PICs need a moment between initialization writes
+fn wait() {
+    crate::time::delay::io_wait();
 }
 
 struct PIC {
     interrupt_offset: u8,
-    command_port: u16,
-    data_port: u16,
+    command_port: WriteOnlyPort<u8>,
+    // Reads and writes the interrupt mask outside of initialization
+    data_port: Port<u8>,
 }
 
 enum PICChainMode {
@@ -51,43 +73,38 @@ impl PIC {
     const fn new(interrupt_offset: u8, command_port: u16) -> Self {
         PIC {
             interrupt_offset,
-            command_port,
-            data_port: command_port + 1,
+            command_port: WriteOnlyPort::new(command_port),
+            data_port: Port::new(command_port + 1),
         }
     }
 
-    unsafe fn init(&self, chain_mode: PICChainMode) {
-        // Save mask to restore after init
-        let mask: u8 = port_read_byte(self.data_port);
+    unsafe fn init(&self, chain_mode: PICChainMode, mask: u8) {
         // Signal a 3 byte initialization sequence for the controller
         // - Byte 1: set interrupt offset
         // - Byte 2: set chaining mode
         // - Byte 3: Set controller mode
         // Trigger a wait in between each.
-        port_write_byte(self.command_port, PIC_COMMAND_INIT);
+        self.command_port.write(PIC_COMMAND_INIT);
         wait();
-        port_write_byte(self.data_port, self.interrupt_offset);
+        self.data_port.write(self.interrupt_offset);
         wait();
-        port_write_byte(self.data_port, chain_mode as u8);
+        self.data_port.write(chain_mode as u8);
         wait();
-        port_write_byte(self.data_port, PIC_MODE_8086);
+        self.data_port.write(PIC_MODE_8086);
         wait();
-        // Re-set mask
-        port_write_byte(self.data_port, mask);
-    }
-
-    fn interrupt_in_range(&self, interrupt: u8) -> bool {
-        (self.interrupt_offset..self.interrupt_offset + 8).contains(&interrupt)
+        self.data_port.write(mask);
     }
 
     unsafe fn signal_end_of_interrupt(&self) {
-        port_write_byte(self.command_port, PIC_COMMAND_END_OF_INTERRUPT);
+        self.command_port.write(PIC_COMMAND_END_OF_INTERRUPT);
     }
 }
 
 pub struct ChainedPIC {
     base_pic: PIC,
     chained_pic: PIC,
+    // Lines drivers have unmasked, a bit per line
+    unmasked: AtomicU16,
 }
 
 // Handles 15 interrupts in chained mode
@@ -96,25 +113,139 @@ impl ChainedPIC {
         ChainedPIC {
             base_pic: PIC::new(interrupt_offset, BASE_PIC_COMMAND_PORT),
             chained_pic: PIC::new(interrupt_offset + 8, CHAINED_PIC_COMMAND_PORT),
+            unmasked: AtomicU16::new(0),
         }
     }
 
     // Unsafe if struct is misconfigured, eg. bad interrupt offsets
     pub unsafe fn init(&self) {
-        self.base_pic.init(PICChainMode::Base);
-        self.chained_pic.init(PICChainMode::Chained);
+        let mask = DEFAULT_UNMASKED
+            .iter()
+            .fold(!self.unmasked.load(Ordering::Relaxed), |mask, irq| {
+                mask & !(1 << irq.line())
+            });
+        self.base_pic.init(PICChainMode::Base, mask as u8);
+        self.chained_pic
+            .init(PICChainMode::Chained, (mask >> 8) as u8);
+    }
+
+    // The PIC and line within it for an IRQ line
+    fn line(&self, irq: u8) -> (&PIC, u8) {
+        assert!(irq < IRQ_LINES, "IRQ {} out of range", irq);
+        match irq {
+            0..=7 => (&self.base_pic, irq),
+            _ => (&self.chained_pic, irq - 8),
+        }
+    }
+
+    pub fn is_masked(&self, irq: u8) -> bool {
+        let (pic, line) = self.line(irq);
+        unsafe { pic.data_port.read() & (1 << line) != 0 }
     }
 
-    // Safety: must only be called from the interrupt handler for Interrupt
-    pub unsafe fn notify_end_of_interrupt(&self, interrupt: Interrupt) {
-        let interrupt = interrupt as u8;
-        if self.chained_pic.interrupt_in_range(interrupt) {
+    // Safety: when unmasking, the line's interrupt must have a handler
+    pub unsafe fn set_masked(&self, irq: u8, masked: bool) {
+        let (pic, line) = self.line(irq);
+        match masked {
+            true => self.unmasked.fetch_and(!(1 << irq), Ordering::Relaxed),
+            false => self.unmasked.fetch_or(1 << irq, Ordering::Relaxed),
+        };
+        let mask = pic.data_port.read();
+        pic.data_port.write(match masked {
+            true => mask | 1 << line,
+            false => mask & !(1 << line),
+        });
+    }
+
+    // Let `irq` through, keeping the mask from init otherwise.
+    // Safety: the IRQ's vector must have a handler
+    pub unsafe fn unmask(&self, irq: Irq) {
+        self.set_masked(irq.line(), false);
+    }
+
+    // Lines on the chained PIC need both PICs told, since they came through the cascade line.
+    // Safety: must only be called from the interrupt handler for `irq`
+    pub unsafe fn notify_end_of_interrupt(&self, irq: Irq) {
+        if irq.line() >= 8 {
             self.chained_pic.signal_end_of_interrupt();
-            self.base_pic.signal_end_of_interrupt();
-        } else if self.base_pic.interrupt_in_range(interrupt) {
-            self.base_pic.signal_end_of_interrupt();
-        } else {
-            panic!("Notified end of unhandled interrupt");
+        }
+        self.base_pic.signal_end_of_interrupt();
+    }
+}
+
+// Mask or unmask an IRQ line (0-15), eg. IRQ 4 for COM1.
+// Safety: when unmasking, the line's interrupt must have a handler
+pub unsafe fn set_masked(irq: u8, masked: bool) {
+    crate::without_interrupt! {{
+        PIC.lock().set_masked(irq, masked);
+    }}
+}
+
+pub fn is_masked(irq: u8) -> bool {
+    crate::without_interrupt! {{ PIC.lock().is_masked(irq) }}
+}
+
+// Keeps an IRQ line masked while a driver reconfigures its device, so it can't interrupt with
+// the device half set up. Puts the mask back how it was when dropped.
+// TODO: an IO APIC equivalent (masking redirection entries), once there's an IO APIC driver
+pub struct IrqGuard {
+    irq: u8,
+    was_masked: bool,
+}
+
+impl IrqGuard {
+    pub fn mask(irq: u8) -> Self {
+        let was_masked = is_masked(irq);
+        // Safety: masking is always safe
+        unsafe { set_masked(irq, true) };
+        IrqGuard { irq, was_masked }
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        // Safety: it was unmasked before, so it has a handler
+        unsafe { set_masked(self.irq, self.was_masked) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_default_mask() {
+        assert!(!is_masked(Irq::Timer.line()));
+        assert!(!is_masked(Irq::Keyboard.line()));
+        // Nobody drives the parallel port
+        assert!(is_masked(7));
+    }
+
+    // COM1's driver unmasks it before the PIC is bound, see driver::PLATFORM_DEVICES
+    #[test_case]
+    fn test_early_unmask_survives_init() {
+        assert!(crate::driver::bindings()
+            .iter()
+            .flatten()
+            .any(|b| b.device.ports.start == 0x3f8));
+        assert!(!is_masked(4));
+    }
+
+    #[test_case]
+    fn test_irq_guard() {
+        let before = crate::time::jiffies();
+        {
+            let _guard = IrqGuard::mask(Irq::Timer.line());
+            assert!(is_masked(Irq::Timer.line()));
+            let masked_at = crate::time::jiffies();
+            for _ in 0..1_000_000 {
+                core::hint::spin_loop();
+            }
+            assert_eq!(crate::time::jiffies(), masked_at);
+        }
+        assert!(!is_masked(Irq::Timer.line()));
+        while crate::time::jiffies() <= before {
+            core::hint::spin_loop();
         }
     }
 }
@@ -0,0 +1,124 @@
+use core::arch::asm;
+
+use crate::acpi;
+use crate::arch::port::{Port, ReadOnlyPort, WriteOnlyPort};
+use crate::syscall::{self, Syscall};
+
+// isa-debug-exit device, see test-args in Cargo.toml
+// exit status will be (status << 1 | 1)
+const QEMU_EXIT_PORT: WriteOnlyPort<u8> = WriteOnlyPort::new(0xF4);
+
+// Hardcoded PM1a control ports for when ACPI tables can't be found.
+// QEMU q35 uses 0x604, QEMU piix4 and Bochs use 0xB004.
+const FALLBACK_PM1A_CONTROL_PORTS: [u16; 2] = [0x604, 0xB004];
+// SLP_TYPa for S5 on QEMU's DSDT, for when we can't read it from \_S5
+const DEFAULT_SLEEP_TYPE_S5: u16 = 0;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+// Reading the command port reads the controller's status
+const KEYBOARD_CONTROLLER_STATUS_PORT: ReadOnlyPort<u8> = ReadOnlyPort::new(0x64);
+const KEYBOARD_CONTROLLER_COMMAND_PORT: WriteOnlyPort<u8> = WriteOnlyPort::new(0x64);
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_PULSE_RESET: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum QemuExitStatus {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+// Exit QEMU with the given status. If we're not running under QEMU (or it wasn't started with
+// isa-debug-exit), fall back to powering off the machine.
+pub fn exit_qemu(status: QemuExitStatus) -> ! {
+    crate::serial::flush();
+    unsafe { QEMU_EXIT_PORT.write(status as u8) };
+    shutdown();
+}
+
+// Enter ACPI sleep state S5 (soft off)
+pub fn shutdown() -> ! {
+    let sleep_control = |sleep_type: u16| sleep_type << 10 | SLEEP_ENABLE;
+    let (sleep_type_a, sleep_type_b) =
+        acpi::s5_sleep_types().unwrap_or((DEFAULT_SLEEP_TYPE_S5, DEFAULT_SLEEP_TYPE_S5));
+    unsafe {
+        if let Some((pm1a, pm1b)) = acpi::pm1_control_blocks() {
+            Port::<u16>::new(pm1a).write(sleep_control(sleep_type_a));
+            if let Some(pm1b) = pm1b {
+                Port::<u16>::new(pm1b).write(sleep_control(sleep_type_b));
+            }
+        }
+        for port in FALLBACK_PM1A_CONTROL_PORTS {
+            Port::<u16>::new(port).write(sleep_control(DEFAULT_SLEEP_TYPE_S5));
+        }
+    };
+    halt();
+}
+
+pub fn reboot() -> ! {
+    unsafe {
+        // Pulse the CPU reset line via the 8042 keyboard controller, once it's ready for a command
+        for _ in 0..0x10000 {
+            if KEYBOARD_CONTROLLER_STATUS_PORT.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        KEYBOARD_CONTROLLER_COMMAND_PORT.write(KEYBOARD_CONTROLLER_PULSE_RESET);
+        triple_fault();
+    }
+}
+
+// Load an empty interrupt table and trigger an interrupt. The CPU can't find a handler for the
+// interrupt, or for the resulting double fault, and resets.
+unsafe fn triple_fault() -> ! {
+    #[repr(C, packed)]
+    struct EmptyTablePointer {
+        limit: u16,
+        base: u64,
+    }
+    let pointer = EmptyTablePointer { limit: 0, base: 0 };
+    asm!("lidt [{}]", "int3", in(reg) &pointer, options(readonly, nostack));
+    halt();
+}
+
+// Stop doing anything, forever
+pub fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
+
+// Orderly versions of shutdown and reboot, which give drivers a chance to quiesce their devices
+fn power_off() -> ! {
+    crate::driver::shutdown();
+    shutdown();
+}
+
+fn restart() -> ! {
+    crate::driver::shutdown();
+    reboot();
+}
+
+fn sys_shutdown(_args: [u64; 6]) -> Result<u64, syscall::Err> {
+    power_off();
+}
+
+fn sys_reboot(_args: [u64; 6]) -> Result<u64, syscall::Err> {
+    restart();
+}
+
+fn shutdown_command(_args: &[&str]) {
+    power_off();
+}
+
+fn reboot_command(_args: &[&str]) {
+    restart();
+}
+
+pub fn init() {
+    syscall::register(Syscall::Shutdown, sys_shutdown);
+    syscall::register(Syscall::Reboot, sys_reboot);
+    crate::shell::register("shutdown", "power off the machine", shutdown_command);
+    crate::shell::register("reboot", "restart the machine", reboot_command);
+}
@@ -0,0 +1,144 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{serial_println, symbols};
+
+// Sampling profiler. While enabled, every timer tick records the interrupted instruction pointer
+// into a ring buffer; `report` buckets the samples by function and prints a flat profile.
+// At the default tick rate a workload needs to run for a few seconds to say anything useful;
+// drivers::pit::set_frequency can sample faster.
+
+const MAX_SAMPLES: usize = 4096;
+// Samples that don't fall in a known function are bucketed by address instead
+const UNKNOWN_BUCKET_SIZE: u64 = 256;
+const REPORT_ROWS: usize = 20;
+
+// TODO: one buffer per CPU once we have more than one
+const EMPTY_SAMPLE: AtomicU64 = AtomicU64::new(0);
+static SAMPLES: [AtomicU64; MAX_SAMPLES] = [EMPTY_SAMPLE; MAX_SAMPLES];
+// Total samples taken; the ring buffer has wrapped if this exceeds MAX_SAMPLES
+static SAMPLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Clear any previous samples and start sampling
+pub fn start() {
+    ENABLED.store(false, Ordering::Relaxed);
+    SAMPLE_COUNT.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+// Called from the timer interrupt
+pub(crate) fn sample(instruction_pointer: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        let index = SAMPLE_COUNT.fetch_add(1, Ordering::Relaxed) % MAX_SAMPLES;
+        SAMPLES[index].store(instruction_pointer, Ordering::Relaxed);
+    }
+}
+
+fn samples() -> impl Iterator<Item = u64> {
+    let count = SAMPLE_COUNT.load(Ordering::Relaxed).min(MAX_SAMPLES);
+    SAMPLES[..count]
+        .iter()
+        .map(|sample| sample.load(Ordering::Relaxed))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    pub start: u64,
+    // The function containing the samples, if we could find one
+    pub name: Option<&'static str>,
+    pub count: usize,
+}
+
+// Samples aggregated by function (or address range), most samples first
+pub fn buckets() -> Vec<Bucket> {
+    let mut buckets: BTreeMap<u64, Bucket> = BTreeMap::new();
+    for address in samples() {
+        let (start, name) = match symbols::lookup(address) {
+            Some(symbol) => (symbol.address, Some(symbol.name)),
+            None => (address & !(UNKNOWN_BUCKET_SIZE - 1), None),
+        };
+        buckets
+            .entry(start)
+            .or_insert(Bucket {
+                start,
+                name,
+                count: 0,
+            })
+            .count += 1;
+    }
+    let mut buckets: Vec<Bucket> = buckets.into_values().collect();
+    buckets.sort_unstable_by(|a, b| b.count.cmp(&a.count));
+    buckets
+}
+
+// Print a flat profile of the samples taken since the last `start`
+pub fn report() {
+    let total = samples().count();
+    let dropped = SAMPLE_COUNT.load(Ordering::Relaxed) - total;
+    serial_println!("profile: {} samples ({} overwritten)", total, dropped);
+    if total == 0 {
+        return;
+    }
+    for bucket in buckets().iter().take(REPORT_ROWS) {
+        let permille = bucket.count * 1000 / total;
+        match bucket.name {
+            Some(name) => serial_println!(
+                "{:>8} {:>3}.{}%  {:#x}  {}",
+                bucket.count,
+                permille / 10,
+                permille % 10,
+                bucket.start,
+                name
+            ),
+            None => serial_println!(
+                "{:>8} {:>3}.{}%  {:#x}  <unknown +{:#x}>",
+                bucket.count,
+                permille / 10,
+                permille % 10,
+                bucket.start,
+                UNKNOWN_BUCKET_SIZE
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn profiled_function() -> u64 {
+        42
+    }
+
+    #[test_case]
+    fn test_samples_bucketed_by_function() {
+        start();
+        let address = profiled_function as usize as u64;
+        for offset in 0..3 {
+            sample(address + offset);
+        }
+        stop();
+        let buckets = buckets();
+        let bucket = buckets
+            .iter()
+            .find(|bucket| bucket.start == address)
+            .expect("No bucket for profiled_function");
+        assert_eq!(bucket.count, 3);
+        assert!(bucket.name.unwrap().contains("profiled_function"));
+    }
+
+    #[test_case]
+    fn test_stopped_profiler_ignores_samples() {
+        start();
+        stop();
+        sample(0x1000);
+        assert_eq!(samples().count(), 0);
+    }
+}
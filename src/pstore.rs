@@ -0,0 +1,218 @@
+use core::fmt::{self, Write};
+use core::panic::{Location, PanicInfo};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::boot::KernelConfig;
+use crate::drivers::cmos;
+use crate::println;
+
+// A crash record that survives a reboot, so a panic early in boot (before anyone's watching the
+// console) still gets reported. The panic handler saves where it panicked, and the next boot
+// prints it with a banner and clears it.
+//
+// The only storage we can count on is CMOS RAM, and most of its 128 bytes belong to the RTC and
+// the firmware. SeaBIOS and QEMU leave 0x60-0x7F alone, but real firmware may not, so it's only
+// used with `pstore` on the kernel command line. That's a magic byte, a length, a checksum and 29
+// bytes of text: enough for the panic location (the end of it, for long paths), not the message
+// or the log.
+// TODO: save the tail of the console log to a reserved disk region once there's a block driver
+
+// Set from the command line; nothing touches CMOS without it
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const REGION_START: u8 = 0x60;
+const REGION_END: u8 = 0x80;
+const MAGIC: u8 = 0xC5;
+const HEADER: usize = 3;
+pub const CAPACITY: usize = (REGION_END - REGION_START) as usize - HEADER;
+
+// The last CAPACITY bytes written to it
+pub struct Record {
+    buffer: [u8; CAPACITY],
+    len: usize,
+}
+
+impl Record {
+    pub const fn new() -> Self {
+        Record {
+            buffer: [0; CAPACITY],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    fn checksum(&self) -> u8 {
+        self.as_bytes()
+            .iter()
+            .fold(self.len as u8, |sum, &byte| sum.wrapping_add(byte))
+    }
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == CAPACITY {
+                self.buffer.copy_within(1.., 0);
+                self.len -= 1;
+            }
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            alloc::string::String::from_utf8_lossy(self.as_bytes())
+        )
+    }
+}
+
+fn register(offset: usize) -> u8 {
+    REGION_START + offset as u8
+}
+
+pub fn save(record: &Record) {
+    unsafe {
+        // Invalidate first, so a reset halfway through doesn't leave a torn record looking valid
+        cmos::write(register(0), 0);
+        for (i, &byte) in record.as_bytes().iter().enumerate() {
+            cmos::write(register(HEADER + i), byte);
+        }
+        cmos::write(register(1), record.len as u8);
+        cmos::write(register(2), record.checksum());
+        cmos::write(register(0), MAGIC);
+    }
+}
+
+// eg. "src/memory/mod.rs:120:9"
+fn location_record(location: &Location) -> Record {
+    let mut record = Record::new();
+    let _ = write!(
+        record,
+        "{}:{}:{}",
+        location.file(),
+        location.line(),
+        location.column()
+    );
+    record
+}
+
+// Called by the panic handler; no heap, and no locks but the CMOS's
+pub fn save_panic(info: &PanicInfo) {
+    if let Some(location) = info.location() {
+        save_location(location);
+    }
+}
+
+fn save_location(location: &Location) {
+    if ENABLED.load(Ordering::Relaxed) {
+        save(&location_record(location));
+    }
+}
+
+// The saved record, if there's a valid one, clearing it so it's only reported once
+pub fn take() -> Option<Record> {
+    if cmos::read(register(0)) != MAGIC {
+        return None;
+    }
+    unsafe { cmos::write(register(0), 0) };
+    let mut record = Record::new();
+    record.len = (cmos::read(register(1)) as usize).min(CAPACITY);
+    for i in 0..record.len {
+        record.buffer[i] = cmos::read(register(HEADER + i));
+    }
+    (cmos::read(register(2)) == record.checksum()).then_some(record)
+}
+
+fn parse_cmdline(cmdline: &str) -> bool {
+    cmdline.split_whitespace().any(|option| option == "pstore")
+}
+
+pub fn init(config: &KernelConfig) {
+    if !parse_cmdline(config.cmdline.as_str()) {
+        return;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    if let Some(record) = take() {
+        println!("Previous boot crashed at ...{}", record);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_record_keeps_tail() {
+        let mut record = Record::new();
+        write!(record, "{}", "x".repeat(CAPACITY)).unwrap();
+        write!(record, "src/lib.rs:12:5").unwrap();
+        assert_eq!(record.as_bytes().len(), CAPACITY);
+        assert!(record.as_bytes().ends_with(b"xsrc/lib.rs:12:5"));
+    }
+
+    #[test_case]
+    fn test_save_and_take() {
+        let mut record = Record::new();
+        write!(record, "panicked at 'test'").unwrap();
+        save(&record);
+        assert_eq!(take().unwrap().as_bytes(), b"panicked at 'test'");
+        // Only reported once
+        assert!(take().is_none());
+    }
+
+    #[test_case]
+    fn test_location_record() {
+        let location = Location::caller();
+        let record = location_record(location);
+        let expected = alloc::format!(
+            "{}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+        let start = expected.len().saturating_sub(CAPACITY);
+        assert_eq!(record.as_bytes(), &expected.as_bytes()[start..]);
+    }
+
+    #[test_case]
+    fn test_only_saved_when_enabled() {
+        assert!(parse_cmdline("quiet pstore"));
+        assert!(!parse_cmdline("pstore=0 headless"));
+        let location = Location::caller();
+        let enabled = ENABLED.swap(false, Ordering::Relaxed);
+        take();
+        save_location(location);
+        assert!(take().is_none());
+        ENABLED.store(true, Ordering::Relaxed);
+        save_location(location);
+        assert_eq!(
+            take().unwrap().as_bytes(),
+            location_record(location).as_bytes()
+        );
+        ENABLED.store(enabled, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_corrupt_record_ignored() {
+        let mut record = Record::new();
+        write!(record, "corrupted").unwrap();
+        save(&record);
+        unsafe { cmos::write(register(HEADER), b'C') };
+        assert!(take().is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use core::arch::{asm, global_asm};
+
+use crate::interrupt::exception_count;
+use crate::interrupt::table::Exception;
+use crate::println;
+
+// Self tests of things that are easy to break without noticing, runnable from the shell on real
+// hardware as well as from the test suite.
+//
+// `interrupts` raises exceptions the kernel can recover from and checks that each one reached its
+// handler, which catches IDT entries lost or crossed when the table gets refactored.
+
+// ud2, resuming at the fixup right after it
+global_asm!(
+    ".global sos_selftest_ud2",
+    "sos_selftest_ud2:",
+    "    ud2",
+    ".global sos_selftest_ud2_fixup",
+    "sos_selftest_ud2_fixup:",
+    "    ret",
+);
+
+extern "C" {
+    fn sos_selftest_ud2();
+    static sos_selftest_ud2_fixup: u8;
+}
+
+// Called from invalid_opcode_handler: where to resume if the fault is our deliberate ud2
+pub(crate) fn fixup(instruction_pointer: u64) -> Option<u64> {
+    (instruction_pointer == sos_selftest_ud2 as usize as u64)
+        .then(|| unsafe { &sos_selftest_ud2_fixup as *const u8 as u64 })
+}
+
+fn raise(exception: Exception) {
+    unsafe {
+        match exception {
+            Exception::Breakpoint => asm!("int3"),
+            // `into` and `bound` are invalid in long mode; the handlers are still reachable
+            Exception::Overflow => asm!("int 4"),
+            Exception::BoundRangeExceeded => asm!("int 5"),
+            Exception::InvalidOpcode => sos_selftest_ud2(),
+            _ => unreachable!("Can't raise {:?} safely", exception),
+        }
+    }
+}
+
+pub const EXCEPTIONS: [Exception; 4] = [
+    Exception::Breakpoint,
+    Exception::Overflow,
+    Exception::BoundRangeExceeded,
+    Exception::InvalidOpcode,
+];
+
+// Raise each of EXCEPTIONS once. Returns the ones whose handler didn't count it.
+pub fn interrupts() -> impl Iterator<Item = Exception> {
+    let mut failed = [None; EXCEPTIONS.len()];
+    for (exception, failed) in EXCEPTIONS.into_iter().zip(failed.iter_mut()) {
+        let before = exception_count(exception);
+        raise(exception);
+        if exception_count(exception) != before + 1 {
+            *failed = Some(exception);
+        }
+    }
+    failed.into_iter().flatten()
+}
+
+fn selftest_command(_args: &[&str]) {
+    let mut failures = 0;
+    for exception in interrupts() {
+        println!("selftest: {:?} didn't reach its handler", exception);
+        failures += 1;
+    }
+    println!(
+        "selftest: {} of {} exceptions handled",
+        EXCEPTIONS.len() - failures,
+        EXCEPTIONS.len()
+    );
+}
+
+pub fn init() {
+    crate::shell::register(
+        "selftest",
+        "raise exceptions and check their handlers",
+        selftest_command,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_interrupt_handlers() {
+        assert_eq!(interrupts().next(), None);
+    }
+}
@@ -1,18 +1,95 @@
-use core::arch::asm;
 use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
 
 use bitflags::bitflags;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+use crate::arch::port::{Port, ReadOnlyPort, WriteOnlyPort};
+use crate::config::{self, Tunable};
+use crate::driver::{Device, Driver};
+use crate::error::{ErrorCode, KError, KResult};
+use crate::interrupt::table::Irq;
+
+// 16550 UARTs at the four standard COM port addresses.
+//
+// Until the driver binds a port, writes busy-wait on the UART one byte at a time. Once bound, writes
+// go into a ring buffer which the transmit-empty interrupt drains a FIFO's worth at a time. Panic
+// handlers can't rely on interrupts, so while panicking writes are synchronous again (after
+// flushing whatever's buffered, to keep output in order).
+//
+// Received bytes go into another ring buffer, filled from the receive interrupt once bound and
+// otherwise whenever someone reads; `read` is a future that's woken when they arrive. With flow
+// control on (the serial_flow_control tunable), RTS is dropped while that buffer is nearly full,
+// and we only send while the other end asserts CTS, so a host pasting faster than we read gets
+// held off rather than losing bytes.
+
 const SERIAL1_PORT: u16 = 0x3F8;
+const BUFFER_SIZE: usize = 1024;
+// How many bytes the UART takes at once when its transmit FIFO is empty
+const TX_FIFO_SIZE: usize = 16;
+// Received bytes buffered before RTS is dropped, and again before it's raised
+const RX_HIGH_WATER: usize = BUFFER_SIZE * 3 / 4;
+const RX_LOW_WATER: usize = BUFFER_SIZE / 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub fn data_port(self) -> u16 {
+        match self {
+            ComPort::Com1 => SERIAL1_PORT,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    pub fn from_data_port(data_port: u16) -> Option<ComPort> {
+        ComPort::ALL
+            .into_iter()
+            .find(|com| com.data_port() == data_port)
+    }
+
+    // COM1 and COM3 share IRQ4, COM2 and COM4 share IRQ3
+    pub fn interrupt(self) -> Irq {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => Irq::Com1,
+            ComPort::Com2 | ComPort::Com4 => Irq::Com2,
+        }
+    }
+
+    pub fn port(self) -> &'static Mutex<SerialPort> {
+        match self {
+            ComPort::Com1 => &SERIAL1,
+            ComPort::Com2 => &SERIAL2,
+            ComPort::Com3 => &SERIAL3,
+            ComPort::Com4 => &SERIAL4,
+        }
+    }
+}
+
+fn open(com: ComPort) -> Mutex<SerialPort> {
+    let mut serial_port = SerialPort::new(com.data_port());
+    serial_port.init();
+    Mutex::new(serial_port)
+}
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let serial_port = SerialPort::new(SERIAL1_PORT);
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    pub static ref SERIAL1: Mutex<SerialPort> = open(ComPort::Com1);
+    pub static ref SERIAL2: Mutex<SerialPort> = open(ComPort::Com2);
+    pub static ref SERIAL3: Mutex<SerialPort> = open(ComPort::Com3);
+    pub static ref SERIAL4: Mutex<SerialPort> = open(ComPort::Com4);
 }
 
 #[macro_export]
@@ -33,67 +110,438 @@ macro_rules! serial_println {
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
 }
 
-#[cfg(target_arch = "x86_64")]
-pub unsafe fn port_write_byte(port: u16, byte: u8) {
-    // Rust inline asm reference: https://doc.rust-lang.org/nightly/reference/inline-assembly.html
-    // OUT instruction reference: https://www.felixcloutier.com/x86/out
-    asm!("out dx, al", in("dx") port, in("al") byte);
+// Print to COM1 without locks, the heap, or anything else that has to be initialized first, so
+// these work from the very first instruction of kernel_main. Call serial::early_init first to set
+// up the line (QEMU doesn't mind if you don't).
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ($crate::serial::force_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
+
+// A line to COM1 prefixed with the uptime and a level, eg. `serial_log!(Level::Warn, "{}", x)`
+// gives `[    1.024000] WARN  ...`. Only the message goes through core::fmt, see serial::log.
+#[macro_export]
+macro_rules! serial_log {
+    ($level:expr, $($arg:tt)*) => ($crate::serial::log($level, format_args!($($arg)*)));
+}
+
+// Configure COM1 with nothing but stack state, for early_print! before anything else is up
+pub fn early_init() {
+    SerialPort::new(SERIAL1_PORT).init();
+}
+
+// Write directly to COM1 without taking the SERIAL1 lock. Only for paths that have to produce
+// output even if whoever holds the lock will never release it (eg. a hung test), or before
+// anything's initialized (see early_print!). Output may interleave with whatever the lock holder
+// was writing.
+pub fn force_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    SerialPort::new(SERIAL1_PORT).write_fmt(args).ok();
 }
 
-#[cfg(target_arch = "x86_64")]
-pub unsafe fn port_read_byte(port: u16) -> u8 {
-    let mut byte: u8;
-    asm!("in al, dx", in("dx") port, out("al") byte);
-    byte
+// Numbers and strings to COM1 without core::fmt, whose formatting machinery is slow enough to
+// show up in interrupt latency when it runs with interrupts disabled
+pub fn write_str_raw(s: &str) {
+    crate::without_interrupt! {{ SERIAL1.lock().write_str_raw(s) }};
+}
+
+pub fn write_dec_u64(value: u64) {
+    crate::without_interrupt! {{ SERIAL1.lock().write_dec_u64(value) }};
+}
+
+pub fn write_hex_u64(value: u64) {
+    crate::without_interrupt! {{ SERIAL1.lock().write_hex_u64(value) }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    // Padded to the same width
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+// The prefix is written with the fast paths, and the whole line under one lock. Anything more
+// verbose than the log_level tunable is dropped.
+pub fn log(level: Level, args: fmt::Arguments) {
+    use core::fmt::Write;
+    if level as usize > crate::config::get(crate::config::Tunable::LogLevel) {
+        return;
+    }
+    let uptime = crate::time::uptime();
+    crate::without_interrupt! {{
+        let mut port = SERIAL1.lock();
+        port.write_str_raw("[");
+        port.write_digits(uptime.as_secs(), 10, 5, b' ');
+        port.write_str_raw(".");
+        port.write_digits(uptime.subsec_micros() as u64, 10, 6, b'0');
+        port.write_str_raw("] ");
+        port.write_str_raw(level.label());
+        port.write_str_raw(" ");
+        port.write_fmt(args).ok();
+        port.write_str_raw("\n");
+    }};
+}
+
+// u64::MAX in decimal
+const MAX_DIGITS: usize = 20;
+
+// Digits of `value` into the end of `buffer`, at least `width` of them padded with `pad`
+fn digits(value: u64, base: u64, width: usize, pad: u8, buffer: &mut [u8; MAX_DIGITS]) -> &[u8] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let (mut value, mut start) = (value, MAX_DIGITS);
+    loop {
+        start -= 1;
+        buffer[start] = DIGITS[(value % base) as usize];
+        value /= base;
+        if value == 0 {
+            break;
+        }
+    }
+    while MAX_DIGITS - start < width.min(MAX_DIGITS) {
+        start -= 1;
+        buffer[start] = pad;
+    }
+    &buffer[start..]
 }
 
 bitflags! {
     struct LineStatus: u8 {
         const INPUT_FULL = 1;
+        // A byte arrived before the last one was read, and was lost
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR = 1 << 2;
+        // No stop bit where one should be, usually a baud rate mismatch
+        const FRAMING_ERROR = 1 << 3;
         const OUTPUT_EMPTY = 1 << 5;
+        // Nothing left to send in the FIFO or the shift register
+        const TRANSMITTER_EMPTY = 1 << 6;
+    }
+}
+
+const LINE_CONTROL_DIVISOR_LATCH: u8 = 0x80;
+// Interrupt identification register bits set when the FIFOs are enabled and working
+const IDENTIFICATION_FIFO: u8 = 0xC0;
+// The UART's input clock divided by 16
+const MAX_BAUD_RATE: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaudRate {
+    B9600,
+    B19200,
+    B38400,
+    B57600,
+    B115200,
+}
+
+impl BaudRate {
+    pub fn bits_per_second(self) -> u32 {
+        match self {
+            BaudRate::B9600 => 9600,
+            BaudRate::B19200 => 19200,
+            BaudRate::B38400 => 38400,
+            BaudRate::B57600 => 57600,
+            BaudRate::B115200 => 115_200,
+        }
+    }
+
+    fn divisor(self) -> u16 {
+        (MAX_BAUD_RATE / self.bits_per_second()) as u16
+    }
+}
+
+// Values are the line control register encodings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five = 0,
+    Six = 1,
+    Seven = 2,
+    Eight = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    Mark = 0b101,
+    Space = 0b111,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One = 0,
+    // 1.5 with five data bits
+    Two = 1,
+}
+
+// Receive errors seen since the port was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineErrors {
+    // Bytes the UART lost because we didn't read its FIFO in time
+    pub overruns: usize,
+    pub parity_errors: usize,
+    pub framing_errors: usize,
+    // Bytes we dropped because the RX buffer was full
+    pub buffer_overflows: usize,
+}
+
+// Interrupt enable register bits
+const INTERRUPT_RX_AVAILABLE: u8 = 1;
+const INTERRUPT_TX_EMPTY: u8 = 1 << 1;
+const INTERRUPT_LINE_STATUS: u8 = 1 << 2;
+const INTERRUPT_MODEM_STATUS: u8 = 1 << 3;
+
+// Modem control register bits. OUT2 gates the UART's interrupt line on PCs.
+const MODEM_DTR: u8 = 1;
+const MODEM_RTS: u8 = 1 << 1;
+const MODEM_OUT2: u8 = 1 << 3;
+// Transmit straight into the receiver, with RTS looped to CTS, for testing
+const MODEM_LOOPBACK: u8 = 1 << 4;
+
+// Modem status register bit for "clear to send"
+const MODEM_STATUS_CTS: u8 = 1 << 4;
+
+// Bytes waiting to be sent or read, oldest first
+struct Ring {
+    bytes: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            bytes: [0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    // False if there's no room
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == BUFFER_SIZE {
+            return false;
+        }
+        self.bytes[(self.start + self.len) % BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// Registers relative to the port's base (data) port. With the divisor latch set in line control,
+// data and interrupt enable hold the low and high bytes of the baud rate divisor instead.
+struct Registers {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    // Two registers at the same port
+    interrupt_identification: ReadOnlyPort<u8>,
+    fifo_control: WriteOnlyPort<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: ReadOnlyPort<u8>,
+    modem_status: ReadOnlyPort<u8>,
+    scratch: Port<u8>,
+}
+
+impl Registers {
+    const fn new(data_port: u16) -> Self {
+        Registers {
+            data: Port::new(data_port),
+            interrupt_enable: Port::new(data_port + 1),
+            interrupt_identification: ReadOnlyPort::new(data_port + 2),
+            fifo_control: WriteOnlyPort::new(data_port + 2),
+            line_control: Port::new(data_port + 3),
+            modem_control: Port::new(data_port + 4),
+            line_status: ReadOnlyPort::new(data_port + 5),
+            modem_status: ReadOnlyPort::new(data_port + 6),
+            scratch: Port::new(data_port + 7),
+        }
     }
 }
 
 pub struct SerialPort {
-    data_port: u16,
+    registers: Registers,
+    tx: Ring,
+    rx: Ring,
+    // The reader waiting for `rx` to have something, see Read
+    rx_waker: Option<Waker>,
+    // Whether writes are buffered and sent from the transmit-empty interrupt, and reads filled
+    // from the receive interrupt
+    interrupt_driven: bool,
+    // RTS/CTS
+    flow_control: bool,
+    // Whether we're asserting RTS
+    rts: bool,
+    loopback: bool,
+    baud_rate: BaudRate,
+    fifo: bool,
+    overruns: AtomicUsize,
+    parity_errors: AtomicUsize,
+    framing_errors: AtomicUsize,
+    buffer_overflows: AtomicUsize,
+}
+
+// Whether to assert RTS with `buffered` bytes waiting to be read. Between the watermarks it stays
+// as it was, so it doesn't flap with every byte.
+fn rts_wanted(buffered: usize, asserted: bool) -> bool {
+    match buffered {
+        buffered if buffered >= RX_HIGH_WATER => false,
+        buffered if buffered <= RX_LOW_WATER => true,
+        _ => asserted,
+    }
 }
 
 impl SerialPort {
+    // Doesn't touch the hardware; see init
     pub fn new(data_port: u16) -> SerialPort {
-        SerialPort { data_port }
+        SerialPort {
+            registers: Registers::new(data_port),
+            tx: Ring::new(),
+            rx: Ring::new(),
+            rx_waker: None,
+            interrupt_driven: false,
+            flow_control: false,
+            rts: true,
+            loopback: false,
+            baud_rate: BaudRate::B38400,
+            fifo: false,
+            overruns: AtomicUsize::new(0),
+            parity_errors: AtomicUsize::new(0),
+            framing_errors: AtomicUsize::new(0),
+            buffer_overflows: AtomicUsize::new(0),
+        }
+    }
+
+    // 38400 baud 8N1, what QEMU and most terminals expect
+    pub fn init(&mut self) {
+        self.configure(
+            BaudRate::B38400,
+            DataBits::Eight,
+            Parity::None,
+            StopBits::One,
+        );
     }
 
-    pub fn init(&self) {
-        let interrupt_enable = self.data_port + 1;
-        let fifo_ctrl = self.data_port + 2;
-        let line_ctrl = self.data_port + 3;
-        let modem_ctrl = self.data_port + 4;
+    // Program the line settings and turn on the FIFOs if the UART has working ones
+    pub fn configure(
+        &mut self,
+        baud_rate: BaudRate,
+        data_bits: DataBits,
+        parity: Parity,
+        stop_bits: StopBits,
+    ) {
+        let [divisor_low, divisor_high] = baud_rate.divisor().to_le_bytes();
+        let line_settings = data_bits as u8 | (stop_bits as u8) << 2 | (parity as u8) << 3;
 
         unsafe {
+            // Clearing the FIFOs below would drop anything still being sent, eg. by early_print!
+            while !self.line_status().contains(LineStatus::TRANSMITTER_EMPTY) {
+                core::hint::spin_loop();
+            }
+
             // Taken from https://github.com/rust-osdev/uart_16550/blob/master/src/port.rs
-            port_write_byte(interrupt_enable, 0x00); // Disable interrupts
-            port_write_byte(line_ctrl, 0x80); // Enable DLAB, TODO docs
+            self.registers.interrupt_enable.write(0x00); // Disable interrupts
 
-            // Set maximum speed to 38400 bps by configuring DLL and DLM
-            port_write_byte(self.data_port, 0x03);
-            port_write_byte(interrupt_enable, 0x00);
+            // Set the baud rate through the divisor latch, see Registers
+            self.registers
+                .line_control
+                .write(LINE_CONTROL_DIVISOR_LATCH);
+            self.registers.data.write(divisor_low);
+            self.registers.interrupt_enable.write(divisor_high);
+            self.registers.line_control.write(line_settings);
 
-            // Disable DLAB and set data word length to 8 bits
-            port_write_byte(line_ctrl, 0x03);
+            // Enable FIFO, clear TX/RX queues and set interrupt watermark at 14 bytes. Only a
+            // 16550A reports both FIFO bits back; the original 16550's FIFOs are broken, and
+            // older UARTs have none.
+            self.registers.fifo_control.write(0xC7);
+            let identification = self.registers.interrupt_identification.read();
+            self.fifo = identification & IDENTIFICATION_FIFO == IDENTIFICATION_FIFO;
+            if !self.fifo {
+                self.registers.fifo_control.write(0x00);
+            }
 
-            // Enable FIFO, clear TX/RX queues and set interrupt watermark at 14 bytes
-            port_write_byte(fifo_ctrl, 0xC7);
+            self.write_modem_control();
+            self.update_interrupts();
+        }
+        self.baud_rate = baud_rate;
+    }
+
+    pub fn baud_rate(&self) -> BaudRate {
+        self.baud_rate
+    }
 
-            // Mark data terminal ready, signal request to send
-            // and enable auxilliary output #2 (used as interrupt line for CPU)
-            port_write_byte(modem_ctrl, 0x08);
-            port_write_byte(interrupt_enable, 0x00); // Enable interrupts
+    pub fn has_fifo(&self) -> bool {
+        self.fifo
+    }
+
+    pub fn errors(&self) -> LineErrors {
+        LineErrors {
+            overruns: self.overruns.load(Ordering::Relaxed),
+            parity_errors: self.parity_errors.load(Ordering::Relaxed),
+            framing_errors: self.framing_errors.load(Ordering::Relaxed),
+            buffer_overflows: self.buffer_overflows.load(Ordering::Relaxed),
         }
     }
 
+    pub fn flow_control(&self) -> bool {
+        self.flow_control
+    }
+
+    // Turning it off asserts RTS for good and sends regardless of CTS
+    pub fn set_flow_control(&mut self, enabled: bool) {
+        self.flow_control = enabled;
+        self.update_rts();
+        // Anything held back for CTS
+        self.drain();
+    }
+
+    // Reading the line status clears its error bits, so every read counts them
     unsafe fn line_status(&self) -> LineStatus {
-        let line_status_port = self.data_port + 5;
-        LineStatus::from_bits_truncate(port_read_byte(line_status_port))
+        let status = LineStatus::from_bits_truncate(self.registers.line_status.read());
+        let counters = [
+            (LineStatus::OVERRUN_ERROR, &self.overruns),
+            (LineStatus::PARITY_ERROR, &self.parity_errors),
+            (LineStatus::FRAMING_ERROR, &self.framing_errors),
+        ];
+        for (error, counter) in counters {
+            if status.contains(error) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        status
     }
 
     unsafe fn wait_for_output_empty(&self) {
@@ -102,21 +550,147 @@ impl SerialPort {
         }
     }
 
-    unsafe fn wait_for_input_fill(&self) {
-        while !self.line_status().contains(LineStatus::INPUT_FULL) {
-            core::hint::spin_loop();
+    // Whether there's a UART here at all, going by whether its scratch register holds a value
+    pub fn is_present(&self) -> bool {
+        let scratch = &self.registers.scratch;
+        unsafe {
+            scratch.write(0xa5);
+            scratch.read() == 0xa5
+        }
+    }
+
+    // Data terminal ready, RTS unless we're holding the other end off, and OUT2
+    unsafe fn write_modem_control(&self) {
+        let rts = if self.rts { MODEM_RTS } else { 0 };
+        let loopback = if self.loopback { MODEM_LOOPBACK } else { 0 };
+        self.registers
+            .modem_control
+            .write(MODEM_DTR | rts | MODEM_OUT2 | loopback);
+    }
+
+    // Receive interrupts whenever interrupt driven, transmit-empty only while there's something
+    // to send, and CTS changes if we might be waiting on one
+    unsafe fn update_interrupts(&self) {
+        let mut interrupt_enable = 0;
+        if self.interrupt_driven {
+            interrupt_enable |= INTERRUPT_RX_AVAILABLE | INTERRUPT_LINE_STATUS;
+            if !self.tx.is_empty() {
+                interrupt_enable |= INTERRUPT_TX_EMPTY;
+            }
+            if self.flow_control {
+                interrupt_enable |= INTERRUPT_MODEM_STATUS;
+            }
+        }
+        self.registers.interrupt_enable.write(interrupt_enable);
+    }
+
+    fn update_rts(&mut self) {
+        let rts = !self.flow_control || rts_wanted(self.rx.len, self.rts);
+        if rts != self.rts {
+            self.rts = rts;
+            unsafe { self.write_modem_control() };
+        }
+    }
+
+    // Whether the other end will take more. Ignored while panicking, so a panic message can't
+    // wait forever on a host that isn't reading.
+    fn clear_to_send(&self) -> bool {
+        !self.flow_control
+            || crate::panicking::is_panicking()
+            || unsafe { self.registers.modem_status.read() } & MODEM_STATUS_CTS != 0
+    }
+
+    // Switch to buffered writes and interrupt driven reads. The caller has to route the port's
+    // IRQ to `handle_interrupt`.
+    pub fn enable_interrupts(&mut self) {
+        self.interrupt_driven = true;
+        unsafe { self.update_interrupts() };
+    }
+
+    // Back to synchronous writes, once everything buffered is sent
+    pub fn disable_interrupts(&mut self) {
+        self.flush();
+        self.interrupt_driven = false;
+        unsafe { self.update_interrupts() };
+    }
+
+    // Move everything the UART has received into the RX buffer, counting what doesn't fit.
+    // Reading the data register is also what clears the receive interrupt.
+    fn receive(&mut self) {
+        unsafe {
+            while self.line_status().contains(LineStatus::INPUT_FULL) {
+                if !self.rx.push(self.registers.data.read()) {
+                    self.buffer_overflows.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        self.update_rts();
+        if !self.rx.is_empty() {
+            if let Some(waker) = self.rx_waker.take() {
+                waker.wake();
+            }
         }
     }
 
-    // TODO: async implementations (one day :>)
-    pub fn write_byte_raw(&self, byte: u8) {
+    // Hand the UART as much buffered output as its FIFO takes, if it's ready for more (and the
+    // other end is, with flow control). The transmit-empty interrupt stays enabled while there's
+    // more to send.
+    fn drain(&mut self) {
         unsafe {
-            self.wait_for_output_empty();
-            port_write_byte(self.data_port, byte);
+            if !self.line_status().contains(LineStatus::OUTPUT_EMPTY) || !self.clear_to_send() {
+                return;
+            }
+            let room = if self.fifo { TX_FIFO_SIZE } else { 1 };
+            for _ in 0..room {
+                match self.tx.pop() {
+                    Some(byte) => self.registers.data.write(byte),
+                    None => break,
+                }
+            }
+            self.update_interrupts();
+        }
+    }
+
+    // Wait for everything buffered to be sent
+    pub fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            unsafe { self.wait_for_output_empty() };
+            self.drain();
         }
     }
 
-    pub fn write_byte(&self, byte: u8) {
+    // From the port's IRQ handler
+    pub fn handle_interrupt(&mut self) {
+        // Reading them acknowledges the transmit-empty and modem status (CTS) interrupts
+        unsafe {
+            self.registers.interrupt_identification.read();
+            self.registers.modem_status.read();
+        }
+        self.receive();
+        self.drain();
+    }
+
+    pub fn write_byte_raw(&mut self, byte: u8) {
+        if !self.interrupt_driven || crate::panicking::is_panicking() {
+            self.flush();
+            unsafe { self.wait_for_output_empty() };
+            while !self.clear_to_send() {
+                core::hint::spin_loop();
+            }
+            unsafe { self.registers.data.write(byte) };
+            return;
+        }
+        // Wait for room rather than dropping output
+        while !self.tx.push(byte) {
+            unsafe { self.wait_for_output_empty() };
+            self.drain();
+        }
+        self.drain();
+        // The interrupt handler skips the port while we hold it
+        self.receive();
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
         match byte {
             8 | 0x7f => {
                 // TODO: docs
@@ -128,13 +702,45 @@ impl SerialPort {
         }
     }
 
+    // The oldest byte received, if there's one
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        self.receive();
+        let byte = self.rx.pop();
+        self.update_rts();
+        byte
+    }
+
     #[allow(dead_code)]
-    pub fn read_byte(&self) -> u8 {
-        unsafe {
-            self.wait_for_input_fill();
-            port_read_byte(self.data_port)
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            match self.try_read_byte() {
+                Some(byte) => return byte,
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    // Without the backspace handling write_byte does, or core::fmt
+    pub fn write_str_raw(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.write_byte_raw(byte));
+    }
+
+    fn write_digits(&mut self, value: u64, base: u64, width: usize, pad: u8) {
+        let mut buffer = [0; MAX_DIGITS];
+        for &digit in digits(value, base, width, pad, &mut buffer) {
+            self.write_byte_raw(digit);
         }
     }
+
+    pub fn write_dec_u64(&mut self, value: u64) {
+        self.write_digits(value, 10, 1, b'0');
+    }
+
+    // All 16 digits, after 0x
+    pub fn write_hex_u64(&mut self, value: u64) {
+        self.write_str_raw("0x");
+        self.write_digits(value, 16, 16, b'0');
+    }
 }
 
 impl fmt::Write for SerialPort {
@@ -143,3 +749,336 @@ impl fmt::Write for SerialPort {
         Ok(())
     }
 }
+
+// Called from the IRQ3 and IRQ4 handlers. Each line is shared by two ports.
+pub fn handle_interrupt(irq: Irq) {
+    for com in ComPort::ALL.iter().filter(|com| com.interrupt() == irq) {
+        // Anyone holding the lock drains the buffer themselves when they write
+        if let Some(mut port) = com.port().try_lock() {
+            if port.interrupt_driven {
+                port.handle_interrupt();
+            }
+        }
+    }
+}
+
+// Wait for `com` to receive something, then take as much of it as fits in `buffer`, eg.
+// `task::block_on(serial::read(ComPort::Com1, &mut buffer))`
+pub fn read(com: ComPort, buffer: &mut [u8]) -> Read<'_> {
+    Read { com, buffer }
+}
+
+// One reader per port at a time: the port only keeps the latest waker
+pub struct Read<'a> {
+    com: ComPort,
+    buffer: &'a mut [u8],
+}
+
+impl Future for Read<'_> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<usize> {
+        let read = self.get_mut();
+        crate::without_interrupt! {{
+            let mut port = read.com.port().lock();
+            let mut count = 0;
+            while count < read.buffer.len() {
+                match port.try_read_byte() {
+                    Some(byte) => read.buffer[count] = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            if count > 0 || read.buffer.is_empty() {
+                return Poll::Ready(count);
+            }
+            // receive wakes it with the port locked, and it's only taken back out under the same
+            // lock, so an interrupt handler never drops the last reference to a waker
+            port.rx_waker = Some(context.waker().clone());
+            Poll::Pending
+        }}
+    }
+}
+
+// Forget the waker of a reader that gave up
+impl Drop for Read<'_> {
+    fn drop(&mut self) {
+        crate::without_interrupt! {{
+            self.com.port().lock().rx_waker = None;
+        }}
+    }
+}
+
+// Send everything buffered on every port, eg. before exiting QEMU
+pub fn flush() {
+    crate::without_interrupt! {{
+        for com in ComPort::ALL {
+            if let Some(mut port) = com.port().try_lock() {
+                port.flush();
+            }
+        }
+    }}
+}
+
+#[cfg(test)]
+impl SerialPort {
+    fn set_loopback(&mut self, enabled: bool) {
+        self.loopback = enabled;
+        unsafe { self.write_modem_control() };
+    }
+
+    // Straight to the UART, so with loopback on only `receive` picks it up
+    fn send_to_uart(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            unsafe {
+                self.wait_for_output_empty();
+                self.registers.data.write(byte);
+            }
+        }
+    }
+}
+
+struct Uart;
+
+impl Driver for Uart {
+    fn probe(&self, device: &Device) -> bool {
+        device.name == "ns16550" && ComPort::from_data_port(device.ports.start).is_some()
+    }
+
+    fn init(&self, device: &Device) -> KResult<()> {
+        let com = ComPort::from_data_port(device.ports.start).ok_or_else(|| {
+            KError::new(ErrorCode::Unsupported)
+                .with_detail(format_args!("no COM port at {:#x}", device.ports.start))
+        })?;
+        let present = crate::without_interrupt! {{
+            let mut port = com.port().lock();
+            let present = port.is_present();
+            if present {
+                port.set_flow_control(config::get(Tunable::SerialFlowControl) != 0);
+                port.enable_interrupts();
+                unsafe { crate::pic8259::PIC.lock().unmask(com.interrupt()) };
+            }
+            present
+        }};
+        if !present {
+            return Err(KError::new(ErrorCode::DeviceNotResponding)
+                .with_message("scratch register didn't hold its value")
+                .with_detail(format_args!("{:?}", com)));
+        }
+        crate::shell::register(
+            "serial",
+            "show serial port settings and errors",
+            serial_command,
+        );
+        Ok(())
+    }
+
+    fn shutdown(&self, device: &Device) {
+        if let Some(com) = ComPort::from_data_port(device.ports.start) {
+            crate::without_interrupt! {{
+                com.port().lock().disable_interrupts();
+            }}
+        }
+    }
+}
+
+crate::register_driver!(UART_DRIVER, Uart);
+
+fn serial_command(_args: &[&str]) {
+    let bound = crate::driver::bindings();
+    let ports = bound
+        .iter()
+        .flatten()
+        .filter_map(|binding| ComPort::from_data_port(binding.device.ports.start));
+    for com in ports {
+        let (baud_rate, fifo, flow_control, errors) = crate::without_interrupt! {{
+            let port = com.port().lock();
+            (port.baud_rate(), port.has_fifo(), port.flow_control(), port.errors())
+        }};
+        crate::println!(
+            "{:?}: {} baud, {}, {}, {} overruns, {} parity errors, {} framing errors, {} bytes dropped",
+            com,
+            baud_rate.bits_per_second(),
+            if fifo { "16550A FIFO" } else { "no FIFO" },
+            if flow_control { "RTS/CTS" } else { "no flow control" },
+            errors.overruns,
+            errors.parity_errors,
+            errors.framing_errors,
+            errors.buffer_overflows
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use core::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::task;
+
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    // Up to 100ms for COM1's receive interrupt to bring `rx` to `count` bytes. Short of the
+    // FIFO's trigger level, that's the character timeout, four characters after the last.
+    fn wait_for_received(count: usize) -> usize {
+        let mut received = 0;
+        for _ in 0..100 {
+            received = crate::without_interrupt! {{
+                let received = ComPort::Com1.port().lock().rx.len;
+                received
+            }};
+            if received >= count {
+                break;
+            }
+            crate::time::delay::delay_us(1000);
+        }
+        received
+    }
+
+    // Run `f` on COM1 looped back on itself, which also keeps anything it sends off the line
+    fn with_loopback<T>(f: impl FnOnce() -> T) -> T {
+        crate::without_interrupt! {{
+            let mut port = ComPort::Com1.port().lock();
+            port.flush();
+            port.set_loopback(true);
+        }}
+        let result = f();
+        crate::without_interrupt! {{
+            let mut port = ComPort::Com1.port().lock();
+            port.set_loopback(false);
+            while port.try_read_byte().is_some() {}
+        }}
+        result
+    }
+
+    #[test_case]
+    fn test_ring_wraps() {
+        let mut buffer = Ring::new();
+        for _ in 0..BUFFER_SIZE - 1 {
+            assert!(buffer.push(0));
+            buffer.pop();
+        }
+        assert!(buffer.push(1));
+        assert!(buffer.push(2));
+        assert_eq!(buffer.pop(), Some(1));
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), None);
+        (0..BUFFER_SIZE).for_each(|_| assert!(buffer.push(3)));
+        assert!(!buffer.push(4));
+    }
+
+    #[test_case]
+    fn test_rts_hysteresis() {
+        assert!(rts_wanted(0, false));
+        assert!(rts_wanted(RX_HIGH_WATER - 1, true));
+        assert!(!rts_wanted(RX_HIGH_WATER, true));
+        // Stays off until the reader catches up
+        assert!(!rts_wanted(RX_LOW_WATER + 1, false));
+        assert!(rts_wanted(RX_LOW_WATER, false));
+    }
+
+    #[test_case]
+    fn test_divisors() {
+        assert_eq!(BaudRate::B115200.divisor(), 1);
+        assert_eq!(BaudRate::B38400.divisor(), 3);
+        assert_eq!(BaudRate::B9600.divisor(), 12);
+    }
+
+    #[test_case]
+    fn test_digits() {
+        let mut buffer = [0; MAX_DIGITS];
+        assert_eq!(digits(0, 10, 1, b'0', &mut buffer), b"0");
+        assert_eq!(digits(1024, 10, 6, b' ', &mut buffer), b"  1024");
+        assert_eq!(
+            digits(u64::MAX, 10, 1, b'0', &mut buffer),
+            b"18446744073709551615"
+        );
+        assert_eq!(digits(0xbeef, 16, 8, b'0', &mut buffer), b"0000beef");
+        assert_eq!(
+            digits(u64::MAX, 16, 16, b'0', &mut buffer),
+            b"ffffffffffffffff"
+        );
+    }
+
+    #[test_case]
+    fn test_com_ports() {
+        assert_eq!(ComPort::from_data_port(0x2E8), Some(ComPort::Com4));
+        assert_eq!(ComPort::from_data_port(0x3F9), None);
+        assert_eq!(ComPort::Com3.interrupt(), Irq::Com1);
+    }
+
+    #[test_case]
+    fn test_loopback_receive_interrupt() {
+        let (interrupt_driven, received, count, buffer) = with_loopback(|| {
+            let interrupt_driven = crate::without_interrupt! {{
+                let port = ComPort::Com1.port().lock();
+                port.send_to_uart(b"sos\n");
+                let interrupt_driven = port.interrupt_driven;
+                interrupt_driven
+            }};
+            // Nothing calls receive but the interrupt handler
+            let received = wait_for_received(4);
+            let mut buffer = [0; 8];
+            let count = task::block_on(read(ComPort::Com1, &mut buffer));
+            (interrupt_driven, received, count, buffer)
+        });
+        assert!(interrupt_driven);
+        assert_eq!(received, 4);
+        assert_eq!(&buffer[..count], b"sos\n");
+    }
+
+    #[test_case]
+    fn test_read_woken_by_interrupt() {
+        let (first, woken, second, buffer) = with_loopback(|| {
+            let woken = Arc::new(Woken(AtomicBool::new(false)));
+            let waker = Waker::from(woken.clone());
+            let mut context = Context::from_waker(&waker);
+            let mut buffer = [0; 4];
+            let mut reader = read(ComPort::Com1, &mut buffer);
+            let first = Pin::new(&mut reader).poll(&mut context);
+            crate::without_interrupt! {{
+                ComPort::Com1.port().lock().send_to_uart(b"x");
+            }}
+            wait_for_received(1);
+            let woken = woken.0.load(Ordering::Acquire);
+            let second = Pin::new(&mut reader).poll(&mut context);
+            drop(reader);
+            (first, woken, second, buffer)
+        });
+        assert_eq!(first, Poll::Pending);
+        assert!(woken);
+        assert_eq!(second, Poll::Ready(1));
+        assert_eq!(buffer[0], b'x');
+        assert!(ComPort::Com1.port().lock().rx_waker.is_none());
+    }
+
+    #[test_case]
+    fn test_transmit_waits_for_cts() {
+        // Looped back, CTS follows our own RTS
+        let (held, clear) = with_loopback(|| {
+            crate::without_interrupt! {{
+                let mut port = ComPort::Com1.port().lock();
+                let flow_control = port.flow_control();
+                port.set_flow_control(true);
+                port.rts = false;
+                unsafe { port.write_modem_control() };
+                let held = port.clear_to_send();
+                port.rts = true;
+                unsafe { port.write_modem_control() };
+                let clear = port.clear_to_send();
+                port.set_flow_control(flow_control);
+                (held, clear)
+            }}
+        });
+        assert!(!held);
+        assert!(clear);
+    }
+}
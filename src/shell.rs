@@ -0,0 +1,166 @@
+use spin::Mutex;
+
+use crate::task::scheduler;
+use crate::{print, println};
+
+// Kernel shell commands. Subsystems register commands by name, and `run` dispatches a line of
+// input to the matching one. `task` runs lines from the console, see console::line.
+
+const MAX_COMMANDS: usize = 32;
+const MAX_ARGS: usize = 16;
+
+// Called with the words after the command name
+pub type CommandFn = fn(args: &[&str]);
+
+#[derive(Clone, Copy)]
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: CommandFn,
+}
+
+static COMMANDS: Mutex<[Option<Command>; MAX_COMMANDS]> = Mutex::new([None; MAX_COMMANDS]);
+
+pub fn register(name: &'static str, help: &'static str, run: CommandFn) {
+    crate::without_interrupt! {{
+        let mut commands = COMMANDS.lock();
+        let command = Command { name, help, run };
+        let index = commands
+            .iter()
+            .position(|c| matches!(c, Some(c) if c.name == name))
+            .or_else(|| commands.iter().position(|c| c.is_none()));
+        match index {
+            Some(index) => commands[index] = Some(command),
+            None => panic!("Too many shell commands registering {}", name),
+        }
+    }}
+}
+
+pub fn unregister(name: &'static str) {
+    crate::without_interrupt! {{
+        COMMANDS
+            .lock()
+            .iter_mut()
+            .filter(|c| matches!(c, Some(c) if c.name == name))
+            .for_each(|c| *c = None);
+    }}
+}
+
+fn find(name: &str) -> Option<Command> {
+    crate::without_interrupt! {{
+        COMMANDS.lock().iter().flatten().find(|c| c.name == name).copied()
+    }}
+}
+
+// Run a line of input. Returns false if there was no such command.
+pub fn run(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return true,
+    };
+    let mut args = [""; MAX_ARGS];
+    let mut count = 0;
+    for word in words {
+        if count == MAX_ARGS {
+            println!("{}: too many arguments", name);
+            return true;
+        }
+        args[count] = word;
+        count += 1;
+    }
+    match find(name) {
+        // Run without the registry locked, so commands can (un)register
+        Some(command) => {
+            (command.run)(&args[..count]);
+            true
+        }
+        None => {
+            println!("{}: command not found", name);
+            false
+        }
+    }
+}
+
+fn help(_: &[&str]) {
+    let commands = crate::without_interrupt! {{
+        let commands = *COMMANDS.lock();
+        commands
+    }};
+    for command in commands.iter().flatten() {
+        println!("{:<12} {}", command.name, command.help);
+    }
+}
+
+// time <command> [args...]: run a command, then report how long it took and how much of that
+// the shell spent running
+fn time_command(args: &[&str]) {
+    let (name, args) = match args.split_first() {
+        Some(split) => split,
+        None => {
+            println!("usage: time <command> [args...]");
+            return;
+        }
+    };
+    let command = match find(name) {
+        Some(command) => command,
+        None => {
+            println!("{}: command not found", name);
+            return;
+        }
+    };
+    let task = scheduler::current_id();
+    let start = crate::time::uptime();
+    let before = scheduler::accounting(task).unwrap_or_default();
+    (command.run)(args);
+    let after = scheduler::accounting(task).unwrap_or_default();
+    let elapsed = crate::time::uptime() - start;
+    println!(
+        "{}: {}.{:03}s elapsed, {} ticks running, {} switches",
+        name,
+        elapsed.as_secs(),
+        elapsed.subsec_millis(),
+        after.run_ticks - before.run_ticks,
+        after.switches - before.switches
+    );
+}
+
+pub fn init() {
+    register("help", "list commands", help);
+    register(
+        "time",
+        "run a command and report its run time",
+        time_command,
+    );
+}
+
+// Read and run commands from the console forever, as a task
+pub fn task() {
+    loop {
+        print!("> ");
+        run(&crate::console::line::read_line());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static ARGS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_args(args: &[&str]) {
+        ARGS.store(args.len(), Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_run_command() {
+        register("test_count", "count arguments", count_args);
+        assert!(run("  test_count a  b c "));
+        assert_eq!(ARGS.load(Ordering::Relaxed), 3);
+        assert!(run("time test_count a"));
+        assert_eq!(ARGS.load(Ordering::Relaxed), 1);
+        unregister("test_count");
+        assert!(!run("test_count"));
+    }
+}
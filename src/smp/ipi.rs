@@ -0,0 +1,133 @@
+use spin::Mutex;
+
+use super::{current_cpu, other_cpus, CpuId};
+use crate::interrupt::table::{InterruptStackFrame, Vector};
+
+// Inter-processor interrupts. Each kind has its own interrupt vector, whose handler runs the
+// function registered for that kind (if any) on the receiving CPU.
+//
+// Until there's a local APIC driver the only CPU we can reach is our own, which we do with a
+// software interrupt so handlers run exactly as they would for a real IPI.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    // Something became runnable; just taking the interrupt knocks an idle CPU out of `hlt`
+    Reschedule,
+    // Page table entries changed, see memory::tlb::flush_range
+    TlbShootdown,
+    // Stop, so a panicking CPU can report without the others scribbling over the screen
+    HaltForPanic,
+}
+
+const KINDS: usize = 3;
+
+impl IpiKind {
+    pub fn vector(&self) -> Vector {
+        match self {
+            IpiKind::Reschedule => Vector::IPI_RESCHEDULE,
+            IpiKind::TlbShootdown => Vector::IPI_TLB_SHOOTDOWN,
+            IpiKind::HaltForPanic => Vector::IPI_HALT_FOR_PANIC,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Err {
+    NoSuchCpu(CpuId),
+}
+
+// Runs in interrupt context on the receiving CPU
+pub type IpiFn = fn();
+
+static HANDLERS: Mutex<[Option<IpiFn>; KINDS]> = Mutex::new([None; KINDS]);
+
+// Replaces any existing handler for `kind`
+pub fn register(kind: IpiKind, handler: IpiFn) {
+    crate::without_interrupt! {{
+        HANDLERS.lock()[kind as usize] = Some(handler);
+    }}
+}
+
+pub fn unregister(kind: IpiKind) {
+    crate::without_interrupt! {{
+        HANDLERS.lock()[kind as usize] = None;
+    }}
+}
+
+fn dispatch(kind: IpiKind) {
+    let _context = crate::interrupt::context::Entered::enter();
+    // Interrupt handlers run with interrupts disabled, so nobody can be holding the lock here
+    let handler = HANDLERS.lock()[kind as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+pub fn send(cpu: CpuId, kind: IpiKind) -> Result<(), Err> {
+    if cpu != current_cpu() {
+        return Err(Err::NoSuchCpu(cpu));
+    }
+    // Without a handler there's nobody to receive it, same as a real IPI to a CPU that hasn't
+    // set one up
+    let _ = crate::interrupt::trigger(kind.vector());
+    Ok(())
+}
+
+// Send to every CPU but this one
+pub fn broadcast(kind: IpiKind) -> Result<(), Err> {
+    for cpu in other_cpus() {
+        send(cpu, kind)?;
+    }
+    Ok(())
+}
+
+pub(crate) extern "x86-interrupt" fn reschedule_handler(_: InterruptStackFrame) {
+    dispatch(IpiKind::Reschedule);
+}
+
+pub(crate) extern "x86-interrupt" fn tlb_shootdown_handler(_: InterruptStackFrame) {
+    dispatch(IpiKind::TlbShootdown);
+}
+
+pub(crate) extern "x86-interrupt" fn halt_for_panic_handler(_: InterruptStackFrame) {
+    dispatch(IpiKind::HaltForPanic);
+}
+
+fn halt_for_panic() {
+    crate::power::halt();
+}
+
+pub fn init() {
+    register(IpiKind::TlbShootdown, crate::memory::tlb::shootdown);
+    register(IpiKind::HaltForPanic, halt_for_panic);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_reschedule() {
+        RECEIVED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_send_to_self() {
+        register(IpiKind::Reschedule, count_reschedule);
+        RECEIVED.store(0, Ordering::Relaxed);
+        send(current_cpu(), IpiKind::Reschedule).unwrap();
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 1);
+        unregister(IpiKind::Reschedule);
+        send(current_cpu(), IpiKind::Reschedule).unwrap();
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn test_send_to_missing_cpu() {
+        assert_eq!(send(7, IpiKind::Reschedule), Err(Err::NoSuchCpu(7)));
+        // No other CPUs to broadcast to
+        broadcast(IpiKind::HaltForPanic).unwrap();
+    }
+}
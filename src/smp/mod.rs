@@ -0,0 +1,25 @@
+pub mod ipi;
+
+// Multiprocessor support.
+// TODO: there's no local APIC driver or AP startup yet, so we only ever run on the boot CPU.
+// Everything here is written against `cpu_count`, so it should keep working once there are more.
+
+pub type CpuId = u32;
+
+pub const BOOT_CPU: CpuId = 0;
+// Upper bound for statically sized per-CPU data
+pub const MAX_CPUS: usize = 8;
+
+pub fn current_cpu() -> CpuId {
+    BOOT_CPU
+}
+
+pub fn cpu_count() -> usize {
+    1
+}
+
+// Every CPU except the one we're running on
+pub fn other_cpus() -> impl Iterator<Item = CpuId> {
+    let current = current_cpu();
+    (0..cpu_count() as CpuId).filter(move |&cpu| cpu != current)
+}
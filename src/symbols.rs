@@ -0,0 +1,161 @@
+use core::fmt;
+use core::mem::size_of;
+
+use crate::memory;
+
+// Function symbols for the kernel, so that panics, exceptions and profiles can print names
+// instead of raw addresses.
+//
+// We can't know our own symbols until after linking, so the kernel reserves a zeroed .ksymtab
+// section which tools/embed_symbols.py fills in place with a sorted, demangled table (the cargo
+// runner does this before booting). If the table is missing, eg. the kernel was booted some other
+// way, we fall back to a slow scan of the ELF .symtab the bootloader left in memory.
+
+const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+const MAGIC: [u8; 4] = *b"KSYM";
+
+#[repr(C, align(8))]
+struct SymbolTable([u8; SYMBOL_TABLE_SIZE]);
+
+#[used]
+#[link_section = ".ksymtab"]
+static SYMBOL_TABLE: SymbolTable = SymbolTable([0; SYMBOL_TABLE_SIZE]);
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 4],
+    count: u32,
+    strings_offset: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct Entry {
+    address: u64,
+    size: u32,
+    name_offset: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub address: u64,
+    pub size: u64,
+    pub name: &'static str,
+}
+
+impl Symbol {
+    pub fn contains(&self, address: u64) -> bool {
+        (self.address..self.address + self.size.max(1)).contains(&address)
+    }
+}
+
+fn table() -> &'static [u8] {
+    // As far as the compiler knows SYMBOL_TABLE is immutable and all zeros, so it's entitled to
+    // fold any read of it to 0. Launder the pointer so it has to actually look.
+    let table: *const SymbolTable = &SYMBOL_TABLE;
+    let table = unsafe { core::ptr::read_volatile(&table) };
+    unsafe { &(*table).0 }
+}
+
+// (entries, strings) from .ksymtab, if it was filled in
+fn embedded() -> Option<(&'static [Entry], &'static [u8])> {
+    let table = table();
+    let header = unsafe { &*(table.as_ptr() as *const Header) };
+    if header.magic != MAGIC {
+        return None;
+    }
+    let entries_size = header.count as usize * size_of::<Entry>();
+    let strings = table.get(header.strings_offset as usize..)?;
+    if size_of::<Header>() + entries_size > table.len() {
+        return None;
+    }
+    let entries = unsafe {
+        core::slice::from_raw_parts(
+            table.as_ptr().add(size_of::<Header>()) as *const Entry,
+            header.count as usize,
+        )
+    };
+    Some((entries, strings))
+}
+
+fn lookup_embedded(
+    entries: &'static [Entry],
+    strings: &'static [u8],
+    address: u64,
+) -> Option<Symbol> {
+    let index = entries.partition_point(|entry| entry.address <= address);
+    let entry = entries.get(index.checked_sub(1)?)?;
+    let name = strings.get(entry.name_offset as usize..)?;
+    let len = name.iter().position(|&b| b == 0)?;
+    let symbol = Symbol {
+        address: entry.address,
+        size: entry.size as u64,
+        name: core::str::from_utf8(&name[..len]).ok()?,
+    };
+    Some(symbol).filter(|symbol| symbol.contains(address))
+}
+
+fn lookup_elf(address: u64) -> Option<Symbol> {
+    memory::kernel_image()?
+        .symbols()
+        .filter(|(symbol, _)| symbol.is_function() && symbol.value != 0)
+        .find(|(symbol, _)| symbol.contains(address))
+        .map(|(symbol, name)| Symbol {
+            address: symbol.value,
+            size: symbol.size,
+            name,
+        })
+}
+
+// The function containing `address`
+pub fn lookup(address: u64) -> Option<Symbol> {
+    match embedded() {
+        Some((entries, strings)) => lookup_embedded(entries, strings, address),
+        None => lookup_elf(address),
+    }
+}
+
+pub fn resolve(address: u64) -> Option<&'static str> {
+    lookup(address).map(|symbol| symbol.name)
+}
+
+// Formats an address along with the function it's in, eg. `0x20a1b3 <sos::memory::init+0x43>`
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match lookup(self.0) {
+            Some(symbol) => write!(
+                f,
+                "{:#x} <{}+{:#x}>",
+                self.0,
+                symbol.name,
+                self.0 - symbol.address
+            ),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn symbolized_function() -> u64 {
+        7
+    }
+
+    #[test_case]
+    fn test_resolve_function() {
+        let address = symbolized_function as usize as u64;
+        let symbol = lookup(address + 1).expect("symbolized_function not found");
+        assert_eq!(symbol.address, address);
+        assert!(symbol.name.contains("symbolized_function"));
+    }
+
+    #[test_case]
+    fn test_resolve_unknown_address() {
+        assert!(resolve(0x10).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::memory::usercopy;
+use crate::syscall::{self, Syscall};
+use crate::task::scheduler::{self, Task};
+
+// Futexes: waiting on a word of memory. `wait` blocks only if the word still holds the value the
+// caller last saw, and `wake` wakes tasks waiting on a word, so locks and condition variables can
+// sleep on their own state instead of spinning, and only enter the kernel when contended.
+//
+// Waiters are keyed by the physical address of the word, so two mappings of the same frame share
+// futexes while the same address in two processes doesn't, and hashed into buckets.
+
+const BUCKETS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    // The word didn't hold the expected value
+    WouldBlock,
+    // The word isn't mapped
+    BadAddress,
+}
+
+struct Waiter {
+    key: usize,
+    task: Box<Task>,
+}
+
+const EMPTY_BUCKET: spin::Mutex<Vec<Waiter>> = spin::Mutex::new(Vec::new());
+static WAITERS: [spin::Mutex<Vec<Waiter>>; BUCKETS] = [EMPTY_BUCKET; BUCKETS];
+
+fn key(word: &AtomicU32) -> Result<usize, Err> {
+    crate::memory::translate_virtual_address(word as *const AtomicU32 as usize)
+        .or(Err(Err::BadAddress))
+}
+
+fn bucket(key: usize) -> &'static spin::Mutex<Vec<Waiter>> {
+    &WAITERS[(key / core::mem::size_of::<AtomicU32>()) % BUCKETS]
+}
+
+// Block until woken, if `word` still holds `expected`
+pub fn wait(word: &AtomicU32, expected: u32) -> Result<(), Err> {
+    let key = key(word)?;
+    // With interrupts off, nothing can change the word and wake us between checking and blocking
+    crate::without_interrupt! {{
+        if word.load(Ordering::SeqCst) != expected {
+            return Err(Err::WouldBlock);
+        }
+        scheduler::block(|task| bucket(key).lock().push(Waiter { key, task }));
+    }}
+    Ok(())
+}
+
+// Wake up to `count` tasks waiting on `word`, oldest first. Returns how many were woken.
+pub fn wake(word: &AtomicU32, count: usize) -> Result<usize, Err> {
+    let key = key(word)?;
+    let mut woken = Vec::new();
+    crate::without_interrupt! {{
+        let mut waiters = bucket(key).lock();
+        while woken.len() < count {
+            match waiters.iter().position(|waiter| waiter.key == key) {
+                Some(index) => woken.push(waiters.remove(index).task),
+                None => break,
+            }
+        }
+    }}
+    let woken_count = woken.len();
+    woken.into_iter().for_each(scheduler::wake);
+    Ok(woken_count)
+}
+
+impl From<Err> for syscall::Err {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::WouldBlock => syscall::Err::WouldBlock,
+            Err::BadAddress => syscall::Err::BadAddress,
+        }
+    }
+}
+
+// The address is checked to be aligned and mapped for the caller before it's dereferenced. The
+// caller's memory stays mapped for as long as it's in the syscall.
+fn word<'a>(address: u64) -> Result<&'a AtomicU32, syscall::Err> {
+    let address = address as usize;
+    if address % core::mem::align_of::<AtomicU32>() != 0 {
+        return Err(syscall::Err::InvalidArgument);
+    }
+    usercopy::check_readable(address, core::mem::size_of::<AtomicU32>())?;
+    Ok(unsafe { &*(address as *const AtomicU32) })
+}
+
+// futex_wait(address, expected)
+fn sys_futex_wait(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    wait(word(args[0])?, args[1] as u32)?;
+    Ok(0)
+}
+
+// futex_wake(address, count) -> woken
+fn sys_futex_wake(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    Ok(wake(word(args[0])?, args[1] as usize)? as u64)
+}
+
+pub fn init() {
+    syscall::register(Syscall::FutexWait, sys_futex_wait);
+    syscall::register(Syscall::FutexWake, sys_futex_wake);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::{AddressSpace, PROCESS_START};
+    use crate::memory::page_table::PageTableFlags;
+    use crate::task;
+
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicU32 = AtomicU32::new(0);
+
+    fn waiter_task() {
+        wait(&WORD, 0).unwrap();
+        WOKEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn test_futex_wait_wake() {
+        task::spawn("futex_waiter", waiter_task).unwrap();
+        task::yield_now();
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+
+        // Someone else's word doesn't wake it
+        let other = AtomicU32::new(0);
+        assert_eq!(wake(&other, 1), Ok(0));
+
+        WORD.store(1, Ordering::SeqCst);
+        assert_eq!(wake(&WORD, 1), Ok(1));
+        task::yield_now();
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn test_futex_wait_value_changed() {
+        let word = AtomicU32::new(1);
+        assert_eq!(wait(&word, 0), Err(Err::WouldBlock));
+        // The syscalls only take words in the caller's memory
+        let address = &word as *const AtomicU32 as u64;
+        assert_eq!(
+            syscall::dispatch(Syscall::FutexWait as usize, [address, 0, 0, 0, 0, 0]),
+            Err(syscall::Err::BadAddress)
+        );
+        let mut address_space = AddressSpace::new().unwrap();
+        let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        address_space.map(PROCESS_START, flags).unwrap();
+        address_space
+            .write(PROCESS_START, &1u32.to_ne_bytes())
+            .unwrap();
+        let address = PROCESS_START as u64;
+        let (waited, misaligned, woken) = address_space.enter(|| {
+            (
+                syscall::dispatch(Syscall::FutexWait as usize, [address, 0, 0, 0, 0, 0]),
+                syscall::dispatch(Syscall::FutexWake as usize, [address + 1, 1, 0, 0, 0, 0]),
+                syscall::dispatch(Syscall::FutexWake as usize, [address, 1, 0, 0, 0, 0]),
+            )
+        });
+        assert_eq!(waited, Err(syscall::Err::WouldBlock));
+        assert_eq!(misaligned, Err(syscall::Err::InvalidArgument));
+        assert_eq!(woken, Ok(0));
+    }
+}
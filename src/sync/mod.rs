@@ -0,0 +1,14 @@
+// Locks which block the task (see task::scheduler) instead of spinning, for state held across
+// long operations. Anything touched from interrupt handlers still needs spin::Mutex plus
+// without_interrupt, since a handler can't block.
+
+pub mod futex;
+pub mod mutex;
+pub mod semaphore;
+pub mod spin_lock;
+pub mod wait_queue;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::SpinLock;
+pub use wait_queue::WaitQueue;
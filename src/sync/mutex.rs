@@ -0,0 +1,92 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::Semaphore;
+
+// Like spin::Mutex, but contending tasks sleep until the lock is released
+pub struct Mutex<T> {
+    lock: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            lock: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.lock.down();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        match self.lock.try_down() {
+            true => Some(MutexGuard { mutex: self }),
+            false => None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.lock.up();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task;
+
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    static FINISHED: Semaphore = Semaphore::new(0);
+
+    fn increment_task() {
+        for _ in 0..10 {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            // Give the other task a chance to see the lock held
+            task::yield_now();
+            *counter = value + 1;
+        }
+        FINISHED.up();
+    }
+
+    #[test_case]
+    fn test_mutex_excludes_tasks() {
+        *COUNTER.lock() = 0;
+        task::spawn("increment_a", increment_task).unwrap();
+        task::spawn("increment_b", increment_task).unwrap();
+        FINISHED.down();
+        FINISHED.down();
+        assert_eq!(*COUNTER.lock(), 20);
+    }
+}
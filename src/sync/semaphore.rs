@@ -0,0 +1,81 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+// Counting semaphore; `down` blocks the task while the count is zero
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_down(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn down(&self) {
+        // With interrupts off, an `up` from a handler can't land between the check and blocking
+        crate::without_interrupt! {{
+            while !self.try_down() {
+                self.waiters.wait();
+            }
+        }}
+    }
+
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        // The woken task retries try_down, so a task that gets in first can still take the count
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task;
+
+    static READY: Semaphore = Semaphore::new(0);
+    static DONE: Semaphore = Semaphore::new(0);
+
+    fn handoff_task() {
+        READY.down();
+        DONE.up();
+    }
+
+    #[test_case]
+    fn test_semaphore_handoff() {
+        task::spawn("handoff", handoff_task).unwrap();
+        // Let it block on READY
+        task::yield_now();
+        assert!(!DONE.try_down());
+        READY.up();
+        DONE.down();
+        assert_eq!(READY.count(), 0);
+        assert_eq!(DONE.count(), 0);
+    }
+
+    #[test_case]
+    fn test_semaphore_try_down() {
+        let semaphore = Semaphore::new(2);
+        assert!(semaphore.try_down());
+        assert!(semaphore.try_down());
+        assert!(!semaphore.try_down());
+        semaphore.up();
+        assert!(semaphore.try_down());
+    }
+}
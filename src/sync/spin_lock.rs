@@ -0,0 +1,271 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::{MutexGuard, Once};
+
+use crate::println;
+
+// A spin::Mutex that keeps contention statistics, so the `locks` command can point at the locks
+// worth fixing. Stats are kept per lock site (by name), so eg. an array of locks shares one row.
+//
+// Contended locks back off exponentially between attempts with `pause`. On one CPU the holder
+// can't make progress while we spin, so once we've spun for a while and interrupts are on (so
+// we're not in a handler or a without_interrupt section) we yield to let it run.
+
+const MAX_SITES: usize = 32;
+// Not looked up yet
+const NO_SITE: usize = usize::MAX;
+// Looked up, but the table was full; this lock just doesn't get stats
+const UNTRACKED: usize = MAX_SITES;
+const MAX_BACKOFF: u64 = 1 << 10;
+const YIELD_AFTER_SPINS: u64 = 1 << 14;
+
+struct Site {
+    name: Once<&'static str>,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    spins: AtomicU64,
+    max_spins: AtomicU64,
+    yields: AtomicU64,
+}
+
+const EMPTY_SITE: Site = Site {
+    name: Once::new(),
+    acquisitions: AtomicU64::new(0),
+    contentions: AtomicU64::new(0),
+    spins: AtomicU64::new(0),
+    max_spins: AtomicU64::new(0),
+    yields: AtomicU64::new(0),
+};
+
+struct SiteTable<const N: usize> {
+    sites: [Site; N],
+    // Rows claimed, saturating at N. A claimed row's name may still be being set.
+    count: AtomicUsize,
+}
+
+impl<const N: usize> SiteTable<N> {
+    const fn new() -> Self {
+        SiteTable {
+            sites: [EMPTY_SITE; N],
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    // The row for `name`, adding one if there's room. Two locks with the same name taken for the
+    // first time at once have to end up in the same row, so rows are claimed with a CAS, and
+    // whoever loses rechecks the rows added in the meantime.
+    fn find_or_add(&self, name: &'static str) -> Option<usize> {
+        let mut count = self.count.load(Ordering::Acquire);
+        let mut searched = 0;
+        loop {
+            // A row can be claimed before its name is set, so wait for it: it may be ours
+            let found = (searched..count).find(|&i| *self.sites[i].name.wait() == name);
+            if found.is_some() {
+                return found;
+            }
+            searched = count;
+            if count >= N {
+                return None;
+            }
+            // No interrupt handler can come in between claiming the row and naming it, and wait
+            // on us forever
+            let claimed = crate::without_interrupt! {{
+                let claimed = self.count.compare_exchange(
+                    count,
+                    count + 1,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                if claimed.is_ok() {
+                    self.sites[count].name.call_once(|| name);
+                }
+                claimed
+            }};
+            match claimed {
+                Ok(index) => return Some(index),
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    // Rows with their names set
+    fn sites(&self) -> impl Iterator<Item = (&'static str, &Site)> + '_ {
+        let count = self.count.load(Ordering::Acquire);
+        self.sites[..count]
+            .iter()
+            .filter_map(|site| site.name.get().map(|&name| (name, site)))
+    }
+}
+
+static SITES: SiteTable<MAX_SITES> = SiteTable::new();
+
+pub struct SpinLock<T> {
+    name: &'static str,
+    site: AtomicUsize,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        SpinLock {
+            name,
+            site: AtomicUsize::new(NO_SITE),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    fn site(&self) -> Option<&'static Site> {
+        let mut index = self.site.load(Ordering::Relaxed);
+        if index == NO_SITE {
+            index = SITES.find_or_add(self.name).unwrap_or(UNTRACKED);
+            self.site.store(index, Ordering::Relaxed);
+        }
+        SITES.sites.get(index)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        if let Some(site) = self.site() {
+            site.acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(guard)
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        let mut spins = 0;
+        let mut backoff = 1;
+        let mut yields = 0;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            for _ in 0..backoff {
+                core::hint::spin_loop();
+            }
+            spins += backoff;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            if spins >= YIELD_AFTER_SPINS * (yields + 1)
+                && crate::interrupt::are_interrupts_enabled()
+            {
+                yields += 1;
+                crate::task::yield_now();
+            }
+        };
+        if let Some(site) = self.site() {
+            site.acquisitions.fetch_add(1, Ordering::Relaxed);
+            site.contentions.fetch_add(1, Ordering::Relaxed);
+            site.spins.fetch_add(spins, Ordering::Relaxed);
+            site.max_spins.fetch_max(spins, Ordering::Relaxed);
+            site.yields.fetch_add(yields, Ordering::Relaxed);
+        }
+        guard
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockStats {
+    pub name: &'static str,
+    pub acquisitions: u64,
+    pub contentions: u64,
+    pub spins: u64,
+    pub max_spins: u64,
+    pub yields: u64,
+}
+
+// Every lock site that's been taken, most spun-on first
+pub fn stats() -> impl Iterator<Item = LockStats> {
+    let mut stats = [None; MAX_SITES];
+    for (slot, (name, site)) in stats.iter_mut().zip(SITES.sites()) {
+        *slot = Some(LockStats {
+            name,
+            acquisitions: site.acquisitions.load(Ordering::Relaxed),
+            contentions: site.contentions.load(Ordering::Relaxed),
+            spins: site.spins.load(Ordering::Relaxed),
+            max_spins: site.max_spins.load(Ordering::Relaxed),
+            yields: site.yields.load(Ordering::Relaxed),
+        });
+    }
+    stats.sort_unstable_by_key(|s| core::cmp::Reverse(s.map_or(0, |s| s.spins)));
+    stats.into_iter().flatten()
+}
+
+fn locks_command(_args: &[&str]) {
+    println!(
+        "{:<20} {:>10} {:>10} {:>12} {:>10} {:>8}",
+        "lock", "taken", "contended", "spins", "max spins", "yields"
+    );
+    for lock in stats() {
+        println!(
+            "{:<20} {:>10} {:>10} {:>12} {:>10} {:>8}",
+            lock.name, lock.acquisitions, lock.contentions, lock.spins, lock.max_spins, lock.yields
+        );
+    }
+}
+
+pub fn init() {
+    crate::shell::register("locks", "spin lock contention, worst first", locks_command);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_spin_lock_counts_acquisitions() {
+        static LOCK: SpinLock<u32> = SpinLock::new("test_lock", 0);
+        *LOCK.lock() += 1;
+        {
+            let _held = LOCK.lock();
+            assert!(LOCK.try_lock().is_none());
+        }
+        *LOCK.try_lock().unwrap() += 1;
+        assert_eq!(*LOCK.lock(), 2);
+        let stats = stats().find(|s| s.name == "test_lock").unwrap();
+        assert_eq!(stats.acquisitions, 4);
+        assert_eq!(stats.contentions, 0);
+    }
+
+    #[test_case]
+    fn test_site_table_saturates() {
+        let table: SiteTable<2> = SiteTable::new();
+        assert_eq!(table.find_or_add("a"), Some(0));
+        assert_eq!(table.find_or_add("b"), Some(1));
+        assert_eq!(table.find_or_add("c"), None);
+        assert_eq!(table.find_or_add("d"), None);
+        assert_eq!(table.count.load(Ordering::Relaxed), 2);
+        assert_eq!(table.find_or_add("a"), Some(0));
+    }
+
+    static SHARED_SITES: SiteTable<4> = SiteTable::new();
+    static FIRST_USES: AtomicUsize = AtomicUsize::new(0);
+
+    // Each task looks up the same names, yielding to the others in between
+    fn first_use_task() {
+        for name in ["x", "y", "x", "z", "y"] {
+            assert!(SHARED_SITES.find_or_add(name).is_some());
+            crate::task::yield_now();
+        }
+        FIRST_USES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_concurrent_first_use_shares_a_row() {
+        const TASKS: usize = 4;
+        for _ in 0..TASKS {
+            crate::task::spawn("site_first_use", first_use_task).unwrap();
+        }
+        while FIRST_USES.load(Ordering::Relaxed) < TASKS {
+            crate::task::yield_now();
+        }
+        let mut names: alloc::vec::Vec<_> = SHARED_SITES.sites().map(|(name, _)| name).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["x", "y", "z"]);
+    }
+}
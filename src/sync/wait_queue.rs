@@ -0,0 +1,54 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::task::scheduler::{self, Task};
+
+// Tasks blocked waiting for something, woken in the order they started waiting
+pub struct WaitQueue {
+    waiting: spin::Mutex<Option<VecDeque<Box<Task>>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiting: spin::Mutex::new(None),
+        }
+    }
+
+    fn park(&self, task: Box<Task>) {
+        self.waiting
+            .lock()
+            .get_or_insert_with(VecDeque::new)
+            .push_back(task);
+    }
+
+    // Block the current task until woken. Callers should check their wakeup condition and call
+    // this with interrupts disabled, so a wakeup can't slip in between.
+    pub fn wait(&self) {
+        scheduler::block(|task| self.park(task));
+    }
+
+    // Returns whether there was a task to wake
+    pub fn wake_one(&self) -> bool {
+        let task = crate::without_interrupt! {{
+            self.waiting.lock().as_mut().and_then(|waiting| waiting.pop_front())
+        }};
+        match task {
+            Some(task) => {
+                scheduler::wake(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        crate::without_interrupt! {{
+            self.waiting.lock().as_ref().map_or(true, |waiting| waiting.is_empty())
+        }}
+    }
+}
@@ -0,0 +1,213 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+
+use spin::Mutex;
+
+use crate::memory::usercopy;
+
+// The system call table. Subsystems register a handler for each call they implement, and
+// `dispatch` looks up and runs the handler for a raw syscall number and arguments.
+//
+// User mode traps in with `int 0x80` (see VECTOR), with the syscall number in rax and arguments
+// in rdi, rsi, rdx, r10, r8 and r9. The result comes back in rax, or -1 if the call failed; every
+// other register is preserved.
+
+pub const VECTOR: u8 = 0x80;
+
+const MAX_SYSCALLS: usize = 64;
+pub const MAX_PATH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Syscall {
+    Exit = 0,
+    Spawn = 1,
+    Wait = 2,
+    Shutdown = 3,
+    Reboot = 4,
+    Mmap = 5,
+    Msync = 6,
+    Munmap = 7,
+    FutexWait = 8,
+    FutexWake = 9,
+    Open = 10,
+    Read = 11,
+    Write = 12,
+    Seek = 13,
+    Close = 14,
+    Pipe = 15,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    NoSuchSyscall(usize),
+    InvalidArgument,
+    // A pointer argument isn't mapped for the caller, see memory::usercopy
+    BadAddress,
+    // Would have blocked on a condition that no longer holds; retry
+    WouldBlock,
+    NotFound,
+    BadFileDescriptor,
+    TooManyFiles,
+    BrokenPipe,
+    OutOfMemory,
+    Io,
+}
+
+pub type SyscallFn = fn(args: [u64; 6]) -> Result<u64, Err>;
+
+static SYSCALLS: Mutex<[Option<SyscallFn>; MAX_SYSCALLS]> = Mutex::new([None; MAX_SYSCALLS]);
+
+pub fn register(syscall: Syscall, handler: SyscallFn) {
+    crate::without_interrupt! {{
+        SYSCALLS.lock()[syscall as usize] = Some(handler);
+    }}
+}
+
+pub fn dispatch(number: usize, args: [u64; 6]) -> Result<u64, Err> {
+    let handler = crate::without_interrupt! {{
+        SYSCALLS.lock().get(number).copied().flatten()
+    }};
+    match handler {
+        Some(handler) => handler(args),
+        None => Err(Err::NoSuchSyscall(number)),
+    }
+}
+
+// A `length` byte argument (at most `max`) the caller passed a pointer to
+pub fn bytes_arg(address: u64, length: u64, max: usize) -> Result<Vec<u8>, Err> {
+    if length > max as u64 {
+        return Err(Err::InvalidArgument);
+    }
+    let mut buffer = vec![0; length as usize];
+    usercopy::copy_from_user(&mut buffer, address as usize)?;
+    Ok(buffer)
+}
+
+// A path the caller passed a pointer to and length of
+pub fn path_arg(address: u64, length: u64) -> Result<String, Err> {
+    let path = bytes_arg(address, length, MAX_PATH)?;
+    String::from_utf8(path).map_err(|_| Err::InvalidArgument)
+}
+
+// The registers sos_syscall_entry saves (everything a call may clobber), lowest address first
+#[repr(C)]
+struct Registers {
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rax: u64,
+}
+
+#[no_mangle]
+extern "C" fn sos_syscall_handler(registers: &mut Registers) {
+    let args = [
+        registers.rdi,
+        registers.rsi,
+        registers.rdx,
+        registers.r10,
+        registers.r8,
+        registers.r9,
+    ];
+    registers.rax = match dispatch(registers.rax as usize, args) {
+        Ok(result) => result,
+        Err(_) => u64::MAX,
+    };
+}
+
+// The gate is an interrupt gate, so we arrive with interrupts off; they're back on while the call
+// runs, since it can block. The CPU has already switched to the task's kernel stack (see
+// global_descriptor_table::set_kernel_stack) and pushed 5 words, and we push 9 more, which
+// leaves the stack 16 byte aligned for the call.
+global_asm!(
+    ".global sos_syscall_entry",
+    "sos_syscall_entry:",
+    "    push rax",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    mov rdi, rsp",
+    "    cld",
+    "    sti",
+    "    call sos_syscall_handler",
+    "    cli",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rax",
+    "    iretq",
+);
+
+extern "C" {
+    pub fn sos_syscall_entry();
+}
+
+#[cfg(test)]
+mod test {
+    use core::arch::asm;
+
+    use super::*;
+
+    #[test_case]
+    fn test_dispatch_unknown_syscall() {
+        assert_eq!(
+            dispatch(MAX_SYSCALLS - 1, [0; 6]),
+            Err(Err::NoSuchSyscall(MAX_SYSCALLS - 1))
+        );
+        assert_eq!(
+            dispatch(usize::MAX, [0; 6]),
+            Err(Err::NoSuchSyscall(usize::MAX))
+        );
+    }
+
+    // The gate also takes traps from the kernel, so the whole round trip can be checked here
+    #[test_case]
+    fn test_trap() {
+        let (result, rdi, r11): (u64, u64, u64);
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") MAX_SYSCALLS as u64 - 1 => result,
+                inlateout("rdi") 1u64 => rdi,
+                inlateout("r11") 2u64 => r11,
+            )
+        };
+        assert_eq!(result, u64::MAX);
+        assert_eq!((rdi, r11), (1, 2));
+    }
+
+    // Just the table lookup and call
+    crate::bench_case! {
+        fn bench_dispatch(b: &mut Bencher) {
+            b.iter(|| dispatch(Syscall::Seek as usize, [u64::MAX; 6]));
+        }
+    }
+
+    // The whole trap, for a syscall that fails straight away
+    crate::bench_case! {
+        fn bench_trap(b: &mut Bencher) {
+            b.iter(|| {
+                let result: u64;
+                unsafe { asm!("int 0x80", inlateout("rax") MAX_SYSCALLS as u64 - 1 => result) };
+                result
+            });
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::sync::Semaphore;
+
+// Just enough of an executor to wait on a future from a task: poll it, and block until its waker
+// is called before polling again. Wakers may be called from interrupt handlers.
+
+struct Wakeup(Semaphore);
+
+impl Wake for Wakeup {
+    fn wake(self: Arc<Self>) {
+        self.0.up();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.up();
+    }
+}
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let wakeup = Arc::new(Wakeup(Semaphore::new(0)));
+    let waker = Waker::from(wakeup.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        // A wake from before the poll returned just means another poll
+        wakeup.0.down();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::task;
+
+    // Pending until `polls` is used up, waking itself each time
+    struct Countdown {
+        polls: usize,
+    }
+
+    impl Future for Countdown {
+        type Output = usize;
+
+        fn poll(mut self: core::pin::Pin<&mut Self>, context: &mut Context) -> Poll<usize> {
+            if self.polls == 0 {
+                return Poll::Ready(7);
+            }
+            self.polls -= 1;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    static WAKER: spin::Mutex<Option<Waker>> = spin::Mutex::new(None);
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    // Wakes whoever's waiting in WAKER, once they are
+    fn waker_task() {
+        loop {
+            let waker = crate::without_interrupt! {{
+                let waker = WAKER.lock().take();
+                waker
+            }};
+            if let Some(waker) = waker {
+                FIRED.store(true, Ordering::Release);
+                waker.wake();
+                return;
+            }
+            task::yield_now();
+        }
+    }
+
+    // Ready once waker_task has run
+    struct Fired;
+
+    impl Future for Fired {
+        type Output = ();
+
+        fn poll(self: core::pin::Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if FIRED.load(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            crate::without_interrupt! {{
+                *WAKER.lock() = Some(context.waker().clone());
+            }}
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn test_block_on() {
+        assert_eq!(block_on(async { 1 }), 1);
+        assert_eq!(block_on(Countdown { polls: 3 }), 7);
+        // Blocks until the other task wakes it
+        task::spawn("waker", waker_task).unwrap();
+        block_on(Fired);
+        assert!(WAKER.lock().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::scheduler::{self, IDLE_PRIORITY};
+use super::signal;
+use crate::time::tick;
+
+// Idling the CPU. Rather than spinning when there's nothing to do, the idle task (which runs at
+// the lowest priority, so only when nothing else is ready) halts until the next interrupt.
+// The timer tick records whether each tick landed while halted, for CPU utilization. In tickless
+// mode the tick is stopped while halted (see time::tick), and the ticks skipped count as idle.
+// TODO: one idle task per CPU, once there's more than one
+
+static HALTED: AtomicBool = AtomicBool::new(false);
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BUSY_TICKS: AtomicU64 = AtomicU64::new(0);
+// The jiffies idle and busy ticks are counted up to
+static COUNTED: AtomicU64 = AtomicU64::new(0);
+
+// Halt until the next interrupt, unless there's another task to run. Either way, returns after
+// giving other tasks of at least our priority a chance to run.
+pub fn idle() {
+    crate::without_interrupt! {{
+        if !scheduler::would_yield() {
+            HALTED.store(true, Ordering::Relaxed);
+            tick::stop_for_idle();
+            // sti only takes effect after the next instruction, so no interrupt (and wakeup) can
+            // sneak in between checking for work and halting
+            unsafe { asm!("sti; hlt", options(nomem, nostack)) };
+            // Before clearing HALTED, so the ticks caught up on count as idle
+            tick::restart_after_idle();
+            HALTED.store(false, Ordering::Relaxed);
+        }
+    }}
+    scheduler::yield_now();
+}
+
+fn idle_task() {
+    loop {
+        // Hands the CPU to the frame scrubber instead of halting, while it has work
+        crate::memory::scrub::kick();
+        idle();
+    }
+}
+
+// Every tick since the last count, which is more than one after a tickless idle
+fn count_tick(now: u64) {
+    let ticks = now.saturating_sub(COUNTED.swap(now, Ordering::Relaxed));
+    match HALTED.load(Ordering::Relaxed) {
+        true => IDLE_TICKS.fetch_add(ticks, Ordering::Relaxed),
+        false => BUSY_TICKS.fetch_add(ticks, Ordering::Relaxed),
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub idle_ticks: u64,
+    pub busy_ticks: u64,
+}
+
+impl Stats {
+    pub fn busy_percent(&self) -> u64 {
+        match self.idle_ticks + self.busy_ticks {
+            0 => 0,
+            total => self.busy_ticks * 100 / total,
+        }
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        idle_ticks: IDLE_TICKS.load(Ordering::Relaxed),
+        busy_ticks: BUSY_TICKS.load(Ordering::Relaxed),
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cpu stats:\n  {}% busy: {} idle ticks, {} busy ticks",
+            self.busy_percent(),
+            self.idle_ticks,
+            self.busy_ticks
+        )
+    }
+}
+
+pub fn init() {
+    let task = scheduler::spawn_with_priority("idle", idle_task, IDLE_PRIORITY)
+        .expect("Failed to spawn idle task");
+    signal::protect(task).unwrap();
+    COUNTED.store(tick::jiffies(), Ordering::Relaxed);
+    tick::register_deferrable("idle_stats", 1, count_tick);
+    tick::set_tickless(true);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_idle_counts_ticks() {
+        let before = stats();
+        // With nothing else ready, each idle() halts until an interrupt (at least every tick)
+        for _ in 0..3 {
+            idle();
+        }
+        let after = stats();
+        assert!(after.idle_ticks + after.busy_ticks > before.idle_ticks + before.busy_ticks);
+        assert!(after.busy_percent() <= 100);
+    }
+
+    static ELAPSED: AtomicU64 = AtomicU64::new(0);
+
+    // Stands in for the one-shot timer: the CPU "halts" for ELAPSED ticks
+    const FAKE_TIMER: tick::OneShotTimer = tick::OneShotTimer {
+        arm: |_| (),
+        stop: || ELAPSED.load(Ordering::Relaxed),
+    };
+
+    #[test_case]
+    fn test_skipped_ticks_count_as_idle() {
+        tick::with_one_shot_timer(FAKE_TIMER, || {
+            // Catch the stats up to now, so only the skipped ticks are new
+            count_tick(tick::jiffies());
+            let before = stats();
+            let start = tick::jiffies();
+            crate::watchdog::register("test_tickless_idle", 1);
+            HALTED.store(true, Ordering::Relaxed);
+            assert!(tick::stop_for_idle());
+            ELAPSED.store(25, Ordering::Relaxed);
+            // Runs the watchdog check, which would panic if nobody counted as having pet it
+            tick::restart_after_idle();
+            HALTED.store(false, Ordering::Relaxed);
+            crate::watchdog::unregister("test_tickless_idle");
+            assert_eq!(tick::jiffies(), start + 25);
+            let after = stats();
+            assert_eq!(after.idle_ticks - before.idle_ticks, 25);
+            assert_eq!(after.busy_ticks, before.busy_ticks);
+        });
+    }
+}
@@ -0,0 +1,12 @@
+pub mod executor;
+pub mod idle;
+pub mod process;
+pub mod scheduler;
+pub mod signal;
+pub mod stack;
+pub mod switch;
+
+pub use executor::block_on;
+pub use scheduler::{set_priority, spawn, spawn_with_priority, yield_now, TaskId};
+pub use signal::{signal, Signal};
+pub use switch::{switch_to, Context};
@@ -0,0 +1,550 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+
+use spin::Mutex;
+
+use super::scheduler::{self, TaskId};
+use crate::elf::{self, ElfFile};
+use crate::fs::{fd, file, vfs};
+use crate::global_descriptor_table;
+use crate::memory::address_space::{self, AddressSpace, PROCESS_END, PROCESS_START};
+use crate::memory::page_table::PageTableFlags;
+use crate::memory::PAGE_SIZE;
+use crate::sync::WaitQueue;
+use crate::syscall::{self, Syscall};
+use crate::time::vdso;
+
+// User processes: tasks running an ELF program in user mode, each in an address space of its own
+// (see memory::address_space).
+//
+// `spawn` reads the program from the VFS, maps its segments and a stack, and starts a task which
+// drops into user mode at the program's entry point, with its parent's file descriptors (see
+// fs::fd). The program starts with its arguments (NUL separated, copied to the top of its stack)
+// at rdi and their length in rsi, and the time page (see time::vdso) mapped read-only at
+// TIME_PAGE. From then on it only gets into the kernel through syscalls and
+// interrupts, until it `exit`s with a code for whoever spawned it to `wait` for. Children whose
+// parent has gone are forgotten as soon as they exit.
+
+// The exit code of a process that didn't exit by itself, eg. because it faulted
+pub const KILLED: u8 = 255;
+
+const MAX_PROGRAM_SIZE: u64 = 64 * 1024;
+const MAX_ARGS: usize = PAGE_SIZE;
+
+// The time page is the last of the process's slot, and the stack sits under it, below an unmapped
+// guard page
+pub const TIME_PAGE: usize = PROCESS_END - PAGE_SIZE;
+const STACK_TOP: usize = TIME_PAGE - PAGE_SIZE;
+const STACK_SIZE: usize = 4 * PAGE_SIZE;
+const STACK_BOTTOM: usize = STACK_TOP - STACK_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Err {
+    File(file::Err),
+    // Not an x86_64 executable we can load
+    BadExecutable,
+    OutOfMemory,
+    OutOfStacks,
+    ArgumentsTooLong,
+    // Not a process the caller spawned, or one already waited for
+    NoSuchChild,
+}
+
+impl From<file::Err> for Err {
+    fn from(err: file::Err) -> Self {
+        Err::File(err)
+    }
+}
+
+impl From<address_space::Err> for Err {
+    fn from(err: address_space::Err) -> Self {
+        match err {
+            address_space::Err::OutOfMemory => Err::OutOfMemory,
+            address_space::Err::OutOfRange(_)
+            | address_space::Err::NotMapped(_)
+            | address_space::Err::AlreadyMapped(_) => Err::BadExecutable,
+        }
+    }
+}
+
+impl From<Err> for syscall::Err {
+    fn from(err: Err) -> Self {
+        match err {
+            Err::File(err) => err.into(),
+            Err::OutOfMemory | Err::OutOfStacks => syscall::Err::OutOfMemory,
+            Err::BadExecutable | Err::ArgumentsTooLong | Err::NoSuchChild => {
+                syscall::Err::InvalidArgument
+            }
+        }
+    }
+}
+
+// Where a process starts in user mode
+#[derive(Debug, Clone, Copy)]
+struct Start {
+    entry: usize,
+    stack: usize,
+    args: usize,
+    args_length: usize,
+}
+
+struct Process {
+    // Whoever spawned it, until they exit
+    parent: Option<TaskId>,
+    start: Start,
+    exit_code: Option<u8>,
+}
+
+static PROCESSES: Mutex<BTreeMap<TaskId, Process>> = Mutex::new(BTreeMap::new());
+// Woken whenever a process exits
+static EXITED: WaitQueue = WaitQueue::new();
+
+fn read_program(path: &str) -> Result<Vec<u8>, Err> {
+    let file = vfs::open(path)?;
+    let size = file.size().ok_or(Err::BadExecutable)?;
+    if size > MAX_PROGRAM_SIZE {
+        return Err(Err::BadExecutable);
+    }
+    let mut image = vec![0; size as usize];
+    let mut read = 0;
+    while read < image.len() {
+        match file.read(read as u64, &mut image[read..])? {
+            0 => return Err(Err::BadExecutable),
+            count => read += count,
+        }
+    }
+    Ok(image)
+}
+
+// Map the program's segments, returning its entry point
+fn load(image: &[u8], address_space: &mut AddressSpace) -> Result<usize, Err> {
+    let elf = ElfFile::new(image).map_err(|_| Err::BadExecutable)?;
+    let header = elf.header();
+    let entry = header.entry as usize;
+    if header.file_type != elf::FILE_TYPE_EXECUTABLE
+        || header.machine != elf::MACHINE_X86_64
+        || !(PROCESS_START..STACK_BOTTOM).contains(&entry)
+    {
+        return Err(Err::BadExecutable);
+    }
+    let segments = elf
+        .program_headers()
+        .filter(|segment| segment.segment_type == elf::SEGMENT_TYPE_LOAD);
+    for segment in segments {
+        let start = segment.virtual_address as usize;
+        let end = match start.checked_add(segment.memory_size as usize) {
+            Some(end) if start >= PROCESS_START && end <= STACK_BOTTOM => end,
+            _ => return Err(Err::BadExecutable),
+        };
+        let data = elf.segment_data(segment).ok_or(Err::BadExecutable)?;
+        if segment.file_size > segment.memory_size {
+            return Err(Err::BadExecutable);
+        }
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+        if segment.flags & elf::SEGMENT_FLAG_WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.flags & elf::SEGMENT_FLAG_EXECUTE == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        for page in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            address_space.map(page, flags)?;
+        }
+        // The rest of memory_size is already zero
+        address_space.write(start, data)?;
+    }
+    Ok(entry)
+}
+
+// Map the stack, with the arguments at the top
+fn map_stack(address_space: &mut AddressSpace, entry: usize, args: &[u8]) -> Result<Start, Err> {
+    let flags =
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in (STACK_BOTTOM..STACK_TOP).step_by(PAGE_SIZE) {
+        address_space.map(page, flags)?;
+    }
+    let args_address = STACK_TOP - args.len();
+    address_space.write(args_address, args)?;
+    Ok(Start {
+        entry,
+        // The ABI wants it 16 byte aligned at the entry point
+        stack: args_address & !0xf,
+        args: args_address,
+        args_length: args.len(),
+    })
+}
+
+// Start the program at `path` as a child of the current task
+pub fn spawn(path: &str, args: &[u8]) -> Result<TaskId, Err> {
+    if args.len() > MAX_ARGS {
+        return Err(Err::ArgumentsTooLong);
+    }
+    let image = read_program(path)?;
+    let mut address_space = AddressSpace::new()?;
+    let entry = load(&image, &mut address_space)?;
+    let start = map_stack(&mut address_space, entry, args)?;
+    // The time page is in the kernel image, so it outlives every process
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_frame(TIME_PAGE, vdso::frame(), flags)?;
+    let parent = scheduler::current_id();
+    // Both at once, so it can't run (or exit) before it's in PROCESSES
+    let id = crate::without_interrupt! {{
+        let id = scheduler::spawn_in("process", run, address_space)
+            .map_err(|_| Err::OutOfStacks)?;
+        PROCESSES.lock().insert(
+            id,
+            Process {
+                parent: Some(parent),
+                start,
+                exit_code: None,
+            },
+        );
+        fd::inherit(id);
+        id
+    }};
+    Ok(id)
+}
+
+// The process's task, which becomes the program
+fn run() {
+    let id = scheduler::current_id();
+    let start = crate::without_interrupt! {{
+        let start = PROCESSES.lock().get(&id).map(|process| process.start);
+        start
+    }};
+    let start = start.expect("Process task isn't in PROCESSES");
+    let (code, data) = global_descriptor_table::user_selectors();
+    // Safety: start was checked to be in the process's slot, which is all we're giving up
+    unsafe {
+        sos_enter_user(
+            start.entry,
+            start.stack,
+            start.args,
+            start.args_length,
+            code.0 as u64,
+            data.0 as u64,
+        )
+    };
+}
+
+// enter_user(entry: rdi, stack: rsi, args: rdx, args_length: rcx, code: r8, data: r9)
+// iretq to ring 3 with interrupts enabled, leaving nothing of the kernel in the registers. The
+// kernel stack we were on is abandoned; we come back in on a fresh one (see
+// global_descriptor_table::set_kernel_stack).
+global_asm!(
+    ".global sos_enter_user",
+    "sos_enter_user:",
+    "    push r9",
+    "    push rsi",
+    "    push 0x202",
+    "    push r8",
+    "    push rdi",
+    "    mov rdi, rdx",
+    "    mov rsi, rcx",
+    "    xor eax, eax",
+    "    xor ebx, ebx",
+    "    xor ecx, ecx",
+    "    xor edx, edx",
+    "    xor ebp, ebp",
+    "    xor r8d, r8d",
+    "    xor r9d, r9d",
+    "    xor r10d, r10d",
+    "    xor r11d, r11d",
+    "    xor r12d, r12d",
+    "    xor r13d, r13d",
+    "    xor r14d, r14d",
+    "    xor r15d, r15d",
+    "    iretq",
+);
+
+extern "C" {
+    fn sos_enter_user(
+        entry: usize,
+        stack: usize,
+        args: usize,
+        args_length: usize,
+        code: u64,
+        data: u64,
+    ) -> !;
+}
+
+// Exit the current task, with `code` for its parent if it's a process
+pub fn exit(code: u8) -> ! {
+    let id = scheduler::current_id();
+    crate::without_interrupt! {{
+        if let Some(process) = PROCESSES.lock().get_mut(&id) {
+            process.exit_code = Some(code);
+        }
+    }}
+    scheduler::exit()
+}
+
+// Called by scheduler::exit as any task exits
+pub(super) fn exited(task: TaskId) {
+    let was_process = crate::without_interrupt! {{
+        let mut processes = PROCESSES.lock();
+        if let Some(process) = processes.get_mut(&task) {
+            process.exit_code.get_or_insert(KILLED);
+        }
+        for process in processes.values_mut() {
+            if process.parent == Some(task) {
+                process.parent = None;
+            }
+        }
+        // Nobody's left to wait for orphans, so they go once they've exited
+        processes.retain(|_, process| process.parent.is_some() || process.exit_code.is_none());
+        processes.contains_key(&task)
+    }};
+    if was_process {
+        EXITED.wake_all();
+    }
+}
+
+// Wait for a process the current task spawned to exit, returning its exit code
+pub fn wait(child: TaskId) -> Result<u8, Err> {
+    let parent = scheduler::current_id();
+    crate::without_interrupt! {{
+        loop {
+            let exit_code = {
+                let mut processes = PROCESSES.lock();
+                let exit_code = match processes.get(&child) {
+                    Some(process) if process.parent == Some(parent) => process.exit_code,
+                    _ => return Err(Err::NoSuchChild),
+                };
+                if exit_code.is_some() {
+                    processes.remove(&child);
+                }
+                exit_code
+            };
+            match exit_code {
+                Some(code) => return Ok(code),
+                None => EXITED.wait(),
+            }
+        }
+    }}
+}
+
+// exit(code)
+fn sys_exit(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    exit(args[0] as u8)
+}
+
+// spawn(path, path_length, args, args_length) -> child's task id
+fn sys_spawn(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    let path = syscall::path_arg(args[0], args[1])?;
+    let arguments = syscall::bytes_arg(args[2], args[3], MAX_ARGS)?;
+    Ok(spawn(&path, &arguments)?.as_u64())
+}
+
+// wait(child) -> exit code
+fn sys_wait(args: [u64; 6]) -> Result<u64, syscall::Err> {
+    Ok(wait(TaskId::from_u64(args[0]))? as u64)
+}
+
+pub fn init() {
+    syscall::register(Syscall::Exit, sys_exit);
+    syscall::register(Syscall::Spawn, sys_spawn);
+    syscall::register(Syscall::Wait, sys_wait);
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::mem::size_of;
+
+    use super::*;
+    use crate::elf::{FileHeader, ProgramHeader};
+    use crate::fs::file::File;
+    use crate::fs::vfs::Filesystem;
+
+    fn bytes_of<T>(value: &T) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+    }
+
+    // An executable with one segment at `base`, holding the headers and then `code`, where it
+    // starts
+    fn program(base: usize, code: &[u8]) -> Vec<u8> {
+        let headers = size_of::<FileHeader>() + size_of::<ProgramHeader>();
+        let size = (headers + code.len()) as u64;
+        let mut ident = [0; 16];
+        ident[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        let header = FileHeader {
+            ident,
+            file_type: elf::FILE_TYPE_EXECUTABLE,
+            machine: elf::MACHINE_X86_64,
+            version: 1,
+            entry: (base + headers) as u64,
+            program_header_offset: size_of::<FileHeader>() as u64,
+            section_header_offset: 0,
+            flags: 0,
+            header_size: size_of::<FileHeader>() as u16,
+            program_header_size: size_of::<ProgramHeader>() as u16,
+            program_header_count: 1,
+            section_header_size: 0,
+            section_header_count: 0,
+            section_name_index: 0,
+        };
+        let segment = ProgramHeader {
+            segment_type: elf::SEGMENT_TYPE_LOAD,
+            flags: elf::SEGMENT_FLAG_READ | elf::SEGMENT_FLAG_EXECUTE,
+            offset: 0,
+            virtual_address: base as u64,
+            physical_address: base as u64,
+            file_size: size,
+            memory_size: size,
+            align: PAGE_SIZE as u64,
+        };
+        let mut image = Vec::from(bytes_of(&header));
+        image.extend_from_slice(bytes_of(&segment));
+        image.extend_from_slice(code);
+        image
+    }
+
+    struct Image(Vec<u8>);
+
+    impl File for Image {
+        fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, file::Err> {
+            let data = self.0.get(offset as usize..).unwrap_or(&[]);
+            let count = buffer.len().min(data.len());
+            buffer[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+
+        fn size(&self) -> Option<u64> {
+            Some(self.0.len() as u64)
+        }
+    }
+
+    static KERNEL_DATA: u64 = 42;
+
+    // Programs that misbehave, and ones that aren't programs at all
+    struct Programs;
+
+    impl Filesystem for Programs {
+        fn open(&self, path: &str) -> Result<Arc<dyn File>, file::Err> {
+            let image = match path {
+                // mov byte ptr [0], 0
+                "write_null" => program(PROCESS_START, &[0xc6, 0x04, 0x25, 0, 0, 0, 0, 0]),
+                // movabs rax, [KERNEL_DATA]
+                "read_kernel" => {
+                    let mut code = Vec::from([0x48, 0xa1]);
+                    code.extend_from_slice(&(&KERNEL_DATA as *const u64 as u64).to_le_bytes());
+                    program(PROCESS_START, &code)
+                }
+                // spawn(&KERNEL_DATA, 4, 0, 0), then exit(-result)
+                "spawn_kernel_path" => {
+                    let mut code = Vec::from([0xb8, 1, 0, 0, 0, 0x48, 0xbf]);
+                    code.extend_from_slice(&(&KERNEL_DATA as *const u64 as u64).to_le_bytes());
+                    code.extend_from_slice(&[0xbe, 4, 0, 0, 0, 0x31, 0xd2, 0x45, 0x31, 0xd2]);
+                    code.extend_from_slice(&[0xcd, 0x80, 0x48, 0xf7, 0xd8, 0x48, 0x89, 0xc7]);
+                    code.extend_from_slice(&[0x31, 0xc0, 0xcd, 0x80]);
+                    program(PROCESS_START, &code)
+                }
+                // mov [TIME_PAGE], al
+                "write_time" => {
+                    let mut code = Vec::from([0xa2]);
+                    code.extend_from_slice(&(TIME_PAGE as u64).to_le_bytes());
+                    program(PROCESS_START, &code)
+                }
+                // movabs rax, [TIME_PAGE + 8], then exit(rax), with the tick rate
+                "ticks_per_second" => {
+                    let mut code = Vec::from([0x48, 0xa1]);
+                    code.extend_from_slice(&(TIME_PAGE as u64 + 8).to_le_bytes());
+                    code.extend_from_slice(&[0x48, 0x89, 0xc7, 0x31, 0xc0, 0xcd, 0x80]);
+                    program(PROCESS_START, &code)
+                }
+                // Privileged
+                "hlt" => program(PROCESS_START, &[0xf4]),
+                "misplaced" => program(0x40_0000, &[0xf4]),
+                "script" => Vec::from(*b"#!/bin/sh\nexit 1\n"),
+                _ => return Err(file::Err::NotFound),
+            };
+            Ok(Arc::new(Image(image)))
+        }
+    }
+
+    fn known(task: TaskId) -> bool {
+        crate::without_interrupt! {{
+            let known = PROCESSES.lock().contains_key(&task);
+            known
+        }}
+    }
+
+    fn spawn_test(name: &str) -> Result<TaskId, Err> {
+        // Already mounted by an earlier test is fine
+        let _ = vfs::mount("/process_test", Arc::new(Programs));
+        spawn(&alloc::format!("/process_test/{}", name), &[])
+    }
+
+    #[test_case]
+    fn test_init_spawns_hello() {
+        let frames = address_space::frames();
+        let init = spawn("/bin/init", &[]).unwrap();
+        // hello's exit code, the length of the "world\0" init gives it
+        assert_eq!(wait(init), Ok(6));
+        assert_eq!(wait(init), Err(Err::NoSuchChild));
+        assert!(!known(init));
+        assert_eq!(address_space::frames(), frames);
+    }
+
+    #[test_case]
+    fn test_arguments() {
+        let hello = spawn("/bin/hello", b"one\0two\0").unwrap();
+        assert_eq!(wait(hello), Ok(8));
+        let too_long = vec![0; MAX_ARGS + 1];
+        assert_eq!(spawn("/bin/hello", &too_long), Err(Err::ArgumentsTooLong));
+    }
+
+    #[test_case]
+    fn test_faults_kill_process() {
+        for name in ["write_null", "read_kernel", "write_time", "hlt"] {
+            let child = spawn_test(name).unwrap();
+            assert_eq!(wait(child), Ok(KILLED), "{}", name);
+        }
+    }
+
+    #[test_case]
+    fn test_time_page() {
+        let child = spawn_test("ticks_per_second").unwrap();
+        assert_eq!(wait(child), Ok(crate::time::ticks_per_second() as u8));
+    }
+
+    #[test_case]
+    fn test_kernel_pointers_refused() {
+        // The spawn fails with -1 rather than reading kernel memory
+        let child = spawn_test("spawn_kernel_path").unwrap();
+        assert_eq!(wait(child), Ok(1));
+    }
+
+    #[test_case]
+    fn test_bad_programs() {
+        assert_eq!(
+            spawn("/bin/missing", &[]),
+            Err(Err::File(file::Err::NotFound))
+        );
+        assert_eq!(spawn_test("misplaced"), Err(Err::BadExecutable));
+        assert_eq!(spawn_test("script"), Err(Err::BadExecutable));
+    }
+
+    #[test_case]
+    fn test_only_parent_waits() {
+        assert_eq!(wait(TaskId::from_u64(u64::MAX)), Err(Err::NoSuchChild));
+        static CHILD: Mutex<Option<TaskId>> = Mutex::new(None);
+        // Spawns a child and exits without waiting for it, so nobody can
+        fn orphaning_task() {
+            *CHILD.lock() = Some(spawn("/bin/hello", &[]).unwrap());
+        }
+        scheduler::spawn("orphaning", orphaning_task).unwrap();
+        while CHILD.lock().is_none() {
+            scheduler::yield_now();
+        }
+        let child = CHILD.lock().unwrap();
+        assert_eq!(wait(child), Err(Err::NoSuchChild));
+        // Forgotten once it exits
+        while known(child) {
+            scheduler::yield_now();
+        }
+    }
+}
@@ -0,0 +1,710 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::signal::{self, Signals};
+use super::stack::Stack;
+use super::{process, switch_to, Context};
+use crate::fs::fd::FileTable;
+use crate::interrupt::context;
+use crate::memory::address_space::{kernel_page_table, AddressSpace};
+use crate::println;
+
+// A simple cooperative priority scheduler for kernel tasks, on a single CPU.
+//
+// Tasks are boxed so their saved Context stays put while the Box moves between the run queue,
+// wait queues (see sync::WaitQueue) and `current`. The code that was running before the first
+// switch (kernel_main, or the test runner) becomes a task without a pool stack. Kernel tasks run
+// on the kernel's page tables, and user processes (see task::process) on their own.
+//
+// The highest priority ready task runs, round robin within a priority. So that low priority
+// tasks aren't starved forever, every AGING_INTERVAL switches the longest waiting task at each
+// level gets bumped up one level; it drops back to its own priority once it gets to run.
+// IDLE_PRIORITY is left alone, so the idle task (see task::idle) only runs when nothing else can.
+
+pub const MIN_PRIORITY: u8 = 0;
+pub const MAX_PRIORITY: u8 = 31;
+pub const IDLE_PRIORITY: u8 = MIN_PRIORITY;
+pub const DEFAULT_PRIORITY: u8 = 16;
+// For work deferred from interrupt handlers, which should run as soon as possible
+pub const HIGH_PRIORITY: u8 = 28;
+
+const PRIORITIES: usize = MAX_PRIORITY as usize + 1;
+const AGING_INTERVAL: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Finished,
+}
+
+pub struct Task {
+    id: TaskId,
+    name: &'static str,
+    state: State,
+    // Priority in the run queue, raised above the task's own priority by aging
+    effective_priority: u8,
+    context: Context,
+    // None for the bootstrap task, which runs on the stack the bootloader gave us
+    stack: Option<Stack>,
+    // What the context's page table belongs to, kept until the task is gone. None for kernel tasks.
+    _address_space: Option<AddressSpace>,
+}
+
+impl Task {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+}
+
+#[derive(Debug)]
+pub enum Err {
+    OutOfStacks,
+    NoSuchTask,
+    InvalidPriority,
+}
+
+// One FIFO per priority, with a bitmap of which are non-empty
+struct RunQueue {
+    queues: [VecDeque<Box<Task>>; PRIORITIES],
+    non_empty: u32,
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        RunQueue {
+            queues: [(); PRIORITIES].map(|_| VecDeque::new()),
+            non_empty: 0,
+        }
+    }
+
+    fn highest_priority(&self) -> Option<u8> {
+        match self.non_empty {
+            0 => None,
+            bits => Some(31 - bits.leading_zeros() as u8),
+        }
+    }
+
+    fn push(&mut self, task: Box<Task>) {
+        let priority = task.effective_priority;
+        self.queues[priority as usize].push_back(task);
+        self.non_empty |= 1 << priority;
+    }
+
+    fn pop_from(&mut self, priority: u8) -> Option<Box<Task>> {
+        let queue = &mut self.queues[priority as usize];
+        let task = queue.pop_front();
+        if queue.is_empty() {
+            self.non_empty &= !(1 << priority);
+        }
+        task
+    }
+
+    fn pop(&mut self) -> Option<Box<Task>> {
+        self.pop_from(self.highest_priority()?)
+    }
+
+    fn contains(&self, id: TaskId) -> bool {
+        self.queues.iter().flatten().any(|task| task.id == id)
+    }
+
+    fn remove(&mut self, id: TaskId) -> Option<Box<Task>> {
+        for priority in 0..PRIORITIES {
+            let queue = &mut self.queues[priority];
+            if let Some(index) = queue.iter().position(|task| task.id == id) {
+                let task = queue.remove(index);
+                if queue.is_empty() {
+                    self.non_empty &= !(1 << priority);
+                }
+                return task;
+            }
+        }
+        None
+    }
+
+    // Move the head of each queue below the highest (but above idle) up a level
+    fn age(&mut self) {
+        let highest = match self.highest_priority() {
+            Some(highest) => highest,
+            None => return,
+        };
+        // Top down, so no task moves more than once
+        for priority in (IDLE_PRIORITY + 1..highest).rev() {
+            if let Some(mut task) = self.pop_from(priority) {
+                task.effective_priority = priority + 1;
+                self.push(task);
+            }
+        }
+    }
+}
+
+// Where a task's time went, updated at every switch. Times are in jiffies, so a task which
+// only ever runs for part of a tick is charged a tick whenever one lands while it's running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Accounting {
+    // Ticks spent running
+    pub run_ticks: u64,
+    // Times it's been switched to
+    pub switches: u64,
+    // When it was last switched to
+    pub last_scheduled: u64,
+}
+
+impl Accounting {
+    fn switched_in(&mut self, now: u64) {
+        self.switches += 1;
+        self.last_scheduled = now;
+    }
+
+    fn switched_out(&mut self, now: u64) {
+        self.run_ticks += now - self.last_scheduled;
+    }
+
+    // Including the current stint, if it's running
+    fn as_of(&self, now: u64, running: bool) -> Accounting {
+        let mut accounting = *self;
+        if running {
+            accounting.switched_out(now);
+        }
+        accounting
+    }
+}
+
+// Bookkeeping for every live task, wherever its Task is
+struct TaskInfo {
+    name: &'static str,
+    priority: u8,
+    signals: Signals,
+    files: FileTable,
+    accounting: Accounting,
+}
+
+impl TaskInfo {
+    fn new(name: &'static str, priority: u8) -> Self {
+        TaskInfo {
+            name,
+            priority,
+            signals: Signals::new(),
+            files: FileTable::new(),
+            accounting: Accounting::default(),
+        }
+    }
+}
+
+struct Scheduler {
+    current: Option<Box<Task>>,
+    ready: RunQueue,
+    // Every live task, including blocked tasks we otherwise can't see
+    tasks: BTreeMap<TaskId, TaskInfo>,
+    switches: u64,
+    // Tasks which exited, see drop_finished
+    finished: Vec<Finished>,
+}
+
+struct Finished {
+    _task: Box<Task>,
+    _info: Option<TaskInfo>,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        current: None,
+        ready: RunQueue::new(),
+        tasks: BTreeMap::new(),
+        switches: 0,
+        finished: Vec::new(),
+    });
+}
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_task_id() -> TaskId {
+    TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+impl Scheduler {
+    fn current(&mut self) -> &mut Box<Task> {
+        if self.current.is_none() {
+            let id = next_task_id();
+            let mut info = TaskInfo::new("bootstrap", DEFAULT_PRIORITY);
+            info.signals.protect();
+            self.tasks.insert(id, info);
+            self.current = Some(Box::new(Task {
+                id,
+                name: "bootstrap",
+                state: State::Running,
+                effective_priority: DEFAULT_PRIORITY,
+                context: Context::default(),
+                stack: None,
+                _address_space: None,
+            }));
+        }
+        self.current.as_mut().unwrap()
+    }
+
+    fn priority(&self, id: TaskId) -> u8 {
+        self.tasks
+            .get(&id)
+            .map_or(DEFAULT_PRIORITY, |info| info.priority)
+    }
+}
+
+extern "C" fn task_entry(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    drop_finished();
+    signal::handle_pending();
+    entry();
+    exit();
+}
+
+// Create a task which will run `entry` once the running task(s) yield
+pub fn spawn(name: &'static str, entry: fn()) -> Result<TaskId, Err> {
+    spawn_with_priority(name, entry, DEFAULT_PRIORITY)
+}
+
+pub fn spawn_with_priority(name: &'static str, entry: fn(), priority: u8) -> Result<TaskId, Err> {
+    spawn_task(name, entry, priority, None)
+}
+
+// Like spawn, but the task runs in `address_space` (eg. a user process, see task::process)
+pub fn spawn_in(
+    name: &'static str,
+    entry: fn(),
+    address_space: AddressSpace,
+) -> Result<TaskId, Err> {
+    spawn_task(name, entry, DEFAULT_PRIORITY, Some(address_space))
+}
+
+fn spawn_task(
+    name: &'static str,
+    entry: fn(),
+    priority: u8,
+    address_space: Option<AddressSpace>,
+) -> Result<TaskId, Err> {
+    if priority > MAX_PRIORITY {
+        return Err(Err::InvalidPriority);
+    }
+    let mut stack = Stack::allocate().ok_or(Err::OutOfStacks)?;
+    let page_table = address_space
+        .as_ref()
+        .map_or_else(kernel_page_table, AddressSpace::page_table);
+    let context = Context::new(unsafe { stack.memory() }, task_entry, entry as usize)
+        .with_address_space(page_table);
+    let task = Box::new(Task {
+        id: next_task_id(),
+        name,
+        state: State::Ready,
+        effective_priority: priority,
+        context,
+        stack: Some(stack),
+        _address_space: address_space,
+    });
+    let id = task.id;
+    let info = TaskInfo::new(name, priority);
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.tasks.insert(id, info);
+        scheduler.ready.push(task);
+    }}
+    Ok(id)
+}
+
+// Live tasks, including blocked ones
+pub fn task_count() -> usize {
+    crate::without_interrupt! {{
+        SCHEDULER.lock().tasks.len()
+    }}
+}
+
+// A live task, as listed by `tasks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskSummary {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub priority: u8,
+    pub state: State,
+    pub accounting: Accounting,
+}
+
+// Every live task, by id
+pub fn tasks() -> Vec<TaskSummary> {
+    let now = crate::time::jiffies();
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current().id;
+        scheduler
+            .tasks
+            .iter()
+            .map(|(&id, info)| TaskSummary {
+                id,
+                name: info.name,
+                priority: info.priority,
+                accounting: info.accounting.as_of(now, id == current),
+                state: if id == current {
+                    State::Running
+                } else if scheduler.ready.contains(id) {
+                    State::Ready
+                } else {
+                    State::Blocked
+                },
+            })
+            .collect()
+    }}
+}
+
+pub fn current_id() -> TaskId {
+    crate::without_interrupt! {{
+        SCHEDULER.lock().current().id
+    }}
+}
+
+pub fn accounting(task: TaskId) -> Option<Accounting> {
+    let now = crate::time::jiffies();
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current().id;
+        scheduler
+            .tasks
+            .get(&task)
+            .map(|info| info.accounting.as_of(now, task == current))
+    }}
+}
+
+pub fn priority(task: TaskId) -> Option<u8> {
+    crate::without_interrupt! {{
+        SCHEDULER.lock().tasks.get(&task).map(|info| info.priority)
+    }}
+}
+
+// Change a task's priority. Takes effect the next time it's queued (immediately, if it's ready).
+pub fn set_priority(task: TaskId, priority: u8) -> Result<(), Err> {
+    if priority > MAX_PRIORITY {
+        return Err(Err::InvalidPriority);
+    }
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current();
+        match scheduler.tasks.get_mut(&task) {
+            Some(info) => info.priority = priority,
+            None => return Err(Err::NoSuchTask),
+        }
+        if let Some(mut ready) = scheduler.ready.remove(task) {
+            ready.effective_priority = priority;
+            scheduler.ready.push(ready);
+        }
+        let current = scheduler.current();
+        if current.id == task {
+            current.effective_priority = priority;
+        }
+    }}
+    Ok(())
+}
+
+// Switch to the next ready task, handing the current one to `park` with its new `state`.
+// Returns false without switching if nothing else should run: there's no ready task or, when
+// just yielding, only ones with a lower priority.
+fn switch(state: State, park: impl FnOnce(&mut Scheduler, Box<Task>)) -> bool {
+    crate::without_interrupt! {{
+        let (old, new) = {
+            let mut scheduler = SCHEDULER.lock();
+            let current_priority = scheduler.current().effective_priority;
+            match scheduler.ready.highest_priority() {
+                Some(highest) if state != State::Ready || highest >= current_priority => (),
+                _ => return false,
+            }
+            scheduler.switches += 1;
+            if scheduler.switches % AGING_INTERVAL == 0 {
+                scheduler.ready.age();
+            }
+            let mut next = scheduler.ready.pop().unwrap();
+            next.state = State::Running;
+            // Interrupts and syscalls from user mode arrive on the task's own stack
+            if let Some(stack) = &next.stack {
+                crate::global_descriptor_table::set_kernel_stack(stack.top());
+            }
+            next.effective_priority = scheduler.priority(next.id);
+            let now = crate::time::jiffies();
+            let current_id = scheduler.current().id;
+            if let Some(info) = scheduler.tasks.get_mut(&current_id) {
+                info.accounting.switched_out(now);
+            }
+            if let Some(info) = scheduler.tasks.get_mut(&next.id) {
+                info.accounting.switched_in(now);
+            }
+            let new = &next.context as *const Context;
+            let mut current = scheduler.current.replace(next).unwrap();
+            current.state = state;
+            current.effective_priority = scheduler.priority(current.id);
+            let old = &mut current.context as *mut Context;
+            match state {
+                State::Finished => {
+                    let info = scheduler.tasks.remove(&current.id);
+                    scheduler.finished.push(Finished {
+                        _task: current,
+                        _info: info,
+                    });
+                }
+                _ => park(&mut scheduler, current),
+            }
+            (old, new)
+        };
+        // Each task keeps its own interrupt depth across the switch; new tasks start outside any
+        // handler
+        let interrupt_depth = context::swap_depth(0);
+        // Safety: both contexts are in boxed tasks which outlive the switch; the old one is only
+        // dropped (from `finished`) after we're running on some other stack
+        unsafe { switch_to(&mut *old, &*new) };
+        context::swap_depth(interrupt_depth);
+    }}
+    // Back in the parked task, which has since been woken up
+    drop_finished();
+    signal::handle_pending();
+    true
+}
+
+// A task that exits is still running on its stack until we switch away from it, so it's dropped
+// by whoever runs next. Not under the lock, or with interrupts off: dropping its TaskInfo closes
+// its files, which can wake tasks blocked on them (eg. reading the other end of a pipe).
+fn drop_finished() {
+    let finished = crate::without_interrupt! {{
+        let finished = core::mem::take(&mut SCHEDULER.lock().finished);
+        finished
+    }};
+    drop(finished);
+}
+
+// Run `f` on a live task's signal state
+pub(super) fn with_signals<R>(task: TaskId, f: impl FnOnce(&mut Signals) -> R) -> Option<R> {
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current();
+        scheduler.tasks.get_mut(&task).map(|info| f(&mut info.signals))
+    }}
+}
+
+// Run `f` on a live task's file descriptor table
+pub(crate) fn with_files<R>(task: TaskId, f: impl FnOnce(&mut FileTable) -> R) -> Option<R> {
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current();
+        scheduler.tasks.get_mut(&task).map(|info| f(&mut info.files))
+    }}
+}
+
+// Whether yield_now would switch to another task
+pub fn would_yield() -> bool {
+    crate::without_interrupt! {{
+        let mut scheduler = SCHEDULER.lock();
+        let current_priority = scheduler.current().effective_priority;
+        matches!(scheduler.ready.highest_priority(), Some(highest) if highest >= current_priority)
+    }}
+}
+
+// Let other ready tasks of at least our priority run; returns once we're scheduled again
+pub fn yield_now() {
+    switch(State::Ready, |scheduler, task| scheduler.ready.push(task));
+}
+
+// Block the current task, handing it to `park` (eg. to put it on a wait queue) until something
+// passes it to `wake`. Callers must disable interrupts around checking their wakeup condition
+// and blocking, or the wakeup can be lost.
+pub fn block(park: impl FnOnce(Box<Task>)) {
+    if !switch(State::Blocked, |_, task| park(task)) {
+        panic!(
+            "Deadlock: task {} blocked with no other tasks ready",
+            current_name()
+        );
+    }
+}
+
+// Make a blocked task runnable again
+pub fn wake(mut task: Box<Task>) {
+    task.state = State::Ready;
+    crate::without_interrupt! {{
+        SCHEDULER.lock().ready.push(task);
+    }}
+}
+
+pub fn exit() -> ! {
+    process::exited(current_id());
+    // switch keeps hold of finished tasks itself
+    let switched = switch(State::Finished, |_, _| ());
+    assert!(switched, "Last task exited");
+    unreachable!("Finished task was resumed");
+}
+
+fn current_name() -> &'static str {
+    crate::without_interrupt! {{
+        SCHEDULER.lock().current().name
+    }}
+}
+
+// nice <task id> <priority>
+fn nice_command(args: &[&str]) {
+    let (task, priority) = match args {
+        [task, priority] => (task.parse::<u64>(), priority.parse::<u8>()),
+        _ => {
+            println!(
+                "usage: nice <task id> <priority {}-{}>",
+                MIN_PRIORITY, MAX_PRIORITY
+            );
+            return;
+        }
+    };
+    match (task, priority) {
+        (Ok(task), Ok(priority)) => match set_priority(TaskId(task), priority) {
+            Ok(()) => (),
+            Err(err) => println!("nice: {:?}", err),
+        },
+        _ => println!("nice: expected numbers"),
+    }
+}
+
+pub fn init() {
+    crate::shell::register("nice", "set a task's priority", nice_command);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+    fn step_task() {
+        for _ in 0..3 {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_spawn_and_yield() {
+        STEPS.store(0, Ordering::Relaxed);
+        let a = spawn("step_a", step_task).unwrap();
+        let b = spawn("step_b", step_task).unwrap();
+        while STEPS.load(Ordering::Relaxed) < 6 {
+            yield_now();
+        }
+        // Let both tasks run off the end and exit
+        yield_now();
+        yield_now();
+        assert_eq!(priority(a), None);
+        assert_eq!(priority(b), None);
+    }
+
+    static LOW_RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn low_task() {
+        LOW_RAN.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_lower_priority_waits() {
+        LOW_RAN.store(0, Ordering::Relaxed);
+        let low = spawn_with_priority("low", low_task, DEFAULT_PRIORITY - 1).unwrap();
+        yield_now();
+        assert_eq!(LOW_RAN.load(Ordering::Relaxed), 0);
+        assert_eq!(priority(low), Some(DEFAULT_PRIORITY - 1));
+        set_priority(low, DEFAULT_PRIORITY).unwrap();
+        yield_now();
+        assert_eq!(LOW_RAN.load(Ordering::Relaxed), 1);
+        assert_eq!(priority(low), None);
+    }
+
+    #[test_case]
+    fn test_aging_promotes_waiting_tasks() {
+        let mut queue = RunQueue::new();
+        for (id, priority) in [(0, 3), (1, 3), (2, 1)] {
+            queue.push(Box::new(Task {
+                id: TaskId(1000 + id),
+                name: "aging",
+                state: State::Ready,
+                effective_priority: priority,
+                context: Context::default(),
+                stack: None,
+                _address_space: None,
+            }));
+        }
+        queue.age();
+        assert_eq!(queue.highest_priority(), Some(3));
+        // Only the task at 1 moved, up to 2
+        assert_eq!(queue.non_empty, 1 << 3 | 1 << 2);
+        queue.age();
+        assert_eq!(queue.non_empty, 1 << 3);
+        assert_eq!(queue.pop().unwrap().id, TaskId(1000));
+    }
+
+    fn yield_once() {
+        yield_now();
+    }
+
+    #[test_case]
+    fn test_accounting() {
+        let me = current_id();
+        let before = accounting(me).unwrap();
+        spawn("accounting", yield_once).unwrap();
+        // Back and forth with it, until it exits
+        yield_now();
+        yield_now();
+        let start = crate::time::jiffies();
+        while crate::time::jiffies() < start + 2 {
+            core::hint::spin_loop();
+        }
+        let after = accounting(me).unwrap();
+        assert!(after.switches >= before.switches + 2);
+        assert!(after.last_scheduled >= before.last_scheduled);
+        // Charged for spinning while running, even though nothing switched since
+        assert!(after.run_ticks >= before.run_ticks + 2);
+        let summary = tasks().into_iter().find(|task| task.id == me).unwrap();
+        assert_eq!(summary.state, State::Running);
+        assert!(summary.accounting.run_ticks >= after.run_ticks);
+    }
+
+    static PARTNER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+    fn yield_partner() {
+        while PARTNER_RUNNING.load(Ordering::Relaxed) {
+            yield_now();
+        }
+    }
+
+    crate::bench_case! {
+        fn bench_context_switch(b: &mut Bencher) {
+            PARTNER_RUNNING.store(true, Ordering::Relaxed);
+            spawn("bench_partner", yield_partner).unwrap();
+            // Each yield is a switch to the partner and one back
+            b.iter(yield_now);
+            PARTNER_RUNNING.store(false, Ordering::Relaxed);
+            // Let it exit
+            yield_now();
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use super::scheduler::{self, TaskId};
+use crate::println;
+
+// Asynchronous notifications to tasks. Sending a signal sets a pending bit on the target; the
+// target acts on it the next time it's switched back in (or starts running), either with the
+// signal's default action or whatever it registered with `set_action`.
+//
+// Tasks are cooperative, so a task that never yields or blocks never sees its signals, and a
+// blocked task only sees them once it's woken.
+// TODO: also check at interrupt and syscall return, once there's preemption and user mode
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    // Always terminates, can't be ignored or handled
+    Kill,
+    Terminate,
+    Interrupt,
+    User1,
+    User2,
+}
+
+const SIGNALS: usize = 5;
+
+impl Signal {
+    pub const ALL: [Signal; SIGNALS] = [
+        Signal::Kill,
+        Signal::Terminate,
+        Signal::Interrupt,
+        Signal::User1,
+        Signal::User2,
+    ];
+
+    fn default_action(&self) -> Action {
+        match self {
+            Signal::Kill | Signal::Terminate | Signal::Interrupt => Action::Terminate,
+            Signal::User1 | Signal::User2 => Action::Ignore,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Signal::Kill => "kill",
+            Signal::Terminate => "term",
+            Signal::Interrupt => "int",
+            Signal::User1 => "usr1",
+            Signal::User2 => "usr2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Signal> {
+        Signal::ALL.into_iter().find(|signal| signal.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    Default,
+    Ignore,
+    Terminate,
+    // Called on the receiving task
+    Handle(fn(Signal)),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Err {
+    NoSuchTask,
+    // Signal::Kill's action can't be changed
+    Uncatchable,
+    // The kernel can't run without the task (idle, scrubber, bootstrap), so it can't be signalled
+    Critical,
+}
+
+// Per-task signal state, kept by the scheduler
+pub struct Signals {
+    pending: u32,
+    actions: [Action; SIGNALS],
+    critical: bool,
+}
+
+impl Signals {
+    pub(super) fn new() -> Self {
+        Signals {
+            pending: 0,
+            actions: [Action::Default; SIGNALS],
+            critical: false,
+        }
+    }
+
+    pub(super) fn protect(&mut self) {
+        self.critical = true;
+    }
+
+    fn take_pending(&mut self) -> Option<Signal> {
+        let signal = Signal::ALL
+            .into_iter()
+            .find(|&signal| self.pending & 1 << signal as u32 != 0)?;
+        self.pending &= !(1 << signal as u32);
+        Some(signal)
+    }
+
+    fn action(&self, signal: Signal) -> Action {
+        match self.actions[signal as usize] {
+            Action::Default => signal.default_action(),
+            action => action,
+        }
+    }
+}
+
+pub fn signal(task: TaskId, signal: Signal) -> Result<(), Err> {
+    scheduler::with_signals(task, |signals| match signals.critical {
+        true => Err(Err::Critical),
+        false => {
+            signals.pending |= 1 << signal as u32;
+            Ok(())
+        }
+    })
+    .ok_or(Err::NoSuchTask)??;
+    if task == scheduler::current_id() {
+        handle_pending();
+    }
+    Ok(())
+}
+
+// Refuse signals to a task the kernel depends on
+pub fn protect(task: TaskId) -> Result<(), Err> {
+    scheduler::with_signals(task, Signals::protect).ok_or(Err::NoSuchTask)
+}
+
+// Choose what the current task does when it receives `signal`
+pub fn set_action(signal: Signal, action: Action) -> Result<(), Err> {
+    if signal == Signal::Kill {
+        return Err(Err::Uncatchable);
+    }
+    scheduler::with_signals(scheduler::current_id(), |signals| {
+        signals.actions[signal as usize] = action
+    })
+    .ok_or(Err::NoSuchTask)
+}
+
+// Act on the current task's pending signals
+pub(super) fn handle_pending() {
+    let current = scheduler::current_id();
+    loop {
+        let next = scheduler::with_signals(current, |signals| {
+            signals
+                .take_pending()
+                .map(|signal| (signal, signals.action(signal)))
+        });
+        let (signal, action) = match next.flatten() {
+            Some(next) => next,
+            None => return,
+        };
+        match action {
+            Action::Terminate | Action::Default => scheduler::exit(),
+            Action::Ignore => (),
+            Action::Handle(handler) => handler(signal),
+        }
+    }
+}
+
+// kill <task id> [signal]
+fn kill_command(args: &[&str]) {
+    let (task, signal) = match args {
+        [task] => (task.parse::<u64>(), Some(Signal::Terminate)),
+        [task, signal] => (task.parse::<u64>(), Signal::from_name(signal)),
+        _ => {
+            println!("usage: kill <task id> [kill|term|int|usr1|usr2]");
+            return;
+        }
+    };
+    match (task, signal) {
+        (Ok(task), Some(sig)) => {
+            if let Err(err) = self::signal(TaskId::from_u64(task), sig) {
+                println!("kill: {:?}", err);
+            }
+        }
+        (Err(_), _) => println!("kill: expected a task id"),
+        (_, None) => println!("kill: unknown signal"),
+    }
+}
+
+pub fn init() {
+    crate::shell::register("kill", "send a signal to a task", kill_command);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static LOOPS: AtomicUsize = AtomicUsize::new(0);
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    fn looping_task() {
+        loop {
+            LOOPS.fetch_add(1, Ordering::Relaxed);
+            scheduler::yield_now();
+        }
+    }
+
+    fn count_signal(_: Signal) {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn exiting_task() {
+        scheduler::yield_now();
+    }
+
+    fn handling_task() {
+        set_action(Signal::User1, Action::Handle(count_signal)).unwrap();
+        loop {
+            scheduler::yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_terminate_task() {
+        let task = scheduler::spawn("looping", looping_task).unwrap();
+        scheduler::yield_now();
+        signal(task, Signal::Terminate).unwrap();
+        scheduler::yield_now();
+        let loops = LOOPS.load(Ordering::Relaxed);
+        scheduler::yield_now();
+        assert_eq!(LOOPS.load(Ordering::Relaxed), loops);
+        assert_eq!(scheduler::priority(task), None);
+        assert_eq!(signal(task, Signal::Kill), Err(Err::NoSuchTask));
+    }
+
+    #[test_case]
+    fn test_handled_signal() {
+        HANDLED.store(0, Ordering::Relaxed);
+        let task = scheduler::spawn("handling", handling_task).unwrap();
+        scheduler::yield_now();
+        signal(task, Signal::User1).unwrap();
+        scheduler::yield_now();
+        assert_eq!(HANDLED.load(Ordering::Relaxed), 1);
+        // Still running, until it's killed
+        signal(task, Signal::Kill).unwrap();
+        scheduler::yield_now();
+        assert_eq!(scheduler::priority(task), None);
+    }
+
+    #[test_case]
+    fn test_critical_task_refuses_signals() {
+        let task = scheduler::spawn("critical", exiting_task).unwrap();
+        protect(task).unwrap();
+        assert_eq!(signal(task, Signal::Kill), Err(Err::Critical));
+        assert_eq!(signal(task, Signal::Terminate), Err(Err::Critical));
+        // It exits on its own
+        scheduler::yield_now();
+        scheduler::yield_now();
+        assert_eq!(scheduler::priority(task), None);
+    }
+}
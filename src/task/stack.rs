@@ -0,0 +1,60 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::config::STACK_SIZE;
+
+// Kernel task stacks.
+// TODO: allocate these (with guard pages) from the page allocator once it can hand out mapped
+// memory; for now there's a small fixed pool, since the kernel heap is far too small for them.
+
+// Including a few taken for good by global_descriptor_table for interrupt stacks
+pub const MAX_STACKS: usize = 12;
+
+#[repr(align(16))]
+struct StackMemory([u8; STACK_SIZE]);
+
+const EMPTY_STACK: StackMemory = StackMemory([0; STACK_SIZE]);
+static mut STACKS: [StackMemory; MAX_STACKS] = [EMPTY_STACK; MAX_STACKS];
+// Bit i set if STACKS[i] is in use
+static IN_USE: AtomicU32 = AtomicU32::new(0);
+
+// Ownership of one stack from the pool, returned when dropped
+pub struct Stack {
+    index: usize,
+}
+
+impl Stack {
+    pub fn allocate() -> Option<Stack> {
+        let mut in_use = IN_USE.load(Ordering::Acquire);
+        loop {
+            let index = in_use.trailing_ones() as usize;
+            if index >= MAX_STACKS {
+                return None;
+            }
+            match IN_USE.compare_exchange_weak(
+                in_use,
+                in_use | 1 << index,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(Stack { index }),
+                Err(current) => in_use = current,
+            }
+        }
+    }
+
+    // Safety: the memory must not be used after the Stack is dropped
+    pub unsafe fn memory(&mut self) -> &'static mut [u8] {
+        &mut STACKS[self.index].0
+    }
+
+    // Address just past the end, where the stack starts (it grows down)
+    pub fn top(&self) -> usize {
+        unsafe { core::ptr::addr_of!(STACKS[self.index]) as usize + STACK_SIZE }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        IN_USE.fetch_and(!(1 << self.index), Ordering::Release);
+    }
+}
@@ -0,0 +1,189 @@
+use core::arch::global_asm;
+use core::mem::size_of;
+
+// Kernel task context switching.
+//
+// Switching only needs to preserve what the System V ABI says a call preserves: the callee-saved
+// registers, the stack, and where to resume. Everything else the compiler already assumes is
+// clobbered by the call to switch_to.
+
+const RFLAGS_RESERVED: u64 = 1 << 1;
+const RFLAGS_INTERRUPTS_ENABLED: u64 = 1 << 9;
+
+// Field offsets are hardcoded in sos_switch_to below
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+    rip: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+    // Physical address of the L4 page table, or 0 to stay in the current address space
+    cr3: u64,
+}
+
+const _: () = assert!(size_of::<Context>() == 80);
+
+impl Context {
+    // A context which will start running `entry(argument)` on `stack` when switched to.
+    // `entry` must never return; there's nothing on the stack to return to.
+    pub fn new(
+        stack: &'static mut [u8],
+        entry: extern "C" fn(usize) -> !,
+        argument: usize,
+    ) -> Self {
+        let stack_top = stack.as_mut_ptr_range().end as u64;
+        Context {
+            // sos_task_start calls entry, which needs a 16 byte aligned stack
+            rsp: stack_top & !0xf,
+            rip: sos_task_start as usize as u64,
+            r12: entry as usize as u64,
+            r13: argument as u64,
+            rflags: RFLAGS_RESERVED | RFLAGS_INTERRUPTS_ENABLED,
+            ..Default::default()
+        }
+    }
+
+    // Run in the address space rooted at the L4 table at physical address `cr3`
+    pub fn with_address_space(mut self, cr3: u64) -> Self {
+        self.cr3 = cr3;
+        self
+    }
+}
+
+// switch_to(old: rdi, new: rsi)
+// Saves the current state into `old` and resumes `new`. Returns when something switches back to
+// `old`. CR3 is only reloaded if the address spaces differ, since it flushes the TLB.
+global_asm!(
+    ".global sos_switch_to",
+    "sos_switch_to:",
+    // Save: resume at our return address, with the stack as it will be after returning
+    "    mov rax, [rsp]",
+    "    mov [rdi + 8], rax",
+    "    lea rax, [rsp + 8]",
+    "    mov [rdi], rax",
+    "    mov [rdi + 16], rbx",
+    "    mov [rdi + 24], rbp",
+    "    mov [rdi + 32], r12",
+    "    mov [rdi + 40], r13",
+    "    mov [rdi + 48], r14",
+    "    mov [rdi + 56], r15",
+    "    pushfq",
+    "    pop qword ptr [rdi + 64]",
+    "    mov rax, cr3",
+    "    mov [rdi + 72], rax",
+    // Restore, switching address spaces first if needed
+    "    mov rcx, [rsi + 72]",
+    "    test rcx, rcx",
+    "    jz 2f",
+    "    cmp rcx, rax",
+    "    je 2f",
+    "    mov cr3, rcx",
+    "2:",
+    "    mov rbx, [rsi + 16]",
+    "    mov rbp, [rsi + 24]",
+    "    mov r12, [rsi + 32]",
+    "    mov r13, [rsi + 40]",
+    "    mov r14, [rsi + 48]",
+    "    mov r15, [rsi + 56]",
+    "    mov rsp, [rsi]",
+    "    push qword ptr [rsi + 64]",
+    "    popfq",
+    "    jmp qword ptr [rsi + 8]",
+    // First entry into a new task: entry (r12) is called with argument (r13)
+    ".global sos_task_start",
+    "sos_task_start:",
+    "    xor ebp, ebp",
+    "    mov rdi, r13",
+    "    call r12",
+    "    ud2",
+);
+
+extern "C" {
+    fn sos_switch_to(old: *mut Context, new: *const Context);
+    fn sos_task_start();
+}
+
+// Save the running task's state into `old` and switch to `new`.
+// Safety: `new` must be a context from Context::new or a previous switch_to which hasn't been
+// resumed since, and whatever it runs on (stack, address space) must still be alive.
+#[inline(always)]
+pub unsafe fn switch_to(old: &mut Context, new: &Context) {
+    sos_switch_to(old, new);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STACK_SIZE: usize = 16 * 1024;
+    const ROUNDS: usize = 10_000;
+
+    #[repr(align(16))]
+    struct Stack([u8; STACK_SIZE]);
+
+    static mut PING_STACK: Stack = Stack([0; STACK_SIZE]);
+    static mut PONG_STACK: Stack = Stack([0; STACK_SIZE]);
+    static mut MAIN: Context = context();
+    static mut PING: Context = context();
+    static mut PONG: Context = context();
+    static mut HITS: [usize; 2] = [0; 2];
+
+    const fn context() -> Context {
+        Context {
+            rsp: 0,
+            rip: 0,
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rflags: 0,
+            cr3: 0,
+        }
+    }
+
+    extern "C" fn ping(rounds: usize) -> ! {
+        unsafe {
+            for _ in 0..rounds {
+                HITS[0] += 1;
+                switch_to(&mut PING, &PONG);
+            }
+            switch_to(&mut PING, &MAIN);
+        }
+        unreachable!("Ping resumed after finishing");
+    }
+
+    extern "C" fn pong(_: usize) -> ! {
+        loop {
+            unsafe {
+                // Ping should always have run exactly once more than us
+                assert_eq!(HITS[0], HITS[1] + 1);
+                HITS[1] += 1;
+                switch_to(&mut PONG, &PING);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_ping_pong() {
+        unsafe {
+            HITS = [0; 2];
+            PING = Context::new(&mut PING_STACK.0, ping, ROUNDS);
+            // Explicitly the same address space; switch_to shouldn't touch CR3
+            let cr3 = x86_64::registers::control::Cr3::read()
+                .0
+                .start_address()
+                .as_u64();
+            PONG = Context::new(&mut PONG_STACK.0, pong, 0).with_address_space(cr3);
+            switch_to(&mut MAIN, &PING);
+            assert_eq!(HITS, [ROUNDS, ROUNDS]);
+        }
+    }
+}
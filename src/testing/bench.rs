@@ -0,0 +1,184 @@
+use alloc::vec::Vec;
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use core::hint::black_box;
+
+use spin::Once;
+
+use super::report;
+
+// Cycle counting benchmarks, declared with bench_case! and run (and filtered, with `tag=bench`)
+// along with the tests. Each benchmark sets up whatever it needs and hands the code to time to
+// Bencher::iter, which runs it some warmup iterations, then times each measured iteration on its
+// own with the TSC. Iterations the timer (or anything else) interrupted stick out, so anything
+// past the upper Tukey fence is dropped as an outlier before the stats are reported, as a line
+// for people or a `{"event":"bench",...}` object in SOS_TEST_FORMAT=json.
+// Cycles are TSC cycles, which on anything recent tick at a constant rate rather than the core's.
+
+pub const DEFAULT_WARMUP: usize = 100;
+pub const DEFAULT_ITERATIONS: usize = 1000;
+
+// Declare a benchmark, eg.
+// bench_case! {
+//     fn bench_something(b: &mut Bencher) {
+//         let thing = setup();
+//         b.iter(|| thing.do_it());
+//     }
+// }
+// It's a test tagged `bench`, so it passes unless it panics.
+#[macro_export]
+macro_rules! bench_case {
+    (fn $name:ident($bencher:ident: &mut Bencher) $body:block) => {
+        $crate::kernel_test! {
+            #[tags(bench)]
+            fn $name() {
+                let mut bencher = $crate::testing::bench::Bencher::new();
+                let $bencher = &mut bencher;
+                $body
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    // Measured iterations, including outliers
+    pub iterations: usize,
+    pub outliers: usize,
+    // Of the rest, in cycles
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+    pub max: u64,
+}
+
+impl Stats {
+    // Sorts `samples`. There must be at least one.
+    fn from_samples(samples: &mut [u64]) -> Self {
+        samples.sort_unstable();
+        let n = samples.len();
+        let (q1, q3) = (samples[n / 4], samples[3 * n / 4]);
+        let fence = q3 + (q3 - q1) * 3 / 2;
+        let kept = &samples[..samples.partition_point(|&sample| sample <= fence)];
+        Stats {
+            iterations: n,
+            outliers: n - kept.len(),
+            min: kept[0],
+            median: kept[kept.len() / 2],
+            mean: kept.iter().sum::<u64>() / kept.len() as u64,
+            max: kept[kept.len() - 1],
+        }
+    }
+}
+
+pub struct Bencher {
+    warmup: usize,
+    iterations: usize,
+}
+
+impl Bencher {
+    pub fn new() -> Self {
+        Bencher {
+            warmup: DEFAULT_WARMUP,
+            iterations: DEFAULT_ITERATIONS,
+        }
+    }
+
+    pub fn warmup(&mut self, warmup: usize) -> &mut Self {
+        self.warmup = warmup;
+        self
+    }
+
+    // At least one
+    pub fn iterations(&mut self, iterations: usize) -> &mut Self {
+        self.iterations = iterations.max(1);
+        self
+    }
+
+    // Time `f`, and report how long it took. Its result is kept from being optimized away.
+    pub fn iter<R>(&mut self, mut f: impl FnMut() -> R) -> Stats {
+        for _ in 0..self.warmup {
+            black_box(f());
+        }
+        // Allocated up front, so it isn't part of what's measured
+        let mut samples = Vec::with_capacity(self.iterations);
+        let overhead = overhead();
+        for _ in 0..self.iterations {
+            let start = start();
+            black_box(f());
+            samples.push((stop() - start).saturating_sub(overhead));
+        }
+        let stats = Stats::from_samples(&mut samples);
+        report::bench(&stats);
+        stats
+    }
+}
+
+impl Default for Bencher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The lfences keep earlier instructions from finishing after the start is read, and the timed
+// code from starting before it is
+#[inline(always)]
+fn start() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+// rdtscp waits for the timed code to finish, and the lfence keeps what follows from starting
+// before it's read
+#[inline(always)]
+fn stop() -> u64 {
+    let mut aux = 0;
+    unsafe {
+        let tsc = __rdtscp(&mut aux);
+        _mm_lfence();
+        tsc
+    }
+}
+
+// Cycles start and stop take between them, to take off every sample
+fn overhead() -> u64 {
+    static OVERHEAD: Once<u64> = Once::new();
+    *OVERHEAD.call_once(|| {
+        (0..DEFAULT_WARMUP)
+            .map(|_| {
+                let start = start();
+                stop() - start
+            })
+            .min()
+            .unwrap_or(0)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_outliers_rejected() {
+        let mut samples = vec![10, 12, 11, 10, 13, 11, 12, 10, 500, 11, 12, 10];
+        let stats = Stats::from_samples(&mut samples);
+        assert_eq!(stats.iterations, 12);
+        assert_eq!(stats.outliers, 1);
+        assert_eq!((stats.min, stats.max), (10, 13));
+        assert_eq!(stats.median, 11);
+        assert_eq!(stats.mean, 122 / 11);
+    }
+
+    crate::bench_case! {
+        fn bench_nothing(b: &mut Bencher) {
+            let stats = b.warmup(10).iterations(100).iter(|| ());
+            assert_eq!(stats.iterations, 100);
+            assert!(stats.min <= stats.median && stats.median <= stats.max);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use core::arch::asm;
+
+use lazy_static::lazy_static;
+
+use super::{single_test_failed, single_test_passed, single_test_started};
+use crate::global_descriptor_table;
+use crate::interrupt::table::{Exception, Handler, InterruptStackFrame, InterruptTable};
+use crate::interrupt::DOUBLE_FAULT_STACK;
+
+// Shared by the harness = false test binaries checking that a fault is handled on its interrupt
+// stack table (IST) stack. Each loads a table with only the handlers it's testing, so anything
+// else escalates instead of being quietly handled by the kernel's own.
+
+lazy_static! {
+    // Just the double fault handler, for tests whose fault should end in a double fault
+    pub static ref DOUBLE_FAULT_TABLE: InterruptTable = {
+        let mut table = InterruptTable::empty();
+        table
+            .set_handler(
+                Exception::DoubleFault,
+                Handler::Exception(double_fault_on_ist_stack),
+            )
+            .set_stack(DOUBLE_FAULT_STACK as u8);
+        table
+    };
+}
+
+// Report the test started, then load `table` with the kernel's GDT, and so its IST stacks, in place
+pub fn start(name: &'static str, table: &'static InterruptTable) {
+    single_test_started(name);
+    global_descriptor_table::init();
+    table.load();
+}
+
+// Fail the test unless we're running on IST stack `index`, eg. interrupt::NMI_STACK
+pub fn check_stack(name: &str, index: usize) {
+    let rsp: usize;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let stack = global_descriptor_table::interrupt_stack(index);
+    if !stack.contains(&rsp) {
+        single_test_failed(format_args!(
+            "{} handler ran on stack {:#x}, expected IST stack {:#x?}",
+            name, rsp, stack
+        ));
+    }
+}
+
+extern "x86-interrupt" fn double_fault_on_ist_stack(_frame: InterruptStackFrame, _error: u64) {
+    check_stack("double fault", DOUBLE_FAULT_STACK);
+    single_test_passed();
+}
@@ -0,0 +1,509 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+use spin::{Mutex, Once};
+
+use crate::backtrace::Backtrace;
+use crate::panicking::{self, Nesting};
+use crate::power::{self, QemuExitStatus};
+use crate::{serial, time, without_interrupt};
+
+pub mod bench;
+pub mod ist;
+pub mod report;
+
+// For kernel_test_main!, so test binaries don't need their own path to it
+pub use bootloader::BootInfo;
+
+// Generous, since tests are expected to take milliseconds. A test that runs this long is
+// almost certainly deadlocked.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Filters can be baked in at build time, eg. `SOS_TEST_FILTER='memory::*' cargo test`,
+// or sent over serial before the tests start, eg. `echo 'test=memory::*,tag=slow'`.
+// Multiple filters are comma separated, and a test runs if it matches any of them.
+const BUILD_TIME_FILTER: Option<&str> = option_env!("SOS_TEST_FILTER");
+const MAX_FILTER_LENGTH: usize = 128;
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self) -> ();
+    fn should_panic(&self) -> bool {
+        false
+    }
+    // Text a should_panic test's panic message has to contain
+    fn expected_panic(&self) -> Option<&'static str> {
+        None
+    }
+    fn ignored(&self) -> bool {
+        false
+    }
+    fn tags(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self();
+    }
+}
+
+// A test with metadata, usually declared with kernel_test!
+pub struct KernelTest {
+    pub name: &'static str,
+    pub function: fn(),
+    pub should_panic: bool,
+    pub expected_panic: Option<&'static str>,
+    pub ignore: bool,
+    pub tags: &'static [&'static str],
+}
+
+impl Testable for KernelTest {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.function)();
+    }
+
+    fn should_panic(&self) -> bool {
+        self.should_panic
+    }
+
+    fn expected_panic(&self) -> Option<&'static str> {
+        self.expected_panic
+    }
+
+    fn ignored(&self) -> bool {
+        self.ignore
+    }
+
+    fn tags(&self) -> &'static [&'static str] {
+        self.tags
+    }
+}
+
+// Declare a test case with metadata, eg.
+// kernel_test! {
+//     #[should_panic(expected = "out of bounds")]
+//     #[ignore]
+//     #[tags(memory, slow)]
+//     fn test_something() { ... }
+// }
+// should_panic tests run inside panicking::catch_panic, so the rest of the suite carries on after.
+#[macro_export]
+macro_rules! kernel_test {
+    (@parse [$($should_panic:tt)*] [$($expected:tt)*] [$($ignore:tt)*] [$($tags:tt)*]
+        #[should_panic] $($rest:tt)*) => {
+        $crate::kernel_test!(@parse [true] [$($expected)*] [$($ignore)*] [$($tags)*] $($rest)*);
+    };
+    (@parse [$($should_panic:tt)*] [$($expected:tt)*] [$($ignore:tt)*] [$($tags:tt)*]
+        #[should_panic(expected = $message:literal)] $($rest:tt)*) => {
+        $crate::kernel_test!(@parse [true] [Some($message)] [$($ignore)*] [$($tags)*] $($rest)*);
+    };
+    (@parse [$($should_panic:tt)*] [$($expected:tt)*] [$($ignore:tt)*] [$($tags:tt)*]
+        #[ignore] $($rest:tt)*) => {
+        $crate::kernel_test!(@parse [$($should_panic)*] [$($expected)*] [true] [$($tags)*] $($rest)*);
+    };
+    (@parse [$($should_panic:tt)*] [$($expected:tt)*] [$($ignore:tt)*] [$($tags:tt)*]
+        #[tags($($tag:ident),* $(,)?)] $($rest:tt)*) => {
+        $crate::kernel_test!(
+            @parse [$($should_panic)*] [$($expected)*] [$($ignore)*] [$(stringify!($tag)),*] $($rest)*
+        );
+    };
+    (@parse [$should_panic:expr] [$expected:expr] [$ignore:expr] [$($tags:expr),*]
+        fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::testing::KernelTest = $crate::testing::KernelTest {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            function: {
+                fn $name() $body
+                $name
+            },
+            should_panic: $should_panic,
+            expected_panic: $expected,
+            ignore: $ignore,
+            tags: &[$($tags),*],
+        };
+    };
+    ($($rest:tt)*) => {
+        $crate::kernel_test!(@parse [false] [None] [false] [] $($rest)*);
+    };
+}
+
+// Everything an integration test binary needs besides its tests: an entry point which runs
+// `setup` (sos::init unless it says otherwise) and then the tests, and a panic handler. Timeouts
+// and reporting come with the test runner. Macros can't add crate attributes, so the binary
+// still starts with
+//     #![no_std]
+//     #![no_main]
+//     #![feature(custom_test_frameworks)]
+//     #![test_runner(sos::test_runner)]
+//     #![reexport_test_harness_main = "test_main"]
+//
+//     sos::kernel_test_main!();
+//
+// Binaries with harness = false are a single test driving the machine themselves, and report
+// through single_test_started and friends instead.
+#[macro_export]
+macro_rules! kernel_test_main {
+    () => {
+        $crate::kernel_test_main!(setup = $crate::init);
+    };
+    (setup = $setup:expr) => {
+        #[export_name = "_start"]
+        pub extern "C" fn __kernel_test_start(boot_info: &'static $crate::testing::BootInfo) -> ! {
+            let setup: fn(&'static $crate::testing::BootInfo) = $setup;
+            setup(boot_info);
+            test_main();
+            loop {}
+        }
+
+        #[panic_handler]
+        fn panic(info: &::core::panic::PanicInfo) -> ! {
+            $crate::test_panic_handler(info);
+        }
+    };
+}
+
+// Assert that evaluating an expression panics, optionally with a message containing some text
+#[macro_export]
+macro_rules! assert_panics {
+    ($expression:expr) => {
+        assert!(
+            $crate::panicking::catch_panic(|| {
+                $expression;
+            })
+            .is_err(),
+            "{} didn't panic",
+            stringify!($expression)
+        )
+    };
+    ($expression:expr, $expected:expr) => {
+        match $crate::panicking::catch_panic(|| {
+            $expression;
+        }) {
+            Ok(()) => panic!("{} didn't panic", stringify!($expression)),
+            Err(message) => assert!(
+                message.as_str().contains($expected),
+                "{} panicked with {:?}, expected {:?}",
+                stringify!($expression),
+                message,
+                $expected
+            ),
+        }
+    };
+}
+
+struct TestRun {
+    // Safety: test_runner never returns, so the slice it was passed outlives the run
+    tests: *const [&'static dyn Testable],
+    current: usize,
+    passed: usize,
+    ignored: usize,
+    filtered: usize,
+}
+
+// Raw pointers aren't Send, but there's only ever one test run and it's never shared
+unsafe impl Send for TestRun {}
+
+static TEST_RUN: Mutex<Option<TestRun>> = Mutex::new(None);
+
+struct SerialFilter {
+    buffer: [u8; MAX_FILTER_LENGTH],
+    len: usize,
+}
+
+static SERIAL_FILTER: Once<SerialFilter> = Once::new();
+
+// Read a `test=...` line if the host already sent one. Never blocks waiting for input.
+fn read_serial_filter() -> &'static str {
+    let filter = SERIAL_FILTER.call_once(|| {
+        let mut filter = SerialFilter {
+            buffer: [0; MAX_FILTER_LENGTH],
+            len: 0,
+        };
+        let mut serial = serial::SERIAL1.lock();
+        while let Some(byte) = serial.try_read_byte() {
+            match byte {
+                b'\n' | b'\r' => break,
+                _ if filter.len < MAX_FILTER_LENGTH => {
+                    filter.buffer[filter.len] = byte;
+                    filter.len += 1;
+                }
+                _ => (),
+            }
+        }
+        filter
+    });
+    core::str::from_utf8(&filter.buffer[..filter.len]).unwrap_or("")
+}
+
+// A file passed in with `-fw_cfg name=opt/sos/<name>,...`, or None if it wasn't (or we're not
+// running under QEMU)
+pub fn fixture(name: &str) -> Option<Vec<u8>> {
+    crate::drivers::fwcfg::read_file(&format!("opt/sos/{}", name))
+}
+
+// Simple glob matching, `*` matches any (possibly empty) sequence of characters
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let name = match name.strip_prefix(prefix) {
+                Some(name) => name,
+                None => return false,
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| glob_matches(rest, &name[i..]))
+        }
+    }
+}
+
+fn filter_matches(filter: &str, test: &dyn Testable) -> bool {
+    let name = test.name();
+    // Test names are module paths; allow filters to leave off the crate name
+    let short_name = name.split_once("::").map_or(name, |(_, rest)| rest);
+    let matches_name =
+        |pattern: &str| glob_matches(pattern, name) || glob_matches(pattern, short_name);
+    filter
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .any(|item| match item.split_once('=') {
+            Some(("tag", tag)) => test.tags().contains(&tag),
+            Some(("test", pattern)) => matches_name(pattern),
+            _ => matches_name(item),
+        })
+}
+
+fn should_run(test: &dyn Testable) -> bool {
+    [BUILD_TIME_FILTER.unwrap_or(""), read_serial_filter()]
+        .iter()
+        .filter(|filter| !filter.trim().is_empty())
+        .all(|filter| filter_matches(filter, test))
+}
+
+// Deadline tick of the test currently running
+static TEST_DEADLINE: Mutex<Option<u64>> = Mutex::new(None);
+
+fn arm_test_timeout() {
+    without_interrupt! {{
+        *TEST_DEADLINE.lock() = Some(time::jiffies() + time::duration_to_ticks(TEST_TIMEOUT));
+    }}
+}
+
+fn disarm_test_timeout() {
+    without_interrupt! {{
+        *TEST_DEADLINE.lock() = None;
+    }}
+}
+
+// Called from the timer tick. A hung test may well be holding the SERIAL1 lock, so report
+// the timeout without it.
+pub(crate) fn check_test_timeout(now: u64) {
+    let deadline = match TEST_DEADLINE.try_lock() {
+        Some(deadline) => *deadline,
+        None => return,
+    };
+    match deadline {
+        Some(deadline) if now > deadline => {
+            report::timed_out(TEST_TIMEOUT);
+            test_runner_exit(QemuExitStatus::Failed);
+        }
+        _ => (),
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+    report::started(tests.len());
+    time::tick::register("test_timeout", 1, check_test_timeout);
+    // Safety: we never return, so `tests` outlives every use of it
+    let tests: &'static [&'static dyn Testable] = unsafe { core::mem::transmute(tests) };
+    *TEST_RUN.lock() = Some(TestRun {
+        tests,
+        current: 0,
+        passed: 0,
+        ignored: 0,
+        filtered: 0,
+    });
+    run_remaining_tests();
+}
+
+// Runs tests starting from TEST_RUN.current
+fn run_remaining_tests() -> ! {
+    loop {
+        let test = {
+            let mut run = TEST_RUN.lock();
+            let run = run.as_mut().expect("No test run in progress");
+            let tests = unsafe { &*run.tests };
+            match tests.get(run.current) {
+                Some(test) => *test,
+                None => break,
+            }
+        };
+        let outcome = run_test(test);
+        let mut run = TEST_RUN.lock();
+        let run = run.as_mut().unwrap();
+        match outcome {
+            Outcome::Passed => run.passed += 1,
+            Outcome::Ignored => run.ignored += 1,
+            Outcome::Filtered => run.filtered += 1,
+        }
+        run.current += 1;
+    }
+    let run = TEST_RUN.lock();
+    let run = run.as_ref().unwrap();
+    report::finished(run.passed, run.ignored, run.filtered);
+    test_runner_exit(QemuExitStatus::Success);
+}
+
+enum Outcome {
+    Passed,
+    Ignored,
+    Filtered,
+}
+
+fn run_test(test: &dyn Testable) -> Outcome {
+    if !should_run(test) {
+        return Outcome::Filtered;
+    }
+    report::test_started(test.name());
+    if test.ignored() {
+        report::ignored();
+        return Outcome::Ignored;
+    }
+    let heap_before = crate::memory::stats().heap.map_or(0, |heap| heap.used);
+    arm_test_timeout();
+    if test.should_panic() {
+        let outcome = panicking::catch_panic(|| test.run());
+        disarm_test_timeout();
+        match (outcome, test.expected_panic()) {
+            (Ok(()), _) => {
+                report::failed(format_args!("test did not panic"), None);
+                test_runner_exit(QemuExitStatus::Failed);
+            }
+            (Err(message), Some(expected)) if !message.as_str().contains(expected) => {
+                report::failed(
+                    format_args!("{}\nexpected a panic containing {:?}", message, expected),
+                    None,
+                );
+                test_runner_exit(QemuExitStatus::Failed);
+            }
+            (Err(_), _) => (),
+        }
+    } else {
+        test.run();
+        disarm_test_timeout();
+    }
+    if cfg!(feature = "debug-alloc") {
+        let heap_report = crate::memory::allocator::validate();
+        if !heap_report.is_ok() {
+            report::failed(
+                format_args!("heap inconsistent after the test\n{}", heap_report),
+                None,
+            );
+            test_runner_exit(QemuExitStatus::Failed);
+        }
+    }
+    let heap_after = crate::memory::stats().heap.map_or(0, |heap| heap.used);
+    report::passed(heap_after as isize - heap_before as isize);
+    Outcome::Passed
+}
+
+// For harness = false test binaries
+pub fn single_test_started(name: &'static str) {
+    report::started(1);
+    report::test_started(name);
+}
+
+pub fn single_test_passed() -> ! {
+    report::passed(0);
+    report::finished(1, 0, 0);
+    test_runner_exit(QemuExitStatus::Success);
+}
+
+pub fn single_test_failed(message: fmt::Arguments) -> ! {
+    report::failed(message, None);
+    test_runner_exit(QemuExitStatus::Failed);
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    if panicking::enter() == Nesting::Nested {
+        panicking::emergency(info);
+    }
+    // Expected panics go back to the catch_panic in run_test (or a test's own)
+    panicking::resume_catch(info);
+    disarm_test_timeout();
+    report::failed(format_args!("{}", info), Some(&Backtrace::capture()));
+    crate::console::screenshot();
+    test_runner_exit(QemuExitStatus::Failed);
+}
+
+pub fn test_runner_exit(status: QemuExitStatus) -> ! {
+    power::exit_qemu(status);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_glob_matches() {
+        assert!(glob_matches(
+            "memory::*",
+            "memory::test::test_page_not_present"
+        ));
+        assert!(glob_matches(
+            "*page*",
+            "memory::test::test_page_not_present"
+        ));
+        assert!(glob_matches("exact", "exact"));
+        assert!(!glob_matches("exact", "exactly"));
+        assert!(!glob_matches("memory::*", "serial::test"));
+        assert!(glob_matches("*", ""));
+    }
+
+    crate::kernel_test! {
+        #[should_panic]
+        fn test_should_panic() {
+            assert_eq!(0, 1);
+        }
+    }
+
+    crate::kernel_test! {
+        #[should_panic(expected = "out of cheese")]
+        fn test_should_panic_expected() {
+            panic!("{} of cheese", "out");
+        }
+    }
+
+    #[test_case]
+    fn test_assert_panics() {
+        assert_panics!(panic!("boom"));
+        assert_panics!(Option::<u8>::None.unwrap(), "on a `None` value");
+        assert_panics!(assert_panics!(()));
+    }
+
+    crate::kernel_test! {
+        #[ignore]
+        #[tags(example)]
+        fn test_ignored() {
+            panic!("ignored tests should never run");
+        }
+    }
+}
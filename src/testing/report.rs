@@ -0,0 +1,223 @@
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use super::bench::Stats;
+use crate::backtrace::Backtrace;
+use crate::{serial, time};
+
+// How test results get printed. By default it's for people: `name...\t[ok]`, and an error and
+// backtrace on failure. Building with SOS_TEST_FORMAT=json prints one JSON object per line
+// instead, for a host-side harness to collect across test binaries:
+//     {"event":"started","tests":12}
+//     {"event":"test","name":"sos::time::test::test_uptime","result":"ok","duration_us":153,"heap_bytes":0}
+//     {"event":"test","name":"sos::testing::test::test_ignored","result":"ignored"}
+//     {"event":"test","name":"...","result":"failed","duration_us":20,"message":"...","backtrace":"..."}
+//     {"event":"bench","name":"...","iterations":1000,"outliers":9,"min_cycles":80,"median_cycles":84,"mean_cycles":85,"max_cycles":130}
+//     {"event":"finished","passed":11,"ignored":1,"filtered":0}
+// A failing run exits at the failing test, so it has no finished event. Anything else on the
+// serial port (boot logs, screenshots) doesn't start with `{"event":`, so it's easy to skip.
+// Durations come from the TSC, so they're finer than the tick. Benchmarks (see testing::bench)
+// report their stats before their test result.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+pub fn format() -> Format {
+    match option_env!("SOS_TEST_FORMAT") {
+        Some("json") => Format::Json,
+        _ => Format::Human,
+    }
+}
+
+// (name, start) of the test currently running, so failures can say which it was and how long
+// it ran for
+static CURRENT: Mutex<Option<(&'static str, Duration)>> = Mutex::new(None);
+
+// Lock-free while panicking, since whoever holds SERIAL1 may be the one who panicked
+fn emit(args: fmt::Arguments) {
+    if crate::panicking::is_panicking() {
+        serial::force_print(args);
+    } else {
+        crate::serial_print!("{}", args);
+    }
+}
+
+// Quoted and escaped as a JSON string
+pub struct Json<T>(pub T);
+
+struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl fmt::Write for Escaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Json<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        write!(Escaper(f), "{}", self.0)?;
+        f.write_char('"')
+    }
+}
+
+pub fn started(tests: usize) {
+    match format() {
+        Format::Human => emit(format_args!("Running {} tests\n", tests)),
+        Format::Json => emit(format_args!(
+            "{{\"event\":\"started\",\"tests\":{}}}\n",
+            tests
+        )),
+    }
+}
+
+pub fn test_started(name: &'static str) {
+    *CURRENT.lock() = Some((name, time::precise_uptime()));
+    if format() == Format::Human {
+        emit(format_args!("{}...\t", name));
+    }
+}
+
+fn test_finished() -> Option<(&'static str, Duration)> {
+    // The lock may be held by whoever panicked
+    let (name, start) = CURRENT.try_lock()?.take()?;
+    Some((name, time::precise_uptime().saturating_sub(start)))
+}
+
+pub fn ignored() {
+    let name = test_finished().map_or("", |(name, _)| name);
+    match format() {
+        Format::Human => emit(format_args!("[ignored]\n")),
+        Format::Json => emit(format_args!(
+            "{{\"event\":\"test\",\"name\":{},\"result\":\"ignored\"}}\n",
+            Json(name)
+        )),
+    }
+}
+
+// `heap_bytes` is how much more heap is in use than before the test
+pub fn passed(heap_bytes: isize) {
+    let (name, duration) = test_finished().unwrap_or_default();
+    match format() {
+        Format::Human if cfg!(feature = "tests-verbose") => emit(format_args!(
+            "[ok] {}us, heap {:+} bytes\n",
+            duration.as_micros(),
+            heap_bytes
+        )),
+        Format::Human => emit(format_args!("[ok]\n")),
+        Format::Json => emit(format_args!(
+            "{{\"event\":\"test\",\"name\":{},\"result\":\"ok\",\"duration_us\":{},\"heap_bytes\":{}}}\n",
+            Json(name),
+            duration.as_micros(),
+            heap_bytes
+        )),
+    }
+}
+
+// A benchmark's stats, from the test running it
+pub fn bench(stats: &Stats) {
+    let name = CURRENT.lock().map_or("", |(name, _)| name);
+    match format() {
+        Format::Human => emit(format_args!(
+            "{} iterations: median {} cycles (min {}, mean {}, max {}), {} outliers\t",
+            stats.iterations, stats.median, stats.min, stats.mean, stats.max, stats.outliers
+        )),
+        Format::Json => emit(format_args!(
+            "{{\"event\":\"bench\",\"name\":{},\"iterations\":{},\"outliers\":{},\"min_cycles\":{},\"median_cycles\":{},\"mean_cycles\":{},\"max_cycles\":{}}}\n",
+            Json(name),
+            stats.iterations,
+            stats.outliers,
+            stats.min,
+            stats.median,
+            stats.mean,
+            stats.max
+        )),
+    }
+}
+
+pub fn failed(message: fmt::Arguments, backtrace: Option<&Backtrace>) {
+    let (name, duration) = test_finished().unwrap_or_default();
+    match (format(), backtrace) {
+        (Format::Human, backtrace) => {
+            emit(format_args!("[failed]\n\nError: {}\n\n", message));
+            if let Some(backtrace) = backtrace {
+                emit(format_args!("{}\n\n", backtrace));
+            }
+        }
+        (Format::Json, None) => emit(format_args!(
+            "{{\"event\":\"test\",\"name\":{},\"result\":\"failed\",\"duration_us\":{},\"message\":{}}}\n",
+            Json(name),
+            duration.as_micros(),
+            Json(message)
+        )),
+        (Format::Json, Some(backtrace)) => emit(format_args!(
+            "{{\"event\":\"test\",\"name\":{},\"result\":\"failed\",\"duration_us\":{},\"message\":{},\"backtrace\":{}}}\n",
+            Json(name),
+            duration.as_micros(),
+            Json(message),
+            Json(backtrace)
+        )),
+    }
+}
+
+// From the timer tick, while the test is still running. A hung test may well be holding the
+// SERIAL1 lock, so this never takes it.
+pub fn timed_out(timeout: Duration) {
+    let (name, duration) = test_finished().unwrap_or_default();
+    match format() {
+        Format::Human => serial::force_print(format_args!(
+            "[timeout]\n\nError: {} exceeded {:?}\n",
+            name, timeout
+        )),
+        Format::Json => serial::force_print(format_args!(
+            "{{\"event\":\"test\",\"name\":{},\"result\":\"timeout\",\"duration_us\":{}}}\n",
+            Json(name),
+            duration.as_micros()
+        )),
+    }
+}
+
+pub fn finished(passed: usize, ignored: usize, filtered: usize) {
+    match format() {
+        Format::Human => emit(format_args!(
+            "{} passed; {} ignored; {} filtered out\n",
+            passed, ignored, filtered
+        )),
+        Format::Json => emit(format_args!(
+            "{{\"event\":\"finished\",\"passed\":{},\"ignored\":{},\"filtered\":{}}}\n",
+            passed, ignored, filtered
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn test_json_escapes() {
+        assert_eq!(format!("{}", Json("plain")), r#""plain""#);
+        assert_eq!(
+            format!("{}", Json("say \"hi\"\n\tC:\\ \x07")),
+            r#""say \"hi\"\n\tC:\\ \u0007""#
+        );
+        assert_eq!(format!("{}", Json(format_args!("{}+{}", 1, 2))), r#""1+2""#);
+    }
+}
@@ -0,0 +1,68 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::port::WriteOnlyPort;
+use crate::drivers::pit;
+
+use super::vdso::rdtsc;
+
+// Busy waits of a few microseconds, for hardware that needs a moment between accesses. The TSC
+// is calibrated against PIT channel 2 early in boot, before anything needs to wait; until then
+// (or if calibration failed) each microsecond is a write to port 0x80, the POST code port,
+// which traditionally takes about that long on the ISA bus.
+// TODO: an invariant TSC check; on CPUs without one the TSC rate changes with power states.
+
+const CALIBRATION_MILLIS: u32 = 10;
+const POST_CODE_PORT: WriteOnlyPort<u8> = WriteOnlyPort::new(0x80);
+
+// 0 until calibrated
+static TSC_PER_MICROSECOND: AtomicU64 = AtomicU64::new(0);
+
+pub fn calibrate() {
+    let tsc_per_second = pit::measure_tsc_frequency(CALIBRATION_MILLIS);
+    TSC_PER_MICROSECOND.store(tsc_per_second / 1_000_000, Ordering::Relaxed);
+    crate::println!("TSC: {} MHz", tsc_per_second / 1_000_000);
+}
+
+// TSC cycles per second, if it's been calibrated
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_PER_MICROSECOND.load(Ordering::Relaxed) {
+        0 => None,
+        per_microsecond => Some(per_microsecond * 1_000_000),
+    }
+}
+
+pub fn delay_us(micros: u64) {
+    match TSC_PER_MICROSECOND.load(Ordering::Relaxed) {
+        0 => {
+            for _ in 0..micros {
+                unsafe { POST_CODE_PORT.write(0) };
+            }
+        }
+        per_microsecond => {
+            let deadline = rdtsc() + micros * per_microsecond;
+            while rdtsc() < deadline {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+// The classic "io_wait" between accesses to slow devices, eg. the PIC during initialization
+pub fn io_wait() {
+    delay_us(1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_delay_us() {
+        assert!(tsc_frequency().is_some());
+        let start = crate::time::precise_uptime();
+        delay_us(2_000);
+        let elapsed = crate::time::precise_uptime() - start;
+        // The uptime clock interpolates with the TSC too, but from the tick's own measurement
+        assert!(elapsed.as_micros() >= 1_000, "delayed {:?}", elapsed);
+    }
+}
@@ -0,0 +1,32 @@
+use core::time::Duration;
+
+use crate::drivers::pit;
+
+pub mod delay;
+pub mod tick;
+pub mod vdso;
+
+pub use delay::delay_us;
+pub use tick::jiffies;
+
+// The tick rate, as programmed into the PIT
+pub fn ticks_per_second() -> u64 {
+    pit::frequency() as u64
+}
+
+// The smallest number of ticks lasting at least `duration` at the current tick rate
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = pit::period_nanos() as u128;
+    ((duration.as_nanos() + nanos_per_tick - 1) / nanos_per_tick) as u64
+}
+
+// Time since the timer started ticking. Tracked separately from jiffies so that it stays
+// correct across changes to the tick rate.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(tick::uptime_nanos())
+}
+
+// Uptime interpolated with the TSC between ticks, for timing things shorter than a tick
+pub fn precise_uptime() -> Duration {
+    vdso::page().snapshot().uptime_at(vdso::rdtsc())
+}
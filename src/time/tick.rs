@@ -0,0 +1,376 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::drivers::pit;
+
+// The timer tick. The timer interrupt calls `hook` exactly once per tick, which advances the
+// jiffies counter and runs any periodic callbacks (watchdog, scheduler preemption, ...) that are
+// due. Callbacks run in interrupt context with interrupts disabled, so keep them short, and they
+// must not block on locks that non-interrupt code might be holding.
+//
+// In tickless mode the tick stops while the CPU idles: the idle task arms a one-shot timer for
+// the earliest deadline of the callbacks that can't wait, and when anything wakes it the jiffies
+// skipped are caught up in one go. Deferrable callbacks (statistics, the watchdog, ...) don't
+// hold the tick up, and just run late. Needs a one-shot capable timer (the PIT's, see
+// drivers::pit), and falls back to the periodic tick without one.
+// TODO: the local APIC timer, once there's a local APIC driver. The PIT can only count to ~55ms,
+// so idling on it wakes up every few ticks anyway.
+
+const MAX_CALLBACKS: usize = 16;
+// The longest the tick stays stopped, so deferrable callbacks still run now and then
+const MAX_IDLE_SECONDS: u64 = 10;
+
+// Called with the current jiffies
+pub type TickFn = fn(u64);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    name: &'static str,
+    period: u64,
+    next_due: u64,
+    callback: TickFn,
+    // Fine to run late while the CPU idles
+    deferrable: bool,
+}
+
+// A timer that can interrupt once, after some number of ticks, rather than every tick
+#[derive(Clone, Copy)]
+pub struct OneShotTimer {
+    // Interrupt once, `ticks` ticks from now, instead of every tick
+    pub arm: fn(u64),
+    // Go back to interrupting every tick, returning the whole ticks elapsed since `arm`
+    pub stop: fn() -> u64,
+}
+
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// Fixed size so that ticking never allocates
+static CALLBACKS: Mutex<[Option<Callback>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+
+static ONE_SHOT: Mutex<Option<OneShotTimer>> = Mutex::new(None);
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+// The tick is stopped, with a one-shot timer armed
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+// Timer ticks since boot, monotonic
+#[inline]
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn uptime_nanos() -> u64 {
+    UPTIME_NANOS.load(Ordering::Relaxed)
+}
+
+// Call `callback` every `period` ticks, starting `period` ticks from now. Registering an existing
+// name replaces it.
+pub fn register(name: &'static str, period: u64, callback: TickFn) {
+    insert(name, period, callback, false);
+}
+
+// As register, for callbacks that can wait while the CPU idles, so don't keep the tick going in
+// tickless mode. They run once the tick restarts.
+pub fn register_deferrable(name: &'static str, period: u64, callback: TickFn) {
+    insert(name, period, callback, true);
+}
+
+fn insert(name: &'static str, period: u64, callback: TickFn, deferrable: bool) {
+    assert!(period > 0, "Tick callback {} needs a nonzero period", name);
+    crate::without_interrupt! {{
+        let mut callbacks = CALLBACKS.lock();
+        let entry = Callback {
+            name,
+            period,
+            next_due: jiffies() + period,
+            callback,
+            deferrable,
+        };
+        let index = callbacks
+            .iter()
+            .position(|c| matches!(c, Some(c) if c.name == name))
+            .or_else(|| callbacks.iter().position(|c| c.is_none()));
+        match index {
+            Some(index) => callbacks[index] = Some(entry),
+            None => panic!("Too many tick callbacks registering {}", name),
+        }
+    }}
+}
+
+pub fn unregister(name: &'static str) {
+    crate::without_interrupt! {{
+        CALLBACKS
+            .lock()
+            .iter_mut()
+            .filter(|c| matches!(c, Some(c) if c.name == name))
+            .for_each(|c| *c = None);
+    }}
+}
+
+fn run_callbacks(now: u64) {
+    // Someone is mid-(un)register; due callbacks will catch up on the next tick
+    let due = match CALLBACKS.try_lock() {
+        Some(mut callbacks) => {
+            let mut due = [None; MAX_CALLBACKS];
+            for (slot, callback) in due.iter_mut().zip(callbacks.iter_mut().flatten()) {
+                if now >= callback.next_due {
+                    callback.next_due = now + callback.period;
+                    *slot = Some(callback.callback);
+                }
+            }
+            due
+        }
+        None => return,
+    };
+    // Called without the lock held, so callbacks may (un)register
+    for callback in due.iter().flatten() {
+        callback(now);
+    }
+}
+
+// The earliest tick a callback that can't be deferred is due at
+pub fn next_deadline() -> Option<u64> {
+    crate::without_interrupt! {{
+        CALLBACKS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|c| !c.deferrable)
+            .map(|c| c.next_due)
+            .min()
+    }}
+}
+
+// For a one-shot capable timer's driver. Replaces any there was.
+pub fn set_one_shot_timer(timer: OneShotTimer) {
+    crate::without_interrupt! {{
+        *ONE_SHOT.lock() = Some(timer);
+    }}
+}
+
+// Ask for tickless mode (or not), returning whether the tick will stop when idle. Without a
+// one-shot timer it won't until one is set.
+pub fn set_tickless(enabled: bool) -> bool {
+    TICKLESS.store(enabled, Ordering::Relaxed);
+    tickless()
+}
+
+pub fn tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed) && crate::without_interrupt! {{ ONE_SHOT.lock().is_some() }}
+}
+
+// Called by the idle task, with interrupts disabled, just before halting. Stops the tick until
+// the next deadline if in tickless mode and that's more than a tick away, returning whether it
+// did.
+pub(crate) fn stop_for_idle() -> bool {
+    if !TICKLESS.load(Ordering::Relaxed) {
+        return false;
+    }
+    let timer = match *ONE_SHOT.lock() {
+        Some(timer) => timer,
+        None => return false,
+    };
+    let longest = MAX_IDLE_SECONDS * super::ticks_per_second();
+    let ticks = match next_deadline() {
+        Some(deadline) => deadline.saturating_sub(jiffies()).min(longest),
+        None => longest,
+    };
+    if ticks <= 1 {
+        return false;
+    }
+    (timer.arm)(ticks);
+    STOPPED.store(true, Ordering::Relaxed);
+    true
+}
+
+// Called by the idle task once it's woken. If the tick was stopped, and wasn't restarted by the
+// one-shot timer going off, restart it and catch up.
+pub(crate) fn restart_after_idle() {
+    crate::without_interrupt! {{
+        if let Some(elapsed) = restart() {
+            let now = advance(elapsed);
+            run_callbacks(now);
+        }
+    }}
+}
+
+// Back to ticking periodically, returning the ticks skipped, if the tick was stopped
+fn restart() -> Option<u64> {
+    if !STOPPED.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    let timer = (*ONE_SHOT.lock())?;
+    let elapsed = (timer.stop)();
+    // Nobody could pet the watchdog while the tick was stopped, and nobody needed to
+    crate::watchdog::touch_all(jiffies() + elapsed);
+    Some(elapsed)
+}
+
+fn advance(ticks: u64) -> u64 {
+    let period = pit::period_nanos();
+    let uptime_nanos = UPTIME_NANOS.fetch_add(period * ticks, Ordering::Relaxed) + period * ticks;
+    let now = JIFFIES.fetch_add(ticks, Ordering::Relaxed) + ticks;
+    super::vdso::update(now, uptime_nanos);
+    now
+}
+
+// Called from the timer interrupt, once per tick, or when the one-shot timer goes off
+pub(crate) fn hook() {
+    let now = advance(restart().unwrap_or(1));
+    run_callbacks(now);
+}
+
+// Run `f` in tickless mode with `timer` standing in for the one-shot timer, and only deferrable
+// callbacks registered (so nothing holds the tick up). Interrupts are disabled throughout.
+#[cfg(test)]
+pub(crate) fn with_one_shot_timer(timer: OneShotTimer, f: impl FnOnce()) {
+    crate::without_interrupt! {{
+        let held = {
+            let mut callbacks = CALLBACKS.lock();
+            let mut held = [None; MAX_CALLBACKS];
+            for (slot, callback) in held.iter_mut().zip(callbacks.iter_mut()) {
+                if matches!(callback, Some(c) if !c.deferrable) {
+                    *slot = callback.take();
+                }
+            }
+            held
+        };
+        let one_shot = ONE_SHOT.lock().replace(timer);
+        let tickless = TICKLESS.swap(true, Ordering::Relaxed);
+        f();
+        TICKLESS.store(tickless, Ordering::Relaxed);
+        *ONE_SHOT.lock() = one_shot;
+        let mut callbacks = CALLBACKS.lock();
+        for callback in held.into_iter().flatten() {
+            if let Some(slot) = callbacks.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(callback);
+            }
+        }
+    }}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_calls(_: u64) {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_periodic_callback() {
+        // Keep the real timer from running our callback underneath us
+        crate::without_interrupt! {{
+            CALLS.store(0, Ordering::Relaxed);
+            let start = jiffies();
+            register("test_periodic", 3, count_calls);
+            run_callbacks(start + 1);
+            run_callbacks(start + 2);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+            run_callbacks(start + 3);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            run_callbacks(start + 4);
+            run_callbacks(start + 6);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+            unregister("test_periodic");
+            run_callbacks(start + 9);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+        }}
+    }
+
+    #[test_case]
+    fn test_deferrable_callbacks_dont_hold_the_tick() {
+        crate::without_interrupt! {{
+            let before = next_deadline();
+            register_deferrable("test_deferrable", 1, count_calls);
+            assert_eq!(next_deadline(), before);
+            register("test_deferrable", 1, count_calls);
+            assert!(next_deadline().unwrap() <= jiffies() + 1);
+            unregister("test_deferrable");
+        }}
+    }
+
+    #[test_case]
+    fn test_periodic_without_one_shot_timer() {
+        crate::without_interrupt! {{
+            let one_shot = ONE_SHOT.lock().take();
+            let tickless = TICKLESS.load(Ordering::Relaxed);
+            assert!(!set_tickless(true));
+            assert!(!stop_for_idle());
+            set_tickless(tickless);
+            *ONE_SHOT.lock() = one_shot;
+        }}
+    }
+
+    static ARMED: AtomicU64 = AtomicU64::new(0);
+    static ELAPSED: AtomicU64 = AtomicU64::new(0);
+
+    const FAKE_TIMER: OneShotTimer = OneShotTimer {
+        arm: |ticks| ARMED.store(ticks, Ordering::Relaxed),
+        stop: || ELAPSED.load(Ordering::Relaxed),
+    };
+
+    #[test_case]
+    fn test_stop_until_next_deadline() {
+        with_one_shot_timer(FAKE_TIMER, || {
+            let longest = MAX_IDLE_SECONDS * crate::time::ticks_per_second();
+            assert!(stop_for_idle());
+            assert_eq!(ARMED.load(Ordering::Relaxed), longest);
+            ELAPSED.store(0, Ordering::Relaxed);
+            assert_eq!(restart(), Some(0));
+            // Only until the next callback that can't wait
+            register("test_deadline", 7, count_calls);
+            assert!(stop_for_idle());
+            assert_eq!(ARMED.load(Ordering::Relaxed), 7);
+            assert_eq!(restart(), Some(0));
+            // Not worth stopping for a tick
+            register("test_deadline", 1, count_calls);
+            assert!(!stop_for_idle());
+            unregister("test_deadline");
+            // And not at all outside tickless mode
+            set_tickless(false);
+            assert!(!stop_for_idle());
+        });
+        assert_eq!(restart(), None);
+    }
+
+    #[test_case]
+    fn test_restart_catches_up() {
+        with_one_shot_timer(FAKE_TIMER, || {
+            CALLS.store(0, Ordering::Relaxed);
+            let start = jiffies();
+            register("test_catch_up", 10, count_calls);
+            assert!(stop_for_idle());
+            // Woken by something else after 4 ticks
+            ELAPSED.store(4, Ordering::Relaxed);
+            restart_after_idle();
+            assert_eq!(jiffies(), start + 4);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+            // The one-shot timer going off at the deadline
+            assert!(stop_for_idle());
+            assert_eq!(ARMED.load(Ordering::Relaxed), 6);
+            ELAPSED.store(6, Ordering::Relaxed);
+            hook();
+            assert_eq!(jiffies(), start + 10);
+            assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            // Nothing to catch up once it's ticking again
+            restart_after_idle();
+            assert_eq!(jiffies(), start + 10);
+            unregister("test_catch_up");
+        });
+    }
+
+    #[test_case]
+    fn test_uptime_advances() {
+        let before = crate::time::uptime();
+        let start = jiffies();
+        while jiffies() < start + 2 {
+            core::hint::spin_loop();
+        }
+        assert!(crate::time::uptime() > before);
+    }
+}
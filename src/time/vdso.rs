@@ -0,0 +1,157 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::memory;
+
+// A page of timekeeping state, rewritten by the timer every tick and mapped read-only into user
+// programs (at task::process::TIME_PAGE) so they can tell the time without a syscall (like
+// Linux's vDSO data page).
+// Between ticks, readers interpolate with the TSC, using how far it moved over the last tick.
+//
+// Updates are a seqlock: the sequence is odd while the timer is writing, so a reader retries
+// if it was odd or changed while it read. Nothing else shares the page, so mapping it hands out
+// nothing but the time.
+// TODO: there's no RTC driver yet, so there's no wall clock to publish
+
+#[repr(C, align(4096))]
+pub struct TimePage {
+    sequence: AtomicU64,
+    ticks_per_second: AtomicU64,
+    jiffies: AtomicU64,
+    uptime_nanos: AtomicU64,
+    // TSC at the last tick, and how many cycles the tick before it took
+    tsc: AtomicU64,
+    tsc_per_tick: AtomicU64,
+}
+
+// A consistent copy of the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub ticks_per_second: u64,
+    pub jiffies: u64,
+    pub uptime_nanos: u64,
+    pub tsc: u64,
+    pub tsc_per_tick: u64,
+}
+
+static PAGE: TimePage = TimePage {
+    sequence: AtomicU64::new(0),
+    ticks_per_second: AtomicU64::new(0),
+    jiffies: AtomicU64::new(0),
+    uptime_nanos: AtomicU64::new(0),
+    tsc: AtomicU64::new(0),
+    tsc_per_tick: AtomicU64::new(0),
+};
+
+pub fn page() -> &'static TimePage {
+    &PAGE
+}
+
+// Physical address of the page, for mapping it elsewhere
+pub fn frame() -> usize {
+    memory::translate_virtual_address(&PAGE as *const TimePage as usize)
+        .expect("The time page is in the kernel image, which is mapped")
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+impl TimePage {
+    // What user programs will do; spins while an update is in progress
+    pub fn snapshot(&self) -> Snapshot {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let snapshot = Snapshot {
+                ticks_per_second: self.ticks_per_second.load(Ordering::Relaxed),
+                jiffies: self.jiffies.load(Ordering::Relaxed),
+                uptime_nanos: self.uptime_nanos.load(Ordering::Relaxed),
+                tsc: self.tsc.load(Ordering::Relaxed),
+                tsc_per_tick: self.tsc_per_tick.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return snapshot;
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    // Uptime when the TSC read `tsc`, at the last tick's resolution if the TSC can't be trusted
+    // (it hasn't been calibrated yet, or went backwards, eg. read on another CPU)
+    pub fn uptime_at(&self, tsc: u64) -> Duration {
+        let mut nanos = self.uptime_nanos;
+        if self.tsc_per_tick > 0 && self.ticks_per_second > 0 && tsc >= self.tsc {
+            let nanos_per_tick = 1_000_000_000 / self.ticks_per_second;
+            // Never past the next tick, which would make time go backwards once it lands
+            let elapsed = (tsc - self.tsc).min(self.tsc_per_tick);
+            nanos += (elapsed as u128 * nanos_per_tick as u128 / self.tsc_per_tick as u128) as u64;
+        }
+        Duration::from_nanos(nanos)
+    }
+}
+
+// Called from the tick hook, with interrupts disabled
+pub(super) fn update(jiffies: u64, uptime_nanos: u64) {
+    let tsc = rdtsc();
+    let sequence = PAGE.sequence.load(Ordering::Relaxed);
+    PAGE.sequence.store(sequence + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    let last_tsc = PAGE.tsc.load(Ordering::Relaxed);
+    if last_tsc != 0 && tsc > last_tsc {
+        PAGE.tsc_per_tick.store(tsc - last_tsc, Ordering::Relaxed);
+    }
+    PAGE.ticks_per_second
+        .store(super::ticks_per_second(), Ordering::Relaxed);
+    PAGE.jiffies.store(jiffies, Ordering::Relaxed);
+    PAGE.uptime_nanos.store(uptime_nanos, Ordering::Relaxed);
+    PAGE.tsc.store(tsc, Ordering::Relaxed);
+    PAGE.sequence.store(sequence + 2, Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_snapshot_follows_ticks() {
+        let start = crate::time::jiffies();
+        while crate::time::jiffies() < start + 2 {
+            core::hint::spin_loop();
+        }
+        let snapshot = page().snapshot();
+        assert!(snapshot.jiffies >= start + 2);
+        assert!(snapshot.tsc_per_tick > 0);
+        assert_eq!(snapshot.ticks_per_second, crate::time::ticks_per_second());
+        let now = snapshot.uptime_at(rdtsc());
+        assert!(now >= Duration::from_nanos(snapshot.uptime_nanos));
+    }
+
+    #[test_case]
+    fn test_uptime_interpolates() {
+        let snapshot = Snapshot {
+            ticks_per_second: 1000,
+            jiffies: 5,
+            uptime_nanos: 5_000_000,
+            tsc: 1_000,
+            tsc_per_tick: 100,
+        };
+        assert_eq!(snapshot.uptime_at(1_050), Duration::from_nanos(5_500_000));
+        // Capped at the next tick
+        assert_eq!(snapshot.uptime_at(5_000), Duration::from_nanos(6_000_000));
+        // Before the last tick
+        assert_eq!(snapshot.uptime_at(500), Duration::from_nanos(5_000_000));
+    }
+
+    #[test_case]
+    fn test_page_is_alone() {
+        assert_eq!(core::mem::size_of::<TimePage>(), memory::PAGE_SIZE);
+        assert_eq!(frame() % memory::PAGE_SIZE, 0);
+    }
+}
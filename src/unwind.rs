@@ -0,0 +1,677 @@
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::memory;
+
+// Stack unwinding from the .eh_frame call frame information the compiler emits for every function
+// (we build with `-C force-unwind-tables=yes`, see .cargo/config.toml). Unlike walking the frame
+// pointer chain this works for code built without frame pointers, eg. optimized release builds.
+//
+// Only as much of DWARF CFI as rustc actually emits for x86_64 is supported: CFA rules relative
+// to rsp or rbp, and registers saved at an offset from the CFA. Anything fancier (expressions,
+// register-to-register rules) just ends the unwind.
+// Reference: https://refspecs.linuxfoundation.org/LSB_5.0.0/LSB-Core-generic/LSB-Core-generic/ehframechpt.html
+// and section 6.4 of the DWARF 5 standard.
+
+// DWARF register numbers
+const RBP: u16 = 6;
+const RSP: u16 = 7;
+
+// Nesting limit for DW_CFA_remember_state
+const MAX_REMEMBERED: usize = 4;
+
+// Pointer encodings, DW_EH_PE_*
+const PE_ABSPTR: u8 = 0x00;
+const PE_ULEB128: u8 = 0x01;
+const PE_UDATA2: u8 = 0x02;
+const PE_UDATA4: u8 = 0x03;
+const PE_UDATA8: u8 = 0x04;
+const PE_SLEB128: u8 = 0x09;
+const PE_SDATA2: u8 = 0x0a;
+const PE_SDATA4: u8 = 0x0b;
+const PE_SDATA8: u8 = 0x0c;
+const PE_PCREL: u8 = 0x10;
+
+#[derive(Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    // Address data[0] is loaded at, for pc-relative pointers
+    base: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let (mut result, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let (mut result, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+
+    // A nul-terminated string, without the nul
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let length = self
+            .data
+            .get(self.position..)?
+            .iter()
+            .position(|&b| b == 0)?;
+        let string = self.bytes(length)?;
+        self.position += 1;
+        Some(string)
+    }
+
+    fn pointer(&mut self, encoding: u8) -> Option<u64> {
+        let address = self.base + self.position as u64;
+        let value = match encoding & 0x0f {
+            PE_ABSPTR | PE_UDATA8 | PE_SDATA8 => self.u64()?,
+            PE_ULEB128 => self.uleb()?,
+            PE_UDATA2 => self.u16()? as u64,
+            PE_UDATA4 => self.u32()? as u64,
+            PE_SLEB128 => self.sleb()? as u64,
+            PE_SDATA2 => self.u16()? as i16 as u64,
+            PE_SDATA4 => self.u32()? as i32 as u64,
+            _ => return None,
+        };
+        match encoding & 0x70 {
+            0 => Some(value),
+            PE_PCREL => Some(address.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+// A CIE or FDE
+struct Entry<'a> {
+    // Where the CIE id / CIE pointer field is, which FDEs' CIE pointers are relative to
+    id_position: usize,
+    id: u32,
+    // The rest of the entry after the id
+    body: Reader<'a>,
+    next: usize,
+}
+
+fn entry_at(eh_frame: &[u8], base: u64, offset: usize) -> Option<Entry> {
+    let mut reader = Reader {
+        data: eh_frame,
+        position: offset,
+        base,
+    };
+    // A zero length terminates the section, and we don't support 64 bit DWARF
+    let length = match reader.u32()? {
+        0 | 0xffff_ffff => return None,
+        length => length as usize,
+    };
+    let id_position = reader.position;
+    let next = id_position.checked_add(length)?;
+    let id = reader.u32()?;
+    let body = Reader {
+        data: eh_frame.get(..next)?,
+        position: reader.position,
+        base,
+    };
+    Some(Entry {
+        id_position,
+        id,
+        body,
+        next,
+    })
+}
+
+struct Cie<'a> {
+    code_alignment: u64,
+    data_alignment: i64,
+    return_register: u16,
+    pointer_encoding: u8,
+    has_augmentation_data: bool,
+    instructions: Reader<'a>,
+}
+
+fn parse_cie(mut reader: Reader) -> Option<Cie> {
+    let version = reader.u8()?;
+    let augmentation = reader.string()?;
+    let code_alignment = reader.uleb()?;
+    let data_alignment = reader.sleb()?;
+    let return_register = match version {
+        1 => reader.u8()? as u16,
+        _ => reader.uleb()? as u16,
+    };
+    let mut pointer_encoding = PE_ABSPTR;
+    let has_augmentation_data = augmentation.first() == Some(&b'z');
+    if has_augmentation_data {
+        let length = reader.uleb()? as usize;
+        let mut data = Reader {
+            data: reader.data.get(..reader.position.checked_add(length)?)?,
+            position: reader.position,
+            base: reader.base,
+        };
+        reader.bytes(length)?;
+        for &c in &augmentation[1..] {
+            match c {
+                b'R' => pointer_encoding = data.u8()?,
+                // Personality routine, which we don't need
+                b'P' => {
+                    let encoding = data.u8()?;
+                    data.pointer(encoding & 0x7f)?;
+                }
+                b'L' => {
+                    data.u8()?;
+                }
+                b'S' => (),
+                _ => return None,
+            }
+        }
+    } else if !augmentation.is_empty() {
+        return None;
+    }
+    Some(Cie {
+        code_alignment,
+        data_alignment,
+        return_register,
+        pointer_encoding,
+        has_augmentation_data,
+        instructions: reader,
+    })
+}
+
+struct Fde<'a> {
+    pc_begin: u64,
+    instructions: Reader<'a>,
+}
+
+// A linear search, since the linker doesn't give us .eh_frame_hdr's sorted table
+fn find_fde<'a>(eh_frame: &'a [u8], base: u64, pc: u64) -> Option<(Cie<'a>, Fde<'a>)> {
+    let mut offset = 0;
+    while let Some(entry) = entry_at(eh_frame, base, offset) {
+        offset = entry.next;
+        // CIEs have an id of 0, FDEs point back at their CIE
+        if entry.id == 0 {
+            continue;
+        }
+        let cie_entry = entry_at(
+            eh_frame,
+            base,
+            entry.id_position.checked_sub(entry.id as usize)?,
+        )?;
+        let cie = parse_cie(cie_entry.body)?;
+        let mut body = entry.body;
+        let pc_begin = body.pointer(cie.pointer_encoding)?;
+        let pc_range = body.pointer(cie.pointer_encoding & 0x0f)?;
+        if !(pc_begin..pc_begin.wrapping_add(pc_range)).contains(&pc) {
+            continue;
+        }
+        if cie.has_augmentation_data {
+            let length = body.uleb()? as usize;
+            body.bytes(length)?;
+        }
+        let fde = Fde {
+            pc_begin,
+            instructions: body,
+        };
+        return Some((cie, fde));
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    SameValue,
+    Undefined,
+    // Saved at CFA + offset
+    Offset(i64),
+}
+
+// How to find the caller's registers at some instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Row {
+    cfa_register: u16,
+    cfa_offset: i64,
+    rbp: Rule,
+    return_address: Rule,
+}
+
+impl Row {
+    fn new() -> Self {
+        Row {
+            cfa_register: RSP,
+            cfa_offset: 0,
+            rbp: Rule::SameValue,
+            return_address: Rule::Undefined,
+        }
+    }
+}
+
+// Registers whose rules we keep track of
+fn is_tracked(cie: &Cie, register: u64) -> bool {
+    register == RBP as u64 || register == RSP as u64 || register == cie.return_register as u64
+}
+
+fn set_rule(cie: &Cie, row: &mut Row, register: u64, rule: Rule) {
+    if register == RBP as u64 {
+        row.rbp = rule;
+    } else if register == cie.return_register as u64 {
+        row.return_address = rule;
+    }
+}
+
+fn restore_rule(cie: &Cie, row: &mut Row, initial: &Row, register: u64) {
+    let rule = match register {
+        r if r == RBP as u64 => initial.rbp,
+        _ => initial.return_address,
+    };
+    set_rule(cie, row, register, rule);
+}
+
+// Run call frame instructions starting at `location`, until the location passes `pc`
+fn execute(
+    cie: &Cie,
+    mut instructions: Reader,
+    row: &mut Row,
+    initial: &Row,
+    mut location: u64,
+    pc: u64,
+) -> Option<()> {
+    let mut remembered = [*row; MAX_REMEMBERED];
+    let mut depth = 0;
+    while !instructions.is_empty() {
+        let opcode = instructions.u8()?;
+        let operand = (opcode & 0x3f) as u64;
+        let advance = match opcode >> 6 {
+            // DW_CFA_advance_loc
+            1 => Some(operand),
+            // DW_CFA_offset
+            2 => {
+                let offset = instructions.uleb()? as i64 * cie.data_alignment;
+                set_rule(cie, row, operand, Rule::Offset(offset));
+                None
+            }
+            // DW_CFA_restore
+            3 => {
+                restore_rule(cie, row, initial, operand);
+                None
+            }
+            _ => match opcode {
+                // DW_CFA_nop
+                0x00 => None,
+                // DW_CFA_set_loc
+                0x01 => {
+                    let new_location = instructions.pointer(cie.pointer_encoding)?;
+                    if new_location > pc {
+                        break;
+                    }
+                    location = new_location;
+                    None
+                }
+                // DW_CFA_advance_loc1, 2 and 4
+                0x02 => Some(instructions.u8()? as u64),
+                0x03 => Some(instructions.u16()? as u64),
+                0x04 => Some(instructions.u32()? as u64),
+                // DW_CFA_offset_extended
+                0x05 => {
+                    let register = instructions.uleb()?;
+                    let offset = instructions.uleb()? as i64 * cie.data_alignment;
+                    set_rule(cie, row, register, Rule::Offset(offset));
+                    None
+                }
+                // DW_CFA_restore_extended
+                0x06 => {
+                    let register = instructions.uleb()?;
+                    restore_rule(cie, row, initial, register);
+                    None
+                }
+                // DW_CFA_undefined
+                0x07 => {
+                    let register = instructions.uleb()?;
+                    set_rule(cie, row, register, Rule::Undefined);
+                    None
+                }
+                // DW_CFA_same_value
+                0x08 => {
+                    let register = instructions.uleb()?;
+                    set_rule(cie, row, register, Rule::SameValue);
+                    None
+                }
+                // DW_CFA_register
+                0x09 => {
+                    let register = instructions.uleb()?;
+                    instructions.uleb()?;
+                    if is_tracked(cie, register) {
+                        return None;
+                    }
+                    None
+                }
+                // DW_CFA_remember_state
+                0x0a => {
+                    *remembered.get_mut(depth)? = *row;
+                    depth += 1;
+                    None
+                }
+                // DW_CFA_restore_state
+                0x0b => {
+                    depth = depth.checked_sub(1)?;
+                    *row = remembered[depth];
+                    None
+                }
+                // DW_CFA_def_cfa
+                0x0c => {
+                    row.cfa_register = instructions.uleb()? as u16;
+                    row.cfa_offset = instructions.uleb()? as i64;
+                    None
+                }
+                // DW_CFA_def_cfa_register
+                0x0d => {
+                    row.cfa_register = instructions.uleb()? as u16;
+                    None
+                }
+                // DW_CFA_def_cfa_offset
+                0x0e => {
+                    row.cfa_offset = instructions.uleb()? as i64;
+                    None
+                }
+                // DW_CFA_expression and DW_CFA_val_expression
+                0x10 | 0x16 => {
+                    let register = instructions.uleb()?;
+                    let length = instructions.uleb()? as usize;
+                    instructions.bytes(length)?;
+                    if is_tracked(cie, register) {
+                        return None;
+                    }
+                    None
+                }
+                // DW_CFA_offset_extended_sf
+                0x11 => {
+                    let register = instructions.uleb()?;
+                    let offset = instructions.sleb()? * cie.data_alignment;
+                    set_rule(cie, row, register, Rule::Offset(offset));
+                    None
+                }
+                // DW_CFA_def_cfa_sf
+                0x12 => {
+                    row.cfa_register = instructions.uleb()? as u16;
+                    row.cfa_offset = instructions.sleb()? * cie.data_alignment;
+                    None
+                }
+                // DW_CFA_def_cfa_offset_sf
+                0x13 => {
+                    row.cfa_offset = instructions.sleb()? * cie.data_alignment;
+                    None
+                }
+                // DW_CFA_val_offset and DW_CFA_val_offset_sf
+                0x14 | 0x15 => {
+                    let register = instructions.uleb()?;
+                    instructions.uleb()?;
+                    if is_tracked(cie, register) {
+                        return None;
+                    }
+                    None
+                }
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    instructions.uleb()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let register = instructions.uleb()?;
+                    let offset = -(instructions.uleb()? as i64) * cie.data_alignment;
+                    set_rule(cie, row, register, Rule::Offset(offset));
+                    None
+                }
+                // Including DW_CFA_def_cfa_expression
+                _ => return None,
+            },
+        };
+        if let Some(delta) = advance {
+            location = location.wrapping_add(delta * cie.code_alignment);
+            if location > pc {
+                break;
+            }
+        }
+    }
+    Some(())
+}
+
+// The unwind rules in effect at `pc`
+fn row_at(eh_frame: &[u8], base: u64, pc: u64) -> Option<Row> {
+    let (cie, fde) = find_fde(eh_frame, base, pc)?;
+    let mut row = Row::new();
+    let initial = row;
+    execute(
+        &cie,
+        cie.instructions.clone(),
+        &mut row,
+        &initial,
+        0,
+        u64::MAX,
+    )?;
+    let initial = row;
+    execute(&cie, fde.instructions, &mut row, &initial, fde.pc_begin, pc)?;
+    Some(row)
+}
+
+// The registers an unwind needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rip, rsp, rbp);
+        unsafe {
+            asm!(
+                "lea {}, [rip]",
+                "mov {}, rsp",
+                "mov {}, rbp",
+                out(reg) rip,
+                out(reg) rsp,
+                out(reg) rbp,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        Registers { rip, rsp, rbp }
+    }
+}
+
+// The caller's registers, or None if we can't unwind any further. Return addresses point just
+// past the call, which can be the start of the next function, so look those up one byte back.
+fn step(eh_frame: &[u8], registers: &Registers, is_return_address: bool) -> Option<Registers> {
+    let pc = match is_return_address {
+        true => registers.rip.checked_sub(1)?,
+        false => registers.rip,
+    };
+    let row = row_at(eh_frame, eh_frame.as_ptr() as u64, pc)?;
+    let cfa = match row.cfa_register {
+        RSP => registers.rsp,
+        RBP => registers.rbp,
+        _ => return None,
+    }
+    .wrapping_add(row.cfa_offset as u64);
+    let restore = |rule: Rule, current: u64| match rule {
+        Rule::SameValue => Some(current),
+        Rule::Undefined => None,
+        Rule::Offset(offset) => memory::try_read(cfa.wrapping_add(offset as u64) as usize).ok(),
+    };
+    Some(Registers {
+        rip: restore(row.return_address, registers.rip)?,
+        rsp: cfa,
+        rbp: restore(row.rbp, registers.rbp).unwrap_or(0),
+    })
+}
+
+// (address, size) of the kernel's .eh_frame, once we've found it in the kernel image
+static EH_FRAME: Once<Option<(usize, usize)>> = Once::new();
+
+// .eh_frame is loaded with the rest of the kernel, we just need the section headers to find it.
+// Not available until memory::init has found the kernel image.
+fn eh_frame() -> Option<&'static [u8]> {
+    let (address, size) = match EH_FRAME.get() {
+        Some(eh_frame) => (*eh_frame)?,
+        None => {
+            let image = memory::kernel_image()?;
+            (*EH_FRAME.call_once(|| {
+                image
+                    .sections()
+                    .find(|s| s.is_alloc() && image.section_name(s) == Some(".eh_frame"))
+                    .map(|s| (s.address as usize, s.size as usize))
+            }))?
+        }
+    };
+    Some(unsafe { core::slice::from_raw_parts(address as *const u8, size) })
+}
+
+// Fill `frames` with return addresses by unwinding from `registers`, returning how many we found.
+// Finds nothing if there's no .eh_frame to unwind with.
+pub fn walk(mut registers: Registers, frames: &mut [u64]) -> usize {
+    let eh_frame = match eh_frame() {
+        Some(eh_frame) => eh_frame,
+        None => return 0,
+    };
+    let mut len = 0;
+    while len < frames.len() {
+        match step(eh_frame, &registers, len > 0) {
+            // Stacks grow down, so callers' frames are always above ours
+            Some(caller) if caller.rip != 0 && caller.rsp > registers.rsp => {
+                frames[len] = caller.rip;
+                len += 1;
+                registers = caller;
+            }
+            _ => break,
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: u64 = 0x1000;
+
+    // A CIE and FDE for a function at 0x2000..0x2010 with the usual frame pointer prologue:
+    //   0x2000: push rbp
+    //   0x2001: mov rbp, rsp
+    //   0x2004: ...
+    #[rustfmt::skip]
+    const EH_FRAME: [u8; 51] = [
+        // CIE: length, id, version, "zR", code alignment 1, data alignment -8, return address
+        // register 16, augmentation length, pcrel|sdata4 pointers
+        18, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x1b,
+        // def_cfa rsp+8, return address at cfa-8
+        0x0c, 7, 8, 0x90, 1,
+        // FDE: length, CIE pointer, pc_begin (relative to BASE + 30), pc_range, augmentation length
+        21, 0, 0, 0, 26, 0, 0, 0, 0xe2, 0x0f, 0, 0, 0x10, 0, 0, 0, 0,
+        // advance 1, def_cfa_offset 16, rbp at cfa-16, advance 3, def_cfa_register rbp
+        0x41, 0x0e, 0x10, 0x86, 0x02, 0x43, 0x0d, 0x06,
+        // terminator
+        0, 0, 0, 0,
+    ];
+
+    #[test_case]
+    fn test_row_at() {
+        let row = |pc| row_at(&EH_FRAME, BASE, pc);
+        let entry = Row {
+            cfa_register: RSP,
+            cfa_offset: 8,
+            rbp: Rule::SameValue,
+            return_address: Rule::Offset(-8),
+        };
+        assert_eq!(row(0x2000), Some(entry));
+        let pushed = Row {
+            cfa_offset: 16,
+            rbp: Rule::Offset(-16),
+            ..entry
+        };
+        assert_eq!(row(0x2001), Some(pushed));
+        assert_eq!(row(0x2003), Some(pushed));
+        let framed = Row {
+            cfa_register: RBP,
+            ..pushed
+        };
+        assert_eq!(row(0x2004), Some(framed));
+        assert_eq!(row(0x200f), Some(framed));
+        assert_eq!(row(0x2010), None);
+        assert_eq!(row(0x1fff), None);
+    }
+
+    #[test_case]
+    fn test_leb128() {
+        let mut reader = Reader {
+            data: &[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f],
+            position: 0,
+            base: 0,
+        };
+        assert_eq!(reader.uleb(), Some(624485));
+        assert_eq!(reader.sleb(), Some(-1));
+        assert_eq!(reader.sleb(), Some(-128));
+        assert_eq!(reader.uleb(), None);
+    }
+
+    #[inline(never)]
+    fn walk_from_callee(frames: &mut [u64]) -> usize {
+        walk(Registers::capture(), frames)
+    }
+
+    #[test_case]
+    fn test_walk_finds_caller() {
+        let mut frames = [0; 4];
+        assert!(walk_from_callee(&mut frames) > 0, "couldn't unwind");
+        let caller = crate::symbols::lookup(frames[0]).expect("caller not found");
+        assert!(caller.name.contains("test_walk_finds_caller"));
+    }
+}
@@ -1,32 +1,26 @@
 use core::fmt;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
+
+use crate::sync::SpinLock;
 
 const VGA_MEM_LOCATION: usize = 0xb8000;
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new());
-}
-
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => {
-        // Static lock, so avoid deadlocks where interrupt handlers try to aquire lock
-        // by disabling interrupts.
-        $crate::without_interrupt! {{
-            use core::fmt::Write;
-            $crate::vga_buffer::WRITER.lock().write_fmt(format_args!($($arg)*)).unwrap();
-        }}
-    };
+    pub static ref WRITER: SpinLock<Writer> = SpinLock::new("WRITER", Writer::new());
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+// The characters on a row of the screen, read straight from video memory without taking WRITER,
+// so it works even if whoever holds it has panicked
+pub fn screen_row(row: usize) -> [u8; BUFFER_WIDTH] {
+    let buffer = VGA_MEM_LOCATION as *const ScreenBuffer;
+    let mut text = [0; BUFFER_WIDTH];
+    for (column, byte) in text.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(&(*buffer)[row][column]) }.ascii_character;
+    }
+    text
 }
 
 #[allow(dead_code)]
@@ -61,12 +55,6 @@ impl ColorCode {
     }
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)] // bytes are laid out in order instead of undefined
 struct ScreenChar {
@@ -76,8 +64,13 @@ struct ScreenChar {
 
 type ScreenBuffer = [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT];
 
+// What was on the screen, for putting it back after drawing over it
+pub struct Screen(ScreenBuffer);
+
 pub struct Writer {
     column_position: usize,
+    // Rows above this are reserved (eg. for the status bar), and don't scroll or get cleared
+    top: usize,
     color_code: ColorCode,
     buffer: &'static mut ScreenBuffer,
 }
@@ -86,6 +79,7 @@ impl Writer {
     pub fn new() -> Writer {
         Writer {
             column_position: 0,
+            top: 0,
             color_code: ColorCode::new(Color::Yellow, Color::Black),
             buffer: unsafe { &mut *(VGA_MEM_LOCATION as *mut ScreenBuffer) },
         }
@@ -94,6 +88,16 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // Backspace erases the previous character on the line
+            0x08 => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    self.buffer[BUFFER_HEIGHT - 1][self.column_position] = ScreenChar {
+                        ascii_character: b' ',
+                        color_code: self.color_code,
+                    };
+                }
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -108,10 +112,84 @@ impl Writer {
         }
     }
 
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    // Blank the screen below any reserved rows in the current colors
+    pub fn clear(&mut self) {
+        (self.top..BUFFER_HEIGHT).for_each(|line| self.clear_line(line));
+        self.column_position = 0;
+    }
+
+    // Keep the top `rows` rows out of scrolling, for write_reserved_row
+    pub fn reserve_rows(&mut self, rows: usize) {
+        assert!(rows < BUFFER_HEIGHT, "Can't reserve the output row");
+        self.top = rows;
+    }
+
+    // The first row output scrolls through
+    pub fn first_row(&self) -> usize {
+        self.top
+    }
+
+    // Replace a reserved row's contents, padded out with spaces
+    pub fn write_reserved_row(
+        &mut self,
+        row: usize,
+        text: &str,
+        foreground: Color,
+        background: Color,
+    ) {
+        assert!(row < self.top, "Row {} isn't reserved", row);
+        self.write_row(row, text, foreground, background);
+    }
+
+    // Replace any row's contents, padded out with spaces. Output scrolls it away, so this is for
+    // fullscreen views which keep print! off the screen.
+    pub fn write_row(&mut self, row: usize, text: &str, foreground: Color, background: Color) {
+        let color_code = ColorCode::new(foreground, background);
+        let mut bytes = text.bytes();
+        for c in self.buffer[row].iter_mut() {
+            *c = ScreenChar {
+                ascii_character: match bytes.next() {
+                    Some(byte @ 0x20..=0x7e) => byte,
+                    Some(_) => 0xfe,
+                    None => b' ',
+                },
+                color_code,
+            };
+        }
+    }
+
+    // The characters on a row of the screen. Output is always written to the bottom row.
+    pub fn row(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        let mut text = [0; BUFFER_WIDTH];
+        for (byte, c) in text.iter_mut().zip(self.buffer[row].iter()) {
+            *byte = c.ascii_character;
+        }
+        text
+    }
+
+    pub fn save(&self) -> Screen {
+        Screen(*self.buffer)
+    }
+
+    pub fn restore(&mut self, screen: &Screen) {
+        *self.buffer = screen.0;
+    }
+
+    // Swap a row's foreground and background colors, eg. to highlight it. Twice undoes it.
+    pub fn invert_row(&mut self, row: usize) {
+        for c in self.buffer[row].iter_mut() {
+            c.color_code = ColorCode(c.color_code.0.rotate_left(4));
+        }
+    }
+
     fn new_line(&mut self) {
         // Can't use copy_from_slice to copy from a vector to itself because of borrow checker
         // self.buffer.chars[..BUFFER_HEIGHT-1].copy_from_slice(&self.buffer.chars[1..])
-        self.buffer.copy_within(1.., 0);
+        self.buffer.copy_within(self.top + 1.., self.top);
         self.clear_line(BUFFER_HEIGHT - 1);
         self.column_position = 0;
     }
@@ -127,7 +205,7 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         s.bytes()
             .map(|c| match c {
-                0x20..=0x7e | b'\n' => c,
+                0x20..=0x7e | b'\n' | 0x08 => c,
                 _ => 0xfe, // non-printable ASCII bytes
             })
             .for_each(|c| self.write_byte(c))
@@ -177,6 +255,28 @@ mod test {
         }
     }
 
+    #[test_case]
+    fn test_reserved_rows_dont_scroll() {
+        use core::fmt::Write;
+        let mut writer = Writer::new();
+        writer.reserve_rows(1);
+        writer.write_reserved_row(0, "status", Color::Black, Color::LightGray);
+        (0..BUFFER_HEIGHT).for_each(|i| writeln!(writer, "{}", i).unwrap());
+        writer.clear();
+        assert_eq!(&writer.row(0)[..7], b"status ");
+    }
+
+    #[test_case]
+    fn test_save_and_restore() {
+        let mut writer = Writer::new();
+        let screen = writer.save();
+        let before = writer.row(BUFFER_HEIGHT - 2);
+        writer.write_row(BUFFER_HEIGHT - 2, "drawn over", Color::White, Color::Blue);
+        assert_eq!(&writer.row(BUFFER_HEIGHT - 2)[..11], b"drawn over ");
+        writer.restore(&screen);
+        assert_eq!(writer.row(BUFFER_HEIGHT - 2), before);
+    }
+
     // TODO: test newline moves previous lines up
     // TODO: test color codes
     // TODO: test unprintable characters
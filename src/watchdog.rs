@@ -0,0 +1,165 @@
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::time;
+
+// Components (tasks, subsystems, the kernel main loop) register with a deadline and then
+// periodically pet the watchdog. The timer interrupt checks that everyone has checked in
+// recently, and panics naming the starved components otherwise. This turns silent hangs
+// (eg. in QEMU CI runs) into failures we can actually debug.
+
+const MAX_COMPONENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct Component {
+    name: &'static str,
+    deadline_ticks: u64,
+    last_pet: u64,
+}
+
+impl Component {
+    fn starved(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_pet) > self.deadline_ticks
+    }
+}
+
+// Fixed size so that neither petting nor checking ever allocates
+static COMPONENTS: Mutex<[Option<Component>; MAX_COMPONENTS]> = Mutex::new([None; MAX_COMPONENTS]);
+
+// Start watching `name`, which must then pet at least once every `deadline_ticks`.
+pub fn register(name: &'static str, deadline_ticks: u64) {
+    crate::without_interrupt! {{
+        let mut components = COMPONENTS.lock();
+        let component = Component {
+            name,
+            deadline_ticks,
+            last_pet: time::jiffies(),
+        };
+        let index = components
+            .iter()
+            .position(|c| matches!(c, Some(c) if c.name == name))
+            .or_else(|| components.iter().position(|c| c.is_none()));
+        match index {
+            Some(index) => components[index] = Some(component),
+            None => panic!("Too many watchdog components registering {}", name),
+        }
+    }}
+}
+
+pub fn unregister(name: &'static str) {
+    crate::without_interrupt! {{
+        COMPONENTS
+            .lock()
+            .iter_mut()
+            .filter(|c| matches!(c, Some(c) if c.name == name))
+            .for_each(|c| *c = None);
+    }}
+}
+
+pub fn pet(name: &'static str) {
+    crate::without_interrupt! {{
+        let now = time::jiffies();
+        COMPONENTS
+            .lock()
+            .iter_mut()
+            .flatten()
+            .filter(|c| c.name == name)
+            .for_each(|c| c.last_pet = now);
+    }}
+}
+
+// Count everyone as having pet the watchdog at `now`, eg. when the tick restarts after being
+// stopped for a tickless idle (see time::tick)
+pub(crate) fn touch_all(now: u64) {
+    crate::without_interrupt! {{
+        COMPONENTS
+            .lock()
+            .iter_mut()
+            .flatten()
+            .for_each(|c| c.last_pet = now);
+    }}
+}
+
+struct StarvedReport<'a> {
+    components: &'a [Option<Component>],
+    now: u64,
+}
+
+impl fmt::Display for StarvedReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let starved = self
+            .components
+            .iter()
+            .flatten()
+            .filter(|c| c.starved(self.now));
+        for component in starved {
+            write!(
+                f,
+                "\n  {}: last pet at tick {} ({} ticks ago, deadline {})",
+                component.name,
+                component.last_pet,
+                self.now - component.last_pet,
+                component.deadline_ticks,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Start checking components on every timer tick. Nothing can starve while the CPU idles, so the
+// check doesn't keep the tick going.
+pub fn init() {
+    time::tick::register_deferrable("watchdog", 1, check);
+}
+
+// Called from the timer interrupt.
+pub(crate) fn check(now: u64) {
+    // Someone is mid-pet or mid-register; they're clearly not hung, check again next tick
+    let components = match COMPONENTS.try_lock() {
+        Some(components) => components,
+        None => return,
+    };
+    if components.iter().flatten().any(|c| c.starved(now)) {
+        panic!(
+            "watchdog: components starved at tick {}:{}",
+            now,
+            StarvedReport {
+                components: &*components,
+                now,
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_pet_keeps_component_alive() {
+        register("test_pet", 10);
+        pet("test_pet");
+        let now = time::jiffies();
+        check(now + 5);
+        pet("test_pet");
+        check(time::jiffies() + 10);
+        unregister("test_pet");
+    }
+
+    #[test_case]
+    fn test_touch_all() {
+        register("test_touch_all", 1);
+        let later = time::jiffies() + 100;
+        touch_all(later);
+        check(later + 1);
+        unregister("test_touch_all");
+    }
+
+    #[test_case]
+    fn test_unregistered_component_not_checked() {
+        register("test_unregister", 1);
+        unregister("test_unregister");
+        check(time::jiffies() + 100);
+    }
+}
@@ -0,0 +1,349 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(sos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::alloc::{Allocator, Layout};
+use core::ops::Range;
+use core::ptr::{addr_of_mut, NonNull};
+
+use sos::memory::allocator::bootstrap_allocator::{Locked, MutAllocator};
+use sos::memory::allocator::bump_allocator::BumpAllocator;
+use sos::memory::allocator::fixed_size_allocator::FixedSizeAllocatorBlock;
+use sos::memory::allocator::meta_allocator::MetaAllocator;
+use sos::memory::allocator::resource_allocator::ResourceAllocator;
+
+// Drives a ResourceAllocator with a seeded random sequence of alloc/free/resize operations,
+// checking it against a shadow model of which quanta are in use after every operation. The
+// MetaAllocator gets the same treatment, checked by filling every live allocation.
+
+const QUANTUM: usize = 4;
+// Two disjoint regions, so that coalescing must never join across the gap
+const REGIONS: [Range<usize>; 2] = [0..1024, 1280..2048];
+const DOMAIN: usize = 2048;
+const MAX_LIVE: usize = 64;
+const OPERATIONS: usize = 2000;
+
+// The allocator's own bookkeeping (list nodes, hash map) comes from this arena rather than the
+// kernel heap, which is a bump allocator that never reuses freed memory.
+const ARENA_SIZE: usize = 1 << 20;
+static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+
+// xorshift64*, plenty for picking operations
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+struct Shadow {
+    in_use: [bool; DOMAIN],
+    live: [Option<Range<usize>>; MAX_LIVE],
+}
+
+impl Shadow {
+    fn mark(&mut self, range: &Range<usize>, in_use: bool) {
+        for i in range.clone() {
+            assert_ne!(
+                self.in_use[i],
+                in_use,
+                "unit {} already {}",
+                i,
+                if in_use { "allocated" } else { "free" }
+            );
+            self.in_use[i] = in_use;
+        }
+    }
+}
+
+type Allocator<'a> = ResourceAllocator<QUANTUM, &'a Locked<BumpAllocator>>;
+
+fn check_allocation(range: &Range<usize>, size: usize) {
+    assert!(
+        range.end - range.start >= size,
+        "{:?} too small for {}",
+        range,
+        size
+    );
+    assert_eq!(range.start % QUANTUM, 0, "{:?} not quantum aligned", range);
+    assert_eq!(range.end % QUANTUM, 0, "{:?} not quantum aligned", range);
+    assert!(
+        REGIONS
+            .iter()
+            .any(|r| r.start <= range.start && range.end <= r.end),
+        "{:?} outside of added regions",
+        range
+    );
+}
+
+fn validate(ra: &Allocator, shadow: &Shadow) {
+    let mut previous: Option<(Range<usize>, bool)> = None;
+    for (range, allocated) in ra.segments() {
+        assert!(range.start < range.end, "empty segment {:?}", range);
+        if let Some((previous_range, previous_allocated)) = previous {
+            assert!(
+                previous_range.end <= range.start,
+                "segments out of order or overlapping: {:?} then {:?}",
+                previous_range,
+                range
+            );
+            assert!(
+                previous_allocated || allocated || previous_range.end != range.start,
+                "adjacent free segments not coalesced: {:?} and {:?}",
+                previous_range,
+                range
+            );
+        }
+        if allocated {
+            assert!(
+                shadow.live.iter().flatten().any(|live| *live == range),
+                "allocated segment {:?} isn't a live allocation",
+                range
+            );
+        }
+        for i in range.clone() {
+            assert_eq!(
+                shadow.in_use[i], allocated,
+                "unit {} in segment {:?} disagrees with the shadow model",
+                i, range
+            );
+        }
+        previous = Some((range, allocated));
+    }
+}
+
+// A failed allocation is only acceptable if no free segment is big enough to satisfy it
+// through the fast path: the freelist search starts at the next power of 2 quanta.
+fn check_failure(ra: &Allocator, size: usize) {
+    let quanta = size.div_ceil(QUANTUM).max(1).next_power_of_two();
+    for (range, allocated) in ra.segments() {
+        assert!(
+            allocated || (range.end - range.start) / QUANTUM < quanta,
+            "allocation of {} failed with free segment {:?}",
+            size,
+            range
+        );
+    }
+}
+
+fn allocate(ra: &mut Allocator, shadow: &mut Shadow, slot: usize, size: usize) {
+    match ra.fast_allocate(size) {
+        Ok(range) => {
+            check_allocation(&range, size);
+            shadow.mark(&range, true);
+            shadow.live[slot] = Some(range);
+        }
+        Err(()) => check_failure(ra, size),
+    }
+}
+
+fn fuzz(seed: u64) {
+    let arena = unsafe { BumpAllocator::new(ARENA.as_ptr() as usize, ARENA_SIZE) }.as_sync();
+    let mut ra: Allocator = ResourceAllocator::new_in(&arena);
+    REGIONS.iter().for_each(|region| ra.add(region.clone()));
+    let mut shadow = Shadow {
+        in_use: [false; DOMAIN],
+        live: [(); MAX_LIVE].map(|_| None),
+    };
+    let mut rng = Rng(seed);
+    for _ in 0..OPERATIONS {
+        let slot = rng.below(MAX_LIVE);
+        let size = 1 + rng.below(64);
+        match (shadow.live[slot].take(), rng.below(3)) {
+            (None, _) => allocate(&mut ra, &mut shadow, slot, size),
+            (Some(range), 0) => {
+                // resize: release and allocate again with a new size
+                ra.release(range.clone());
+                shadow.mark(&range, false);
+                allocate(&mut ra, &mut shadow, slot, size);
+            }
+            (Some(range), _) => {
+                ra.release(range.clone());
+                shadow.mark(&range, false);
+            }
+        }
+        validate(&ra, &shadow);
+    }
+    // Releasing everything should coalesce back into exactly the original regions
+    for range in shadow.live.clone().iter().flatten() {
+        ra.release(range.clone());
+        shadow.mark(range, false);
+    }
+    let segments = ra.segments().map(|(range, _)| range);
+    assert!(segments.eq(REGIONS.iter().cloned()));
+}
+
+// MetaAllocator size classes, each in its own 1MB region so frees route back to it. Sizes from
+// the last class's up go to the big region allocator, ie. the kernel's page allocator.
+const CLASS_REGION: usize = 1 << 20;
+const CLASS_SIZES: [usize; 3] = [16, 64, 512];
+const MAX_SIZE: usize = 4096;
+
+#[repr(C, align(0x100000))]
+struct ClassRegions([[u8; CLASS_REGION]; CLASS_SIZES.len()]);
+static mut CLASS_REGIONS: ClassRegions = ClassRegions([[0; CLASS_REGION]; CLASS_SIZES.len()]);
+
+struct Live {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    fill: u8,
+}
+
+fn class_region(class: usize) -> Range<usize> {
+    let start = unsafe { addr_of_mut!(CLASS_REGIONS.0[class]) } as usize;
+    start..start + CLASS_REGION
+}
+
+fn class_block<const S: usize>(class: usize) -> Locked<FixedSizeAllocatorBlock<S>> {
+    assert_eq!(CLASS_SIZES[class], S);
+    let region = class_region(class);
+    unsafe { FixedSizeAllocatorBlock::new(region.start as *mut u8..region.end as *mut u8) }
+        .as_sync()
+}
+
+fn expected_class(size: usize) -> Option<usize> {
+    CLASS_SIZES.iter().position(|&class| size < class)
+}
+
+fn meta_allocate(
+    meta: &mut MetaAllocator<&Locked<BumpAllocator>>,
+    live: &mut [Option<Live>],
+    slot: usize,
+    layout: Layout,
+) {
+    let block = meta
+        .allocate(layout)
+        .unwrap_or_else(|_| panic!("allocation of {:?} failed", layout));
+    let range = block.as_mut_ptr() as usize..block.as_mut_ptr() as usize + block.len();
+    assert!(
+        block.len() >= layout.size(),
+        "{:?} too small for {:?}",
+        range,
+        layout
+    );
+    assert_eq!(
+        range.start % layout.align(),
+        0,
+        "{:?} misaligned for {:?}",
+        range,
+        layout
+    );
+    if let Some(class) = expected_class(layout.size()) {
+        let region = class_region(class);
+        assert!(
+            region.start <= range.start && range.end <= region.end,
+            "{:?} routed outside of size class {}",
+            range,
+            CLASS_SIZES[class]
+        );
+    }
+    for other in live.iter().flatten() {
+        let other_start = other.ptr.as_ptr() as usize;
+        assert!(
+            range.end <= other_start || other_start + other.layout.size() <= range.start,
+            "{:?} overlaps live allocation at {:#x}",
+            range,
+            other_start
+        );
+    }
+    let fill = slot as u8 ^ layout.size() as u8;
+    unsafe { block.as_mut_ptr().write_bytes(fill, layout.size()) };
+    live[slot] = Some(Live {
+        ptr: NonNull::new(block.as_mut_ptr()).unwrap(),
+        layout,
+        fill,
+    });
+}
+
+// Checks nothing else wrote over the allocation while it was live
+fn meta_release(meta: &mut MetaAllocator<&Locked<BumpAllocator>>, live: Live) {
+    let bytes = unsafe { core::slice::from_raw_parts(live.ptr.as_ptr(), live.layout.size()) };
+    if let Some(offset) = bytes.iter().position(|&byte| byte != live.fill) {
+        panic!(
+            "allocation at {:?} corrupted at offset {}",
+            live.ptr, offset
+        );
+    }
+    unsafe { meta.deallocate(live.ptr, live.layout) };
+}
+
+fn fuzz_meta(seed: u64) {
+    let arena = unsafe { BumpAllocator::new(ARENA.as_ptr() as usize, ARENA_SIZE) }.as_sync();
+    let small = class_block::<16>(0);
+    let medium = class_block::<64>(1);
+    let large = class_block::<512>(2);
+    let classes: [NonNull<dyn Allocator>; 3] = [
+        NonNull::from(&small),
+        NonNull::from(&medium),
+        NonNull::from(&large),
+    ];
+    let mut meta = MetaAllocator::new_in(&arena);
+    let mut smallest = 0;
+    for (class, allocator) in classes.into_iter().enumerate() {
+        unsafe {
+            meta.set_size_class(smallest..CLASS_SIZES[class], allocator);
+            meta.add_region(class_region(class), allocator);
+        }
+        smallest = CLASS_SIZES[class];
+    }
+    let mut live: [Option<Live>; MAX_LIVE] = [(); MAX_LIVE].map(|_| None);
+    let mut rng = Rng(seed);
+    for _ in 0..OPERATIONS {
+        let slot = rng.below(MAX_LIVE);
+        let size = 1 + rng.below(MAX_SIZE);
+        // The fixed size blocks only guarantee alignment up to the largest power of 2 <= size
+        let align_bits = size.ilog2().min(4) as usize;
+        let layout = Layout::from_size_align(size, 1 << rng.below(align_bits + 1)).unwrap();
+        match (live[slot].take(), rng.below(3)) {
+            (None, _) => meta_allocate(&mut meta, &mut live, slot, layout),
+            (Some(allocation), 0) => {
+                meta_release(&mut meta, allocation);
+                meta_allocate(&mut meta, &mut live, slot, layout);
+            }
+            (Some(allocation), _) => meta_release(&mut meta, allocation),
+        }
+    }
+    for allocation in live.iter_mut().filter_map(Option::take) {
+        meta_release(&mut meta, allocation);
+    }
+    assert!(small.lock().is_empty());
+    assert!(medium.lock().is_empty());
+    assert!(large.lock().is_empty());
+}
+
+#[test_case]
+fn fuzz_seed_1() {
+    fuzz(1);
+}
+
+#[test_case]
+fn fuzz_seed_0xdeadbeef() {
+    fuzz(0xdeadbeef);
+}
+
+#[test_case]
+fn fuzz_seed_0x5eed() {
+    fuzz(0x5eed);
+}
+
+#[test_case]
+fn fuzz_meta_seed_1() {
+    fuzz_meta(1);
+}
+
+#[test_case]
+fn fuzz_meta_seed_0xdeadbeef() {
+    fuzz_meta(0xdeadbeef);
+}
+
+sos::kernel_test_main!();
@@ -4,21 +4,10 @@
 #![test_runner(sos::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use core::panic::PanicInfo;
-
 use sos::println;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    test_main();
-
-    loop {}
-}
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    sos::test_panic_handler(info);
-}
+// Deliberately without sos::init, to check printing works straight after boot
+sos::kernel_test_main!(setup = |_| ());
 
 #[test_case]
 fn test_println() {
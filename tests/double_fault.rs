@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use sos::testing::ist::{self, DOUBLE_FAULT_TABLE};
+use sos::testing::single_test_failed;
+
+// There's deliberately no page fault handler in the table, so a page fault can't be delivered
+// and escalates to a double fault, which has to be handled on its IST stack.
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    ist::start(
+        "double_fault::double_fault_on_ist_stack",
+        &DOUBLE_FAULT_TABLE,
+    );
+
+    unsafe { *(0xdeadbeef as *mut u64) = 42 };
+
+    single_test_failed(format_args!("execution continued after the page fault"));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    sos::test_panic_handler(info);
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+use lazy_static::lazy_static;
+use sos::interrupt::table::{Exception, Handler, InterruptStackFrame, InterruptTable};
+use sos::interrupt::{MACHINE_CHECK_STACK, NMI_STACK, PAGE_FAULT_STACK};
+use sos::testing::ist::{self, check_stack};
+use sos::testing::{single_test_failed, single_test_passed};
+
+// Raise an NMI with the stack pointer somewhere unmapped. The CPU can only push the exception
+// frame if it switches to the NMI's IST stack first. The NMI handler then raises a machine check,
+// which has to land on its own IST stack, and report from there. With the page_fault_stack
+// feature the machine check handler also page faults, and that has to land on its stack too.
+lazy_static! {
+    static ref TEST_INTERRUPT_TABLE: InterruptTable = {
+        let mut table = InterruptTable::empty();
+        table
+            .set_handler(
+                Exception::NonMaskableInterrupt,
+                Handler::Interrupt(test_nmi_handler),
+            )
+            .set_stack(NMI_STACK as u8);
+        table
+            .set_handler(
+                Exception::MachineCheck,
+                Handler::Interrupt(test_machine_check_handler),
+            )
+            .set_stack(MACHINE_CHECK_STACK as u8);
+        if cfg!(feature = "page_fault_stack") {
+            table
+                .set_handler(
+                    Exception::PageFault,
+                    Handler::Exception(test_page_fault_handler),
+                )
+                .set_stack(PAGE_FAULT_STACK as u8);
+        }
+        table
+    };
+}
+
+extern "x86-interrupt" fn test_nmi_handler(frame: InterruptStackFrame) {
+    check_stack("NMI", NMI_STACK);
+    if frame.stack_pointer() != CORRUPT_STACK as u64 {
+        single_test_failed(format_args!(
+            "interrupted rsp was {:#x}",
+            frame.stack_pointer()
+        ));
+    }
+    unsafe { asm!("int 18") };
+}
+
+extern "x86-interrupt" fn test_machine_check_handler(_frame: InterruptStackFrame) {
+    check_stack("machine check", MACHINE_CHECK_STACK);
+    if cfg!(feature = "page_fault_stack") {
+        unsafe { core::ptr::read_volatile(CORRUPT_STACK as *const u64) };
+        single_test_failed(format_args!("execution continued after the page fault"));
+    }
+    single_test_passed();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_frame: InterruptStackFrame, _error: u64) {
+    check_stack("page fault", PAGE_FAULT_STACK);
+    single_test_passed();
+}
+
+// Canonical, but nothing's mapped there
+const CORRUPT_STACK: usize = 0x7000_0000_0000;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    ist::start(
+        "interrupt_stacks::nested_faults_on_corrupt_stack",
+        &TEST_INTERRUPT_TABLE,
+    );
+    // Without the feature the page fault stack isn't allocated, and the handler can't switch to it
+    let page_fault_stack = sos::global_descriptor_table::interrupt_stack(PAGE_FAULT_STACK);
+    if page_fault_stack.is_empty() == cfg!(feature = "page_fault_stack") {
+        single_test_failed(format_args!(
+            "page fault stack is {:#x?}, should only be allocated with page_fault_stack",
+            page_fault_stack
+        ));
+    }
+
+    unsafe { asm!("mov rsp, {}", "int 2", in(reg) CORRUPT_STACK) };
+
+    single_test_failed(format_args!("execution continued after the NMI"));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    sos::test_panic_handler(info);
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use core::fmt;
+use core::panic::PanicInfo;
+
+use sos::panicking::{self, Nesting};
+use sos::serial_println;
+use sos::testing::{single_test_failed, single_test_passed, single_test_started};
+
+// Formatting the panic message panics again, from inside serial_println! while it holds the
+// SERIAL1 lock. The nested panic has to be noticed, and reported without that lock.
+struct Explosive;
+
+impl fmt::Display for Explosive {
+    fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
+        panic!("Display panicked");
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    single_test_started("nested_panic::panic_inside_display");
+    panic!("{}", Explosive);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    match panicking::enter() {
+        Nesting::First => {
+            serial_println!("{}", info);
+            single_test_failed(format_args!("formatting the panic message didn't panic"));
+        }
+        // Reported without the SERIAL1 lock, since we're panicking
+        Nesting::Nested => single_test_passed(),
+    }
+}
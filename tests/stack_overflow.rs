@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use sos::testing::ist::{self, DOUBLE_FAULT_TABLE};
+use sos::testing::single_test_failed;
+
+// Overflowing the stack hits the guard page. The CPU can't push the page fault's exception frame
+// onto the overflowed stack, so it double faults. Without the IST stack switch it would then fail
+// to push the double fault's frame too, and triple fault (QEMU reboots and the test times out).
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // keep this from being turned into a loop by tail call optimization
+    unsafe { core::ptr::read_volatile(&0u8) };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    ist::start(
+        "stack_overflow::stack_overflow_on_ist_stack",
+        &DOUBLE_FAULT_TABLE,
+    );
+
+    stack_overflow();
+
+    single_test_failed(format_args!("execution continued after stack overflow"));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    sos::test_panic_handler(info);
+}
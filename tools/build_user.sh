@@ -0,0 +1,18 @@
+#!/bin/bash
+# Assemble the user programs in user/ into user/bin, where fs::initfs embeds them. Cargo doesn't
+# run this; rerun it after changing a program and check in the result.
+set -e
+
+cd "$(dirname "$0")/../user"
+mkdir -p bin
+BUILD="$(mktemp -d)"
+trap 'rm -rf "$BUILD"' EXIT
+for source in *.s; do
+    name="${source%.s}"
+    as --64 -o "$BUILD/$name.o" "$source"
+    # Linked into task::process's slot of the address space (see PROCESS_START), with the
+    # headers and code sharing pages to keep the images small
+    ld -static -nostdlib -z noseparate-code -z max-page-size=0x1000 --build-id=none \
+        -Ttext-segment=0x7f8000000000 -e _start -o "bin/$name" "$BUILD/$name.o"
+    strip "bin/$name"
+done
@@ -0,0 +1,125 @@
+#!/usr/bin/env python3
+"""Write a sorted, demangled function symbol table into a linked kernel's .ksymtab section.
+
+The kernel reserves a zeroed .ksymtab section (see src/symbols.rs), which we fill in place after
+linking so that symbols::resolve can work without parsing ELF at runtime.
+
+Layout (little endian):
+    header:  magic b"KSYM", count: u32, strings_offset: u32, reserved: u32
+    entries: count * (address: u64, size: u32, name_offset: u32), sorted by address
+    strings: nul-terminated names, name_offset is relative to strings_offset
+"""
+import re
+import struct
+import sys
+
+SECTION_NAME = b".ksymtab"
+MAGIC = b"KSYM"
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QII")
+SECTION_HEADER = struct.Struct("<IIQQQQIIQQ")
+SYMBOL = struct.Struct("<IBBHQQ")
+
+ESCAPES = [
+    ("$SP$", "@"),
+    ("$BP$", "*"),
+    ("$RF$", "&"),
+    ("$LT$", "<"),
+    ("$GT$", ">"),
+    ("$LP$", "("),
+    ("$RP$", ")"),
+    ("$C$", ","),
+    ("$u20$", " "),
+    ("$u27$", "'"),
+    ("$u5b$", "["),
+    ("$u5d$", "]"),
+    ("$u7b$", "{"),
+    ("$u7d$", "}"),
+    ("$u7e$", "~"),
+    ("..", "::"),
+]
+
+
+def demangle(name):
+    """Demangle legacy Rust symbols, dropping the trailing hash. Anything else is left as is."""
+    if not (name.startswith("_ZN") and name.endswith("E")):
+        return name
+    body, i, parts = name[3:-1], 0, []
+    while i < len(body):
+        match = re.match(r"\d+", body[i:])
+        if not match:
+            return name
+        length = int(match.group())
+        start = i + len(match.group())
+        parts.append(body[start : start + length])
+        i = start + length
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    demangled = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, char in ESCAPES:
+            part = part.replace(escape, char)
+        demangled.append(part)
+    return "::".join(demangled)
+
+
+def c_string(data, offset):
+    return data[offset : data.index(b"\0", offset)]
+
+
+def main(path):
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit(f"{path}: not an ELF64 file")
+
+    (section_offset,) = struct.unpack_from("<Q", elf, 0x28)
+    entry_size, count, names_index = struct.unpack_from("<HHH", elf, 0x3A)
+    sections = [
+        SECTION_HEADER.unpack_from(elf, section_offset + i * entry_size)
+        for i in range(count)
+    ]
+    names = sections[names_index]
+
+    def section_name(section):
+        return c_string(elf, names[4] + section[0])
+
+    ksymtab = next((s for s in sections if section_name(s) == SECTION_NAME), None)
+    symtab = next((s for s in sections if s[1] == SHT_SYMTAB), None)
+    if ksymtab is None:
+        sys.exit(f"{path}: no {SECTION_NAME.decode()} section")
+    if symtab is None:
+        sys.exit(f"{path}: no symbol table, was the kernel stripped?")
+    strtab = sections[symtab[6]]
+
+    functions = {}
+    for i in range(symtab[5] // SYMBOL.size):
+        name, info, _, _, value, size = SYMBOL.unpack_from(elf, symtab[4] + i * SYMBOL.size)
+        if info & 0xF == STT_FUNC and value != 0:
+            functions.setdefault(value, (size, c_string(elf, strtab[4] + name).decode()))
+
+    entries, strings = [], bytearray()
+    for address, (size, name) in sorted(functions.items()):
+        entries.append(ENTRY.pack(address, min(size, 0xFFFFFFFF), len(strings)))
+        strings += demangle(name).encode() + b"\0"
+    strings_offset = HEADER.size + len(entries) * ENTRY.size
+    table = HEADER.pack(MAGIC, len(entries), strings_offset, 0) + b"".join(entries) + strings
+
+    offset, size = ksymtab[4], ksymtab[5]
+    if len(table) > size:
+        sys.exit(
+            f"{path}: symbol table needs {len(table)} bytes but {SECTION_NAME.decode()} "
+            f"is {size}, increase SYMBOL_TABLE_SIZE in src/symbols.rs"
+        )
+    elf[offset : offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1])
@@ -0,0 +1,7 @@
+#!/bin/bash
+# Cargo runner: embed the kernel's symbol table (see src/symbols.rs), then hand off to bootimage.
+set -e
+
+KERNEL="$1"
+python3 "$(dirname "$0")/embed_symbols.py" "$KERNEL"
+exec bootimage runner "$@"
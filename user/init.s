@@ -0,0 +1,46 @@
+# The first user program (see task::process): spawns /bin/hello with an argument, waits for it,
+# and exits with its exit code, or 1 if it couldn't be spawned.
+# Syscall numbers are syscall::Syscall's; see syscall.rs for the calling convention.
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_SPAWN, 1
+    .set SYS_WAIT, 2
+
+    .text
+    .global _start
+_start:
+    lea rdi, [rip + path]
+    mov esi, path_end - path
+    lea rdx, [rip + args]
+    mov r10d, args_end - args
+    mov eax, SYS_SPAWN
+    int 0x80
+    cmp rax, -1
+    je failed
+
+    mov rdi, rax
+    mov eax, SYS_WAIT
+    int 0x80
+    cmp rax, -1
+    je failed
+
+    mov rdi, rax
+    mov eax, SYS_EXIT
+    int 0x80
+
+failed:
+    mov edi, 1
+    mov eax, SYS_EXIT
+    int 0x80
+
+    .section .rodata
+path:
+    .ascii "/bin/hello"
+path_end:
+args:
+    .asciz "world"
+args_end:
+
+    .section .note.GNU-stack, "", @progbits